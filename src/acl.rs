@@ -0,0 +1,145 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Destination access control, so the router only relays traffic to the
+//! networks it is meant to serve instead of to any host of the Internet.
+//!
+//! Besides, unless reflection is allowed, destinations that make the router
+//! useful for reflection and amplification attacks are refused: well-known
+//! ports, broadcast and multicast addresses, and the router itself.
+
+use ipnet::IpNet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+/// Ports below this one are well-known, those of system services
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// Why a destination is refused to avoid reflection attacks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    WellKnownPort,
+    Broadcast,
+    Multicast,
+    OwnAddress,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Refusal::WellKnownPort => "a well-known port",
+            Refusal::Broadcast => "a broadcast address",
+            Refusal::Multicast => "a multicast address",
+            Refusal::OwnAddress => "the router itself",
+        })
+    }
+}
+
+/// Networks packets may and may not be relayed to
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    /// Allowed destination networks. Any destination is allowed if empty.
+    pub allow: Vec<IpNet>,
+    /// Denied destination networks, even if also allowed
+    pub deny: Vec<IpNet>,
+    /// Relay to the destinations refused to avoid reflection attacks
+    pub allow_reflection: bool,
+    /// Addresses of the router. Those unspecified stand for its loopback ones,
+    /// its interface addresses are listed besides.
+    pub own: Vec<SocketAddr>,
+}
+
+impl Acl {
+    /// Whether packets may be relayed to `ip`
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of a dual stack socket show up as mapped addresses
+        let ip = ip.to_canonical();
+
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// Why relaying to `dst` would make the router a reflector, if it would
+    pub fn refusal(&self, dst: SocketAddr) -> Option<Refusal> {
+        if self.allow_reflection {
+            return None;
+        }
+
+        let ip = dst.ip().to_canonical();
+        let local = ip.is_loopback() || ip.is_unspecified();
+        if self
+            .own
+            .iter()
+            .any(|own| own.port() == dst.port() && (own.ip().to_canonical() == ip || local))
+        {
+            Some(Refusal::OwnAddress)
+        } else if dst.port() < FIRST_UNPRIVILEGED_PORT {
+            Some(Refusal::WellKnownPort)
+        } else if ip.is_multicast() {
+            Some(Refusal::Multicast)
+        } else if matches!(ip, IpAddr::V4(ip) if ip.is_broadcast()) {
+            Some(Refusal::Broadcast)
+        } else {
+            None
+        }
+    }
+}
+
+/// Destination networks whose packets are dropped, which unlike the denied
+/// ones can change while the router runs. Shared by every thread.
+#[derive(Debug, Default)]
+pub struct Blackholes {
+    /// How many networks there are, to skip the lock when none
+    count: AtomicUsize,
+    networks: RwLock<Vec<IpNet>>,
+}
+
+impl Blackholes {
+    /// Drops the packets to `net` from now on
+    pub fn add(&self, net: IpNet) {
+        let mut networks = self.networks.write().unwrap();
+        if !networks.contains(&net) {
+            networks.push(net);
+            self.count.store(networks.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Relays the packets to `net` again. Returns whether it was blackholed.
+    pub fn remove(&self, net: IpNet) -> bool {
+        let mut networks = self.networks.write().unwrap();
+        let before = networks.len();
+        networks.retain(|blackholed| *blackholed != net);
+        self.count.store(networks.len(), Ordering::Relaxed);
+        networks.len() < before
+    }
+
+    /// Whether the packets to `ip` are dropped
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+
+        let ip = ip.to_canonical();
+        self.networks
+            .read()
+            .unwrap()
+            .iter()
+            .any(|net| net.contains(&ip))
+    }
+}
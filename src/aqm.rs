@@ -0,0 +1,227 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Active queue management: dropping packets before the queue fills up, so
+//! senders notice the congestion early.
+
+use rand::Rng;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AqmError {
+    #[error("invalid RED parameters {0:?}. Expected MIN,MAX,MAX_P")]
+    InvalidRed(String),
+    #[error("the RED thresholds must satisfy 0 <= min < max, not {0} and {1}")]
+    RedThresholds(f64, f64),
+    #[error("the RED maximum drop probability {0} is not between 0 and 1")]
+    RedProbability(f64),
+}
+
+/// Random Early Detection configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RedParams {
+    /// Average queue length, in packets, from which packets can be dropped
+    pub min_threshold: f64,
+    /// Average queue length, in packets, from which every packet is dropped
+    pub max_threshold: f64,
+    /// Drop probability right below the maximum threshold
+    pub max_p: f64,
+    /// Weight of the current length in the average one
+    pub weight: f64,
+}
+
+/// Weight RED gives by default to the current queue length
+pub const RED_WEIGHT: f64 = 0.002;
+
+impl RedParams {
+    pub fn new(min_threshold: f64, max_threshold: f64, max_p: f64) -> Result<RedParams, AqmError> {
+        if !(0.0 <= min_threshold && min_threshold < max_threshold) {
+            return Err(AqmError::RedThresholds(min_threshold, max_threshold));
+        }
+        if !(0.0..=1.0).contains(&max_p) {
+            return Err(AqmError::RedProbability(max_p));
+        }
+
+        Ok(RedParams {
+            min_threshold,
+            max_threshold,
+            max_p,
+            weight: RED_WEIGHT,
+        })
+    }
+}
+
+/// Parses `MIN,MAX,MAX_P`
+impl FromStr for RedParams {
+    type Err = AqmError;
+
+    fn from_str(s: &str) -> Result<RedParams, AqmError> {
+        let invalid = || AqmError::InvalidRed(s.to_owned());
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match values[..] {
+            [min_threshold, max_threshold, max_p] => {
+                RedParams::new(min_threshold, max_threshold, max_p)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// State of RED for a queue
+#[derive(Clone, Debug)]
+pub struct Red {
+    params: RedParams,
+    average: f64,
+    /// Packets accepted since the last drop while over the minimum threshold
+    count: u64,
+}
+
+impl Red {
+    pub fn new(params: RedParams) -> Red {
+        Red {
+            params,
+            average: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Average queue length, in packets
+    pub fn average(&self) -> f64 {
+        self.average
+    }
+
+    /// Whether to drop a packet arriving at a queue holding `len` packets
+    pub fn early_drop<R: Rng + ?Sized>(&mut self, len: usize, rng: &mut R) -> bool {
+        let RedParams {
+            min_threshold,
+            max_threshold,
+            max_p,
+            weight,
+        } = self.params;
+
+        self.average += weight * (len as f64 - self.average);
+        if self.average < min_threshold {
+            self.count = 0;
+            return false;
+        }
+        if self.average >= max_threshold {
+            self.count = 0;
+            return true;
+        }
+
+        // Spread the drops evenly, as the original algorithm does
+        let p = max_p * (self.average - min_threshold) / (max_threshold - min_threshold);
+        let p = p / (1.0 - self.count as f64 * p).max(p);
+        if rng.gen_bool(p.clamp(0.0, 1.0)) {
+            self.count = 0;
+            true
+        } else {
+            self.count += 1;
+            false
+        }
+    }
+}
+
+/// Backlog below which CoDel never drops, in bytes
+const CODEL_MIN_BACKLOG: usize = 1500;
+
+/// State of CoDel (RFC 8289) for a queue
+#[derive(Clone, Debug)]
+pub struct CoDel {
+    target: Duration,
+    interval: Duration,
+    /// When the sojourn time will have been over the target for an interval
+    first_above_time: Option<Instant>,
+    drop_next: Instant,
+    count: u32,
+    last_count: u32,
+    dropping: bool,
+}
+
+impl CoDel {
+    /// Drops packets once their sojourn time stays over `target` for `interval`
+    pub fn new(target: Duration, interval: Duration, now: Instant) -> CoDel {
+        CoDel {
+            target,
+            interval,
+            first_above_time: None,
+            drop_next: now,
+            count: 0,
+            last_count: 0,
+            dropping: false,
+        }
+    }
+
+    fn control_law(&self, t: Instant) -> Instant {
+        t + self.interval.div_f64(f64::from(self.count).sqrt())
+    }
+
+    fn ok_to_drop(&mut self, sojourn: Duration, backlog: usize, now: Instant) -> bool {
+        if sojourn < self.target || backlog <= CODEL_MIN_BACKLOG {
+            self.first_above_time = None;
+            return false;
+        }
+
+        match self.first_above_time {
+            None => {
+                self.first_above_time = Some(now + self.interval);
+                false
+            }
+            Some(time) => now >= time,
+        }
+    }
+
+    /// Whether to drop a packet leaving a queue of `backlog` bytes, after
+    /// waiting in it for `sojourn`
+    pub fn drop(&mut self, sojourn: Duration, backlog: usize, now: Instant) -> bool {
+        let ok_to_drop = self.ok_to_drop(sojourn, backlog, now);
+
+        if self.dropping {
+            if !ok_to_drop {
+                self.dropping = false;
+                return false;
+            }
+            if now < self.drop_next {
+                return false;
+            }
+            self.count += 1;
+            self.drop_next = self.control_law(self.drop_next);
+            true
+        } else if ok_to_drop {
+            // Resume close to the previous drop rate if it stopped recently
+            self.dropping = true;
+            let delta = self.count.saturating_sub(self.last_count);
+            self.count = if delta > 1
+                && now.saturating_duration_since(self.drop_next) < 16 * self.interval
+            {
+                delta
+            } else {
+                1
+            };
+            self.last_count = self.count;
+            self.drop_next = self.control_law(now);
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -15,20 +15,50 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-const MAX_BUFFER_SIZE: usize = 1500;
-const MAX_BUFFER_CAPACITY: usize = 16 * 1024;
+/// Largest datagram held by default, that of an Ethernet frame
+pub const DEFAULT_BUFFER_SIZE: usize = 1500;
+/// Buffers kept for reuse by default
+pub const DEFAULT_POOL_SIZE: usize = 16 * 1024;
 
 use std::ops::{Deref, DerefMut};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Bytes of memory held by a buffer of `size` bytes
+fn footprint(size: usize) -> usize {
+    std::mem::size_of::<Buffer>() + size
+}
 
-#[derive(Clone)]
+/// Datagram storage. It lives on the heap, so moving a buffer, or a packet
+/// holding it, copies a pointer rather than its contents.
 pub struct Buffer {
-    buf: [u8; MAX_BUFFER_SIZE],
+    buf: Box<[u8]>,
     len: usize,
+    usage: Option<Arc<AtomicUsize>>, // Shared count of bytes held by buffers
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl Buffer {
-    fn get_mut(&mut self) -> &mut [u8; MAX_BUFFER_SIZE] {
+    /// Creates a buffer for datagrams of up to `size` bytes
+    pub fn with_size(size: usize) -> Buffer {
+        Buffer {
+            buf: vec![0; size].into_boxed_slice(),
+            len: size,
+            usage: None,
+        }
+    }
+
+    fn tracked(usage: Arc<AtomicUsize>, size: usize) -> Buffer {
+        usage.fetch_add(footprint(size), Ordering::Relaxed);
+
+        let mut buffer = Buffer::with_size(size);
+        buffer.usage = Some(usage);
+        buffer
+    }
+
+    fn get_mut(&mut self) -> &mut [u8] {
         &mut self.buf
     }
 
@@ -43,13 +73,37 @@ impl Buffer {
     pub fn len(&self) -> usize {
         self.len
     }
+
+    /// Largest length the buffer can hold
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
 }
 
 impl Default for Buffer {
     fn default() -> Buffer {
+        Buffer::with_size(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+impl Clone for Buffer {
+    fn clone(&self) -> Buffer {
+        if let Some(usage) = &self.usage {
+            usage.fetch_add(footprint(self.buf.len()), Ordering::Relaxed);
+        }
+
         Buffer {
-            buf: [0; MAX_BUFFER_SIZE],
-            len: MAX_BUFFER_SIZE,
+            buf: self.buf.clone(),
+            len: self.len,
+            usage: self.usage.clone(),
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(usage) = &self.usage {
+            usage.fetch_sub(footprint(self.buf.len()), Ordering::Relaxed);
         }
     }
 }
@@ -82,29 +136,79 @@ impl DerefMut for Buffer {
 
 pub struct BufferPool {
     queue: Vec<Buffer>,
+    usage: Option<Arc<AtomicUsize>>,
+    budget: Option<usize>,
+    capacity: usize,
+    buffer_size: usize,
 }
 
 impl BufferPool {
+    /// Creates a pool whose buffers account their memory in `usage`. Once it
+    /// grows over `budget` bytes recycled buffers are freed instead of kept.
+    pub fn new(usage: Arc<AtomicUsize>, budget: Option<usize>) -> BufferPool {
+        BufferPool {
+            usage: Some(usage),
+            budget,
+            ..BufferPool::default()
+        }
+    }
+
+    /// Keeps at most `capacity` buffers for reuse. Those recycled beyond it
+    /// are freed.
+    pub fn with_capacity(mut self, capacity: usize) -> BufferPool {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Hands out buffers for datagrams of up to `size` bytes
+    pub fn with_buffer_size(mut self, size: usize) -> BufferPool {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Buffers held for reuse
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
     pub fn get_buffer(&mut self) -> Buffer {
-        if self.queue.is_empty() {
-            Buffer::default()
-        } else {
-            self.queue.pop().unwrap()
+        match self.queue.pop() {
+            Some(buffer) => buffer,
+            None => match &self.usage {
+                Some(usage) => Buffer::tracked(usage.clone(), self.buffer_size),
+                None => Buffer::with_size(self.buffer_size),
+            },
         }
     }
 
     pub fn recycle_buffer(&mut self, mut buffer: Buffer) {
-        buffer.set_len(MAX_BUFFER_SIZE);
-        if self.queue.len() <= MAX_BUFFER_CAPACITY {
+        buffer.set_len(buffer.capacity());
+        if self.queue.len() < self.capacity && !self.over_budget() {
             self.queue.push(buffer)
         }
     }
+
+    /// Whether the memory held by the buffers exceeds the configured budget
+    pub fn over_budget(&self) -> bool {
+        match (&self.usage, self.budget) {
+            (Some(usage), Some(budget)) => usage.load(Ordering::Relaxed) > budget,
+            _ => false,
+        }
+    }
 }
 
 impl Default for BufferPool {
     fn default() -> BufferPool {
         BufferPool {
             queue: Vec::with_capacity(1024),
+            usage: None,
+            budget: None,
+            capacity: DEFAULT_POOL_SIZE,
+            buffer_size: DEFAULT_BUFFER_SIZE,
         }
     }
 }
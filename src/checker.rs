@@ -0,0 +1,155 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Assignment checking: passive validation of the packets students send.
+//!
+//! Every packet is checked against a set of rules and the violations are
+//! tallied per source address, keeping a few examples of each, so the
+//! protocol conformance of every student can be graded from the report.
+
+use crate::packet::{self, Header};
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+/// Examples kept for every kind of violation and source
+const MAX_EXAMPLES: usize = 3;
+/// Bytes shown of every example packet
+const EXAMPLE_BYTES: usize = 16;
+
+/// What a conforming packet looks like
+#[derive(Clone, Debug, Default)]
+pub struct CheckRules {
+    /// Allowed destination networks. Any destination is allowed if empty.
+    pub allow: Vec<IpNet>,
+    /// Minimum size in bytes, header included
+    pub min_size: Option<usize>,
+    /// Maximum size in bytes, header included
+    pub max_size: Option<usize>,
+}
+
+#[derive(Default)]
+struct Violations {
+    count: usize,
+    examples: Vec<String>,
+}
+
+#[derive(Default)]
+struct SourceReport {
+    packets: usize,
+    violations: BTreeMap<&'static str, Violations>,
+}
+
+/// Collects the violations of every source. It can be shared among threads.
+pub struct Checker {
+    rules: CheckRules,
+    reports: Mutex<BTreeMap<IpAddr, SourceReport>>,
+}
+
+impl Checker {
+    pub fn new(rules: CheckRules) -> Checker {
+        Checker {
+            rules,
+            reports: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn violations(&self, data: &[u8]) -> Vec<(&'static str, String)> {
+        let mut found = Vec::new();
+
+        match Header::decode(data) {
+            Err(e) => found.push(("malformed header", e.to_string())),
+            Ok(header) => {
+                let dst = header.addr();
+                if let Err(e) = packet::check_dst(&dst) {
+                    found.push(("invalid destination", e.to_string()));
+                } else if !self.rules.allow.is_empty()
+                    && !self.rules.allow.iter().any(|net| net.contains(&dst.ip()))
+                {
+                    found.push(("destination not allowed", format!("destination {dst}")));
+                }
+            }
+        }
+        if self.rules.min_size.is_some_and(|min| data.len() < min) {
+            found.push(("too short", format!("{} bytes", data.len())));
+        }
+        if self.rules.max_size.is_some_and(|max| data.len() > max) {
+            found.push(("too long", format!("{} bytes", data.len())));
+        }
+
+        found
+    }
+
+    /// Checks a packet received from `src`
+    pub fn check(&self, src: SocketAddr, data: &[u8]) {
+        let found = self.violations(data);
+        let mut reports = self.reports.lock().unwrap();
+        let report = reports.entry(src.ip()).or_default();
+
+        report.packets += 1;
+        for (kind, detail) in found {
+            let violations = report.violations.entry(kind).or_default();
+            violations.count += 1;
+            if violations.examples.len() < MAX_EXAMPLES {
+                let bytes: Vec<_> = data
+                    .iter()
+                    .take(EXAMPLE_BYTES)
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+                let ellipsis = if data.len() > EXAMPLE_BYTES {
+                    "…"
+                } else {
+                    ""
+                };
+                violations.examples.push(format!(
+                    "from port {}, {detail}: {}{ellipsis}",
+                    src.port(),
+                    bytes.join(" ")
+                ));
+            }
+        }
+    }
+
+    /// Writes the per source report
+    pub fn write_report(&self, out: &mut impl Write) -> io::Result<()> {
+        let reports = self.reports.lock().unwrap();
+
+        writeln!(out, "Assignment check report")?;
+        for (src, report) in reports.iter() {
+            let wrong: usize = report.violations.values().map(|v| v.count).sum();
+            writeln!(
+                out,
+                "\n{src}: {} packets, {}",
+                report.packets,
+                if wrong == 0 {
+                    "all correct".to_owned()
+                } else {
+                    format!("{wrong} violations")
+                }
+            )?;
+            for (kind, violations) in &report.violations {
+                writeln!(out, "  {kind}: {}", violations.count)?;
+                for example in &violations.examples {
+                    writeln!(out, "    {example}")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
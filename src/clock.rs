@@ -0,0 +1,174 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Sources of the current time, so the scheduling of packets can run on a
+//! clock other than the system one.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Tells the time to the router
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Blocks until `deadline`, returning at once if it is already past
+    fn sleep_until(&self, deadline: Instant) {
+        if let Some(wait) = deadline.checked_duration_since(self.now()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// The monotonic clock of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Sleeping on it advances it to the
+/// deadline without waiting.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    /// Starts at `start`
+    pub fn new(start: Instant) -> MockClock {
+        MockClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Moves the clock to `instant`, unless it is already later
+    pub fn advance_to(&self, instant: Instant) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(instant);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new(Instant::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        self.advance_to(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+    use crate::packet::{self, Packet};
+    use crate::profile::{Profile, SharedProfile};
+    use crate::queue::Queue;
+    use crate::schedule::Schedule;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn packet(clock: &MockClock, port: u16, delay: Duration) -> Packet {
+        let mut data = Buffer::with_size(16);
+        packet::put_addr(&mut data, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000).into();
+        let now = clock.now();
+
+        Packet::create(src, data, now, now + delay).unwrap()
+    }
+
+    #[test]
+    fn sleeping_advances_the_mock_clock() {
+        let start = Instant::now();
+        let clock = MockClock::new(start);
+
+        clock.sleep_until(start + Duration::from_secs(3600));
+        assert_eq!(clock.now(), start + Duration::from_secs(3600));
+
+        // It never goes back
+        clock.sleep_until(start);
+        assert_eq!(clock.now(), start + Duration::from_secs(3600));
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(clock.now(), start + Duration::from_millis(3_600_005));
+    }
+
+    #[test]
+    fn packets_leave_in_exit_time_order() {
+        let clock = MockClock::default();
+        let mut queue = Queue::new();
+        queue.push(packet(&clock, 6001, Duration::from_millis(30)));
+        queue.push(packet(&clock, 6002, Duration::from_millis(10)));
+        clock.advance(Duration::from_millis(5));
+        queue.push(packet(&clock, 6003, Duration::from_millis(10)));
+
+        assert!(queue.pop_due(clock.now()).is_none());
+
+        let mut ports = Vec::new();
+        while let Some(exit) = queue.peek().map(Packet::exit_time) {
+            clock.sleep_until(exit);
+            let packet = queue.pop_due(clock.now()).unwrap();
+            assert_eq!(packet.exit_time(), clock.now());
+            ports.push(packet.dst().port());
+        }
+        assert_eq!(ports, [6002, 6003, 6001]);
+    }
+
+    #[test]
+    fn schedule_applies_changes_when_due() {
+        let base = Profile::new(0.0, 10, 0).unwrap();
+        let schedule = Schedule::new(
+            &base,
+            [
+                (Duration::from_secs(60), "drop=0.2"),
+                (Duration::from_secs(120), "min_delay=300"),
+            ],
+        )
+        .unwrap();
+        let profile = SharedProfile::new(base);
+        let clock = MockClock::default();
+        let start = clock.now();
+
+        clock.advance(Duration::from_secs(59));
+        schedule.apply(start, clock.now(), &profile);
+        assert_eq!(profile.load().0.drop(), 0.0);
+
+        clock.sleep_until(schedule.next(start).unwrap());
+        schedule.apply(start, clock.now(), &profile);
+        assert_eq!(profile.load().0.drop(), 0.2);
+        assert_eq!(profile.load().0.min_delay(), 10);
+
+        // A late check applies the latest change, on top of the earlier ones
+        clock.advance(Duration::from_secs(600));
+        schedule.apply(start, clock.now(), &profile);
+        assert_eq!(profile.load().0.drop(), 0.2);
+        assert_eq!(profile.load().0.min_delay(), 300);
+        assert_eq!(schedule.next(start), None);
+    }
+}
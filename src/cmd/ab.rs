@@ -0,0 +1,246 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use clap::Args;
+use log::{debug, warn};
+use rand::distributions::{Bernoulli, Distribution};
+use shufflerouter::buffer::{Buffer, BufferPool};
+use shufflerouter::packet::{self, Packet};
+use shufflerouter::profile::{DelayDistribution, Profile};
+use shufflerouter::queue::Queue;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct AbArgs {
+    /// Listening port
+    #[clap(short = 'p', long = "port", default_value = "2021")]
+    port: u16,
+
+    /// Impairments of the first copy, e.g. "drop=0.1 min_delay=20 rand_delay=10"
+    #[clap(long = "a")]
+    a: Profile,
+
+    /// Impairments of the second copy
+    #[clap(long = "b")]
+    b: Profile,
+
+    /// Destination of the second copy [default: that of the packet, on the next port]
+    #[clap(long = "b-dest")]
+    b_dest: Option<SocketAddrV4>,
+
+    /// Stop after this many seconds [default: on Ctrl-C]
+    #[clap(long = "duration")]
+    duration: Option<f64>,
+}
+
+/// One of the compared paths
+struct Arm {
+    profile: Profile,
+    drop: Bernoulli,
+    delay: DelayDistribution,
+    queue: Queue,
+    received: u64,
+    delivered: u64,
+    total_delay: Duration,
+    reordered: u64,
+    /// Latest exit time scheduled for every flow
+    last_exit: HashMap<(SocketAddrV4, SocketAddr), Instant>,
+}
+
+impl Arm {
+    fn new(profile: Profile) -> Arm {
+        Arm {
+            drop: profile.drop_distribution(),
+            delay: profile.delay_distribution(),
+            profile,
+            queue: Queue::new(),
+            received: 0,
+            delivered: 0,
+            total_delay: Duration::ZERO,
+            reordered: 0,
+            last_exit: HashMap::new(),
+        }
+    }
+
+    /// Takes a copy of a datagram received from `src`, already addressed to
+    /// this arm's destination
+    fn admit(&mut self, src: SocketAddrV4, data: Buffer, arrival: Instant) -> Option<Buffer> {
+        self.received += 1;
+        if self.drop.sample(&mut rand::thread_rng()) {
+            return Some(data);
+        }
+
+        let delay = Duration::from_millis(self.delay.sample(&mut rand::thread_rng()));
+        let packet = match Packet::create(src.into(), data, arrival, arrival + delay) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Ignoring packet from {}: {}", src, e);
+                return None;
+            }
+        };
+
+        let exit = packet.exit_time();
+        let last = self.last_exit.entry((src, packet.dst())).or_insert(exit);
+        if exit < *last {
+            self.reordered += 1;
+        }
+        *last = exit.max(*last);
+
+        self.delivered += 1;
+        self.total_delay += delay;
+        self.queue.push(packet);
+        None
+    }
+
+    fn flush(&mut self, socket: &UdpSocket, buffer_pool: &mut BufferPool, now: Instant) {
+        while self.queue.peek().is_some_and(|p| p.exit_time() <= now) {
+            let packet = self.queue.pop().unwrap();
+            if let Err(e) = socket.send_to(packet.get(), packet.dst()) {
+                warn!("Error transmitting to {}: {}", packet.dst(), e);
+            }
+            buffer_pool.recycle_buffer(packet.into());
+        }
+    }
+}
+
+/// Flag set on Ctrl-C
+fn interrupted() -> Result<Arc<AtomicBool>> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let set = flag.clone();
+    thread::spawn(move || {
+        if runtime.block_on(tokio::signal::ctrl_c()).is_ok() {
+            set.store(true, Ordering::Relaxed);
+        }
+    });
+
+    Ok(flag)
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / total as f64
+    }
+}
+
+pub fn run(args: AbArgs) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port))?;
+    let stop = interrupted()?;
+    let deadline = args
+        .duration
+        .map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+    let mut buffer_pool = BufferPool::default();
+    let mut arms = [Arm::new(args.a), Arm::new(args.b)];
+
+    while !stop.load(Ordering::Relaxed) && deadline.is_none_or(|deadline| Instant::now() < deadline)
+    {
+        let now = Instant::now();
+        for arm in &mut arms {
+            arm.flush(&socket, &mut buffer_pool, now);
+        }
+
+        let timeout = arms
+            .iter()
+            .filter_map(|arm| arm.queue.peek())
+            .map(|p| p.exit_time().saturating_duration_since(now))
+            .min()
+            .unwrap_or(Duration::from_millis(100))
+            .clamp(Duration::from_millis(1), Duration::from_millis(100));
+        socket.set_read_timeout(Some(timeout))?;
+
+        let mut buffer = buffer_pool.get_buffer();
+        let (len, src) = match socket.recv_from(&mut buffer) {
+            Ok((len, SocketAddr::V4(src))) => (len, src),
+            Ok(_) => continue,
+            Err(_) => {
+                buffer_pool.recycle_buffer(buffer);
+                continue;
+            }
+        };
+        buffer.set_len(len);
+        let arrival = Instant::now();
+
+        let dst = match packet::get_dst(&buffer) {
+            Ok(SocketAddr::V4(dst)) => dst,
+            Ok(dst) => {
+                debug!("Ignoring packet from {} to IPv6 destination {}", src, dst);
+                buffer_pool.recycle_buffer(buffer);
+                continue;
+            }
+            Err(e) => {
+                debug!("Ignoring packet from {}: {}", src, e);
+                buffer_pool.recycle_buffer(buffer);
+                continue;
+            }
+        };
+        let b_dst = args
+            .b_dest
+            .unwrap_or_else(|| SocketAddrV4::new(*dst.ip(), dst.port().wrapping_add(1)));
+
+        let mut copy = buffer.clone();
+        packet::put_addr(&mut copy, b_dst);
+        for (arm, data) in arms.iter_mut().zip([buffer, copy]) {
+            if let Some(dropped) = arm.admit(src, data, arrival) {
+                buffer_pool.recycle_buffer(dropped);
+            }
+        }
+    }
+
+    let ms = |arm: &Arm| {
+        if arm.delivered == 0 {
+            0.0
+        } else {
+            arm.total_delay.as_secs_f64() * 1000.0 / arm.delivered as f64
+        }
+    };
+    let [a, b] = &arms;
+    println!("\n{:<12} {:>36} {:>36}", "", "A", "B");
+    println!(
+        "{:<12} {:>36} {:>36}",
+        "profile",
+        a.profile.to_string(),
+        b.profile.to_string()
+    );
+    println!("{:<12} {:>36} {:>36}", "received", a.received, b.received);
+    println!(
+        "{:<12} {:>35.2}% {:>35.2}%",
+        "delivered",
+        percent(a.delivered, a.received),
+        percent(b.delivered, b.received)
+    );
+    println!("{:<12} {:>33.3} ms {:>33.3} ms", "mean delay", ms(a), ms(b));
+    println!(
+        "{:<12} {:>35.2}% {:>35.2}%",
+        "reordered",
+        percent(a.reordered, a.delivered),
+        percent(b.reordered, b.delivered)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::measure;
+use anyhow::{ensure, Result};
+use clap::Args;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Router under test, as HOST:PORT
+    #[clap(long = "target")]
+    target: String,
+
+    /// Echo endpoint answering the probes. Without it the router reflects
+    /// them back to this client
+    #[clap(long = "dest")]
+    dest: Option<SocketAddrV4>,
+
+    /// Packets per second offered in the first step
+    #[clap(long = "start-rate", default_value = "100")]
+    start_rate: f64,
+
+    /// Highest packet rate to try
+    #[clap(long = "max-rate", default_value = "100000")]
+    max_rate: f64,
+
+    /// Rate increase between steps
+    #[clap(long = "factor", default_value = "2")]
+    factor: f64,
+
+    /// Duration of every step, in milliseconds
+    #[clap(long = "step", default_value = "2000")]
+    step: u64,
+
+    /// Packet size in bytes, header included
+    #[clap(long = "size", default_value = "64")]
+    size: usize,
+
+    /// Extra loss, over that of the first step, tolerated before saturation
+    #[clap(long = "max-loss", default_value = "0.01")]
+    max_loss: f64,
+
+    /// Extra median latency, over that of the first step, tolerated before
+    /// saturation, in milliseconds
+    #[clap(long = "max-latency", default_value = "10")]
+    max_latency: u64,
+}
+
+pub fn run(args: BenchArgs) -> Result<()> {
+    ensure!(args.factor > 1.0, "the rate factor must be greater than 1");
+    ensure!(args.start_rate > 0.0, "the start rate must be positive");
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mut baseline: Option<(f64, Duration)> = None;
+    let mut knee = None;
+    let mut rate = args.start_rate;
+
+    println!(
+        "{:>10} {:>8} {:>10} {:>10}",
+        "rate(pps)", "loss(%)", "p50(ms)", "p99(ms)"
+    );
+    while rate <= args.max_rate {
+        let count = (rate * args.step as f64 / 1000.0).ceil() as u64;
+        let m = measure::probe(
+            &args.target,
+            args.dest,
+            count,
+            rate,
+            args.size,
+            Duration::from_secs(1),
+        )?;
+        let (p50, p99) = (m.percentile(0.5), m.percentile(0.99));
+        println!(
+            "{:>10.0} {:>8.2} {:>10.3} {:>10.3}",
+            rate,
+            100.0 * m.loss(),
+            p50.map_or(f64::NAN, ms),
+            p99.map_or(f64::NAN, ms)
+        );
+
+        let p50 = p50.unwrap_or(Duration::MAX);
+        let (base_loss, base_p50) = *baseline.get_or_insert((m.loss(), p50));
+        if m.loss() > base_loss + args.max_loss
+            || p50.saturating_sub(base_p50) > Duration::from_millis(args.max_latency)
+        {
+            break;
+        }
+        knee = Some(rate);
+        rate *= args.factor;
+    }
+
+    match knee {
+        Some(knee) if rate > args.max_rate => println!(
+            "No saturation up to {:.0} pps, the highest rate tried",
+            knee
+        ),
+        Some(knee) => println!(
+            "Knee point: {:.0} pps sustained, saturated at {:.0} pps",
+            knee, rate
+        ),
+        None => println!("The router is saturated even at {:.0} pps", rate),
+    }
+
+    Ok(())
+}
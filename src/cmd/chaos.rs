@@ -0,0 +1,95 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{ensure, Result};
+use clap::Args;
+use log::info;
+use rand::Rng;
+use shufflerouter::profile::{Profile, SharedProfile};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct ChaosArgs {
+    /// Highest drop probability to set
+    #[clap(long = "max-drop", default_value = "0.3")]
+    max_drop: f64,
+
+    /// Highest minimum delay to set, in milliseconds
+    #[clap(long = "max-delay", default_value = "500")]
+    max_delay: u64,
+
+    /// Highest delay randomness to set, in milliseconds
+    #[clap(long = "max-rand-delay", default_value = "500")]
+    max_rand_delay: u64,
+
+    /// Shortest time between changes, in seconds
+    #[clap(long = "min-interval", default_value = "5")]
+    min_interval: f64,
+
+    /// Longest time between changes, in seconds
+    #[clap(long = "max-interval", default_value = "30")]
+    max_interval: f64,
+}
+
+/// Starts changing `profile` at random until `shutdown` is set
+pub(crate) fn spawn(
+    args: ChaosArgs,
+    profile: Arc<SharedProfile>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    ensure!(
+        (0.0..=1.0).contains(&args.max_drop),
+        "the maximum drop probability must be between 0 and 1"
+    );
+    ensure!(
+        args.min_interval > 0.0 && args.min_interval <= args.max_interval,
+        "the change interval must be positive and its minimum not above its maximum"
+    );
+    ensure!(
+        args.max_delay.checked_add(args.max_rand_delay).is_some(),
+        "the maximum delays are too large"
+    );
+
+    Ok(thread::Builder::new().name("chaos".into()).spawn(move || {
+        let mut rng = rand::thread_rng();
+
+        loop {
+            let wait = rng.gen_range(args.min_interval..=args.max_interval);
+            let next = Instant::now() + Duration::from_secs_f64(wait);
+            while Instant::now() < next {
+                if shutdown.load(Ordering::Relaxed) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(100).min(next - Instant::now()));
+            }
+
+            let new = Profile::new(
+                rng.gen_range(0.0..=args.max_drop),
+                rng.gen_range(0..=args.max_delay),
+                rng.gen_range(0..=args.max_rand_delay),
+            )
+            .unwrap(); // Bounds checked above
+            info!("Chaos: impairments changed to {}", new);
+            profile.set(new);
+        }
+    })?)
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use log::info;
+use shufflerouter::packet::Header;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+#[derive(Subcommand, Debug)]
+pub enum ClientCommand {
+    /// Send a datagram to a destination through the router
+    Send(SendArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct SendArgs {
+    /// Router address, as HOST:PORT
+    #[clap(long = "router")]
+    router: String,
+
+    /// Final destination of the datagram, as IP:PORT or [IPv6]:PORT
+    #[clap(long = "dest")]
+    dest: SocketAddr,
+
+    /// Payload to send after the header
+    #[clap(long = "payload", default_value = "")]
+    payload: String,
+
+    /// Wait for replies during this many milliseconds, printing them
+    #[clap(long = "wait")]
+    wait: Option<u64>,
+}
+
+pub fn run(command: ClientCommand) -> Result<()> {
+    match command {
+        ClientCommand::Send(args) => send(args),
+    }
+}
+
+/// Resolves `router` and binds a socket of the same family to talk to it
+pub(crate) fn connect(router: &str) -> Result<(UdpSocket, SocketAddr)> {
+    let router = router
+        .to_socket_addrs()
+        .with_context(|| format!("could not resolve {router}"))?
+        .next()
+        .with_context(|| format!("no address found for {router}"))?;
+
+    let local = match router {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    Ok((UdpSocket::bind(local)?, router))
+}
+
+/// Builds a datagram for the router: the destination header plus `payload`
+pub(crate) fn datagram(dest: impl Into<SocketAddr>, payload: &[u8]) -> Vec<u8> {
+    let mut data = Header::new(dest).encode();
+    data.extend_from_slice(payload);
+
+    data
+}
+
+fn send(args: SendArgs) -> Result<()> {
+    let (socket, router) = connect(&args.router)?;
+
+    let data = datagram(args.dest, args.payload.as_bytes());
+    let len = socket.send_to(&data, router)?;
+    println!(
+        "Sent {} bytes to {} through {} from {}",
+        len,
+        args.dest,
+        router,
+        socket.local_addr()?
+    );
+
+    if let Some(wait) = args.wait {
+        let deadline = Instant::now() + Duration::from_millis(wait);
+        let mut buf = [0; 65536];
+
+        while let Some(timeout) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|timeout| !timeout.is_zero())
+        {
+            socket.set_read_timeout(Some(timeout))?;
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match Header::decode(&buf[..len]) {
+                Ok(origin) => println!(
+                    "Received {} bytes from {} via {}: {}",
+                    len,
+                    origin.addr(),
+                    from,
+                    String::from_utf8_lossy(&buf[origin.encoded_len()..len])
+                ),
+                Err(e) => info!("Ignoring {} bytes from {}: {}", len, from, e),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use clap::Args;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct DissectorArgs {
+    /// Port the router listens on
+    #[clap(long = "port", default_value = "2021")]
+    port: u16,
+
+    /// File to write the dissector to [default: standard output]
+    #[clap(long = "output")]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: DissectorArgs) -> Result<()> {
+    let dissector =
+        include_str!("dissector/shufflerouter.lua").replace("@PORT@", &args.port.to_string());
+
+    match args.output {
+        Some(path) => {
+            std::fs::write(&path, dissector)?;
+            println!("Wrote {}", path.display());
+        }
+        None => print!("{dissector}"),
+    }
+
+    Ok(())
+}
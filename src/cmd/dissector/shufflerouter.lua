@@ -0,0 +1,51 @@
+-- Wireshark dissector for the ShuffleRouter header
+--
+-- Copy it to the Wireshark personal plugins directory (see Help > About >
+-- Folders) or load it with `wireshark -X lua_script:shufflerouter.lua`.
+
+local proto = Proto("shufflerouter", "ShuffleRouter")
+
+local ROUTER_PORT = @PORT@
+
+local f_dst_addr = ProtoField.ipv4("shufflerouter.dst.addr", "Destination address")
+local f_dst_port = ProtoField.uint16("shufflerouter.dst.port", "Destination port")
+local f_src_addr = ProtoField.ipv4("shufflerouter.src.addr", "Origin address")
+local f_src_port = ProtoField.uint16("shufflerouter.src.port", "Origin port")
+local f_query = ProtoField.string("shufflerouter.query", "Status query")
+local f_payload = ProtoField.bytes("shufflerouter.payload", "Payload")
+
+proto.fields = { f_dst_addr, f_dst_port, f_src_addr, f_src_port, f_query, f_payload }
+
+function proto.dissector(buffer, pinfo, tree)
+    pinfo.cols.protocol = proto.name
+    local subtree = tree:add(proto, buffer(), "ShuffleRouter")
+
+    if buffer:len() == 2 and buffer(0, 2):string() == "S?" then
+        subtree:add(f_query, buffer(0, 2))
+        pinfo.cols.info = "Status query"
+        return
+    end
+
+    if buffer:len() < 6 then
+        subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Shorter than the 6 byte header")
+        return
+    end
+
+    -- Packets sent to the router carry their destination, those leaving it
+    -- the address of their origin
+    if pinfo.dst_port == ROUTER_PORT then
+        subtree:add(f_dst_addr, buffer(0, 4))
+        subtree:add(f_dst_port, buffer(4, 2))
+        pinfo.cols.info = string.format("To %s:%d", tostring(buffer(0, 4):ipv4()), buffer(4, 2):uint())
+    else
+        subtree:add(f_src_addr, buffer(0, 4))
+        subtree:add(f_src_port, buffer(4, 2))
+        pinfo.cols.info = string.format("From %s:%d", tostring(buffer(0, 4):ipv4()), buffer(4, 2):uint())
+    end
+
+    if buffer:len() > 6 then
+        subtree:add(f_payload, buffer(6))
+    end
+end
+
+DissectorTable.get("udp.port"):add(ROUTER_PORT, proto)
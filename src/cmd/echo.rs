@@ -0,0 +1,66 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use clap::Args;
+use log::{info, warn};
+use shufflerouter::{net, packet};
+use std::io;
+
+#[derive(Args, Debug)]
+pub struct EchoArgs {
+    /// Listening port
+    #[clap(short = 'p', long = "port")]
+    port: u16,
+}
+
+/// Answers every datagram forwarded by a router back to its origin, through
+/// the same router
+pub fn run(args: EchoArgs) -> Result<()> {
+    let socket = net::bind(None, args.port)?;
+    info!("Echoing datagrams on {}", socket.local_addr()?);
+
+    let mut buf = [0; 65536];
+    loop {
+        let (len, router) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            // Pending ICMP error from a previous reply
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+            Err(e) => {
+                warn!("Error while reading datagram: {}", e);
+                continue;
+            }
+        };
+
+        // The router put the address of the original sender in the header, so
+        // sending the datagram back unchanged returns it to that sender
+        match packet::get_dst(&buf[..len]) {
+            Ok(origin) => {
+                info!(
+                    "Echoing {} bytes from {} via {}",
+                    len,
+                    origin,
+                    net::canonical(router)
+                );
+                if let Err(e) = socket.send_to(&buf[..len], router) {
+                    warn!("Could not reply to {}: {}", router, e);
+                }
+            }
+            Err(e) => warn!("Ignoring {} bytes from {}: {}", len, router, e),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::client;
+use anyhow::{bail, ensure, Result};
+use clap::Args;
+use log::debug;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct FuzzclientArgs {
+    /// Router under test, as HOST:PORT
+    #[clap(long = "router")]
+    router: String,
+
+    /// Time to wait for the packets of every case, in milliseconds
+    #[clap(long = "timeout", default_value = "300")]
+    timeout: u64,
+}
+
+/// Marks the payload of the test packets
+const TAG: &[u8] = b"FUZZ";
+
+/// What a conforming router does with a test packet
+enum Expect {
+    /// Forward it with the header rewritten and the payload intact
+    Forward,
+    /// Either forward it intact or drop it
+    MayForward,
+    /// Drop it. Forwarding is only reported as a warning if `required` is false.
+    Reject { required: bool },
+}
+
+struct Case {
+    name: String,
+    data: Vec<u8>,
+    expect: Expect,
+}
+
+/// Recognizable payload of `len` bytes for case `id`
+fn payload(id: usize, len: usize) -> Vec<u8> {
+    TAG.iter()
+        .chain(&(id as u16).to_be_bytes())
+        .copied()
+        .chain((0..).map(|i: usize| i as u8))
+        .take(len)
+        .collect()
+}
+
+fn cases(me: SocketAddrV4) -> Vec<Case> {
+    let mut cases = Vec::new();
+
+    for len in 0..6 {
+        cases.push(Case {
+            name: format!("{len} bytes, shorter than the header"),
+            data: client::datagram(me, &[])[..len].to_vec(),
+            expect: Expect::Reject { required: true },
+        });
+    }
+
+    for len in [6, 7, 1000, 1472, 1500] {
+        cases.push(Case {
+            name: format!("{len} bytes"),
+            data: client::datagram(me, &payload(cases.len(), len - 6)),
+            expect: Expect::Forward,
+        });
+    }
+    for len in [1501, 9000, 65507] {
+        cases.push(Case {
+            name: format!("{len} bytes, above the usual MTU"),
+            data: client::datagram(me, &payload(cases.len(), len - 6)),
+            expect: Expect::MayForward,
+        });
+    }
+
+    for (name, ip, port) in [
+        ("port zero", *me.ip(), 0),
+        ("the unspecified address", Ipv4Addr::UNSPECIFIED, me.port()),
+        ("the broadcast address", Ipv4Addr::BROADCAST, me.port()),
+        ("a reserved address", Ipv4Addr::new(240, 0, 0, 1), me.port()),
+        (
+            "a multicast address",
+            Ipv4Addr::new(224, 0, 0, 1),
+            me.port(),
+        ),
+    ] {
+        cases.push(Case {
+            name: format!("destination with {name}"),
+            data: client::datagram(SocketAddrV4::new(ip, port), &payload(cases.len(), 16)),
+            expect: Expect::Reject { required: false },
+        });
+    }
+
+    cases
+}
+
+/// Collects the datagrams arriving during `window`
+fn receive(socket: &UdpSocket, window: Duration) -> Result<Vec<Vec<u8>>> {
+    let deadline = Instant::now() + window;
+    let mut buf = vec![0; 65536];
+    let mut received = Vec::new();
+
+    while let Some(timeout) = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|timeout| !timeout.is_zero())
+    {
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buf) {
+            Ok(len) => received.push(buf[..len].to_vec()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            // ICMP errors from the router host, if it is down
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                bail!("the router is not running: {}", e)
+            }
+            Err(e) => debug!("Error while receiving: {}", e),
+        }
+    }
+
+    Ok(received)
+}
+
+/// Checks that the router still forwards a valid packet
+fn alive(socket: &UdpSocket, me: SocketAddrV4, window: Duration) -> Result<bool> {
+    let ping = client::datagram(me, b"PING");
+    for _ in 0..3 {
+        socket.send(&ping)?;
+        if receive(socket, window)?.contains(&ping) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+pub fn run(args: FuzzclientArgs) -> Result<()> {
+    let (socket, router) = client::connect(&args.router)?;
+    socket.connect(router)?;
+    let me = match socket.local_addr()? {
+        SocketAddr::V4(me) => me,
+        SocketAddr::V6(_) => bail!("the router must be reachable over IPv4"),
+    };
+    let window = Duration::from_millis(args.timeout);
+
+    ensure!(
+        alive(&socket, me, window)?,
+        "the router does not forward valid packets. Is it running without impairments?"
+    );
+
+    let (mut failures, mut warnings) = (0, 0);
+    for case in cases(me) {
+        if let Err(e) = socket.send(&case.data) {
+            println!("[SKIP] {}: could not send it: {}", case.name, e);
+            continue;
+        }
+        let received = receive(&socket, window)?;
+
+        // The router rewrites the header with our own address
+        let mut expected = case.data.clone();
+        if expected.len() >= 6 {
+            client::datagram(me, &[])
+                .iter()
+                .enumerate()
+                .for_each(|(i, b)| expected[i] = *b);
+        }
+        let intact = received.iter().filter(|d| **d == expected).count();
+        let altered = received.len() - intact;
+        let truncated = received
+            .iter()
+            .find(|d| d.len() < expected.len() && expected.starts_with(d));
+
+        let (verdict, detail) = match case.expect {
+            _ if truncated.is_some() => (
+                "FAIL",
+                format!("forwarded truncated to {} bytes", truncated.unwrap().len()),
+            ),
+            _ if altered > 0 => ("FAIL", format!("{altered} altered datagrams received")),
+            _ if intact > 1 => ("FAIL", format!("{intact} copies received")),
+            Expect::Forward if intact == 0 => ("WARN", "not forwarded".to_owned()),
+            Expect::Forward | Expect::MayForward => (" OK ", format!("{intact} forwarded")),
+            Expect::Reject { .. } if intact == 0 => (" OK ", "rejected".to_owned()),
+            Expect::Reject { required: true } => ("FAIL", "forwarded".to_owned()),
+            Expect::Reject { required: false } => ("WARN", "forwarded".to_owned()),
+        };
+        println!("[{}] {}: {}", verdict, case.name, detail);
+        match verdict {
+            "FAIL" => failures += 1,
+            "WARN" => warnings += 1,
+            _ => (),
+        }
+
+        if !alive(&socket, me, window)? {
+            bail!("the router stopped forwarding after: {}", case.name);
+        }
+    }
+
+    println!("{failures} failures, {warnings} warnings");
+    ensure!(failures == 0, "the router misbehaved");
+
+    Ok(())
+}
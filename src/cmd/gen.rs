@@ -0,0 +1,111 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::client;
+use anyhow::{ensure, Result};
+use clap::Args;
+use log::warn;
+use std::net::SocketAddrV4;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct GenArgs {
+    /// Router address, as HOST:PORT
+    #[clap(long = "router")]
+    router: String,
+
+    /// Final destination of the traffic, as IP:PORT
+    #[clap(long = "dest")]
+    dest: SocketAddrV4,
+
+    /// Datagram size in bytes, header included
+    #[clap(long = "size", default_value = "64")]
+    size: usize,
+
+    /// Packets per second, for all flows together
+    #[clap(long = "rate", default_value = "100")]
+    rate: f64,
+
+    /// Number of packets to send [default: unlimited]
+    #[clap(long = "count")]
+    count: Option<u64>,
+
+    /// Number of flows, each one from its own source port
+    #[clap(long = "flows", default_value = "1")]
+    flows: usize,
+
+    /// Start the payload with a per flow sequence number (8 bytes, network byte order)
+    #[clap(long = "sequence")]
+    sequence: bool,
+}
+
+pub fn run(args: GenArgs) -> Result<()> {
+    ensure!(
+        args.size >= 6,
+        "datagrams need at least six bytes for the header"
+    );
+    ensure!(
+        !args.sequence || args.size >= 14,
+        "sequence numbers need datagrams of at least 14 bytes"
+    );
+    ensure!(args.rate > 0.0, "the rate must be positive");
+    ensure!(args.flows > 0, "at least one flow is needed");
+
+    let mut flows = Vec::with_capacity(args.flows);
+    for _ in 0..args.flows {
+        let (socket, router) = client::connect(&args.router)?;
+        flows.push((socket, router, 0u64));
+    }
+
+    let mut data = client::datagram(args.dest, &vec![0; args.size - 6]);
+    let interval = Duration::from_secs_f64(1.0 / args.rate);
+    let start = Instant::now();
+    let mut sent = 0u64;
+    let mut bytes = 0usize;
+
+    while args.count.is_none_or(|count| sent < count) {
+        let (socket, router, seq) = &mut flows[(sent % args.flows as u64) as usize];
+
+        if args.sequence {
+            data[6..14].copy_from_slice(&seq.to_be_bytes());
+        }
+        match socket.send_to(&data, *router) {
+            Ok(len) => bytes += len,
+            Err(e) => warn!("Could not send to {}: {}", router, e),
+        }
+        *seq += 1;
+        sent += 1;
+
+        let next = start + interval.mul_f64(sent as f64);
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "Sent {} packets ({} bytes) in {:.3} s: {:.1} packets/s, {:.1} kbit/s",
+        sent,
+        bytes,
+        elapsed,
+        sent as f64 / elapsed,
+        bytes as f64 * 8.0 / elapsed / 1000.0
+    );
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{anyhow, ensure, Result};
+use clap::Subcommand;
+use shufflerouter::packet::{self, Header};
+use std::net::SocketAddr;
+
+#[derive(Subcommand, Debug)]
+pub enum HeaderCommand {
+    /// Show the header bytes addressing a destination
+    Encode {
+        /// Destination, as IP:PORT
+        addr: SocketAddr,
+
+        /// Use the version 2 header
+        #[clap(long = "v2")]
+        v2: bool,
+
+        /// Routers the packet can go through, in a version 2 header
+        #[clap(long = "hop-limit")]
+        hop_limit: Option<u8>,
+    },
+    /// Show the address carried in the first bytes of a packet
+    Decode {
+        /// Packet bytes in hexadecimal. Spaces and colons are ignored
+        hex: Vec<String>,
+    },
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let digits: Vec<char> = text
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    ensure!(
+        digits.len().is_multiple_of(2),
+        "odd number of hexadecimal digits"
+    );
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| anyhow!("invalid hex byte {:?}", pair))
+        })
+        .collect()
+}
+
+pub fn run(command: HeaderCommand) -> Result<()> {
+    match command {
+        HeaderCommand::Encode {
+            addr,
+            v2,
+            hop_limit,
+        } => {
+            if let Err(e) = packet::check_dst(&addr) {
+                eprintln!("Warning: {}", e);
+            }
+            let header = match (v2, hop_limit) {
+                (_, Some(hop_limit)) => Header::extended(addr).with_hop_limit(hop_limit),
+                (true, None) => Header::extended(addr),
+                (false, None) => Header::new(addr),
+            };
+            let bytes = header.encode();
+            println!(
+                "{}",
+                bytes
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        HeaderCommand::Decode { hex } => {
+            let data = parse_hex(&hex.join(""))?;
+            let header = Header::decode(&data)?;
+            println!("Version: {}", if header.is_extended() { 2 } else { 1 });
+            println!("Address: {}", header.addr());
+            if let Some(hop_limit) = header.hop_limit() {
+                println!("Hop limit: {}", hop_limit);
+            }
+            if let Err(e) = packet::check_dst(&header.addr()) {
+                println!("Warning: {}", e);
+            }
+            println!("Payload: {} bytes", data.len() - header.encoded_len());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::probe;
+use anyhow::{Context, Result};
+use clap::Args;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[derive(Args, Debug)]
+pub struct HealthcheckArgs {
+    /// Port of the local router
+    #[clap(short = 'p', long = "port", default_value = "2021")]
+    port: u16,
+
+    /// Time to wait for the answer, in milliseconds
+    #[clap(long = "timeout", default_value = "1000")]
+    timeout: u64,
+}
+
+pub fn run(args: HealthcheckArgs) -> Result<()> {
+    let router = format!("{}:{}", Ipv4Addr::LOCALHOST, args.port);
+    let (status, _) = probe::query(&router, Duration::from_millis(args.timeout))
+        .context("the router is not healthy")?;
+
+    println!("healthy, {} packets queued", status.queued);
+
+    Ok(())
+}
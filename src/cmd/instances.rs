@@ -0,0 +1,164 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use crate::ShutdownSource;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::Args;
+use log::info;
+use serde::Deserialize;
+use shufflerouter::profile::Profile;
+use shufflerouter::stats::Stats;
+use shufflerouter::Router;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Args, Debug)]
+pub struct InstancesArgs {
+    /// Instances file
+    instances: PathBuf,
+
+    /// Only print the instances, without starting them
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+/// Routers to run side by side, each one with a port and impairments of its
+/// own
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct InstancesFile {
+    /// Address every instance listens on, instead of every IPv4 and IPv6 one
+    bind: Option<IpAddr>,
+    #[serde(default, rename = "instance")]
+    instances: Vec<Instance>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Instance {
+    name: String,
+    port: u16,
+    #[serde(default)]
+    drop: f64,
+    #[serde(default)]
+    min_delay: u64,
+    #[serde(default)]
+    rand_delay: u64,
+    /// Further `key=value` profile settings, e.g. `dup=0.01 reorder=0.05`
+    #[serde(default)]
+    settings: String,
+}
+
+impl Instance {
+    fn profile(&self) -> Result<Profile> {
+        Profile::new(self.drop, self.min_delay, self.rand_delay)
+            .and_then(|profile| profile.with_settings(&self.settings))
+            .with_context(|| format!("instance {}", self.name))
+    }
+}
+
+pub fn run(args: InstancesArgs) -> Result<()> {
+    let file: InstancesFile = toml::from_str(&std::fs::read_to_string(&args.instances)?)
+        .context("invalid instances file")?;
+    if file.instances.is_empty() {
+        bail!("no instances defined");
+    }
+
+    let (mut names, mut ports) = (HashSet::new(), HashSet::new());
+    let mut plans = Vec::new();
+    for instance in &file.instances {
+        ensure!(
+            names.insert(&instance.name),
+            "instance {:?} is defined twice",
+            instance.name
+        );
+        ensure!(
+            ports.insert(instance.port),
+            "instance {}: port {} is already used",
+            instance.name,
+            instance.port
+        );
+        plans.push((instance, instance.profile()?));
+    }
+
+    println!("Instances:");
+    for (instance, profile) in &plans {
+        println!(
+            "  {:<20} port {:<6} {}",
+            instance.name, instance.port, profile
+        );
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    // Every instance runs in this very process, with its own router
+    let mut routers = Vec::new();
+    for (instance, profile) in plans {
+        let router = Arc::new(
+            Router::builder()
+                .bind(file.bind)
+                .port(instance.port)
+                .profile(&profile)
+                .build()
+                .with_context(|| {
+                    format!(
+                        "instance {}: could not bind port {}",
+                        instance.name, instance.port
+                    )
+                })?,
+        );
+        let thread = {
+            let router = router.clone();
+            thread::spawn(move || router.run())
+        };
+        info!(
+            "Instance {} listening on port {}",
+            instance.name, instance.port
+        );
+        routers.push((&instance.name, router, thread));
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let shutdown_signal = {
+        let _guard = runtime.enter();
+        ShutdownSource::new()?
+    };
+    runtime.block_on(shutdown_signal.wait())?;
+
+    println!();
+    for (name, router, thread) in routers {
+        router.shutdown()?;
+        thread
+            .join()
+            .map_err(|_| anyhow!("instance {}: the router thread panicked", name))??;
+        let stats = router.stats();
+        println!(
+            "{}: {} packets received, {} dropped, {} bytes sent.",
+            name,
+            Stats::get(&stats.received),
+            Stats::get(&stats.random_drops),
+            Stats::get(&stats.bytes_sent)
+        );
+    }
+
+    Ok(())
+}
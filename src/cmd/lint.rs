@@ -0,0 +1,87 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{ensure, Result};
+use clap::Args;
+use shufflerouter::scenario::{Scenario, Severity};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct LintArgs {
+    /// Scenario file
+    scenario: PathBuf,
+}
+
+fn print_step(from: f64, to: Option<f64>, name: &str, description: String) {
+    let window = match to {
+        Some(to) => format!("{from} s - {to} s"),
+        None => format!("{from} s -"),
+    };
+    println!("  {:<20} {:<16} {}", window, name, description);
+}
+
+pub fn run(args: LintArgs) -> Result<()> {
+    let scenario = Scenario::load(&args.scenario)?;
+    let issues = scenario.lint();
+
+    for issue in &issues {
+        let level = match issue.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        println!("{}: {}", level, issue.message);
+    }
+
+    let defaults = scenario.defaults;
+    println!("Timeline:");
+    let mut now = 0.0;
+    for phase in scenario.timeline() {
+        if phase.start > now {
+            print_step(now, Some(phase.start), "defaults", defaults.to_string());
+        }
+        print_step(
+            phase.start,
+            Some(phase.end()),
+            phase.name.as_deref().unwrap_or("(unnamed)"),
+            phase.impairments().or(defaults).to_string(),
+        );
+        now = phase.end().max(now);
+    }
+    print_step(now, None, "defaults", defaults.to_string());
+
+    if !scenario.rules.is_empty() {
+        println!("Rules, first match wins:");
+        for rule in &scenario.rules {
+            println!("  {:<37} {}", rule.source, rule.impairments().or(defaults));
+        }
+    }
+
+    if !scenario.actions.is_empty() {
+        println!("Actions:");
+        for action in scenario.script() {
+            println!("  {:<20} {}", format!("{} s", action.at), action);
+        }
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .count();
+    ensure!(errors == 0, "{} errors found", errors);
+
+    Ok(())
+}
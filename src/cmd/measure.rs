@@ -0,0 +1,237 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::client;
+use anyhow::{ensure, Context, Result};
+use clap::Args;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Probe payload: sequence number and sending time, in nanoseconds since
+/// the start of the measurement
+const PROBE_LEN: usize = 16;
+
+#[derive(Args, Debug)]
+pub struct MeasureArgs {
+    /// Router address, as HOST:PORT
+    #[clap(long = "router")]
+    router: String,
+
+    /// Echo endpoint answering the probes. Without it the probes are addressed
+    /// to this very client, so the router reflects them back
+    #[clap(long = "dest")]
+    dest: Option<SocketAddrV4>,
+
+    /// Number of probes
+    #[clap(long = "count", default_value = "100")]
+    count: u64,
+
+    /// Probes per second
+    #[clap(long = "rate", default_value = "10")]
+    rate: f64,
+
+    /// Probe size in bytes, header included
+    #[clap(long = "size", default_value = "22")]
+    size: usize,
+
+    /// Time to wait for late answers after the last probe, in milliseconds
+    #[clap(long = "timeout", default_value = "1000")]
+    timeout: u64,
+}
+
+/// Value at quantile `q` of an already sorted sample
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    let rank = (q * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+/// Outcome of a probing run
+pub(crate) struct Measurement {
+    pub sent: u64,
+    pub received: u64,
+    pub duplicates: u64,
+    pub reordered: u64,
+    /// Round trip times of the received probes, sorted
+    pub rtts: Vec<Duration>,
+}
+
+impl Measurement {
+    pub fn loss(&self) -> f64 {
+        (self.sent - self.received) as f64 / self.sent as f64
+    }
+
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        (!self.rtts.is_empty()).then(|| percentile(&self.rtts, q))
+    }
+}
+
+/// Sends `count` probes of `size` bytes at `rate` probes per second through
+/// `router` and collects their answers until `timeout` after the last one
+pub(crate) fn probe(
+    router: &str,
+    dest: Option<SocketAddrV4>,
+    count: u64,
+    rate: f64,
+    size: usize,
+    timeout: Duration,
+) -> Result<Measurement> {
+    ensure!(
+        size >= 6 + PROBE_LEN,
+        "probes need at least {} bytes",
+        6 + PROBE_LEN
+    );
+    ensure!(rate > 0.0, "the rate must be positive");
+    ensure!(count > 0, "at least one probe is needed");
+
+    let (socket, router) = client::connect(router)?;
+    let connected = dest.is_none();
+    let dest = match dest {
+        Some(dest) => dest,
+        None => {
+            // Learn the address the router will see by connecting to it
+            socket.connect(router)?;
+            match socket.local_addr()? {
+                SocketAddr::V4(local) => local,
+                SocketAddr::V6(_) => anyhow::bail!("reflection needs an IPv4 router"),
+            }
+        }
+    };
+    debug!("Measuring {} through {}", dest, router);
+
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    let start = Instant::now();
+
+    let sender = {
+        let socket = socket.try_clone()?;
+        let mut data = client::datagram(dest, &vec![0; size - 6]);
+
+        thread::spawn(move || {
+            for seq in 0..count {
+                let next = start + interval.mul_f64(seq as f64);
+                if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+
+                data[6..14].copy_from_slice(&seq.to_be_bytes());
+                data[14..22].copy_from_slice(&(start.elapsed().as_nanos() as u64).to_be_bytes());
+                let res = if connected {
+                    socket.send(&data)
+                } else {
+                    socket.send_to(&data, router)
+                };
+                if let Err(e) = res {
+                    warn!("Could not send probe {}: {}", seq, e);
+                }
+            }
+        })
+    };
+
+    let deadline = start + interval.mul_f64(count as f64) + timeout;
+    let mut buf = [0; 65536];
+    let mut rtts = Vec::new();
+    let mut seen = HashSet::new();
+    let (mut duplicates, mut reordered, mut highest) = (0, 0, None);
+
+    while let Some(timeout) = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|timeout| !timeout.is_zero())
+    {
+        socket.set_read_timeout(Some(timeout))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(e) => return Err(e).context("could not receive probes"),
+        };
+        if len < 6 + PROBE_LEN {
+            debug!("Ignoring {} bytes datagram", len);
+            continue;
+        }
+
+        let seq = u64::from_be_bytes(buf[6..14].try_into()?);
+        let sent = Duration::from_nanos(u64::from_be_bytes(buf[14..22].try_into()?));
+        if !seen.insert(seq) {
+            duplicates += 1;
+            continue;
+        }
+        if highest.is_some_and(|highest| seq < highest) {
+            reordered += 1;
+        }
+        highest = highest.max(Some(seq));
+        rtts.push(start.elapsed().saturating_sub(sent));
+
+        if seen.len() as u64 == count && sender.is_finished() {
+            break;
+        }
+    }
+    let _ = sender.join();
+    rtts.sort();
+
+    Ok(Measurement {
+        sent: count,
+        received: seen.len() as u64,
+        duplicates,
+        reordered,
+        rtts,
+    })
+}
+
+pub fn run(args: MeasureArgs) -> Result<()> {
+    let measurement = probe(
+        &args.router,
+        args.dest,
+        args.count,
+        args.rate,
+        args.size,
+        Duration::from_millis(args.timeout),
+    )?;
+
+    println!(
+        "{} probes sent, {} received, {:.2}% loss, {} duplicated, {} reordered",
+        measurement.sent,
+        measurement.received,
+        100.0 * measurement.loss(),
+        measurement.duplicates,
+        measurement.reordered
+    );
+
+    let rtts = &measurement.rtts;
+    if !rtts.is_empty() {
+        let mean = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        println!(
+            "RTT min/mean/p50/p90/p99/max = {:.3}/{:.3}/{:.3}/{:.3}/{:.3}/{:.3} ms",
+            ms(rtts[0]),
+            ms(mean),
+            ms(percentile(rtts, 0.5)),
+            ms(percentile(rtts, 0.9)),
+            ms(percentile(rtts, 0.99)),
+            ms(rtts[rtts.len() - 1])
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Auxiliary subcommands. Without any of them the program runs as a router.
+
+mod ab;
+mod bench;
+pub(crate) mod chaos;
+mod client;
+mod dissector;
+mod echo;
+mod fuzzclient;
+mod gen;
+mod header;
+mod healthcheck;
+mod instances;
+mod lint;
+mod measure;
+mod netem;
+mod playback;
+mod probe;
+mod replay;
+mod scaffold;
+mod selftest;
+pub(crate) mod service;
+mod simulate;
+mod stats;
+mod topo;
+
+use anyhow::Result;
+use clap::Subcommand;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Duplicate traffic through two impairment profiles and compare them
+    Ab(ab::AbArgs),
+
+    /// Find the highest packet rate a router sustains
+    Bench(bench::BenchArgs),
+
+    /// Run the router, changing its impairments at random
+    Chaos(chaos::ChaosArgs),
+
+    /// Talk to a running router
+    Client {
+        #[clap(subcommand)]
+        command: client::ClientCommand,
+    },
+
+    /// Write a Wireshark dissector for the packet header
+    Dissector(dissector::DissectorArgs),
+
+    /// Answer forwarded datagrams back to their origin through the router
+    Echo(echo::EchoArgs),
+
+    /// Send malformed packets to a router and check it survives them
+    Fuzzclient(fuzzclient::FuzzclientArgs),
+
+    /// Generate a stream of traffic through the router
+    Gen(gen::GenArgs),
+
+    /// Encode and decode packet headers
+    Header {
+        #[clap(subcommand)]
+        command: header::HeaderCommand,
+    },
+
+    /// Check the router on this host, exiting with an error if it does not answer
+    Healthcheck(healthcheck::HealthcheckArgs),
+
+    /// Run several routers, each with its own port and impairments, in one process
+    Instances(instances::InstancesArgs),
+
+    /// Validate a scenario file and preview its timeline
+    Lint(lint::LintArgs),
+
+    /// Measure RTT, loss, duplication and reordering through the router
+    Measure(measure::MeasureArgs),
+
+    /// Print the tc netem command closest to a profile
+    Netem(netem::NetemArgs),
+
+    /// Reproduce the output of a recorded router session
+    Playback(playback::PlaybackArgs),
+
+    /// Check whether a router is alive and show its status
+    Probe(probe::ProbeArgs),
+
+    /// Replay the UDP payloads of a pcap capture through the router
+    Replay(replay::ReplayArgs),
+
+    /// Write a sample scenario and a client snippet to start from
+    Scaffold(scaffold::ScaffoldArgs),
+
+    /// Check that forwarding, header rewriting, drops and delays work
+    Selftest(selftest::SelftestArgs),
+
+    /// Install the router as a Windows service or a macOS launchd daemon
+    Service {
+        #[clap(subcommand)]
+        command: service::ServiceCommand,
+    },
+
+    /// Run the router on generated traffic in simulated time
+    Simulate(simulate::SimulateArgs),
+
+    /// Work with exported stats
+    Stats {
+        #[clap(subcommand)]
+        command: stats::StatsCommand,
+    },
+
+    /// Start a router for every link of a lab topology
+    Topo(topo::TopoArgs),
+}
+
+pub fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Ab(args) => ab::run(args),
+        Command::Bench(args) => bench::run(args),
+        Command::Chaos(_) => unreachable!("Chaos mode runs within the router"),
+        Command::Client { command } => client::run(command),
+        Command::Dissector(args) => dissector::run(args),
+        Command::Echo(args) => echo::run(args),
+        Command::Fuzzclient(args) => fuzzclient::run(args),
+        Command::Gen(args) => gen::run(args),
+        Command::Header { command } => header::run(command),
+        Command::Healthcheck(args) => healthcheck::run(args),
+        Command::Instances(args) => instances::run(args),
+        Command::Lint(args) => lint::run(args),
+        Command::Measure(args) => measure::run(args),
+        Command::Netem(args) => netem::run(args),
+        Command::Playback(args) => playback::run(args),
+        Command::Probe(args) => probe::run(args),
+        Command::Replay(args) => replay::run(args),
+        Command::Scaffold(args) => scaffold::run(args),
+        Command::Selftest(args) => selftest::run(args),
+        Command::Service { command } => service::run(command),
+        Command::Simulate(args) => simulate::run(args),
+        Command::Stats { command } => stats::run(command),
+        Command::Topo(args) => topo::run(args),
+    }
+}
@@ -0,0 +1,55 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::probe;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use shufflerouter::profile::Profile;
+use std::time::Duration;
+
+#[derive(Args, Debug)]
+pub struct NetemArgs {
+    /// Running router whose active profile is translated, as HOST:PORT
+    #[clap(long = "router", conflicts_with = "profile")]
+    router: Option<String>,
+
+    /// Profile to translate, e.g. "drop=0.1 min_delay=20 rand_delay=10"
+    #[clap(long = "profile")]
+    profile: Option<Profile>,
+
+    /// Network interface for the command
+    #[clap(long = "dev", default_value = "eth0")]
+    dev: String,
+}
+
+pub fn run(args: NetemArgs) -> Result<()> {
+    let profile = match (args.profile, args.router) {
+        (Some(profile), _) => profile,
+        (None, Some(router)) => {
+            let (status, _) = probe::query(&router, Duration::from_secs(1))?;
+            status
+                .profile
+                .parse()
+                .with_context(|| format!("{router} reported an unknown profile"))?
+        }
+        (None, None) => bail!("either --router or --profile is needed"),
+    };
+
+    println!("{}", profile.to_netem(&args.dev)?);
+
+    Ok(())
+}
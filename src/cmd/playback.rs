@@ -0,0 +1,81 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use clap::Args;
+use log::{debug, warn};
+use shufflerouter::clock::{Clock, SystemClock};
+use shufflerouter::packet;
+use shufflerouter::record::{self, Decision};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct PlaybackArgs {
+    /// Session recording made with --record
+    recording: PathBuf,
+
+    /// Local port to send from. Use the original router port to reproduce it exactly.
+    #[clap(short = 'p', long = "port", default_value = "0")]
+    port: u16,
+}
+
+/// Sends again every datagram the recorded router forwarded, with the same
+/// contents, to the same destinations and at the same moments
+pub fn run(args: PlaybackArgs) -> Result<()> {
+    let events = record::read_session(&args.recording)?;
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, args.port)))?;
+
+    let mut departures = events
+        .iter()
+        .filter_map(|event| match event.decision {
+            Decision::Delay(delay) => Some((event.offset + delay, event)),
+            Decision::Drop => None,
+        })
+        .collect::<Vec<_>>();
+    departures.sort_by_key(|(departure, _)| *departure);
+
+    let clock = SystemClock;
+    let start = clock.now();
+    let mut sent = 0;
+    for (departure, event) in &departures {
+        let mut data = event.data.clone();
+        let dst = match packet::replace_header(&mut data, event.src) {
+            Ok(dst) => dst,
+            Err(e) => {
+                debug!("Skipping datagram from {}: {}", event.src, e);
+                continue;
+            }
+        };
+
+        clock.sleep_until(start + *departure);
+
+        match socket.send_to(&data, dst) {
+            Ok(_) => sent += 1,
+            Err(e) => warn!("Could not send to {}: {}", dst, e),
+        }
+    }
+
+    println!(
+        "{} arrivals recorded, {} dropped, {} datagrams sent again",
+        events.len(),
+        events.len() - departures.len(),
+        sent
+    );
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::client;
+use anyhow::{bail, Result};
+use clap::Args;
+use log::debug;
+use shufflerouter::inband::{self, Status};
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct ProbeArgs {
+    /// Router address, as HOST:PORT
+    router: String,
+
+    /// Time to wait for the answer, in milliseconds
+    #[clap(long = "timeout", default_value = "1000")]
+    timeout: u64,
+}
+
+/// Sends a status query to `router` and waits for its answer
+pub(crate) fn query(router: &str, timeout: Duration) -> Result<(Status, Duration)> {
+    let (socket, router) = client::connect(router)?;
+    socket.connect(router)?;
+
+    let start = Instant::now();
+    socket.send(inband::QUERY)?;
+
+    let mut buf = [0; 1500];
+    while let Some(timeout) = (start + timeout)
+        .checked_duration_since(Instant::now())
+        .filter(|timeout| !timeout.is_zero())
+    {
+        socket.set_read_timeout(Some(timeout))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(e) => bail!("{} is not reachable: {}", router, e),
+        };
+
+        match Status::decode(&buf[..len]) {
+            Some(status) => return Ok((status, start.elapsed())),
+            None => debug!("Ignoring unexpected {} bytes answer", len),
+        }
+    }
+
+    bail!("no answer from {}", router)
+}
+
+pub fn run(args: ProbeArgs) -> Result<()> {
+    let (status, rtt) = query(&args.router, Duration::from_millis(args.timeout))?;
+
+    println!(
+        "{} is alive (RTT {:.3} ms)",
+        args.router,
+        rtt.as_secs_f64() * 1000.0
+    );
+    println!("version: {}", status.version);
+    println!("uptime: {} s", status.uptime.as_secs());
+    println!("profile: {}", status.profile);
+    println!("queued packets: {}", status.queued);
+    println!("pooled buffers: {}", status.pooled);
+    if let Some(addr) = status.public_address {
+        println!("public address: {addr}");
+    }
+
+    Ok(())
+}
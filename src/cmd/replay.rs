@@ -0,0 +1,108 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::client;
+use anyhow::{ensure, Context, Result};
+use clap::Args;
+use log::{debug, warn};
+use shufflerouter::pcap::{self, PcapReader};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Capture file, in pcap format
+    capture: PathBuf,
+
+    /// Router address, as HOST:PORT
+    #[clap(long = "router")]
+    router: String,
+
+    /// Final destination of the replayed payloads, as IP:PORT
+    #[clap(long = "dest")]
+    dest: SocketAddrV4,
+
+    /// Timing scale: 2 replays twice as fast, 0 sends as fast as possible
+    #[clap(long = "speed", default_value = "1.0")]
+    speed: f64,
+
+    /// Only replay datagrams from or to this UDP port
+    #[clap(long = "port")]
+    port: Option<u16>,
+}
+
+pub fn run(args: ReplayArgs) -> Result<()> {
+    ensure!(args.speed >= 0.0, "the speed cannot be negative");
+
+    let file = File::open(&args.capture)
+        .with_context(|| format!("could not open {}", args.capture.display()))?;
+    let mut capture = PcapReader::new(BufReader::new(file))?;
+    let (socket, router) = client::connect(&args.router)?;
+
+    let start = Instant::now();
+    let mut first = None;
+    let (mut sent, mut skipped) = (0, 0);
+
+    while let Some(record) = capture.next_record()? {
+        let datagram = match pcap::udp_datagram(capture.linktype(), &record.data) {
+            Some(datagram)
+                if args.port.is_none_or(|port| {
+                    datagram.src.port() == port || datagram.dst.port() == port
+                }) =>
+            {
+                datagram
+            }
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        if args.speed > 0.0 {
+            let offset = record.timestamp - *first.get_or_insert(record.timestamp);
+            if let Some(wait) =
+                (start + offset.div_f64(args.speed)).checked_duration_since(Instant::now())
+            {
+                thread::sleep(wait);
+            }
+        }
+
+        debug!(
+            "Replaying {} bytes from {} to {}",
+            datagram.payload.len(),
+            datagram.src,
+            datagram.dst
+        );
+        match socket.send_to(&client::datagram(args.dest, datagram.payload), router) {
+            Ok(_) => sent += 1,
+            Err(e) => warn!("Could not send to {}: {}", router, e),
+        }
+    }
+
+    println!(
+        "Replayed {} datagrams in {:.3} s, {} frames skipped",
+        sent,
+        start.elapsed().as_secs_f64(),
+        skipped
+    );
+
+    Ok(())
+}
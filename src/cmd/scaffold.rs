@@ -0,0 +1,72 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{ensure, Result};
+use clap::{Args, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Lang {
+    Python,
+    C,
+    Rust,
+}
+
+#[derive(Args, Debug)]
+pub struct ScaffoldArgs {
+    /// Language of the client snippet
+    #[clap(long = "lang", value_enum)]
+    lang: Lang,
+
+    /// Directory to write the files to
+    #[clap(long = "dir", default_value = ".")]
+    dir: PathBuf,
+
+    /// Overwrite existing files
+    #[clap(long = "force")]
+    force: bool,
+}
+
+pub fn run(args: ScaffoldArgs) -> Result<()> {
+    let client = match args.lang {
+        Lang::Python => ("client.py", include_str!("scaffold/client.py")),
+        Lang::C => ("client.c", include_str!("scaffold/client.c")),
+        Lang::Rust => ("client.rs", include_str!("scaffold/client.rs")),
+    };
+    let files = [
+        ("scenario.toml", include_str!("scaffold/scenario.toml")),
+        client,
+    ];
+
+    for (name, _) in files {
+        let path = args.dir.join(name);
+        ensure!(
+            args.force || !path.exists(),
+            "{} already exists. Use --force to overwrite it",
+            path.display()
+        );
+    }
+
+    std::fs::create_dir_all(&args.dir)?;
+    for (name, contents) in files {
+        let path = args.dir.join(name);
+        std::fs::write(&path, contents)?;
+        println!("Wrote {}", path.display());
+    }
+
+    Ok(())
+}
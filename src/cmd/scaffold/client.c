@@ -0,0 +1,71 @@
+/*
+ * Minimal ShuffleRouter client.
+ *
+ * Every datagram sent to the router starts with a six bytes header: the IPv4
+ * address and the UDP port of the final destination, in network byte order.
+ * The router replaces it with the address of the sender before forwarding.
+ *
+ * Build with: cc -o client client.c
+ */
+
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/socket.h>
+#include <sys/time.h>
+#include <unistd.h>
+
+#define ROUTER_IP "127.0.0.1"
+#define ROUTER_PORT 2021
+#define DEST_IP "127.0.0.1"
+#define DEST_PORT 4000
+#define HEADER_LEN 6
+
+int main(void)
+{
+    int sock = socket(AF_INET, SOCK_DGRAM, 0);
+    if (sock < 0) {
+        perror("socket");
+        return 1;
+    }
+
+    struct sockaddr_in router = { .sin_family = AF_INET, .sin_port = htons(ROUTER_PORT) };
+    inet_pton(AF_INET, ROUTER_IP, &router.sin_addr);
+
+    /* Header: destination address and port, already in network byte order */
+    unsigned char packet[1500];
+    struct in_addr dest_ip;
+    uint16_t dest_port = htons(DEST_PORT);
+    inet_pton(AF_INET, DEST_IP, &dest_ip);
+    memcpy(packet, &dest_ip.s_addr, 4);
+    memcpy(packet + 4, &dest_port, 2);
+
+    const char *message = "Hello";
+    size_t len = strlen(message);
+    memcpy(packet + HEADER_LEN, message, len);
+
+    if (sendto(sock, packet, HEADER_LEN + len, 0, (struct sockaddr *)&router, sizeof router) < 0) {
+        perror("sendto");
+        return 1;
+    }
+
+    struct timeval timeout = { .tv_sec = 5 };
+    setsockopt(sock, SOL_SOCKET, SO_RCVTIMEO, &timeout, sizeof timeout);
+    ssize_t received = recv(sock, packet, sizeof packet, 0);
+    if (received < HEADER_LEN) {
+        printf("No answer. The packet may have been dropped.\n");
+        return 0;
+    }
+
+    /* The header now holds the address of the sender */
+    char ip[INET_ADDRSTRLEN];
+    uint16_t port;
+    inet_ntop(AF_INET, packet, ip, sizeof ip);
+    memcpy(&port, packet + 4, 2);
+    printf("%zd bytes from %s:%u: %.*s\n", received - HEADER_LEN, ip, ntohs(port),
+        (int)(received - HEADER_LEN), packet + HEADER_LEN);
+
+    close(sock);
+    return 0;
+}
@@ -0,0 +1,37 @@
+#!/usr/bin/env python3
+"""Minimal ShuffleRouter client.
+
+Every datagram sent to the router starts with a six bytes header: the IPv4
+address and the UDP port of the final destination, in network byte order.
+The router replaces it with the address of the sender before forwarding.
+"""
+
+import socket
+import struct
+
+ROUTER = ("127.0.0.1", 2021)
+DESTINATION = ("127.0.0.1", 4000)
+
+
+def header(ip, port):
+    return socket.inet_aton(ip) + struct.pack("!H", port)
+
+
+def main():
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    sock.bind(("0.0.0.0", 0))
+    sock.sendto(header(*DESTINATION) + b"Hello", ROUTER)
+
+    sock.settimeout(5)
+    try:
+        data, _ = sock.recvfrom(1500)
+    except socket.timeout:
+        print("No answer. The packet may have been dropped.")
+        return
+    ip = socket.inet_ntoa(data[:4])
+    (port,) = struct.unpack("!H", data[4:6])
+    print(f"{len(data) - 6} bytes from {ip}:{port}: {data[6:]!r}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,47 @@
+//! Minimal ShuffleRouter client.
+//!
+//! Every datagram sent to the router starts with a six bytes header: the IPv4
+//! address and the UDP port of the final destination, in network byte order.
+//! The router replaces it with the address of the sender before forwarding.
+//!
+//! Build with: rustc client.rs
+
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+const ROUTER: &str = "127.0.0.1:2021";
+const DESTINATION: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 4000);
+
+fn header(addr: SocketAddrV4) -> [u8; 6] {
+    let mut header = [0; 6];
+    header[..4].copy_from_slice(&addr.ip().octets());
+    header[4..].copy_from_slice(&addr.port().to_be_bytes());
+    header
+}
+
+fn main() -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+
+    let mut packet = header(DESTINATION).to_vec();
+    packet.extend_from_slice(b"Hello");
+    socket.send_to(&packet, ROUTER)?;
+
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = [0; 1500];
+    match socket.recv(&mut buf) {
+        Ok(len) if len >= 6 => {
+            let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+            let port = u16::from_be_bytes([buf[4], buf[5]]);
+            println!(
+                "{} bytes from {}:{}: {}",
+                len - 6,
+                ip,
+                port,
+                String::from_utf8_lossy(&buf[6..len])
+            );
+        }
+        _ => println!("No answer. The packet may have been dropped."),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,204 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use super::{client, probe};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::Args;
+use shufflerouter::packet;
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct SelftestArgs {
+    /// Number of test packets
+    #[clap(long = "count", default_value = "200")]
+    count: u64,
+
+    /// Drop probability configured in the router under test
+    #[clap(long = "drop", default_value = "0.2")]
+    drop: f64,
+
+    /// Minimum delay configured in the router under test, in milliseconds
+    #[clap(long = "min-delay", default_value = "20")]
+    min_delay: u64,
+
+    /// Delay randomness configured in the router under test, in milliseconds
+    #[clap(long = "rand-delay", default_value = "30")]
+    rand_delay: u64,
+}
+
+/// Extra delay tolerated on top of the configured one, to absorb scheduling
+const DELAY_SLACK: Duration = Duration::from_millis(25);
+
+/// Stops the router under test when dropped
+struct RouterProcess(Child);
+
+impl Drop for RouterProcess {
+    fn drop(&mut self) {
+        // Ask for a clean exit, so the router removes its pid file
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(self.0.id() as libc::pid_t, libc::SIGTERM);
+        }
+        #[cfg(not(unix))]
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> Result<u16> {
+    Ok(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+fn local_v4(socket: &UdpSocket) -> Result<SocketAddrV4> {
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(addr),
+        SocketAddr::V6(addr) => bail!("unexpected IPv6 address {}", addr),
+    }
+}
+
+fn check(name: &str, passed: bool, detail: String) -> bool {
+    println!(
+        "[{}] {}: {}",
+        if passed { " OK " } else { "FAIL" },
+        name,
+        detail
+    );
+    passed
+}
+
+pub fn run(args: SelftestArgs) -> Result<()> {
+    ensure!(
+        (0.0..1.0).contains(&args.drop),
+        "the drop probability must be below 1"
+    );
+    ensure!(args.count > 0, "at least one packet is needed");
+
+    let port = free_port()?;
+    let _router = RouterProcess(
+        Command::new(std::env::current_exe()?)
+            .args(["-p", &port.to_string()])
+            .args(["-d", &args.drop.to_string()])
+            .args(["-m", &args.min_delay.to_string()])
+            .args(["-r", &args.rand_delay.to_string()])
+            .stdout(Stdio::null())
+            .spawn()
+            .context("could not start the router under test")?,
+    );
+    let router = format!("127.0.0.1:{port}");
+
+    // Wait for the router to come up
+    let start = Instant::now();
+    while probe::query(&router, Duration::from_millis(100)).is_err() {
+        ensure!(
+            start.elapsed() < Duration::from_secs(5),
+            "the router did not start"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    println!("Router under test listening on {router}");
+
+    let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let (sender_addr, receiver_addr) = (local_v4(&sender)?, local_v4(&receiver)?);
+
+    let max_delay = Duration::from_millis(args.min_delay + args.rand_delay) + DELAY_SLACK;
+    let epoch = Instant::now();
+    let count = args.count;
+    let target = router.clone();
+    let sending = thread::spawn(move || -> Result<()> {
+        for seq in 0..count {
+            let sent = epoch.elapsed().as_nanos() as u64;
+            let payload = [seq.to_be_bytes(), sent.to_be_bytes()].concat();
+            sender.send_to(&client::datagram(receiver_addr, &payload), &target)?;
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    });
+
+    // Stop once the router has been silent for longer than any allowed delay
+    receiver.set_read_timeout(Some(max_delay + Duration::from_millis(500)))?;
+    let mut buf = [0; 1500];
+    let mut seen = HashSet::new();
+    let (mut bad_headers, mut delays) = (0, Vec::new());
+
+    while let Ok(len) = receiver.recv(&mut buf) {
+        let arrival = epoch.elapsed();
+
+        if len != 22 || packet::get_dst(&buf[..len]).ok() != Some(SocketAddr::V4(sender_addr)) {
+            bad_headers += 1;
+            continue;
+        }
+        let seq = u64::from_be_bytes(buf[6..14].try_into()?);
+        let sent = Duration::from_nanos(u64::from_be_bytes(buf[14..22].try_into()?));
+        if seen.insert(seq) {
+            delays.push(arrival.saturating_sub(sent));
+        }
+    }
+    sending
+        .join()
+        .map_err(|_| anyhow!("the sender thread panicked"))??;
+    let received = seen.len();
+
+    let mut passed = check(
+        "forwarding",
+        received > 0,
+        format!("{} of {} packets received", received, args.count),
+    );
+    passed &= check(
+        "header rewriting",
+        bad_headers == 0,
+        format!("{bad_headers} packets without the sender address {sender_addr}"),
+    );
+
+    let observed = 1.0 - received as f64 / args.count as f64;
+    let tolerance = 4.0 * (args.drop * (1.0 - args.drop) / args.count as f64).sqrt() + 0.01;
+    passed &= check(
+        "drop rate",
+        (observed - args.drop).abs() <= tolerance,
+        format!(
+            "{:.3} observed, {:.3} ± {:.3} expected",
+            observed, args.drop, tolerance
+        ),
+    );
+
+    let min_delay = Duration::from_millis(args.min_delay);
+    let out_of_bounds = delays
+        .iter()
+        .filter(|delay| **delay < min_delay || **delay > max_delay)
+        .count();
+    passed &= check(
+        "delay bounds",
+        out_of_bounds == 0,
+        format!(
+            "{} packets outside [{}, {}] ms",
+            out_of_bounds,
+            min_delay.as_millis(),
+            max_delay.as_millis()
+        ),
+    );
+
+    ensure!(passed, "self test failed");
+    println!("Self test passed");
+
+    Ok(())
+}
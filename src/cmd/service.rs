@@ -0,0 +1,284 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Installation as a persistent service: a Windows service or a macOS launchd
+//! daemon. Stopping the service drains the router queue as a termination
+//! signal does.
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+/// Name of the Windows service and label of the launchd daemon
+#[cfg(windows)]
+const NAME: &str = "shufflerouter";
+#[cfg(target_os = "macos")]
+const LABEL: &str = "gal.uvigo.det.shufflerouter";
+
+#[derive(Subcommand, Debug)]
+pub enum ServiceCommand {
+    /// Install and start the router as a service running with the given options
+    Install {
+        /// Router options, e.g. `-- -p 2021 -d 0.1`
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        options: Vec<String>,
+    },
+
+    /// Stop and remove the service
+    Uninstall,
+}
+
+pub fn run(command: ServiceCommand) -> Result<()> {
+    match command {
+        ServiceCommand::Install { options } => {
+            // Reject mistakes now rather than when the service starts
+            let opt = crate::Opt::try_parse_from(
+                std::iter::once("shufflerouter".to_owned()).chain(options.iter().cloned()),
+            )
+            .context("invalid router options")?;
+            anyhow::ensure!(
+                matches!(opt.command, None | Some(super::Command::Chaos(_))),
+                "a service can only run the router, with or without chaos mode"
+            );
+
+            platform::install(&options)
+        }
+        ServiceCommand::Uninstall => platform::uninstall(),
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::NAME;
+    use anyhow::Result;
+    use std::ffi::OsString;
+    use windows_service::service::{
+        ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState,
+        ServiceType,
+    };
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    pub fn install(options: &[String]) -> Result<()> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+        let info = ServiceInfo {
+            name: OsString::from(NAME),
+            display_name: OsString::from("ShuffleRouter"),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: std::iter::once("--service")
+                .chain(options.iter().map(String::as_str))
+                .map(OsString::from)
+                .collect(),
+            dependencies: vec![],
+            account_name: None, // LocalSystem
+            account_password: None,
+        };
+        let service =
+            manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)?;
+        service.set_description("A shuffling router for the Redes de Ordenadores subject")?;
+        service.start::<&str>(&[])?;
+        println!("Service {NAME} installed and started");
+
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(
+            NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        println!("Service {NAME} removed");
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::LABEL;
+    use anyhow::{ensure, Result};
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    fn plist_path() -> PathBuf {
+        PathBuf::from(format!("/Library/LaunchDaemons/{LABEL}.plist"))
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    fn launchctl(args: &[&str]) -> Result<()> {
+        let status = Command::new("launchctl").args(args).status()?;
+        ensure!(status.success(), "launchctl {} failed", args.join(" "));
+        Ok(())
+    }
+
+    pub fn install(options: &[String]) -> Result<()> {
+        let exe = std::env::current_exe()?;
+        let arguments: String = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(options.iter().cloned())
+            .map(|arg| format!("        <string>{}</string>\n", escape(&arg)))
+            .collect();
+        // launchd stops daemons with SIGTERM, which makes the router drain its queue
+        let plist = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardErrorPath</key>
+    <string>/var/log/shufflerouter.log</string>
+</dict>
+</plist>
+"#
+        );
+
+        let path = plist_path();
+        std::fs::write(&path, plist)?;
+        launchctl(&["load", "-w", &path.to_string_lossy()])?;
+        println!("Daemon {LABEL} installed and started");
+
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        let path = plist_path();
+        launchctl(&["unload", "-w", &path.to_string_lossy()])?;
+        std::fs::remove_file(&path)?;
+        println!("Daemon {LABEL} removed");
+
+        Ok(())
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod platform {
+    use anyhow::{bail, Result};
+
+    const UNSUPPORTED: &str =
+        "services are only supported on Windows and macOS. Use a systemd unit instead";
+
+    pub fn install(_options: &[String]) -> Result<()> {
+        bail!(UNSUPPORTED)
+    }
+
+    pub fn uninstall() -> Result<()> {
+        bail!(UNSUPPORTED)
+    }
+}
+
+#[cfg(windows)]
+mod dispatcher {
+    use super::NAME;
+    use anyhow::Result;
+    use log::error;
+    use std::ffi::OsString;
+    use std::sync::{Arc, OnceLock};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    /// Signalled when the service control manager asks the router to stop
+    pub(super) static STOP: OnceLock<Arc<Notify>> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn dispatch() -> Result<()> {
+        service_dispatcher::start(NAME, ffi_service_main)?;
+        Ok(())
+    }
+
+    fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: match state {
+                ServiceState::Running => {
+                    ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                }
+                _ => ServiceControlAccept::empty(),
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let stop = STOP.get_or_init(|| Arc::new(Notify::new())).clone();
+        let handle = match service_control_handler::register(NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }) {
+            Ok(handle) => handle,
+            Err(e) => {
+                error!("Could not register the service control handler: {}", e);
+                return;
+            }
+        };
+
+        let _ = handle.set_service_status(status(ServiceState::Running, 0));
+        // The options the service was installed with
+        let result = crate::run_router(<crate::Opt as clap::Parser>::parse());
+        if let Err(e) = &result {
+            error!("{:#}", e);
+        }
+        let _ = handle.set_service_status(status(ServiceState::Stopped, result.is_err() as u32));
+    }
+}
+
+/// Runs the router as a Windows service. Only returns once it stops.
+#[cfg(windows)]
+pub fn dispatch() -> Result<()> {
+    dispatcher::dispatch()
+}
+
+/// Notified when the router runs as a Windows service and has been asked to stop
+#[cfg(windows)]
+pub(crate) fn stop_request() -> Option<std::sync::Arc<tokio::sync::Notify>> {
+    dispatcher::STOP.get().cloned()
+}
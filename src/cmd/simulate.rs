@@ -0,0 +1,338 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+use anyhow::{ensure, Result};
+use clap::Args;
+use log::debug;
+use rand::distributions::Distribution;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use shufflerouter::buffer::BufferPool;
+use shufflerouter::clock::{Clock, MockClock};
+use shufflerouter::packet::{self, Packet};
+use shufflerouter::profile::Profile;
+use shufflerouter::queue::Queue;
+use shufflerouter::record::{self, Decision, SessionRecorder};
+use shufflerouter::scenario::{Scenario, Severity};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Port of the first destination. Flow `i` goes to this port plus `i`.
+const FIRST_DEST_PORT: u16 = 6000;
+/// Sequence number and arrival time, in nanoseconds since the start
+const PAYLOAD_LEN: usize = 16;
+/// Simulated time when there is no trace to bound it
+const DEFAULT_DURATION: f64 = 3600.0;
+
+const DEPARTURES_HEADER: &str = "# shufflerouter departures v1";
+
+#[derive(Args, Debug)]
+pub struct SimulateArgs {
+    /// Simulated time, in seconds. Defaults to an hour, or to the whole trace.
+    #[clap(long = "duration")]
+    duration: Option<f64>,
+
+    /// Number of flows, each from a different source
+    #[clap(long = "flows", default_value = "10")]
+    flows: u16,
+
+    /// Mean packets per second of every flow. Arrivals follow a Poisson process.
+    #[clap(long = "rate", default_value = "10")]
+    rate: f64,
+
+    /// Packet size in bytes, header included
+    #[clap(long = "size", default_value = "64")]
+    size: usize,
+
+    /// Session recording whose arrivals replace the generated traffic. The
+    /// decisions recorded in it are ignored.
+    #[clap(long = "trace", conflicts_with_all = &["flows", "rate", "size"])]
+    trace: Option<PathBuf>,
+
+    /// Impairments, e.g. "drop=0.1 min_delay=20 rand_delay=10"
+    #[clap(long = "profile", default_value = "drop=0")]
+    profile: Profile,
+
+    /// Scenario file to apply instead of --profile
+    #[clap(long = "scenario")]
+    scenario: Option<PathBuf>,
+
+    /// Seed for the random decisions, to repeat a simulation
+    #[clap(long = "seed")]
+    seed: Option<u64>,
+
+    /// Record every arrival and the decision taken for it, in simulated time
+    #[clap(long = "record")]
+    record: Option<PathBuf>,
+
+    /// Write the departure time, arrival time, source, destination and length
+    /// of every forwarded datagram, in simulated time
+    #[clap(long = "departures")]
+    departures: Option<PathBuf>,
+}
+
+struct Flow {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    sent: u64,
+}
+
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    sorted[(q * (sorted.len() - 1) as f64).round() as usize]
+}
+
+/// Scenario rules only match IPv4 sources
+fn scenario_src(src: SocketAddr) -> Ipv4Addr {
+    match src.ip().to_canonical() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    }
+}
+
+pub fn run(args: SimulateArgs) -> Result<()> {
+    // A trace runs to its end unless told otherwise
+    let duration = match (args.duration, &args.trace) {
+        (None, None) => Some(DEFAULT_DURATION),
+        (duration, _) => duration,
+    };
+    ensure!(
+        duration.is_none_or(|duration| duration > 0.0),
+        "the duration must be positive"
+    );
+    ensure!(args.rate > 0.0, "the rate must be positive");
+    ensure!(args.flows > 0, "at least one flow is needed");
+    ensure!(
+        (6 + PAYLOAD_LEN..=1500).contains(&args.size),
+        "the packet size must be between {} and 1500 bytes",
+        6 + PAYLOAD_LEN
+    );
+
+    let scenario = args.scenario.as_deref().map(Scenario::load).transpose()?;
+    if let Some(issue) = scenario.iter().flat_map(Scenario::lint).next() {
+        ensure!(
+            issue.severity != Severity::Error,
+            "{}. Check the scenario with the lint subcommand",
+            issue.message
+        );
+    }
+    let mut trace = args
+        .trace
+        .as_deref()
+        .map(record::read_session)
+        .transpose()?
+        .map(|mut events| {
+            events.sort_by_key(|event| event.offset);
+            events.into_iter().peekable()
+        });
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    // Virtual clock: instants are offsets from an arbitrary origin and nothing waits
+    let origin = Instant::now();
+    let end = duration.map(|duration| origin + Duration::from_secs_f64(duration));
+    let clock = MockClock::new(origin);
+    let recorder = args
+        .record
+        .as_deref()
+        .map(|path| SessionRecorder::create(path, origin))
+        .transpose()?;
+    let mut departures = args
+        .departures
+        .as_deref()
+        .map(|path| -> Result<_> {
+            let mut out = BufWriter::new(File::create(path)?);
+            writeln!(out, "{DEPARTURES_HEADER}")?;
+            Ok(out)
+        })
+        .transpose()?;
+
+    let mut flows: Vec<Flow> = (0..args.flows)
+        .map(|i| Flow {
+            src: SocketAddrV4::new(Ipv4Addr::from(0x0a00_0001 + u32::from(i)), 5000),
+            dst: SocketAddrV4::new(Ipv4Addr::new(10, 1, 0, 1), FIRST_DEST_PORT + i),
+            sent: 0,
+        })
+        .collect();
+
+    let interarrival =
+        |rng: &mut StdRng| Duration::from_secs_f64(-(1.0 - rng.gen::<f64>()).ln() / args.rate);
+    let mut arrivals: BinaryHeap<_> = match trace {
+        Some(_) => BinaryHeap::new(),
+        None => (0..flows.len())
+            .map(|i| Reverse((origin + interarrival(&mut rng), i)))
+            .collect(),
+    };
+
+    let started = Instant::now();
+    let mut queue = Queue::new();
+    let mut buffer_pool = BufferPool::default();
+    let (mut received, mut dropped, mut invalid, mut reordered, mut bytes) =
+        (0u64, 0u64, 0u64, 0u64, 0usize);
+    let mut delays = Vec::new();
+    // Latest arrival forwarded so far in every flow
+    let mut latest = HashMap::new();
+
+    loop {
+        let next_arrival = match &mut trace {
+            Some(events) => events.peek().map(|event| origin + event.offset),
+            None => arrivals.peek().map(|Reverse((time, _))| *time),
+        }
+        .filter(|time| end.is_none_or(|end| *time < end));
+        let next_departure = queue.peek().map(Packet::exit_time);
+
+        match (next_arrival, next_departure) {
+            (None, None) => break,
+            (Some(now), departure) if departure.is_none_or(|departure| now < departure) => {
+                clock.advance_to(now);
+                let offset = now - origin;
+                let mut buffer = buffer_pool.get_buffer();
+                let src = match &mut trace {
+                    Some(events) => {
+                        let event = events.next().unwrap();
+                        received += 1;
+                        if event.data.len() > buffer.capacity() {
+                            debug!("{:?}: oversized datagram from {}", offset, event.src);
+                            invalid += 1;
+                            buffer_pool.recycle_buffer(buffer);
+                            continue;
+                        }
+                        if let Err(e) = packet::get_dst(&event.data) {
+                            debug!("{:?}: datagram from {}: {}", offset, event.src, e);
+                            invalid += 1;
+                            buffer_pool.recycle_buffer(buffer);
+                            continue;
+                        }
+                        buffer.set_len(event.data.len());
+                        buffer[..event.data.len()].copy_from_slice(&event.data);
+                        event.src
+                    }
+                    None => {
+                        let Reverse((_, i)) = arrivals.pop().unwrap();
+                        arrivals.push(Reverse((now + interarrival(&mut rng), i)));
+
+                        let flow = &mut flows[i];
+                        buffer.set_len(args.size);
+                        packet::put_addr(&mut buffer, flow.dst);
+                        buffer[6..14].copy_from_slice(&flow.sent.to_be_bytes());
+                        buffer[14..22].copy_from_slice(&(offset.as_nanos() as u64).to_be_bytes());
+                        flow.sent += 1;
+                        received += 1;
+                        flow.src.into()
+                    }
+                };
+
+                let profile = match &scenario {
+                    Some(scenario) => scenario
+                        .impairments_at(offset.as_secs_f64(), scenario_src(src))
+                        .profile()?,
+                    None => args.profile.clone(),
+                };
+                let decision = if profile.drop_distribution().sample(&mut rng) {
+                    Decision::Drop
+                } else {
+                    Decision::Delay(Duration::from_millis(
+                        profile.delay_distribution().sample(&mut rng),
+                    ))
+                };
+                debug!("{:?}: {} from {}", offset, profile, src);
+
+                if let Some(recorder) = &recorder {
+                    recorder.record(now, src, &buffer, decision)?;
+                }
+                match decision {
+                    Decision::Drop => {
+                        dropped += 1;
+                        buffer_pool.recycle_buffer(buffer);
+                    }
+                    Decision::Delay(delay) => {
+                        queue.push(Packet::create(src, buffer, now, now + delay)?);
+                    }
+                }
+            }
+            (_, Some(now)) => {
+                clock.advance_to(now);
+                let packet = queue.pop().unwrap();
+                let (src, dst, arrival) = (packet.src(), packet.dst(), packet.arrival());
+
+                let highest = latest.entry((src, dst)).or_insert(arrival);
+                if arrival < *highest {
+                    reordered += 1;
+                }
+                *highest = arrival.max(*highest);
+                delays.push(now - arrival);
+                bytes += packet.get().len();
+
+                if let Some(out) = &mut departures {
+                    writeln!(
+                        out,
+                        "{} {} {} {} {}",
+                        (now - origin).as_micros(),
+                        (arrival - origin).as_micros(),
+                        src,
+                        dst,
+                        packet.get().len()
+                    )?;
+                }
+                buffer_pool.recycle_buffer(packet.into());
+            }
+            (Some(_), None) => unreachable!(),
+        }
+    }
+    if let Some(mut out) = departures {
+        out.flush()?;
+    }
+
+    let delivered = delays.len() as u64;
+    println!(
+        "Simulated {:.3} s of traffic in {:.3} s",
+        duration.unwrap_or((clock.now() - origin).as_secs_f64()),
+        started.elapsed().as_secs_f64()
+    );
+    println!(
+        "{} packets received, {} delivered, {} dropped ({:.2}%), {} reordered",
+        received,
+        delivered,
+        dropped,
+        100.0 * dropped as f64 / received.max(1) as f64,
+        reordered
+    );
+    if invalid > 0 {
+        println!("{invalid} packets of the trace had an invalid header.");
+    }
+    println!("{bytes} bytes sent.");
+    if !delays.is_empty() {
+        delays.sort();
+        let mean = delays.iter().sum::<Duration>() / delays.len() as u32;
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        println!(
+            "Delay min/mean/p50/p99/max = {:.3}/{:.3}/{:.3}/{:.3}/{:.3} ms",
+            ms(delays[0]),
+            ms(mean),
+            ms(percentile(&delays, 0.5)),
+            ms(percentile(&delays, 0.99)),
+            ms(delays[delays.len() - 1])
+        );
+    }
+
+    Ok(())
+}
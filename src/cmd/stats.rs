@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use serde_json::json;
+use shufflerouter::stats::StatsSnapshot;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand, Debug)]
+pub enum StatsCommand {
+    /// Compare two stats files exported with --stats-json
+    Diff(DiffArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// Stats before the change
+    a: PathBuf,
+
+    /// Stats after the change
+    b: PathBuf,
+
+    /// Print the delta as JSON
+    #[clap(long = "json")]
+    json: bool,
+}
+
+pub fn run(command: StatsCommand) -> Result<()> {
+    match command {
+        StatsCommand::Diff(args) => diff(args),
+    }
+}
+
+fn load(path: &Path) -> Result<StatsSnapshot> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("could not read {path:?}"))?;
+    serde_json::from_str(&text).with_context(|| format!("{path:?} is not a stats file"))
+}
+
+/// Percentage of `part` over `total`
+fn share(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / total as f64
+    }
+}
+
+fn diff(args: DiffArgs) -> Result<()> {
+    let (a, b) = (load(&args.a)?, load(&args.b)?);
+
+    let mut names: Vec<&String> = a.counters.keys().chain(b.counters.keys()).collect();
+    names.sort();
+    names.dedup();
+    let value = |stats: &StatsSnapshot, name: &str| stats.counters.get(name).copied().unwrap_or(0);
+
+    let total = |stats: &StatsSnapshot| stats.delay_histogram.iter().map(|b| b.count).sum();
+    let (total_a, total_b) = (total(&a), total(&b));
+    let buckets = a.delay_histogram.len().max(b.delay_histogram.len());
+    let bucket = |stats: &StatsSnapshot, i: usize| {
+        stats
+            .delay_histogram
+            .get(i)
+            .map_or(0, |bucket| bucket.count)
+    };
+    let label = |i: usize| {
+        a.delay_histogram
+            .get(i)
+            .or(b.delay_histogram.get(i))
+            .and_then(|bucket| bucket.below_ms)
+    };
+
+    if args.json {
+        let counters: serde_json::Map<_, _> = names
+            .iter()
+            .map(|name| {
+                let (va, vb) = (value(&a, name), value(&b, name));
+                let (ra, rb) = (a.rate(name), b.rate(name));
+                (
+                    name.to_string(),
+                    json!({
+                        "a": va,
+                        "b": vb,
+                        "delta": vb as i64 - va as i64,
+                        "rate_a": ra,
+                        "rate_b": rb,
+                        "rate_delta": rb - ra,
+                    }),
+                )
+            })
+            .collect();
+        let histogram: Vec<_> = (0..buckets)
+            .map(|i| {
+                let (sa, sb) = (share(bucket(&a, i), total_a), share(bucket(&b, i), total_b));
+                json!({
+                    "below_ms": label(i),
+                    "share_a": sa,
+                    "share_b": sb,
+                    "delta": sb - sa,
+                })
+            })
+            .collect();
+        let delta = json!({
+            "duration_ms": { "a": a.duration_ms, "b": b.duration_ms },
+            "counters": counters,
+            "delay_histogram": histogram,
+        });
+        println!("{}", serde_json::to_string_pretty(&delta)?);
+
+        return Ok(());
+    }
+
+    println!(
+        "Duration: {:.1} s -> {:.1} s",
+        a.duration_ms as f64 / 1000.0,
+        b.duration_ms as f64 / 1000.0
+    );
+    println!(
+        "\n{:<24} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "counter", "a", "b", "delta", "a per s", "b per s"
+    );
+    for name in &names {
+        let (va, vb) = (value(&a, name), value(&b, name));
+        println!(
+            "{:<24} {:>12} {:>12} {:>+12} {:>12.2} {:>12.2}",
+            name,
+            va,
+            vb,
+            vb as i64 - va as i64,
+            a.rate(name),
+            b.rate(name)
+        );
+    }
+
+    println!(
+        "\n{:<24} {:>12} {:>12} {:>12}",
+        "delay", "a %", "b %", "delta (pp)"
+    );
+    for i in 0..buckets {
+        let (sa, sb) = (share(bucket(&a, i), total_a), share(bucket(&b, i), total_b));
+        let range = match label(i) {
+            Some(ms) => format!("< {ms} ms"),
+            None => "longer".to_owned(),
+        };
+        println!("{:<24} {:>12.2} {:>12.2} {:>+12.2}", range, sa, sb, sb - sa);
+    }
+
+    Ok(())
+}
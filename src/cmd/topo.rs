@@ -0,0 +1,212 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::ShutdownSource;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::Args;
+use log::info;
+use serde::Deserialize;
+use shufflerouter::profile::Profile;
+use shufflerouter::stats::Stats;
+use shufflerouter::Router;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Args, Debug)]
+pub struct TopoArgs {
+    /// Topology file
+    topology: PathBuf,
+
+    /// Only print the addressing plan, without starting the routers
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+
+fn default_base_port() -> u16 {
+    3000
+}
+
+/// A lab topology: hosts joined by links, each one emulated by a router
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Topology {
+    /// Port of the first link without an explicit one. The rest follow.
+    #[serde(default = "default_base_port")]
+    base_port: u16,
+    #[serde(default, rename = "node")]
+    nodes: Vec<Node>,
+    #[serde(default, rename = "link")]
+    links: Vec<Link>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Node {
+    name: String,
+    address: Option<Ipv4Addr>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Link {
+    a: String,
+    b: String,
+    port: Option<u16>,
+    #[serde(default)]
+    drop: f64,
+    #[serde(default)]
+    min_delay: u64,
+    #[serde(default)]
+    rand_delay: u64,
+}
+
+/// A link ready to be started
+struct Plan {
+    name: String,
+    port: u16,
+    profile: Profile,
+}
+
+impl Topology {
+    fn node(&self, name: &str) -> Result<&Node> {
+        self.nodes
+            .iter()
+            .find(|node| node.name == name)
+            .with_context(|| format!("unknown node {name:?}"))
+    }
+
+    fn plan(&self) -> Result<Vec<Plan>> {
+        let explicit: HashSet<u16> = self.links.iter().filter_map(|link| link.port).collect();
+        let mut free = (self.base_port..=u16::MAX).filter(|port| !explicit.contains(port));
+        let mut ports = HashSet::new();
+
+        self.links
+            .iter()
+            .map(|link| {
+                self.node(&link.a)?;
+                self.node(&link.b)?;
+                let name = format!("{}-{}", link.a, link.b);
+                let port = match link.port {
+                    Some(port) => port,
+                    None => free.next().context("no ports left")?,
+                };
+                ensure!(
+                    ports.insert(port),
+                    "link {name}: port {port} is already used"
+                );
+                let profile = Profile::new(link.drop, link.min_delay, link.rand_delay)
+                    .with_context(|| format!("link {name}"))?;
+
+                Ok(Plan {
+                    name,
+                    port,
+                    profile,
+                })
+            })
+            .collect()
+    }
+}
+
+fn describe(node: &Node) -> String {
+    match node.address {
+        Some(address) => format!("{} ({})", node.name, address),
+        None => node.name.clone(),
+    }
+}
+
+pub fn run(args: TopoArgs) -> Result<()> {
+    let topology: Topology =
+        toml::from_str(&std::fs::read_to_string(&args.topology)?).context("invalid topology")?;
+    if topology.links.is_empty() {
+        bail!("the topology has no links");
+    }
+    let plans = topology.plan()?;
+
+    println!("Links:");
+    for plan in &plans {
+        println!("  {:<20} port {:<6} {}", plan.name, plan.port, plan.profile);
+    }
+    println!("Addressing plan. Send to the router port, with the peer address in the header:");
+    for (link, plan) in topology.links.iter().zip(&plans) {
+        let (a, b) = (topology.node(&link.a)?, topology.node(&link.b)?);
+        println!(
+            "  {} reaches {} through port {}",
+            describe(a),
+            describe(b),
+            plan.port
+        );
+        println!(
+            "  {} reaches {} through port {}",
+            describe(b),
+            describe(a),
+            plan.port
+        );
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    // Every link runs in this very process, with its own router
+    let mut routers = Vec::new();
+    for plan in plans {
+        let router = Arc::new(
+            Router::builder()
+                .bind(IpAddr::from(Ipv4Addr::UNSPECIFIED))
+                .port(plan.port)
+                .profile(&plan.profile)
+                .build()
+                .with_context(|| {
+                    format!("link {}: could not bind port {}", plan.name, plan.port)
+                })?,
+        );
+        let thread = {
+            let router = router.clone();
+            thread::spawn(move || router.run())
+        };
+        info!("Link {} listening on port {}", plan.name, plan.port);
+        routers.push((plan.name, router, thread));
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let shutdown_signal = {
+        let _guard = runtime.enter();
+        ShutdownSource::new()?
+    };
+    runtime.block_on(shutdown_signal.wait())?;
+
+    println!();
+    for (name, router, thread) in routers {
+        router.shutdown()?;
+        thread
+            .join()
+            .map_err(|_| anyhow!("link {}: the router thread panicked", name))??;
+        let stats = router.stats();
+        println!(
+            "{}: {} packets received, {} bytes sent.",
+            name,
+            Stats::get(&stats.received),
+            Stats::get(&stats.bytes_sent)
+        );
+    }
+
+    Ok(())
+}
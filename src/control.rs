@@ -0,0 +1,154 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Control socket: a Unix-domain stream socket taking one command per line,
+//! so the impairments can be changed and the counters read while the router
+//! keeps running. Try `socat - UNIX-CONNECT:<path>` and then `help`.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use shufflerouter::router::Settings;
+use shufflerouter::stats::Stats;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+const HELP: &str = "\
+show                      Show the impairments in effect
+set <key=value>...        Change drop, min_delay, rand_delay, dup, corrupt, reorder or truncate, e.g. set drop=0.1
+stats                     Show the counters
+help                      Show this help
+quit                      Close the connection
+";
+
+/// Wait after a failed accept, so persistent errors such as running out of
+/// file descriptors do not spin
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Removes the socket file when dropped
+pub(crate) struct ControlSocket(PathBuf);
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Starts answering the commands sent to a socket at `path` until `shutdown`
+/// is set. A leftover socket nobody listens on is replaced.
+pub(crate) fn spawn(
+    path: &Path,
+    settings: Settings,
+    stats: Arc<Stats>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<ControlSocket> {
+    if path.exists() && UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("could not create the control socket {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+
+    thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            while !shutdown.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let (settings, stats) = (settings.clone(), stats.clone());
+                        thread::spawn(move || {
+                            if let Err(e) = serve(stream, &settings, &stats) {
+                                warn!("Control connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(100))
+                    }
+                    Err(e) => {
+                        warn!("Error accepting control connection: {}", e);
+                        thread::sleep(ACCEPT_BACKOFF)
+                    }
+                }
+            }
+        })?;
+
+    Ok(ControlSocket(path.to_owned()))
+}
+
+fn serve(stream: UnixStream, settings: &Settings, stats: &Stats) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut out = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+        match command {
+            "" => continue,
+            "show" => writeln!(out, "{}", settings.profile.load().0)?,
+            "set" => {
+                let (current, _) = settings.profile.load();
+                match current.with_settings(args) {
+                    Ok(profile) => {
+                        info!("Control: impairments changed to {}", profile);
+                        settings.profile.set(profile.clone());
+                        writeln!(out, "{profile}")?;
+                    }
+                    Err(e) => writeln!(out, "error: {e}")?,
+                }
+            }
+            "stats" => {
+                let snapshot = stats.snapshot(settings.uptime());
+                writeln!(out, "uptime_ms {}", snapshot.duration_ms)?;
+                if let Some(latency) = &snapshot.latency {
+                    writeln!(
+                        out,
+                        "latency_ms min={} mean={} p95={} max={}",
+                        latency.min_ms, latency.mean_ms, latency.p95_ms, latency.max_ms
+                    )?;
+                }
+                for (name, value) in snapshot.counters {
+                    writeln!(out, "{name} {value}")?;
+                }
+                for flow in snapshot.flows {
+                    let counters = flow.counters;
+                    writeln!(
+                        out,
+                        "flow {} {} received={} bytes={} dropped={} sent={}",
+                        flow.src,
+                        flow.dst,
+                        counters.received,
+                        counters.bytes,
+                        counters.dropped,
+                        counters.sent
+                    )?;
+                }
+            }
+            "help" => out.write_all(HELP.as_bytes())?,
+            "quit" => break,
+            _ => writeln!(out, "error: unknown command {command:?}. Try help")?,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::eventlog;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error("another instance (pid {1}) already holds {0}")]
+    AlreadyRunning(PathBuf, String),
+    #[error("could not use pid file {0}: {1}")]
+    PidFile(PathBuf, io::Error),
+    #[error("could not detach from the terminal: {0}")]
+    Detach(io::Error),
+    #[error("could not use log file {0}: {1}")]
+    LogFile(PathBuf, io::Error),
+}
+
+/// An exclusively locked pid file. The lock lasts as long as the value lives.
+pub struct PidFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Default pid file location for a router listening on `port`
+    pub fn default_path(port: u16) -> PathBuf {
+        std::env::temp_dir().join(format!("shufflerouter-{port}.pid"))
+    }
+
+    /// Opens and locks `path`, failing if another process holds the lock
+    pub fn lock(path: &Path) -> Result<PidFile, DaemonError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| DaemonError::PidFile(path.to_owned(), e))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(DaemonError::AlreadyRunning(
+                    path.to_owned(),
+                    pid.trim().to_owned(),
+                ));
+            }
+            return Err(DaemonError::PidFile(path.to_owned(), err));
+        }
+
+        // Keep the absolute path, so the file can still be removed after
+        // daemonize changes the working directory
+        Ok(PidFile {
+            file,
+            path: std::path::absolute(path).unwrap_or_else(|_| path.to_owned()),
+        })
+    }
+
+    /// Stores the pid of the current process
+    pub fn write_pid(&mut self) -> Result<(), DaemonError> {
+        let pid = std::process::id();
+        self.file
+            .set_len(0)
+            .and_then(|_| self.file.rewind())
+            .and_then(|_| writeln!(self.file, "{pid}"))
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| DaemonError::PidFile(self.path.clone(), e))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Detaches the process from the controlling terminal. The parent process
+/// exits, so this must be called before spawning any thread.
+pub fn daemonize() -> Result<(), DaemonError> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(DaemonError::Detach(io::Error::last_os_error())),
+        0 => (),
+        _ => std::process::exit(0),
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(DaemonError::Detach(io::Error::last_os_error()));
+    }
+
+    let devnull = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(DaemonError::Detach)?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(devnull.as_raw_fd(), fd) } == -1 {
+            return Err(DaemonError::Detach(io::Error::last_os_error()));
+        }
+    }
+
+    std::env::set_current_dir("/").map_err(DaemonError::Detach)
+}
+
+/// Opens `path` for appending the output of the process with
+/// [`redirect_output`]. Relative paths are resolved now, so it can be
+/// called before [`daemonize`] changes the working directory.
+pub fn open_log(path: &Path) -> Result<File, DaemonError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| DaemonError::LogFile(path.to_owned(), e))
+}
+
+/// Makes the standard output and error of the process, and so the log, go
+/// to `file`
+pub fn redirect_output(file: &File) -> Result<(), DaemonError> {
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(DaemonError::Detach(io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// A logger sending the messages of the modules under `module` to syslog
+pub struct SyslogLogger {
+    module: String,
+    level: LevelFilter,
+}
+
+impl SyslogLogger {
+    /// Installs the logger, up to the level `stderrlog` uses for `verbosity`
+    pub fn init(module: &str, verbosity: usize) -> Result<(), log::SetLoggerError> {
+        let level = eventlog::verbosity_level(verbosity);
+        unsafe { libc::openlog(c"shufflerouter".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+        log::set_boxed_logger(Box::new(SyslogLogger {
+            module: module.to_owned(),
+            level,
+        }))?;
+        log::set_max_level(level);
+
+        Ok(())
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && metadata
+                .target()
+                .strip_prefix(self.module.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let priority = match record.level() {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug | Level::Trace => libc::LOG_DEBUG,
+        };
+        // Messages with a NUL byte in them would be cut short
+        if let Ok(message) = CString::new(record.args().to_string().replace('\0', "")) {
+            unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Per-packet CSV export, one row for every packet once its fate is known,
+//! for the analysis of the impairments with a spreadsheet.
+//!
+//! Times are in seconds since the router started, and delays in
+//! milliseconds. The delay and departure of dropped packets are left empty,
+//! and so is the destination of those dropped before reading it.
+
+use crate::packet::Packet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const HEADER: &str = "arrival,source,destination,size,decision,delay_ms,departure,reason";
+
+/// Writes the rows of the packets. It can be shared among threads.
+pub struct EventCsv {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl EventCsv {
+    pub fn create(path: &Path, start: Instant) -> io::Result<EventCsv> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{HEADER}")?;
+
+        Ok(EventCsv {
+            start,
+            out: Mutex::new(out),
+        })
+    }
+
+    fn seconds(&self, time: Instant) -> f64 {
+        time.saturating_duration_since(self.start).as_secs_f64()
+    }
+
+    /// Writes the row of `packet`, sent at `departure`
+    pub fn forwarded(&self, packet: &Packet, departure: Instant) -> io::Result<()> {
+        writeln!(
+            self.out.lock().unwrap(),
+            "{:.6},{},{},{},forwarded,{:.3},{:.6},",
+            self.seconds(packet.arrival()),
+            packet.src(),
+            packet.dst(),
+            packet.get().len(),
+            packet
+                .exit_time()
+                .saturating_duration_since(packet.arrival())
+                .as_secs_f64()
+                * 1000.0,
+            self.seconds(departure)
+        )
+    }
+
+    /// Writes the row of a datagram of `len` bytes dropped for `reason`
+    pub fn dropped(
+        &self,
+        arrival: Instant,
+        src: SocketAddr,
+        dst: Option<SocketAddr>,
+        len: usize,
+        reason: &str,
+    ) -> io::Result<()> {
+        writeln!(
+            self.out.lock().unwrap(),
+            "{:.6},{},{},{},dropped,,,{}",
+            self.seconds(arrival),
+            src,
+            dst.map(|dst| dst.to_string()).unwrap_or_default(),
+            len,
+            reason
+        )
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Structured logging: one JSON object per line on the standard error, for
+//! log collectors such as Logstash.
+//!
+//! Once [`JsonLogger::init`] is called, the router events passed to [`emit`]
+//! are written along the usual log messages, which become `log` objects, or
+//! `error` ones for warnings and errors. They are also sent to the receivers
+//! returned by [`subscribe`].
+
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// Events kept for a subscriber that falls behind
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SUBSCRIBERS: OnceLock<broadcast::Sender<(SystemTime, Event)>> = OnceLock::new();
+
+/// What happened to a datagram
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Receive {
+        src: SocketAddr,
+        len: usize,
+    },
+    Enqueue {
+        src: SocketAddr,
+        dst: SocketAddr,
+        len: usize,
+        delay_ms: u128,
+    },
+    Drop {
+        src: SocketAddr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dst: Option<SocketAddr>,
+        len: usize,
+        reason: &'static str,
+    },
+    Send {
+        src: SocketAddr,
+        dst: SocketAddr,
+        len: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<T: Serialize> {
+    ts: String,
+    #[serde(flatten)]
+    body: T,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Message<'a> {
+    Log {
+        level: &'static str,
+        target: &'a str,
+        message: String,
+    },
+    Error {
+        level: &'static str,
+        target: &'a str,
+        message: String,
+    },
+}
+
+fn write<T: Serialize>(body: T) {
+    let line = Line {
+        ts: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        body,
+    };
+    if let Ok(mut json) = serde_json::to_vec(&line) {
+        json.push(b'\n');
+        // A single write, so lines of different threads do not mix
+        let _ = io::stderr().lock().write_all(&json);
+    }
+}
+
+/// Writes `event`, if JSON logging is enabled, and sends it to the
+/// subscribers
+pub fn emit(event: &Event) {
+    if ENABLED.load(Ordering::Relaxed) {
+        write(event);
+    }
+    if let Some(subscribers) = SUBSCRIBERS.get() {
+        if subscribers.receiver_count() > 0 {
+            let _ = subscribers.send((SystemTime::now(), *event));
+        }
+    }
+}
+
+/// Receives every event emitted from now on, along with when it happened.
+/// A receiver that falls behind loses the oldest ones.
+pub fn subscribe() -> broadcast::Receiver<(SystemTime, Event)> {
+    SUBSCRIBERS
+        .get_or_init(|| broadcast::channel(SUBSCRIBER_BACKLOG).0)
+        .subscribe()
+}
+
+/// A logger writing the messages of the modules under `module` as JSON
+pub struct JsonLogger {
+    module: String,
+    level: LevelFilter,
+}
+
+/// The level `stderrlog` logs up to for `verbosity`
+pub fn verbosity_level(verbosity: usize) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+impl JsonLogger {
+    /// Installs the logger, up to the level `stderrlog` uses for `verbosity`
+    pub fn init(module: &str, verbosity: usize) -> Result<(), log::SetLoggerError> {
+        let level = verbosity_level(verbosity);
+        log::set_boxed_logger(Box::new(JsonLogger {
+            module: module.to_owned(),
+            level,
+        }))?;
+        log::set_max_level(level);
+        ENABLED.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && metadata
+                .target()
+                .strip_prefix(self.module.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let (level, target, message) = (
+            record.level().as_str(),
+            record.target(),
+            record.args().to_string(),
+        );
+        write(if record.level() <= Level::Warn {
+            Message::Error {
+                level,
+                target,
+                message,
+            }
+        } else {
+            Message::Log {
+                level,
+                target,
+                message,
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
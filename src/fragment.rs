@@ -0,0 +1,64 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Fragmentation emulation.
+//!
+//! When the router fragments, every datagram it forwards carries a tag right
+//! after the header, even those short enough to leave whole:
+//!
+//! ```text
+//! +--------+---------+-------------+----------+---------+
+//! | header | id (16) | offset (16) | more (8) | payload |
+//! +--------+---------+-------------+----------+---------+
+//! ```
+//!
+//! The id, in network byte order, is shared by every fragment of a datagram.
+//! The offset, also in network byte order, is the position of the fragment
+//! in the original payload, in bytes. The last byte is one while more
+//! fragments follow and zero for the last one, so a datagram sent whole
+//! has offset zero and no more fragments.
+
+/// Length of the tag, in bytes
+pub const TAG_LEN: usize = 5;
+
+/// Where a fragment belongs in the datagram it is part of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FragmentTag {
+    pub id: u16,
+    pub offset: u16,
+    pub more: bool,
+}
+
+impl FragmentTag {
+    /// Writes the tag in the first `TAG_LEN` bytes of `data`
+    pub fn write(&self, data: &mut [u8]) {
+        data[..2].copy_from_slice(&self.id.to_be_bytes());
+        data[2..4].copy_from_slice(&self.offset.to_be_bytes());
+        data[4] = u8::from(self.more);
+    }
+
+    /// Reads the tag from the start of `data`, the bytes following the header
+    pub fn decode(data: &[u8]) -> Option<FragmentTag> {
+        let tag = data.get(..TAG_LEN)?;
+
+        Some(FragmentTag {
+            id: u16::from_be_bytes([tag[0], tag[1]]),
+            offset: u16::from_be_bytes([tag[2], tag[3]]),
+            more: tag[4] != 0,
+        })
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! gRPC control API, for orchestration services: the operations of the
+//! control socket and a stream of packet events. The service is described in
+//! `proto/control.proto`; its messages and routing are written out here, so
+//! building does not need `protoc`.
+
+// Status, however large, is what every method has to fail with
+#![allow(clippy::result_large_err)]
+
+use anyhow::Result;
+use log::{info, warn};
+use shufflerouter::eventlog::{self, Event};
+use shufflerouter::profile::Profile;
+use shufflerouter::router::Router;
+use shufflerouter::stats::Stats;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::StreamExt;
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, BoxFuture, BoxStream, Context, Poll, Service};
+use tonic::server::{Grpc, NamedService};
+use tonic::Status;
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Flow {
+    #[prost(string, tag = "1")]
+    pub src: String,
+    #[prost(string, tag = "2")]
+    pub dst: String,
+    #[prost(uint64, tag = "3")]
+    pub received: u64,
+    #[prost(uint64, tag = "4")]
+    pub bytes: u64,
+    #[prost(uint64, tag = "5")]
+    pub dropped: u64,
+    #[prost(uint64, tag = "6")]
+    pub sent: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StatsReply {
+    #[prost(string, tag = "1")]
+    pub version: String,
+    #[prost(uint64, tag = "2")]
+    pub uptime_ms: u64,
+    #[prost(map = "string, uint64", tag = "3")]
+    pub counters: HashMap<String, u64>,
+    #[prost(uint64, tag = "4")]
+    pub queued: u64,
+    #[prost(message, repeated, tag = "5")]
+    pub flows: Vec<Flow>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Impairments {
+    #[prost(map = "string, string", tag = "1")]
+    pub settings: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct FlushQueueReply {
+    #[prost(uint64, tag = "1")]
+    pub discarded: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketEvent {
+    #[prost(uint64, tag = "1")]
+    pub timestamp_us: u64,
+    #[prost(string, tag = "2")]
+    pub event: String,
+    #[prost(string, tag = "3")]
+    pub src: String,
+    #[prost(string, tag = "4")]
+    pub dst: String,
+    #[prost(uint64, tag = "5")]
+    pub len: u64,
+    #[prost(uint64, tag = "6")]
+    pub delay_ms: u64,
+    #[prost(string, tag = "7")]
+    pub reason: String,
+    #[prost(uint64, tag = "8")]
+    pub missed: u64,
+}
+
+/// Answers the requests of a method with a function
+struct Unary<F>(F);
+
+impl<F, Request, Response> Service<tonic::Request<Request>> for Unary<F>
+where
+    F: FnMut(Request) -> Result<Response, Status>,
+{
+    type Response = tonic::Response<Response>;
+    type Error = Status;
+    type Future = Ready<Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::Request<Request>) -> Self::Future {
+        ready((self.0)(request.into_inner()).map(tonic::Response::new))
+    }
+}
+
+/// Answers StreamEvents requests
+struct Events;
+
+impl Service<tonic::Request<Empty>> for Events {
+    type Response = tonic::Response<BoxStream<PacketEvent>>;
+    type Error = Status;
+    type Future = Ready<Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _request: tonic::Request<Empty>) -> Self::Future {
+        let mut missed = 0;
+        let events =
+            BroadcastStream::new(eventlog::subscribe()).filter_map(move |event| match event {
+                Ok((time, event)) => {
+                    let mut event = packet_event(event);
+                    event.timestamp_us = time
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_micros() as u64;
+                    event.missed = std::mem::take(&mut missed);
+                    Some(Ok(event))
+                }
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    missed += skipped;
+                    None
+                }
+            });
+
+        ready(Ok(tonic::Response::new(Box::pin(events))))
+    }
+}
+
+fn packet_event(event: Event) -> PacketEvent {
+    match event {
+        Event::Receive { src, len } => PacketEvent {
+            event: "receive".to_owned(),
+            src: src.to_string(),
+            len: len as u64,
+            ..Default::default()
+        },
+        Event::Enqueue {
+            src,
+            dst,
+            len,
+            delay_ms,
+        } => PacketEvent {
+            event: "enqueue".to_owned(),
+            src: src.to_string(),
+            dst: dst.to_string(),
+            len: len as u64,
+            delay_ms: delay_ms as u64,
+            ..Default::default()
+        },
+        Event::Drop {
+            src,
+            dst,
+            len,
+            reason,
+        } => PacketEvent {
+            event: "drop".to_owned(),
+            src: src.to_string(),
+            dst: dst.map(|dst| dst.to_string()).unwrap_or_default(),
+            len: len as u64,
+            reason: reason.to_owned(),
+            ..Default::default()
+        },
+        Event::Send { src, dst, len } => PacketEvent {
+            event: "send".to_owned(),
+            src: src.to_string(),
+            dst: dst.to_string(),
+            len: len as u64,
+            ..Default::default()
+        },
+    }
+}
+
+/// The `key=value` pairs of `profile`
+fn impairments(profile: &Profile) -> Impairments {
+    let settings = profile
+        .to_string()
+        .split_whitespace()
+        .filter_map(|setting| setting.split_once('='))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+
+    Impairments { settings }
+}
+
+fn get_stats(router: &Router) -> StatsReply {
+    let stats = router.stats();
+    let snapshot = stats.snapshot(router.settings().uptime());
+
+    StatsReply {
+        version: snapshot.version,
+        uptime_ms: snapshot.duration_ms,
+        counters: snapshot
+            .counters
+            .into_iter()
+            .map(|(name, value)| (name, value as u64))
+            .collect(),
+        queued: Stats::get(&stats.queued) as u64,
+        flows: snapshot
+            .flows
+            .into_iter()
+            .map(|flow| Flow {
+                src: flow.src,
+                dst: flow.dst,
+                received: flow.counters.received as u64,
+                bytes: flow.counters.bytes as u64,
+                dropped: flow.counters.dropped as u64,
+                sent: flow.counters.sent as u64,
+            })
+            .collect(),
+    }
+}
+
+fn set_impairments(router: &Router, changes: Impairments) -> Result<Impairments, Status> {
+    let profile = &router.settings().profile;
+    if changes.settings.is_empty() {
+        return Ok(impairments(&profile.load().0));
+    }
+
+    let changes = changes
+        .settings
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let changed = profile
+        .load()
+        .0
+        .with_settings(&changes)
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    info!("gRPC: impairments changed to {}", changed);
+    profile.set(changed.clone());
+
+    Ok(impairments(&changed))
+}
+
+fn flush_queue(router: &Router) -> Result<FlushQueueReply, Status> {
+    let discarded = Stats::get(&router.stats().queued) as u64;
+    router
+        .flush_queue()
+        .map_err(|e| Status::internal(e.to_string()))?;
+    info!("gRPC: queue flushed");
+
+    Ok(FlushQueueReply { discarded })
+}
+
+/// The `shufflerouter.Control` service
+#[derive(Clone)]
+struct ControlService(Arc<Router>);
+
+impl NamedService for ControlService {
+    const NAME: &'static str = "shufflerouter.Control";
+}
+
+impl Service<http::Request<BoxBody>> for ControlService {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        let router = self.0.clone();
+
+        Box::pin(async move {
+            let response = match request.uri().path() {
+                "/shufflerouter.Control/GetStats" => {
+                    let method = Unary(|_: Empty| Ok(get_stats(&router)));
+                    Grpc::new(ProstCodec::default())
+                        .unary(method, request)
+                        .await
+                }
+                "/shufflerouter.Control/SetImpairments" => {
+                    let method = Unary(|changes| set_impairments(&router, changes));
+                    Grpc::new(ProstCodec::default())
+                        .unary(method, request)
+                        .await
+                }
+                "/shufflerouter.Control/FlushQueue" => {
+                    let method = Unary(|_: Empty| flush_queue(&router));
+                    Grpc::new(ProstCodec::default())
+                        .unary(method, request)
+                        .await
+                }
+                "/shufflerouter.Control/StreamEvents" => {
+                    Grpc::new(ProstCodec::default())
+                        .server_streaming(Events, request)
+                        .await
+                }
+                path => Status::unimplemented(format!("no method {path}")).into_http(),
+            };
+
+            Ok(response)
+        })
+    }
+}
+
+/// Starts serving the control API to the connections accepted by `listener`,
+/// on the tokio runtime entered
+pub(crate) fn spawn(listener: TcpListener, router: Arc<Router>) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+
+    tokio::spawn(async move {
+        let server = tonic::transport::Server::builder()
+            .add_service(ControlService(router))
+            .serve_with_incoming(incoming);
+        if let Err(e) = server.await {
+            warn!("The gRPC server failed: {}", e);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Virtual hops: emulated links every packet goes through, one after the
+//! other, before the router sends it out, so a single router can stand for a
+//! path through several routers.
+//!
+//! A hops file is a TOML file with a list of `[[hop]]` tables, in the order
+//! packets traverse them. Each one has its own drop probability, delays, in
+//! milliseconds, and rate, in megabits per second. A hop listing networks in
+//! `to` only carries the packets to them, which lets paths branch.
+//!
+//! ```toml
+//! [[hop]]
+//! name = "access"
+//! min_delay = 2
+//! rate = 10
+//!
+//! [[hop]]
+//! name = "backbone"
+//! drop = 0.01
+//! min_delay = 40
+//! rand_delay = 10
+//!
+//! [[hop]]
+//! name = "wifi"
+//! drop = 0.05
+//! rand_delay = 20
+//! to = ["10.0.2.0/24"]
+//! ```
+
+use crate::profile::{Profile, ProfileError};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum HopsError {
+    #[error("could not read the hops: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid hops")]
+    Parse(#[from] toml::de::Error),
+    #[error("hop {0}: {1}")]
+    Profile(String, ProfileError),
+    #[error("hop {0}: the rate must be positive")]
+    InvalidRate(String),
+    #[error("hop {0}: invalid network {1:?}")]
+    InvalidNetwork(String, String),
+    #[error("no hops defined")]
+    Empty,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct HopSpec {
+    name: String,
+    #[serde(default)]
+    drop: f64,
+    #[serde(default)]
+    min_delay: u64,
+    #[serde(default)]
+    rand_delay: u64,
+    /// Megabits per second
+    rate: Option<f64>,
+    /// Addresses or networks
+    #[serde(default)]
+    to: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct HopsFile {
+    #[serde(default, rename = "hop")]
+    hops: Vec<HopSpec>,
+}
+
+/// An emulated link
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub name: String,
+    pub profile: Profile,
+    /// Bits per second, transmitting one packet at a time
+    pub rate: Option<f64>,
+    /// Destinations of the packets it carries. Empty for all of them.
+    pub to: Vec<IpNet>,
+}
+
+impl Hop {
+    /// Whether packets to `dst` go through this hop
+    pub fn carries(&self, dst: IpAddr) -> bool {
+        let dst = dst.to_canonical();
+        self.to.is_empty() || self.to.iter().any(|net| net.contains(&dst))
+    }
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.profile)?;
+        if let Some(rate) = self.rate {
+            write!(f, " rate={} Mbps", rate / 1e6)?;
+        }
+        if !self.to.is_empty() {
+            let to: Vec<_> = self.to.iter().map(IpNet::to_string).collect();
+            write!(f, " to {}", to.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the hops in `path`, in the order packets go through them
+pub fn load(path: &Path) -> Result<Vec<Hop>, HopsError> {
+    let file: HopsFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+    if file.hops.is_empty() {
+        return Err(HopsError::Empty);
+    }
+
+    file.hops
+        .into_iter()
+        .map(|spec| {
+            if spec
+                .rate
+                .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+            {
+                return Err(HopsError::InvalidRate(spec.name));
+            }
+            let profile = match Profile::new(spec.drop, spec.min_delay, spec.rand_delay) {
+                Ok(profile) => profile,
+                Err(e) => return Err(HopsError::Profile(spec.name, e)),
+            };
+            let mut to = Vec::new();
+            for net in &spec.to {
+                match net
+                    .parse()
+                    .ok()
+                    .or_else(|| net.parse::<IpAddr>().ok().map(IpNet::from))
+                {
+                    Some(net) => to.push(net),
+                    None => return Err(HopsError::InvalidNetwork(spec.name, net.clone())),
+                }
+            }
+
+            Ok(Hop {
+                name: spec.name,
+                profile,
+                rate: spec.rate.map(|mbps| mbps * 1e6),
+                to,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,287 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Status page and admin API: a tiny HTTP server showing the state of the
+//! router in a browser, so it can be checked during lab sessions without a
+//! terminal, and answering the JSON requests of scripts driving the router.
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Map, Number, Value};
+use shufflerouter::profile::Profile;
+use shufflerouter::router::{Router, Settings};
+use shufflerouter::stats::Stats;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+/// Flows shown, those with the most bytes
+const TOP_TALKERS: usize = 10;
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest request body accepted
+const MAX_BODY: usize = 64 * 1024;
+/// Wait after a failed accept, so persistent errors such as running out of
+/// file descriptors do not spin
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: body.to_owned(),
+        }
+    }
+
+    fn json(status: &'static str, value: &impl Serialize) -> Response {
+        match serde_json::to_string(value) {
+            Ok(body) => Response {
+                status,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Response::error("500 Internal Server Error", e),
+        }
+    }
+
+    fn error(status: &'static str, e: impl std::fmt::Display) -> Response {
+        Response::json(status, &json!({ "error": e.to_string() }))
+    }
+}
+
+/// Starts serving the status page, and the API, to the connections accepted
+/// by `listener` until `shutdown` is set. Only with `admin` can requests
+/// change the router.
+pub(crate) fn spawn(
+    listener: TcpListener,
+    router: Arc<Router>,
+    admin: bool,
+    shutdown: Arc<AtomicBool>,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+
+    thread::Builder::new().name("http".into()).spawn(move || {
+        while !shutdown.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let router = router.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &router, admin) {
+                            warn!("HTTP connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                Err(e) => {
+                    warn!("Error accepting HTTP connection: {}", e);
+                    thread::sleep(ACCEPT_BACKOFF)
+                }
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Answers a single request, closing the connection afterwards
+fn serve(stream: TcpStream, router: &Router, admin: bool) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut out = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Only the length of the body matters among the headers
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let response = if length > MAX_BODY {
+        Response::text("413 Payload Too Large", "Request too large\n")
+    } else {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        let mut words = request.split_whitespace();
+        let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        respond(method, path, &body, router, admin)
+    };
+
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+fn respond(method: &str, path: &str, body: &[u8], router: &Router, admin: bool) -> Response {
+    let (settings, stats) = (router.settings(), router.stats());
+    match (method, path) {
+        ("GET", "/") => Response {
+            status: "200 OK",
+            content_type: "text/html",
+            body: page(settings, &stats),
+        },
+        ("GET", "/api/stats") => Response::json("200 OK", &stats.snapshot(settings.uptime())),
+        ("GET", "/api/flows") => Response::json("200 OK", &stats.snapshot(settings.uptime()).flows),
+        ("GET", "/api/impairments") => {
+            Response::json("200 OK", &impairments(&settings.profile.load().0))
+        }
+        ("PUT", "/api/impairments") if admin => set_impairments(body, settings),
+        ("POST", "/api/flush") if admin => {
+            let queued = Stats::get(&stats.queued);
+            match router.flush_queue() {
+                Ok(()) => {
+                    info!("HTTP: queue flushed");
+                    Response::json("200 OK", &json!({ "discarded": queued }))
+                }
+                Err(e) => Response::error("500 Internal Server Error", e),
+            }
+        }
+        ("PUT", "/api/impairments") | ("POST", "/api/flush") => Response::error(
+            "403 Forbidden",
+            "changes need the router to be started with --http-admin",
+        ),
+        (_, "/" | "/api/stats" | "/api/flows" | "/api/impairments" | "/api/flush") => {
+            Response::error("405 Method Not Allowed", format!("{method} not allowed"))
+        }
+        _ => Response::error("404 Not Found", format!("no such resource {path}")),
+    }
+}
+
+/// The `key=value` pairs of `profile`, as a JSON object
+fn impairments(profile: &Profile) -> Map<String, Value> {
+    profile
+        .to_string()
+        .split_whitespace()
+        .filter_map(|setting| setting.split_once('='))
+        .map(|(key, value)| {
+            let value = serde_json::from_str::<Number>(value)
+                .map_or_else(|_| Value::from(value), Value::Number);
+            (key.to_owned(), value)
+        })
+        .collect()
+}
+
+/// Applies the impairments of a JSON object, with the keys and values the
+/// control socket `set` command takes
+fn set_impairments(body: &[u8], settings: &Settings) -> Response {
+    let changes: Map<String, Value> = match serde_json::from_slice(body) {
+        Ok(changes) => changes,
+        Err(e) => return Response::error("400 Bad Request", e),
+    };
+    let changes = changes
+        .iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => format!("{key}={value}"),
+            value => format!("{key}={value}"),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let (current, _) = settings.profile.load();
+    match current.with_settings(&changes) {
+        Ok(profile) => {
+            info!("HTTP: impairments changed to {}", profile);
+            settings.profile.set(profile.clone());
+            Response::json("200 OK", &impairments(&profile))
+        }
+        Err(e) => Response::error("400 Bad Request", e),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn page(settings: &Settings, stats: &Stats) -> String {
+    let snapshot = stats.snapshot(settings.uptime());
+    let uptime = snapshot.duration_ms / 1000;
+    let mut html = String::new();
+
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><title>ShuffleRouter</title>\
+         <meta http-equiv=\"refresh\" content=\"5\"></head><body>\n\
+         <h1>ShuffleRouter {}</h1>\n\
+         <p>Up for {}:{:02}:{:02}. {} packets queued.</p>\n\
+         <h2>Impairments</h2>\n<p>{}</p>\n",
+        snapshot.version,
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60,
+        Stats::get(&stats.queued),
+        escape(&settings.profile.load().0.to_string())
+    );
+
+    if let Some(latency) = &snapshot.latency {
+        let _ = writeln!(html, "<h2>Time in the router</h2>\n<p>{latency}</p>");
+    }
+
+    html.push_str("<h2>Counters</h2>\n<table>\n");
+    for (name, value) in snapshot.counters.iter().filter(|(_, value)| **value > 0) {
+        let _ = writeln!(html, "<tr><td>{name}</td><td>{value}</td></tr>");
+    }
+    html.push_str("</table>\n");
+
+    let mut flows = snapshot.flows;
+    flows.sort_by_key(|flow| std::cmp::Reverse(flow.counters.bytes));
+    html.push_str(
+        "<h2>Top talkers</h2>\n<table>\n<tr><th>Source</th><th>Destination</th>\
+         <th>Received</th><th>Bytes</th><th>Dropped</th><th>Sent</th></tr>\n",
+    );
+    for flow in flows.iter().take(TOP_TALKERS) {
+        let counters = &flow.counters;
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            flow.src, flow.dst, counters.received, counters.bytes, counters.dropped, counters.sent
+        );
+    }
+    html.push_str("</table>\n</body></html>\n");
+
+    html
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_PORT_UNREACH: u8 = 3;
+const ICMP6_DST_UNREACH: u8 = 1;
+const ICMP6_DST_UNREACH_NOPORT: u8 = 4;
+
+/// Payload sent back to a sender whose destination is unreachable, after the
+/// header with the address of that destination
+pub const UNREACHABLE_NOTICE: &[u8] = b"shufflerouter: destination port unreachable";
+
+/// An error reported by the kernel for a previously sent datagram
+pub struct IcmpError {
+    /// Address the failed datagram was sent to
+    pub dst: SocketAddr,
+    /// Length of the returned copy of the failed datagram
+    pub len: usize,
+    origin: u8,
+    icmp_type: u8,
+    icmp_code: u8,
+}
+
+impl IcmpError {
+    pub fn is_port_unreachable(&self) -> bool {
+        match self.origin {
+            libc::SO_EE_ORIGIN_ICMP => {
+                self.icmp_type == ICMP_DEST_UNREACH && self.icmp_code == ICMP_PORT_UNREACH
+            }
+            libc::SO_EE_ORIGIN_ICMP6 => {
+                self.icmp_type == ICMP6_DST_UNREACH && self.icmp_code == ICMP6_DST_UNREACH_NOPORT
+            }
+            _ => false,
+        }
+    }
+}
+
+fn set_option(socket: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Asks the kernel to queue the ICMP errors received for the socket. IPv6
+/// sockets get both the ICMPv6 errors and those of their IPv4 peers.
+pub fn enable_recverr(socket: &impl AsRawFd, ipv6: bool) -> io::Result<()> {
+    set_option(socket, libc::IPPROTO_IP, libc::IP_RECVERR)?;
+    if ipv6 {
+        set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR)?;
+    }
+
+    Ok(())
+}
+
+/// Decodes the address the kernel stored in `name`, turning IPv4-mapped
+/// addresses back into IPv4 ones
+fn decode_name(name: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match libc::c_int::from(name.ss_family) {
+        libc::AF_INET => {
+            let name = unsafe { &*(name as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr)),
+                u16::from_be(name.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let name = unsafe { &*(name as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(name.sin6_addr.s6_addr);
+            let port = u16::from_be(name.sin6_port);
+            Ok(match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                None => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+            })
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected address family {family}"),
+        )),
+    }
+}
+
+/// Reads the next entry of the socket error queue, copying the failed datagram
+/// into `buf`. Fails with `WouldBlock` once the queue is empty.
+pub fn recv_error(socket: &impl AsRawFd, buf: &mut [u8]) -> io::Result<IcmpError> {
+    let mut name = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut control = [0u64; 64]; // u64 to keep the control messages aligned
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = name.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let len = unsafe {
+        libc::recvmsg(
+            socket.as_raw_fd(),
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let dst = decode_name(unsafe { name.assume_init_ref() })?;
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if (header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVERR)
+            || (header.cmsg_level == libc::IPPROTO_IPV6 && header.cmsg_type == libc::IPV6_RECVERR)
+        {
+            let err = unsafe {
+                (libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err).read_unaligned()
+            };
+            return Ok(IcmpError {
+                dst,
+                len: len as usize,
+                origin: err.ee_origin,
+                icmp_type: err.ee_type,
+                icmp_code: err.ee_code,
+            });
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "error queue entry without extended error",
+    ))
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! In-band status queries.
+//!
+//! A datagram whose whole payload is `QUERY` is answered immediately by the
+//! router with its status, as text lines: a first `shufflerouter <version>`
+//! line followed by `key=value` lines. Regular packets cannot be mistaken for
+//! queries, as they need at least six bytes.
+
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+pub const QUERY: &[u8] = b"S?";
+
+pub fn is_query(data: &[u8]) -> bool {
+    data == QUERY
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub version: String,
+    pub uptime: Duration,
+    /// Impairments in effect
+    pub profile: String,
+    /// Packets waiting in the queue
+    pub queued: usize,
+    /// Buffers held for reuse
+    pub pooled: usize,
+    /// Address the router is reachable at from the Internet, if known
+    pub public_address: Option<SocketAddrV4>,
+}
+
+impl Status {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut text = format!(
+            "shufflerouter {}\nuptime_ms={}\nprofile={}\nqueued={}\npooled={}\n",
+            self.version,
+            self.uptime.as_millis(),
+            self.profile,
+            self.queued,
+            self.pooled
+        );
+        if let Some(addr) = self.public_address {
+            text += &format!("public_address={addr}\n");
+        }
+
+        text.into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Status> {
+        let text = std::str::from_utf8(data).ok()?;
+        let mut lines = text.lines();
+        let version = lines.next()?.strip_prefix("shufflerouter ")?.to_owned();
+
+        let (mut uptime, mut profile, mut queued) = (None, None, None);
+        let (mut pooled, mut public_address) = (None, None);
+        for line in lines {
+            match line.split_once('=')? {
+                ("uptime_ms", value) => uptime = Some(Duration::from_millis(value.parse().ok()?)),
+                ("profile", value) => profile = Some(value.to_owned()),
+                ("queued", value) => queued = Some(value.parse().ok()?),
+                ("pooled", value) => pooled = Some(value.parse().ok()?),
+                ("public_address", value) => public_address = Some(value.parse().ok()?),
+                _ => (), // Unknown keys are ignored, so new ones can be added
+            }
+        }
+
+        Some(Status {
+            version,
+            uptime: uptime?,
+            profile: profile?,
+            queued: queued?,
+            // Older routers do not report it
+            pooled: pooled.unwrap_or_default(),
+            public_address,
+        })
+    }
+}
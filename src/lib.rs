@@ -15,6 +15,43 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+pub mod acl;
+pub mod aqm;
 pub mod buffer;
+pub mod checker;
+pub mod clock;
+#[cfg(unix)]
+pub mod daemon;
+pub mod eventcsv;
+pub mod eventlog;
+pub mod fragment;
+pub mod hops;
+#[cfg(target_os = "linux")]
+pub mod icmp;
+pub mod inband;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod nat;
+pub mod net;
+pub mod ns3;
 pub mod packet;
+pub mod pcap;
+pub mod pcapng;
+pub mod profile;
 pub mod queue;
+pub mod ratelimit;
+pub mod record;
+pub mod report;
+pub mod router;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod scenario;
+pub mod schedule;
+pub mod stats;
+pub mod stun;
+pub mod trace;
+pub mod watchdog;
+
+pub use router::{Router, RouterBuilder};
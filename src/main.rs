@@ -15,27 +15,106 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use log::{debug, info, warn};
-use shufflerouter::buffer::BufferPool;
-use shufflerouter::packet::Packet;
-use shufflerouter::queue::Queue;
+mod cmd;
+#[cfg(unix)]
+mod control;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod http;
+mod tcp;
+mod tui;
+
+use log::{info, warn};
+use num_format::{CustomFormat, Grouping, Locale, ToFormattedString};
+use shufflerouter::acl::Acl;
+use shufflerouter::aqm::RedParams;
+use shufflerouter::checker::{CheckRules, Checker};
+#[cfg(unix)]
+use shufflerouter::daemon::{self, PidFile, SyslogLogger};
+use shufflerouter::eventcsv::EventCsv;
+use shufflerouter::eventlog::JsonLogger;
+use shufflerouter::hops;
+#[cfg(feature = "mqtt")]
+use shufflerouter::mqtt::MqttTelemetry;
+use shufflerouter::nat::{Filtering, Nat};
+use shufflerouter::net::{self, PortRange};
+use shufflerouter::ns3::Ns3Trace;
+use shufflerouter::pcap::TrafficCapture;
+use shufflerouter::pcapng::PacketCapture;
+#[cfg(unix)]
+use shufflerouter::profile::SharedProfile;
+use shufflerouter::profile::{DelayModel, Fraction, Profile, SourceProfile, Truncation};
+use shufflerouter::queue::QueueLimit;
+use shufflerouter::ratelimit::{SourceLimiter, TokenBucket};
+use shufflerouter::record::SessionRecorder;
+use shufflerouter::report::IntervalReport;
+use shufflerouter::router::Settings;
+#[cfg(target_os = "linux")]
+use shufflerouter::sandbox;
+use shufflerouter::scenario::{Action, Scenario, Severity};
+#[cfg(unix)]
+use shufflerouter::schedule::Schedule;
+use shufflerouter::stats::Stats;
+use shufflerouter::trace::ImpairmentTrace;
+#[cfg(target_os = "linux")]
+use shufflerouter::watchdog::SystemdNotifier;
+use shufflerouter::watchdog::Watchdog;
+use shufflerouter::Router;
 
 use anyhow::Result;
 use clap::Parser;
-use mio::{Interest, Token};
-use rand::distributions::{Bernoulli, Distribution, Uniform};
-use std::{
-    net::{Ipv4Addr, SocketAddr, UdpSocket},
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-};
+use std::time::Duration;
 use std::{
-    thread,
-    time::{Duration, Instant},
+    net::IpAddr,
+    sync::{Arc, Mutex},
 };
-use tokio::signal;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DelayDist {
+    /// Uniform up to the delay randomness
+    Uniform,
+    /// Exponential with the given mean
+    Exponential,
+    /// Normal with the given mean and standard deviation
+    Normal,
+    /// Pareto with the given mean and shape
+    Pareto,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RatePolicy {
+    /// Delay them until the rate allows them
+    Delay,
+    /// Drop them
+    Drop,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TraceEnd {
+    /// Start over
+    Wrap,
+    /// Apply the profile to the remaining packets
+    Stop,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum NatFiltering {
+    /// Anyone may answer through a mapped port (full cone)
+    EndpointIndependent,
+    /// Only the addresses it sent to (restricted cone)
+    Address,
+    /// Only the addresses and ports it sent to (port restricted cone)
+    AddressPort,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+    /// Through syslog, as the daemon facility
+    #[cfg(unix)]
+    Syslog,
+}
 
 /// A shuffling router for Redes de Ordenadores subject
 ///
@@ -48,9 +127,17 @@ use tokio::signal;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Opt {
-    /// Listening port
+    #[clap(subcommand)]
+    command: Option<cmd::Command>,
+
+    /// Listening port, or range of them as FIRST-LAST. Can be repeated. Packets leave through the
+    /// port they arrived at
     #[clap(short = 'p', long = "port", default_value = "2021")]
-    port: u16,
+    port: Vec<PortRange>,
+
+    /// Address to listen on [default: every IPv4 and IPv6 address]
+    #[clap(long = "bind")]
+    bind: Option<IpAddr>,
 
     /// Packet drop probability
     #[clap(short = 'd', long = "drop", default_value = "0.0")]
@@ -60,197 +147,1328 @@ struct Opt {
     #[clap(short = 'm', long = "min_delay", default_value = "0")]
     min_delay: u64,
 
+    /// Probability of flipping random bits of the payload of a packet, past the header
+    #[clap(long = "corrupt", default_value = "0.0")]
+    corrupt: f64,
+
+    /// Probability of repeating the previous drop decision, as a fraction or a percentage, so losses
+    /// come in bursts
+    #[clap(long = "drop-correlation", default_value = "0")]
+    drop_correlation: Fraction,
+
+    /// Correlation of every delay with the previous one, as a fraction or a percentage, as in
+    /// netem
+    #[clap(long = "delay-correlation", default_value = "0")]
+    delay_correlation: Fraction,
+
+    /// Forward only the first BYTES of the payload of a packet with PROBABILITY, given as
+    /// PROBABILITY:BYTES
+    #[clap(long = "truncate")]
+    truncate: Option<Truncation>,
+
+    /// Probability of holding back a packet until another one to the same destination leaves
+    #[clap(long = "reorder", default_value = "0.0")]
+    reorder: f64,
+
+    /// Probability of sending a second copy of a packet, with its own delay
+    #[clap(long = "dup", default_value = "0.0")]
+    dup: f64,
+
+    /// Distribution of the delay over the minimum one. Only the uniform one uses -r
+    #[clap(long = "delay-dist", value_enum, default_value = "uniform")]
+    delay_dist: DelayDist,
+
+    /// Mean of the delay over the minimum one, in milliseconds, for the exponential, normal and
+    /// Pareto distributions
+    #[clap(
+        long = "mean",
+        required_if_eq_any([("delay_dist", "exponential"), ("delay_dist", "normal"), ("delay_dist", "pareto")])
+    )]
+    mean: Option<f64>,
+
+    /// Shape of the Pareto distribution, over 1. The lower, the heavier its tail
+    #[clap(long = "shape", required_if_eq("delay_dist", "pareto"))]
+    shape: Option<f64>,
+
+    /// Standard deviation of the delay, in milliseconds, for the normal distribution
+    #[clap(long = "stddev", required_if_eq("delay_dist", "normal"))]
+    stddev: Option<f64>,
+
     /// Packet delay randomness, in milliseconds
     #[clap(short = 'r', long = "rand_delay", default_value = "0")]
     rand_delay: u64,
 
     /// Verbose level
-    #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
-    verbose: usize,
+    #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log as free text or as one JSON object per event (receive, enqueue, drop, send, error), or to syslog
+    #[clap(long = "log-format", value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
 
     /// Show log timestamp (sec, ms, ns, none)
-    #[clap(short = 't', long = "timestamp")]
+    #[clap(short = 't', long = "timestamp", global = true)]
     ts: Option<stderrlog::Timestamp>,
 
     /// EXPERIMENTAL: Multithreaded version
     #[clap(short = 'j', long = "parallel")]
     parallel: bool,
+
+    /// Number of sockets sharing the port, each with a thread, queue and buffer pool of its own
+    #[cfg(unix)]
+    #[clap(long = "workers", default_value = "1", conflicts_with = "parallel", value_parser = clap::value_parser!(u64).range(1..))]
+    workers: u64,
+
+    /// Seed for the random decisions, to repeat the same impairments for the same traffic
+    #[clap(long = "seed")]
+    seed: Option<u64>,
+
+    /// Time allowed to flush queued packets on shutdown, in milliseconds
+    #[clap(short = 'g', long = "drain_timeout", default_value = "1000")]
+    drain_timeout: u64,
+
+    /// Memory budget for queued packets, in bytes. New packets are dropped when exceeded
+    #[clap(long = "max-memory")]
+    max_memory: Option<usize>,
+
+    /// Buffers kept for reuse (per processing thread). Those freed beyond it go back to the system
+    #[clap(long = "pool-size", default_value = "16384")]
+    pool_size: usize,
+
+    /// Rate of the emulated link, in megabits per second. Packets take their transmission time on
+    /// top of their delay, one after the other
+    #[clap(long = "link-rate")]
+    link_rate: Option<f64>,
+
+    /// Largest datagram forwarded, header included, in bytes. Larger ones are dropped, or split
+    /// with --fragment
+    #[clap(long = "max-size")]
+    max_size: Option<usize>,
+
+    /// Split the datagrams over --max-size into fragments, tagged after the header, that receivers
+    /// have to reassemble. Every datagram forwarded carries the tag
+    #[clap(long = "fragment", requires = "max_size")]
+    fragment: bool,
+
+    /// Largest datagram accepted, in bytes. Buffers are this size, and longer datagrams are truncated
+    #[clap(long = "mtu", default_value = "1500", value_parser = clap::value_parser!(u16).range(64..))]
+    mtu: u16,
+
+    /// Maximum bytes a single source address may have queued (per processing thread)
+    #[clap(long = "client-limit")]
+    client_limit: Option<usize>,
+
+    /// Maximum queue length (per processing thread), in packets, or in bytes when followed by B, K
+    /// or M. New packets are dropped when exceeded
+    #[clap(long = "queue-limit")]
+    queue_limit: Option<QueueLimit>,
+
+    /// Drop packets early with Random Early Detection, given the minimum and maximum average queue
+    /// lengths, in packets, and the maximum drop probability in between, as MIN,MAX,MAX_P
+    #[clap(long = "red")]
+    red: Option<RedParams>,
+
+    /// Weight of the current queue length in the RED average
+    #[clap(long = "red-weight", default_value = "0.002", requires = "red")]
+    red_weight: f64,
+
+    /// Drop packets with CoDel once they keep waiting past their planned exit for too long
+    #[clap(long = "codel")]
+    codel: bool,
+
+    /// Acceptable waiting time for CoDel, in milliseconds
+    #[clap(long = "codel-target", default_value = "5", requires = "codel")]
+    codel_target: u64,
+
+    /// Time the waiting has to stay over the target before CoDel drops packets, in milliseconds
+    #[clap(long = "codel-interval", default_value = "100", requires = "codel", value_parser = clap::value_parser!(u64).range(1..))]
+    codel_interval: u64,
+
+    /// Warn when an event loop iteration takes longer than this, in milliseconds
+    #[clap(long = "watchdog")]
+    watchdog: Option<u64>,
+
+    /// Record every arrival and the decision taken for it, for later playback
+    #[clap(long = "record")]
+    record: Option<std::path::PathBuf>,
+
+    /// Maximum forwarding rate, in kilobits per second
+    #[clap(long = "rate", value_parser = clap::value_parser!(u64).range(1..))]
+    rate: Option<u64>,
+
+    /// Bytes that can be sent at once over the --rate
+    #[clap(long = "burst", default_value = "1500", requires = "rate")]
+    burst: usize,
+
+    /// What to do with the packets over the --rate: delay them until it allows them, or drop them
+    #[clap(
+        long = "rate-policy",
+        value_enum,
+        default_value = "delay",
+        requires = "rate"
+    )]
+    rate_policy: RatePolicy,
+
+    /// Maximum packets per second from a single source address. Those over it are dropped
+    #[clap(long = "source-pps", value_parser = clap::value_parser!(u64).range(1..))]
+    source_pps: Option<u64>,
+
+    /// Maximum kilobits per second from a single source address. Packets over it are dropped
+    #[clap(long = "source-kbps", value_parser = clap::value_parser!(u64).range(1..))]
+    source_kbps: Option<u64>,
+
+    /// Return every datagram to its sender after its delay, as is, without a destination header
+    #[clap(long = "echo", conflicts_with_all = ["nat", "strict"])]
+    echo: bool,
+
+    /// Emulate a NAT with this public address: the header of packets leaving carries it with a
+    /// port of --nat-ports, and only answers to a mapped port get back in
+    #[clap(long = "nat")]
+    nat: Option<IpAddr>,
+
+    /// Public ports the NAT maps senders to
+    #[clap(long = "nat-ports", default_value = "40000-40999", requires = "nat")]
+    nat_ports: PortRange,
+
+    /// Seconds a NAT mapping lasts without outgoing packets
+    #[clap(long = "nat-timeout", default_value = "30", requires = "nat")]
+    nat_timeout: u64,
+
+    /// Which peers may answer through a NAT mapping
+    #[clap(
+        long = "nat-filtering",
+        value_enum,
+        default_value = "address-port",
+        requires = "nat"
+    )]
+    nat_filtering: NatFiltering,
+
+    /// File describing virtual hops, emulated links packets go through one after the other on top
+    /// of the router impairments
+    #[clap(long = "hops")]
+    hops: Option<std::path::PathBuf>,
+
+    /// Delay and drop the packets as the successive records of this file, instead of as the profile
+    #[clap(long = "trace")]
+    trace: Option<std::path::PathBuf>,
+
+    /// What to do once every record of the trace is used: start over, or stop following it
+    #[clap(
+        long = "trace-end",
+        value_enum,
+        default_value = "wrap",
+        requires = "trace"
+    )]
+    trace_end: TraceEnd,
+
+    /// Capture received and sent datagrams to a pcap file
+    #[clap(long = "pcap")]
+    pcap: Option<std::path::PathBuf>,
+
+    /// Capture received packets to a pcapng file, commented with the decision taken for each
+    #[clap(long = "capture")]
+    capture: Option<std::path::PathBuf>,
+
+    /// Trace enqueue, dequeue and drop events to this file, in the ns-3 ASCII trace format
+    #[clap(long = "ns3-trace")]
+    ns3_trace: Option<std::path::PathBuf>,
+
+    /// Scenario file whose actions are carried out at their times: printing markers, changing the
+    /// impairments, blackholing destinations and flushing the queue
+    #[clap(long = "scenario")]
+    scenario: Option<std::path::PathBuf>,
+
+    /// Write a CSV row for every packet to this file, with its arrival, decision, delay and departure
+    #[clap(long = "events-csv")]
+    events_csv: Option<std::path::PathBuf>,
+
+    /// Print the packets per second received and sent, the drops and the queue length every this many seconds
+    #[clap(long = "report-interval", conflicts_with = "tui", value_parser = clap::value_parser!(u64).range(1..))]
+    report_interval: Option<u64>,
+
+    /// STUN server queried at startup for the public address of the router, as HOST:PORT
+    #[clap(long = "stun")]
+    stun: Option<String>,
+
+    /// MQTT broker to publish stats and impairment changes to, as HOST[:PORT]
+    #[cfg(feature = "mqtt")]
+    #[clap(long = "mqtt-broker")]
+    mqtt_broker: Option<String>,
+
+    /// Topic prefix for the MQTT telemetry [default: shufflerouter/<port>]
+    #[cfg(feature = "mqtt")]
+    #[clap(long = "mqtt-topic")]
+    mqtt_topic: Option<String>,
+
+    /// Seconds between stats published to MQTT
+    #[cfg(feature = "mqtt")]
+    #[clap(long = "mqtt-interval", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    mqtt_interval: u64,
+
+    /// Serve the gRPC control API on this TCP port
+    #[cfg(feature = "grpc")]
+    #[clap(long = "grpc-port")]
+    grpc_port: Option<u16>,
+
+    /// Run under the Windows service control manager. Set by `service install`
+    #[cfg(windows)]
+    #[clap(long = "service", hide = true)]
+    service: bool,
+
+    /// File with drop=, min_delay= and rand_delay= settings overriding the command line ones, and
+    /// changes of them scheduled with lines like `at 60 drop=0.2`. Its settings are re-read on SIGHUP
+    #[cfg(unix)]
+    #[clap(long = "config")]
+    config: Option<std::path::PathBuf>,
+
+    /// Export the stats to this JSON file on exit
+    #[clap(long = "stats-json")]
+    stats_json: Option<std::path::PathBuf>,
+
+    /// Reject unspecified, port zero and reserved destinations
+    #[clap(long = "strict")]
+    strict: bool,
+
+    /// Destination network packets may be relayed to. Can be repeated [default: any]
+    #[clap(long = "allow-dst")]
+    allow_dst: Vec<ipnet::IpNet>,
+
+    /// Destination network packets must not be relayed to, even if allowed. Can be repeated
+    #[clap(long = "deny-dst")]
+    deny_dst: Vec<ipnet::IpNet>,
+
+    /// Impairments of the packets from a network instead of the shared ones, as NET=SETTINGS, with
+    /// the settings of the config file separated by commas, e.g. 10.0.1.0/24=drop=0.1,min_delay=50.
+    /// Can be repeated, and the first network holding the source applies
+    #[clap(long = "source-profile")]
+    source_profile: Vec<String>,
+
+    /// Relay to well-known ports, broadcast and multicast addresses and the router itself, which
+    /// are refused by default so it cannot be abused as a reflector
+    #[clap(long = "allow-reflection")]
+    allow_reflection: bool,
+
+    /// Answer status queries from other hosts too, not only from this one. Their answers are
+    /// larger than the queries, so spoofed ones could turn the router into an amplifier.
+    #[clap(long = "status-queries")]
+    status_queries: bool,
+
+    /// Validate incoming packets and report the violations of every source on exit
+    #[clap(long = "check")]
+    check: bool,
+
+    /// Destination network allowed by --check. Can be repeated [default: any]
+    #[clap(long = "check-allow", requires = "check")]
+    check_allow: Vec<ipnet::IpNet>,
+
+    /// Minimum packet size accepted by --check, header included
+    #[clap(long = "check-min-size", requires = "check")]
+    check_min_size: Option<usize>,
+
+    /// Maximum packet size accepted by --check, header included
+    #[clap(long = "check-max-size", requires = "check")]
+    check_max_size: Option<usize>,
+
+    /// File for the --check report [default: standard output]
+    #[clap(long = "check-report", requires = "check")]
+    check_report: Option<std::path::PathBuf>,
+
+    /// Lock file preventing two routers on the same port [default: shufflerouter-<port>.pid in the temporary directory]
+    #[cfg(unix)]
+    #[clap(long = "pid-file")]
+    pid_file: Option<std::path::PathBuf>,
+
+    /// Detach from the terminal and run in the background
+    #[cfg(unix)]
+    #[clap(long = "daemonize", alias = "daemon", conflicts_with = "tui")]
+    daemonize: bool,
+
+    /// Append the log and the exit summary to this file instead of the terminal
+    #[cfg(unix)]
+    #[clap(long = "log-file")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Show a live dashboard of the traffic on the terminal
+    #[clap(long = "tui")]
+    tui: bool,
+
+    /// Unix-domain socket taking commands to change the impairments and read the counters
+    #[cfg(unix)]
+    #[clap(long = "control")]
+    control: Option<std::path::PathBuf>,
+
+    /// Serve a status page on this TCP port, for browsers
+    #[clap(long = "http-port")]
+    http_port: Option<u16>,
+
+    /// Let HTTP requests change the impairments and flush the queue
+    #[clap(long = "http-admin", requires = "http_port")]
+    http_admin: bool,
+
+    /// Also relay length-prefixed messages over TCP connections, on the same port
+    #[clap(long = "tcp")]
+    tcp: bool,
+
+    /// Restrict the system calls available once initialized
+    #[cfg(target_os = "linux")]
+    #[clap(long = "seccomp")]
+    seccomp: bool,
+
+    /// Tell senders when their destination port is unreachable
+    #[cfg(target_os = "linux")]
+    #[clap(long = "notify-unreachable")]
+    notify_unreachable: bool,
+}
+
+/// Parses a `--source-profile` as NET=SETTINGS, applying the settings over `base`
+fn source_profile(spec: &str, base: &Profile) -> Result<SourceProfile> {
+    use anyhow::Context;
+
+    let (net, settings) = spec
+        .split_once('=')
+        .with_context(|| format!("invalid source profile {spec:?}, expected NET=SETTINGS"))?;
+
+    Ok(SourceProfile {
+        net: net
+            .parse()
+            .with_context(|| format!("invalid network {net:?} in source profile"))?,
+        profile: base
+            .with_settings(settings)
+            .with_context(|| format!("invalid settings for source network {net}"))?,
+    })
 }
 
-fn process_queue(
-    queue: &mut Queue,
-    socket: &mio::net::UdpSocket,
-    buffer_pool: &mut BufferPool,
-) -> usize {
-    let mut bytes_sent = 0;
-    let now = Instant::now();
-
-    while queue.peek().map_or(false, |p| p.exit_time() <= now) {
-        let p = queue.peek().unwrap();
-        bytes_sent += match socket.send_to(p.get(), p.dst()) {
-            Ok(len) => {
-                debug!("Sent {} bytes to {}", len, p.dst());
-                buffer_pool.recycle_buffer(queue.pop().unwrap().into()); // Only remove transmitted packets
-                len
+/// Applies the settings of a config file over `base`. Settings may be split
+/// over several lines, and everything after a `#` is ignored. Lines starting
+/// with `at SECONDS` hold the changes scheduled for that time, returned as a
+/// schedule if there are any.
+#[cfg(unix)]
+fn read_config(path: &std::path::Path, base: &Profile) -> Result<(Profile, Option<Schedule>)> {
+    use anyhow::Context;
+
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let (mut settings, mut changes) = (Vec::new(), Vec::new());
+    for line in text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+    {
+        match line.trim_start().strip_prefix("at ") {
+            Some(change) => {
+                let (time, change) = change
+                    .trim_start()
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((change.trim(), ""));
+                let at = time
+                    .strip_suffix('s')
+                    .unwrap_or(time)
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .with_context(|| format!("invalid time {time:?} in {}", path.display()))?;
+                changes.push((at, change));
             }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // We can not send more data without blocking
-                break;
+            None => settings.push(line),
+        }
+    }
+
+    let profile = base.with_settings(&settings.join(" "))?;
+    let schedule = match changes.is_empty() {
+        true => None,
+        false => Some(Schedule::new(&profile, changes)?),
+    };
+    Ok((profile, schedule))
+}
+
+/// Carries out the actions of a scenario at their times since the router started
+async fn run_actions(actions: Vec<Action>, router: Arc<Router>) {
+    let settings = router.settings();
+
+    for action in actions {
+        let at = settings.started + Duration::from_secs_f64(action.at);
+        tokio::time::sleep_until(at.into()).await;
+
+        if let Some(marker) = &action.log {
+            info!("Scenario: {}", marker);
+            println!("[{:7.1} s] {}", action.at, marker);
+        }
+        if let Some(changes) = &action.set {
+            let (current, _) = settings.profile.load();
+            match current.with_settings(changes) {
+                Ok(profile) => {
+                    info!("Scenario: impairments changed to {}", profile);
+                    settings.profile.set(profile);
+                }
+                Err(e) => warn!("Scenario: could not change the impairments: {}", e),
             }
-            Err(e) => {
-                warn!(
-                    "Error transmitting {} bytes to {}: {}",
-                    p.get().len(),
-                    p.dst(),
-                    e
-                );
-                buffer_pool.recycle_buffer(queue.pop().unwrap().into()); // Remove the packet causing the error
-                0
+        }
+        if let Some(network) = action.blackhole_network() {
+            info!("Scenario: {} blackholed", network);
+            settings.blackholes.add(network);
+        }
+        if let Some(network) = action.restore_network() {
+            info!("Scenario: {} restored", network);
+            settings.blackholes.remove(network);
+        }
+        if action.flush {
+            if let Err(e) = router.flush_queue() {
+                warn!("Scenario: could not flush the queue: {}", e);
             }
-        };
+        }
     }
+}
 
-    bytes_sent
+/// Swaps in the impairments of the config file every time SIGHUP arrives,
+/// keeping the current ones if it cannot be read
+#[cfg(unix)]
+async fn reload_on_hangup(
+    mut hangup: tokio::signal::unix::Signal,
+    path: std::path::PathBuf,
+    base: Profile,
+    profile: Arc<SharedProfile>,
+) {
+    while hangup.recv().await.is_some() {
+        // The schedule keeps running, so only the settings are swapped in
+        match read_config(&path, &base) {
+            Ok((new, _)) => {
+                info!("Reloaded {}: {}", path.display(), new);
+                profile.set(new);
+            }
+            Err(e) => warn!("Keeping the current impairments: {:#}", e),
+        }
+    }
 }
 
-fn process_traffic(
-    socket: UdpSocket,
-    drop_distribution: impl Distribution<bool>,
-    delay_distribution: impl Distribution<u64>,
-    bytes_sent: Arc<AtomicUsize>,
-) -> Result<()> {
-    let mut rng = rand::thread_rng();
-    let mut queue = Queue::new();
-    let mut poll = mio::Poll::new()?;
+/// Termination requests: Ctrl-C everywhere, plus SIGTERM and SIGQUIT on Unix,
+/// and Ctrl-Break, closing the console and stopping the service on Windows.
+///
+/// Signal handlers are installed on creation, which must happen inside the
+/// runtime context.
+#[cfg(unix)]
+struct ShutdownSource {
+    int: tokio::signal::unix::Signal,
+    term: tokio::signal::unix::Signal,
+    quit: tokio::signal::unix::Signal,
+}
 
-    let mut socket = mio::net::UdpSocket::from_std(socket);
+#[cfg(unix)]
+impl ShutdownSource {
+    fn new() -> Result<ShutdownSource> {
+        use tokio::signal::unix::{signal, SignalKind};
 
-    const SOCKACT: Token = Token(0);
+        Ok(ShutdownSource {
+            int: signal(SignalKind::interrupt())?,
+            term: signal(SignalKind::terminate())?,
+            quit: signal(SignalKind::quit())?,
+        })
+    }
 
-    poll.registry()
-        .register(&mut socket, SOCKACT, Interest::READABLE)?;
+    async fn wait(mut self) -> Result<()> {
+        tokio::select! {
+            _ = self.int.recv() => (),
+            _ = self.term.recv() => (),
+            _ = self.quit.recv() => (),
+        }
 
-    let mut events = mio::Events::with_capacity(32); // Just a few to store those received while transmiitting if needed
-    let mut buffer_pool = BufferPool::default();
+        Ok(())
+    }
+}
 
-    loop {
-        let now = Instant::now();
-        let max_delay = match queue.peek() {
-            None => None,
-            Some(packet) => packet.get_duration_till_next(now),
-        };
+#[cfg(windows)]
+struct ShutdownSource {
+    ctrl_c: tokio::signal::windows::CtrlC,
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+    close: tokio::signal::windows::CtrlClose,
+}
 
-        poll.registry().reregister(
-            &mut socket,
-            SOCKACT,
-            match queue.peek() {
-                Some(packet) if packet.exit_time() <= now => {
-                    Interest::READABLE | Interest::WRITABLE
-                }
-                _ => Interest::READABLE,
-            },
-        )?;
+#[cfg(windows)]
+impl ShutdownSource {
+    fn new() -> Result<ShutdownSource> {
+        use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close};
 
-        poll.poll(&mut events, max_delay)?;
-
-        for event in &events {
-            match event.token() {
-                SOCKACT => {
-                    if event.is_writable() {
-                        bytes_sent.fetch_add(
-                            process_queue(&mut queue, &socket, &mut buffer_pool),
-                            Ordering::Relaxed,
-                        );
-                    }
-
-                    if event.is_readable() {
-                        loop {
-                            // Get all pending packets
-                            let mut buffer = buffer_pool.get_buffer();
-                            let (len, addr) = match socket.recv_from(&mut buffer) {
-                                Ok((len, addr)) => match addr {
-                                    SocketAddr::V4(addrv4) => (len, addrv4),
-                                    _ => panic!("Unimplemented"),
-                                },
-
-                                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                    // We can not read more data without blocking
-                                    break;
-                                }
-                                _ => {
-                                    panic!("Error while reading datagram.");
-                                }
-                            };
-                            let arrival_time = Instant::now();
-                            buffer.set_len(len);
-
-                            debug!("Received {} bytes from {}", len, addr);
-
-                            if drop_distribution.sample(&mut rng) {
-                                info!("Τύχη decided it. Packet dropped.");
-                            } else {
-                                let frame_delay =
-                                    Duration::from_millis(delay_distribution.sample(&mut rng));
-
-                                info!(
-                                    "Packet will be delayed for {} milliseconds",
-                                    frame_delay.as_millis()
-                                );
-
-                                if let Err(e) =
-                                    Packet::create(addr, buffer, arrival_time + frame_delay)
-                                        .map(|packet| queue.push(packet))
-                                {
-                                    warn!("Could not parse packet {:?}", e);
-                                }
-                            };
-                        }
-                    }
-                }
-                _ => unreachable!(),
+        Ok(ShutdownSource {
+            ctrl_c: ctrl_c()?,
+            ctrl_break: ctrl_break()?,
+            close: ctrl_close()?,
+        })
+    }
+
+    async fn wait(mut self) -> Result<()> {
+        let stop = cmd::service::stop_request();
+        let stopped = async {
+            match stop {
+                Some(stop) => stop.notified().await,
+                None => std::future::pending().await,
             }
+        };
+        // Windows kills the process a few seconds after the console is
+        // closed, which is enough for the default drain timeout
+        tokio::select! {
+            _ = self.ctrl_c.recv() => (),
+            _ = self.ctrl_break.recv() => (),
+            _ = self.close.recv() => (),
+            _ = stopped => (),
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+struct ShutdownSource;
+
+#[cfg(not(any(unix, windows)))]
+impl ShutdownSource {
+    fn new() -> Result<ShutdownSource> {
+        Ok(ShutdownSource)
+    }
+
+    async fn wait(self) -> Result<()> {
+        Ok(tokio::signal::ctrl_c().await?)
     }
 }
 
-#[tokio::main]
-pub async fn main() -> Result<()> {
+/// Digit grouping of the user's locale, or none if it is unknown
+fn number_format() -> CustomFormat {
+    let locale = sys_locale::get_locale().and_then(|name| {
+        // num-format only knows some regional variants, so fall back to the language
+        Locale::from_name(&name)
+            .or_else(|_| Locale::from_name(name.split(['-', '_']).next().unwrap_or_default()))
+            .ok()
+    });
+
+    locale
+        .and_then(|locale| {
+            CustomFormat::builder()
+                .grouping(locale.grouping())
+                .separator(locale.separator())
+                .decimal(locale.decimal())
+                .minus_sign(locale.minus_sign())
+                .build()
+                .ok()
+        })
+        .unwrap_or_else(|| {
+            CustomFormat::builder()
+                .grouping(Grouping::Posix)
+                .build()
+                .unwrap_or_default()
+        })
+}
+
+pub fn main() -> Result<()> {
     let opt = Opt::parse();
 
-    stderrlog::new()
-        .module(module_path!())
-        .verbosity(opt.verbose)
-        .timestamp(opt.ts.unwrap_or(stderrlog::Timestamp::Off))
-        .init()?;
+    match opt.log_format {
+        LogFormat::Text => stderrlog::new()
+            .module(module_path!())
+            .verbosity(usize::from(opt.verbose))
+            .timestamp(opt.ts.unwrap_or(stderrlog::Timestamp::Off))
+            .init()?,
+        LogFormat::Json => JsonLogger::init(module_path!(), usize::from(opt.verbose))?,
+        #[cfg(unix)]
+        LogFormat::Syslog => SyslogLogger::init(module_path!(), usize::from(opt.verbose))?,
+    }
+
+    #[cfg(windows)]
+    if opt.service {
+        return cmd::service::dispatch();
+    }
+
+    match opt.command {
+        Some(cmd::Command::Chaos(_)) | None => run_router(opt),
+        Some(command) => cmd::run(command),
+    }
+}
+
+/// Forwards traffic with the tokio event loop
+#[cfg(feature = "tokio-backend")]
+fn forward(router: &Router) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    Ok(runtime.block_on(router.run_async())?)
+}
+
+/// Runs the router until a termination request arrives
+fn run_router(mut opt: Opt) -> Result<()> {
+    let chaos = match opt.command.take() {
+        Some(cmd::Command::Chaos(args)) => Some(args),
+        _ => None,
+    };
+    // Passed by systemd socket activation, replacing the ports given
+    #[cfg(unix)]
+    let inherited = net::listen_fds()?;
+    #[cfg(not(unix))]
+    let inherited = Vec::new();
+    let ports: Vec<u16> = if inherited.is_empty() {
+        opt.port.iter().flat_map(PortRange::ports).collect()
+    } else {
+        inherited
+            .iter()
+            .map(|socket| Ok(socket.local_addr()?.port()))
+            .collect::<std::io::Result<_>>()?
+    };
+    // The one naming the files and topics of the router
+    let port = ports[0];
+
+    let base_profile = Profile::new(opt.drop, opt.min_delay, opt.rand_delay)?
+        .with_duplicate(opt.dup)?
+        .with_corrupt(opt.corrupt)?
+        .with_reorder(opt.reorder)?
+        .with_truncation(opt.truncate)?
+        .with_delay_correlation(opt.delay_correlation.0)?
+        .with_drop_correlation(opt.drop_correlation.0)?
+        .with_delay_model(match opt.delay_dist {
+            DelayDist::Uniform => DelayModel::Uniform,
+            DelayDist::Exponential => DelayModel::Exponential {
+                mean: opt.mean.unwrap_or_default(), // Required by clap
+            },
+            DelayDist::Normal => DelayModel::Normal {
+                mean: opt.mean.unwrap_or_default(),
+                stddev: opt.stddev.unwrap_or_default(),
+            },
+            DelayDist::Pareto => DelayModel::Pareto {
+                mean: opt.mean.unwrap_or_default(),
+                shape: opt.shape.unwrap_or_default(),
+            },
+        })?;
+    #[cfg(unix)]
+    let (profile, schedule) = match &opt.config {
+        Some(path) => read_config(path, &base_profile)?,
+        None => (base_profile.clone(), None),
+    };
+    #[cfg(not(unix))]
+    let (profile, schedule) = (base_profile.clone(), None);
+
+    let scenario = opt.scenario.as_deref().map(Scenario::load).transpose()?;
+    if let Some(scenario) = &scenario {
+        if let Some(issue) = scenario.lint().first() {
+            anyhow::ensure!(
+                issue.severity != Severity::Error,
+                "{}. Check the scenario with the lint subcommand",
+                issue.message
+            );
+        }
+        if !scenario.phases.is_empty()
+            || !scenario.rules.is_empty()
+            || !scenario.defaults.is_empty()
+        {
+            warn!("The router only carries out the actions of the scenario. Its defaults, phases and rules are simulated by the simulate subcommand");
+        }
+    }
 
-    let drop_distribution = Bernoulli::new(opt.drop)?;
+    // Every epoch comes from the clock of the router
+    let defaults = Settings::new(profile.clone());
+    let now = defaults.clock.now();
+    let settings = Settings {
+        drain_timeout: Duration::from_millis(opt.drain_timeout),
+        schedule: schedule.map(Arc::new),
+        source_profiles: opt
+            .source_profile
+            .iter()
+            .map(|spec| source_profile(spec, &base_profile))
+            .collect::<Result<_>>()?,
+        red: opt.red.map(|red| RedParams {
+            weight: opt.red_weight,
+            ..red
+        }),
+        codel: opt.codel.then(|| {
+            (
+                Duration::from_millis(opt.codel_target),
+                Duration::from_millis(opt.codel_interval),
+            )
+        }),
+        seed: opt.seed,
+        strict: opt.strict,
+        recorder: opt
+            .record
+            .as_deref()
+            .map(|path| SessionRecorder::create(path, now))
+            .transpose()?
+            .map(Arc::new),
+        capture: opt
+            .capture
+            .as_deref()
+            .map(|path| PacketCapture::create(path, port))
+            .transpose()?
+            .map(Arc::new),
+        pcap: opt
+            .pcap
+            .as_deref()
+            .map(|path| TrafficCapture::create(path, port))
+            .transpose()?
+            .map(Arc::new),
+        rate_limit: opt.rate.map(|kbps| {
+            Arc::new(Mutex::new(TokenBucket::new(
+                kbps as f64 * 1000.0 / 8.0,
+                opt.burst,
+                now,
+            )))
+        }),
+        rate_drop: opt.rate_policy == RatePolicy::Drop,
+        max_size: opt.max_size,
+        fragment: opt.fragment,
+        link_rate: opt.link_rate.map(|mbps| mbps * 1e6),
+        source_limit: (opt.source_pps.is_some() || opt.source_kbps.is_some()).then(|| {
+            Arc::new(SourceLimiter::new(
+                opt.source_pps,
+                opt.source_kbps.map(|kbps| kbps * 1000 / 8),
+            ))
+        }),
+        echo: opt.echo,
+        status_queries: opt.status_queries,
+        nat: opt.nat.map(|address| {
+            Arc::new(Nat::new(
+                address.to_canonical(),
+                opt.nat_ports.ports(),
+                Duration::from_secs(opt.nat_timeout),
+                match opt.nat_filtering {
+                    NatFiltering::EndpointIndependent => Filtering::EndpointIndependent,
+                    NatFiltering::Address => Filtering::Address,
+                    NatFiltering::AddressPort => Filtering::AddressPort,
+                },
+            ))
+        }),
+        hops: opt
+            .hops
+            .as_deref()
+            .map(hops::load)
+            .transpose()?
+            .unwrap_or_default(),
+        trace: opt
+            .trace
+            .as_deref()
+            .map(|path| ImpairmentTrace::load(path, opt.trace_end == TraceEnd::Wrap))
+            .transpose()?
+            .map(Arc::new),
+        events_csv: opt
+            .events_csv
+            .as_deref()
+            .map(|path| EventCsv::create(path, now))
+            .transpose()?
+            .map(Arc::new),
+        report: opt
+            .report_interval
+            .map(|secs| Arc::new(IntervalReport::new(Duration::from_secs(secs), now))),
+        ns3_trace: opt
+            .ns3_trace
+            .as_deref()
+            .map(|path| Ns3Trace::create(path, now))
+            .transpose()?
+            .map(Arc::new),
+        checker: opt.check.then(|| {
+            Arc::new(Checker::new(CheckRules {
+                allow: opt.check_allow.clone(),
+                min_size: opt.check_min_size,
+                max_size: opt.check_max_size,
+            }))
+        }),
+        acl: Acl {
+            allow: opt.allow_dst.clone(),
+            deny: opt.deny_dst.clone(),
+            allow_reflection: opt.allow_reflection,
+            own: Vec::new(),
+        },
+        #[cfg(target_os = "linux")]
+        notify_unreachable: opt.notify_unreachable,
+        ..defaults
+    };
 
-    let delay_distribution = Uniform::new_inclusive(opt.min_delay, opt.min_delay + opt.rand_delay);
+    // Without an explicit pid file, one for every port, so routers sharing
+    // any of them are detected
+    #[cfg(unix)]
+    let mut pid_files = match &opt.pid_file {
+        Some(path) => vec![PidFile::lock(path)?],
+        None => {
+            let mut ports = ports.clone();
+            ports.sort_unstable();
+            ports.dedup();
+            ports
+                .into_iter()
+                .map(|port| PidFile::lock(&PidFile::default_path(port)))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
 
-    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, opt.port)))?;
-    socket.set_nonblocking(true)?;
+    anyhow::ensure!(
+        opt.link_rate
+            .is_none_or(|rate| rate.is_finite() && rate > 0.0),
+        "the link rate must be positive"
+    );
+    anyhow::ensure!(
+        opt.red_weight > 0.0 && opt.red_weight <= 1.0,
+        "the RED weight must be over 0 and not over 1"
+    );
+    #[cfg(target_os = "linux")]
+    anyhow::ensure!(
+        !(opt.tcp && opt.seccomp),
+        "the TCP relay cannot run within the seccomp sandbox"
+    );
+    #[cfg(target_os = "linux")]
+    anyhow::ensure!(
+        !(opt.config.is_some() && opt.seccomp),
+        "the config file cannot be reloaded within the seccomp sandbox"
+    );
+    #[cfg(target_os = "linux")]
+    anyhow::ensure!(
+        !(opt.http_port.is_some() && opt.seccomp),
+        "the HTTP server cannot run within the seccomp sandbox"
+    );
+    #[cfg(target_os = "linux")]
+    anyhow::ensure!(
+        !(opt.control.is_some() && opt.seccomp),
+        "the control socket cannot run within the seccomp sandbox"
+    );
+    #[cfg(target_os = "linux")]
+    anyhow::ensure!(
+        !(opt.tui && opt.seccomp),
+        "the dashboard cannot run within the seccomp sandbox"
+    );
+    #[cfg(all(target_os = "linux", feature = "grpc"))]
+    anyhow::ensure!(
+        !(opt.grpc_port.is_some() && opt.seccomp),
+        "the gRPC server cannot run within the seccomp sandbox"
+    );
+    #[cfg(all(target_os = "linux", feature = "mqtt"))]
+    anyhow::ensure!(
+        !(opt.mqtt_broker.is_some() && opt.seccomp),
+        "the MQTT telemetry cannot run within the seccomp sandbox"
+    );
+    #[cfg(all(target_os = "linux", feature = "tokio-backend"))]
+    anyhow::ensure!(
+        !opt.seccomp,
+        "the tokio backend cannot run within the seccomp sandbox"
+    );
 
-    let bytes_sent = Arc::new(AtomicUsize::default());
+    for hop in &settings.hops {
+        info!("Virtual hop {}", hop);
+    }
+    for source in &settings.source_profiles {
+        info!("Source profile {}", source);
+    }
+    for (at, profile) in settings
+        .schedule
+        .iter()
+        .flat_map(|schedule| schedule.changes())
+    {
+        info!(
+            "Impairments scheduled at {} s: {}",
+            at.as_secs_f64(),
+            profile
+        );
+    }
+
+    if !inherited.is_empty() {
+        info!("Listening on {} sockets passed by systemd", inherited.len());
+    }
+    let router = Router::builder()
+        .bind(opt.bind)
+        .ports(ports)
+        .sockets(inherited)
+        .profile(&profile)
+        .queue_limit(opt.queue_limit)
+        .client_limit(opt.client_limit)
+        .max_memory(opt.max_memory)
+        .pool_size(opt.pool_size)
+        .mtu(opt.mtu as usize)
+        .threads(if opt.parallel { num_cpus::get() } else { 1 })
+        .stun(opt.stun.clone())
+        .settings(settings);
+    #[cfg(unix)]
+    let router = router.workers(opt.workers as usize);
+    let router = router.build()?;
+    let (settings, stats, shutdown) = (
+        router.settings().clone(),
+        router.stats(),
+        router.shutdown_flag(),
+    );
+    let tcp_listener = opt.tcp.then(|| net::bind_tcp(opt.bind, port)).transpose()?;
 
-    for _i in 1..=if opt.parallel { num_cpus::get() } else { 1 } {
-        let bytes_sent = bytes_sent.clone();
-        let socket = socket.try_clone()?;
+    #[cfg(unix)]
+    {
+        let log_file = opt.log_file.as_deref().map(daemon::open_log).transpose()?;
+        if opt.daemonize {
+            daemon::daemonize()?;
+        }
+        if let Some(file) = &log_file {
+            daemon::redirect_output(file)?;
+        }
+        for pid_file in &mut pid_files {
+            pid_file.write_pid()?;
+        }
+    }
 
-        let _thread = thread::spawn(move || {
-            if let Err(e) =
-                process_traffic(socket, drop_distribution, delay_distribution, bytes_sent)
-            {
-                warn!("Error while processing traffic: {:?}", e);
-            };
+    let router = Arc::new(router);
+    // Pollers, wakers and threads are set up right away, as the sandbox
+    // forbids creating them later
+    #[cfg(not(feature = "tokio-backend"))]
+    let traffic = router.start()?;
+    #[cfg(feature = "tokio-backend")]
+    let (traffic, traffic_failed) = {
+        let (failed, traffic_failed) = tokio::sync::oneshot::channel();
+        let router = router.clone();
+        let traffic = std::thread::spawn(move || {
+            let result = forward(&router);
+            if result.is_err() {
+                let _ = failed.send(());
+            }
+            result
         });
+        (traffic, traffic_failed)
+    };
+
+    if let Some(args) = chaos {
+        cmd::chaos::spawn(args, settings.profile.clone(), shutdown.clone())?;
     }
 
-    signal::ctrl_c().await?;
+    #[cfg(unix)]
+    let _control = opt
+        .control
+        .as_deref()
+        .map(|path| control::spawn(path, settings.clone(), stats.clone(), shutdown.clone()))
+        .transpose()?;
+
+    if let Some(port) = opt.http_port {
+        http::spawn(
+            net::bind_tcp(opt.bind, port)?,
+            router.clone(),
+            opt.http_admin,
+            shutdown.clone(),
+        )?;
+    }
+
+    let tcp_relay = tcp_listener
+        .map(|listener| tcp::spawn(listener, settings.clone(), stats.clone(), shutdown.clone()))
+        .transpose()?;
+
+    let dashboard = opt
+        .tui
+        .then(|| tui::spawn(settings.clone(), stats.clone(), port, shutdown.clone()))
+        .transpose()?;
 
+    #[cfg(feature = "mqtt")]
+    if let Some(broker) = &opt.mqtt_broker {
+        let topic = opt
+            .mqtt_topic
+            .clone()
+            .unwrap_or_else(|| format!("shufflerouter/{}", port));
+        MqttTelemetry::new(
+            broker,
+            &format!("shufflerouter-{}-{}", port, std::process::id()),
+            &topic,
+            Duration::from_secs(opt.mqtt_interval),
+        )
+        .spawn(stats.clone(), settings.clone(), shutdown.clone())?;
+    }
+
+    let heartbeats = router.heartbeats();
+    let watchdog = opt
+        .watchdog
+        .map(|ms| Watchdog::new(heartbeats.clone(), Duration::from_millis(ms)));
+    #[cfg(target_os = "linux")]
+    let watchdog = match SystemdNotifier::from_env()? {
+        Some(notifier) if notifier.watchdog_interval().is_some() => Some(
+            watchdog
+                .unwrap_or_else(|| Watchdog::new(heartbeats, Duration::MAX))
+                .with_systemd(notifier),
+        ),
+        _ => watchdog,
+    };
+    if let Some(watchdog) = watchdog {
+        watchdog.spawn(shutdown.clone())?;
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let shutdown_signal = {
+        let _guard = runtime.enter();
+        #[cfg(unix)]
+        if let Some(path) = opt.config.clone() {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            runtime.spawn(reload_on_hangup(
+                signal(SignalKind::hangup())?,
+                path,
+                base_profile,
+                settings.profile.clone(),
+            ));
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(port) = opt.grpc_port {
+            grpc::spawn(net::bind_tcp(opt.bind, port)?, router.clone())?;
+        }
+        if let Some(scenario) = &scenario {
+            let actions = scenario.script().into_iter().cloned().collect();
+            runtime.spawn(run_actions(actions, router.clone()));
+        }
+        ShutdownSource::new()?
+    };
+
+    // Created now, as the sandbox does not let files be opened
+    let mut stats_json = opt
+        .stats_json
+        .as_ref()
+        .map(std::fs::File::create)
+        .transpose()?;
+    let mut check_report = opt
+        .check_report
+        .as_ref()
+        .map(std::fs::File::create)
+        .transpose()?;
+
+    #[cfg(target_os = "linux")]
+    if opt.seccomp {
+        sandbox::install()?;
+        info!("Seccomp sandbox enabled");
+    }
+
+    #[cfg(not(feature = "tokio-backend"))]
+    runtime.block_on(shutdown_signal.wait())?;
+    // Stop at once if the router cannot forward
+    #[cfg(feature = "tokio-backend")]
+    runtime.block_on(async {
+        tokio::select! {
+            signal = shutdown_signal.wait() => signal,
+            _ = traffic_failed => Ok(()),
+        }
+    })?;
+
+    router.shutdown()?;
+    #[cfg(not(feature = "tokio-backend"))]
+    traffic.join();
+    #[cfg(feature = "tokio-backend")]
+    traffic
+        .join()
+        .map_err(|_| anyhow::anyhow!("the router thread panicked"))??;
+    if tcp_relay.is_some_and(|thread| thread.join().is_err()) {
+        warn!("The TCP relay thread panicked");
+    }
+    if dashboard.is_some_and(|thread| thread.join().is_err()) {
+        warn!("The dashboard thread panicked");
+    }
+
+    let format = number_format();
+    let count = |value: usize| value.to_formatted_string(&format);
     println!(
         "\n{} bytes sent during latest execution.",
-        bytes_sent.fetch_add(0, Ordering::Relaxed) // Trick to cast to usize
+        count(Stats::get(&stats.bytes_sent))
+    );
+    println!(
+        "{} packets received ({} bytes), {} sent and {} dropped.",
+        count(Stats::get(&stats.received)),
+        count(Stats::get(&stats.bytes_received)),
+        count(Stats::get(&stats.sent)),
+        count(stats.dropped())
+    );
+    println!(
+        "Queue occupancy: {} packets on average, {} at most.",
+        format!("{:.1}", stats.occupancy.mean(settings.uptime())).replace('.', format.decimal()),
+        count(stats.occupancy.max())
+    );
+    if let Some(latency) = stats.latency.summary() {
+        println!("Time in the router of the packets sent: {latency}.");
+    }
+    let random_drops = Stats::get(&stats.random_drops);
+    if random_drops > 0 {
+        println!("{} packets dropped at random.", count(random_drops));
+    }
+    let (retries, errors) = (
+        Stats::get(&stats.send_retries),
+        Stats::get(&stats.send_errors),
     );
+    if retries + errors > 0 {
+        println!(
+            "{} transmissions retried, {} packets dropped after send errors.",
+            count(retries),
+            count(errors)
+        );
+    }
+    let unreachable = Stats::get(&stats.unreachable);
+    if unreachable > 0 {
+        println!(
+            "{} ICMP port unreachable errors received.",
+            count(unreachable)
+        );
+    }
+    let duplicated = Stats::get(&stats.duplicated);
+    if duplicated > 0 {
+        println!("{} packets duplicated.", count(duplicated));
+    }
+    let (rate_delays, rate_drops) = (
+        Stats::get(&stats.rate_delays),
+        Stats::get(&stats.rate_drops),
+    );
+    if rate_delays + rate_drops > 0 {
+        println!(
+            "{} packets delayed and {} dropped for exceeding the rate.",
+            count(rate_delays),
+            count(rate_drops)
+        );
+    }
+    let trace_drops = Stats::get(&stats.trace_drops);
+    if trace_drops > 0 {
+        println!("{} packets dropped by the trace.", count(trace_drops));
+    }
+    let reordered = Stats::get(&stats.reordered);
+    if reordered > 0 {
+        println!("{} packets held back to reorder them.", count(reordered));
+    }
+    let corrupted = Stats::get(&stats.corrupted);
+    if corrupted > 0 {
+        println!("{} packets corrupted.", count(corrupted));
+    }
+    let truncated = Stats::get(&stats.truncated);
+    if truncated > 0 {
+        println!("{} packets truncated.", count(truncated));
+    }
+    let overflows = Stats::get(&stats.overflow_drops);
+    if overflows > 0 {
+        println!(
+            "{} packets dropped for exceeding the memory budget.",
+            count(overflows)
+        );
+    }
+    let red_drops = Stats::get(&stats.red_drops);
+    if red_drops > 0 {
+        println!("{} packets dropped early by RED.", count(red_drops));
+    }
+    let codel_drops = Stats::get(&stats.codel_drops);
+    if codel_drops > 0 {
+        println!("{} packets dropped by CoDel.", count(codel_drops));
+    }
+    if let Some(nat) = &settings.nat {
+        println!(
+            "{} packets refused by the NAT, {} mappings still active.",
+            count(Stats::get(&stats.nat_drops)),
+            count(nat.mappings(settings.clock.now()))
+        );
+    }
+    let hop_drops = Stats::get(&stats.hop_drops);
+    if hop_drops > 0 {
+        println!("{} packets dropped by virtual hops.", count(hop_drops));
+    }
+    let flushed = Stats::get(&stats.flushed);
+    if flushed > 0 {
+        println!("{} packets discarded by queue flushes.", count(flushed));
+    }
+    let drain_discards = Stats::get(&stats.drain_discards);
+    if drain_discards > 0 {
+        println!(
+            "{} packets still queued when the drain timeout expired were discarded.",
+            count(drain_discards)
+        );
+    }
+    let hop_limit_drops = Stats::get(&stats.hop_limit_drops);
+    if hop_limit_drops > 0 {
+        println!(
+            "{} packets dropped for exhausting their hop limit.",
+            count(hop_limit_drops)
+        );
+    }
+    let acl_drops = Stats::get(&stats.acl_drops);
+    if acl_drops > 0 {
+        println!(
+            "{} packets dropped for a destination not allowed.",
+            count(acl_drops)
+        );
+    }
+    let blackhole_drops = Stats::get(&stats.blackhole_drops);
+    if blackhole_drops > 0 {
+        println!(
+            "{} packets dropped for a blackholed destination.",
+            count(blackhole_drops)
+        );
+    }
+    let reflection_drops = Stats::get(&stats.reflection_drops);
+    if reflection_drops > 0 {
+        println!(
+            "{} packets dropped to avoid reflection attacks.",
+            count(reflection_drops)
+        );
+    }
+    let oversize_drops = Stats::get(&stats.oversize_drops);
+    if oversize_drops > 0 {
+        println!(
+            "{} packets dropped for exceeding the maximum size.",
+            count(oversize_drops)
+        );
+    }
+    let fragmented = Stats::get(&stats.fragmented);
+    if fragmented > 0 {
+        println!("{} packets split into fragments.", count(fragmented));
+    }
+    let source_drops = Stats::get(&stats.source_rate_drops);
+    if source_drops > 0 {
+        println!(
+            "{} packets dropped for exceeding the per source rate:",
+            count(source_drops)
+        );
+        if let Some(limit) = &settings.source_limit {
+            for (src, drops) in limit.drops() {
+                println!("  {src}: {}", count(drops));
+            }
+        }
+    }
+    let queue_drops = Stats::get(&stats.queue_drops);
+    if queue_drops > 0 {
+        println!(
+            "{} packets dropped for finding the queue full.",
+            count(queue_drops)
+        );
+    }
+    let client_drops = Stats::get(&stats.client_limit_drops);
+    if client_drops > 0 {
+        println!(
+            "{} packets dropped for exceeding the per client limit.",
+            count(client_drops)
+        );
+    }
+    for (counter, reason) in [
+        (&stats.malformed_short, "too short"),
+        (
+            &stats.malformed_unspecified,
+            "with an unspecified destination",
+        ),
+        (&stats.malformed_zero_port, "with destination port zero"),
+        (&stats.malformed_reserved, "with a reserved destination"),
+        (&stats.malformed_other, "with an undecodable header"),
+    ] {
+        let malformed = Stats::get(counter);
+        if malformed > 0 {
+            println!("{} malformed packets {reason}.", count(malformed));
+        }
+    }
+
+    let flows = stats.flows();
+    if !flows.is_empty() {
+        println!("\nFlows (received, bytes, dropped, sent):");
+        for (src, dst, flow) in flows {
+            println!(
+                "  {} -> {}: {} {} {} {}",
+                src,
+                dst,
+                count(flow.received),
+                count(flow.bytes),
+                count(flow.dropped),
+                count(flow.sent)
+            );
+        }
+    }
+
+    if let Some(file) = &mut stats_json {
+        let mut snapshot = stats.snapshot(settings.uptime());
+        snapshot.public_address = settings.public_address.map(|addr| addr.to_string());
+        serde_json::to_writer_pretty(file, &snapshot)?;
+    }
+
+    if let Some(checker) = &settings.checker {
+        match &mut check_report {
+            Some(file) => checker.write_report(file)?,
+            None => checker.write_report(&mut std::io::stdout())?,
+        }
+    }
 
     Ok(())
 }
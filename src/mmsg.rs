@@ -0,0 +1,126 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Batches of datagrams handled in a single system call, with `recvmmsg` and
+//! `sendmmsg`
+
+use socket2::SockAddr;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+fn header(
+    iov: &mut libc::iovec,
+    name: *mut libc::c_void,
+    namelen: libc::socklen_t,
+) -> libc::mmsghdr {
+    let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+    header.msg_hdr.msg_name = name;
+    header.msg_hdr.msg_namelen = namelen;
+    header.msg_hdr.msg_iov = iov;
+    header.msg_hdr.msg_iovlen = 1;
+    header
+}
+
+/// Receives a datagram into each of `buffers`, as long as there are some
+/// waiting. Returns the length and sender of those received, or fails with
+/// `WouldBlock` if there were none.
+pub fn recv_batch(
+    socket: &impl AsRawFd,
+    buffers: &mut [&mut [u8]],
+) -> io::Result<Vec<(usize, SocketAddr)>> {
+    let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; buffers.len()];
+    let mut iovs: Vec<libc::iovec> = buffers
+        .iter_mut()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .zip(names.iter_mut())
+        .map(|(iov, name)| {
+            header(
+                iov,
+                name as *mut _ as *mut libc::c_void,
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+            )
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            headers.as_mut_ptr(),
+            headers.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    headers[..received as usize]
+        .iter()
+        .zip(names)
+        .map(|(header, name)| {
+            let name = unsafe { SockAddr::new(name, header.msg_hdr.msg_namelen) };
+            let sender = name.as_socket().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "datagram from a non IP address")
+            })?;
+            Ok((header.msg_len as usize, sender))
+        })
+        .collect()
+}
+
+/// Sends `datagrams` in order. Returns how many were sent, or the error of
+/// the first one if none could be.
+pub fn send_batch(socket: &impl AsRawFd, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+    let names: Vec<SockAddr> = datagrams
+        .iter()
+        .map(|&(_, dst)| SockAddr::from(dst))
+        .collect();
+    let mut iovs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|(data, _)| libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .zip(&names)
+        .map(|(iov, name)| header(iov, name.as_ptr() as *mut libc::c_void, name.len()))
+        .collect();
+
+    let sent = unsafe {
+        libc::sendmmsg(
+            socket.as_raw_fd(),
+            headers.as_mut_ptr(),
+            headers.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(sent as usize)
+}
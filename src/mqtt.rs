@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Telemetry published to an MQTT broker.
+//!
+//! Stats snapshots are published as JSON to `<topic>/stats` periodically, and
+//! every change of impairments as text to `<topic>/profile`, retained so that
+//! new subscribers learn the current ones.
+
+use crate::router::Settings;
+use crate::stats::Stats;
+use log::{debug, warn};
+use rumqttc::{Client, MqttOptions, QoS};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 1883;
+
+/// Publishes the router telemetry to an MQTT broker
+pub struct MqttTelemetry {
+    options: MqttOptions,
+    topic: String,
+    interval: Duration,
+}
+
+impl MqttTelemetry {
+    /// Prepares publishing every `interval` under `topic` to `broker`, given
+    /// as HOST or HOST:PORT
+    pub fn new(broker: &str, client_id: &str, topic: &str, interval: Duration) -> MqttTelemetry {
+        let (host, port) = match broker.rsplit_once(':').map(|(h, p)| (h, p.parse())) {
+            Some((host, Ok(port))) => (host, port),
+            _ => (broker, DEFAULT_PORT),
+        };
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+
+        MqttTelemetry {
+            options,
+            topic: topic.trim_end_matches('/').to_owned(),
+            interval,
+        }
+    }
+
+    /// Runs the publisher in its own thread until `shutdown` is set. The
+    /// connection is retried in the background while the broker is unreachable.
+    pub fn spawn(
+        self,
+        stats: Arc<Stats>,
+        settings: Settings,
+        shutdown: Arc<AtomicBool>,
+    ) -> std::io::Result<JoinHandle<()>> {
+        let (client, mut connection) = Client::new(self.options, 16);
+
+        thread::Builder::new()
+            .name("mqtt-connection".into())
+            .spawn(move || {
+                for event in connection.iter() {
+                    match event {
+                        Ok(event) => debug!("MQTT: {:?}", event),
+                        Err(e) => {
+                            warn!("MQTT connection error: {}", e);
+                            thread::sleep(Duration::from_secs(1));
+                        }
+                    }
+                }
+            })?;
+
+        thread::Builder::new().name("mqtt".into()).spawn(move || {
+            let (stats_topic, profile_topic) = (
+                format!("{}/stats", self.topic),
+                format!("{}/profile", self.topic),
+            );
+            let mut published_version = None;
+            let mut next_report = Instant::now() + self.interval;
+
+            while !shutdown.load(Ordering::Relaxed) {
+                let (current, version) = settings.profile.load();
+                if published_version != Some(version) {
+                    published_version = Some(version);
+                    if let Err(e) = client.try_publish(
+                        &profile_topic,
+                        QoS::AtLeastOnce,
+                        true,
+                        current.to_string(),
+                    ) {
+                        warn!("Could not publish the impairments: {}", e);
+                    }
+                }
+
+                if Instant::now() >= next_report {
+                    next_report += self.interval;
+                    let snapshot = stats.snapshot(settings.uptime());
+                    let payload = serde_json::to_vec(&snapshot).unwrap(); // Plain data
+                    if let Err(e) =
+                        client.try_publish(&stats_topic, QoS::AtMostOnce, false, payload)
+                    {
+                        warn!("Could not publish the stats: {}", e);
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            let _ = client.disconnect();
+        })
+    }
+}
@@ -0,0 +1,220 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! NAT emulation: packets leave with the source rewritten to a public
+//! address and a port of a pool, and only the answers to a port still mapped,
+//! from a peer the filtering accepts, are let back in.
+//!
+//! Mappings are endpoint independent: every internal address and port keeps
+//! its public port whatever the destination, until it goes unused for longer
+//! than the timeout. Only outgoing packets keep a mapping alive.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Which peers may send to a mapped port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filtering {
+    /// Anyone (full cone)
+    EndpointIndependent,
+    /// The addresses it sent to (restricted cone)
+    Address,
+    /// The addresses and ports it sent to (port restricted cone)
+    AddressPort,
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatRefusal {
+    #[error("no port left in the pool")]
+    Exhausted,
+    #[error("port {0} is not mapped")]
+    NoMapping(u16),
+    #[error("port {0} does not accept packets from {1}")]
+    Filtered(u16, SocketAddr),
+}
+
+/// Addresses a packet is forwarded with: the one written in its header and
+/// the one it is sent to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Translation {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+struct Mapping {
+    internal: SocketAddr,
+    last_used: Instant,
+    /// Destinations it sent to
+    peers: HashSet<SocketAddr>,
+}
+
+#[derive(Default)]
+struct Table {
+    by_port: HashMap<u16, Mapping>,
+    by_internal: HashMap<SocketAddr, u16>,
+    /// Next port to try, so freed ports are not reused at once
+    next: u16,
+}
+
+/// A translation table. It can be shared among threads, so every one sees
+/// the same mappings.
+pub struct Nat {
+    address: IpAddr,
+    ports: RangeInclusive<u16>,
+    timeout: Duration,
+    filtering: Filtering,
+    table: Mutex<Table>,
+}
+
+impl Nat {
+    /// Maps to `address` and the `ports`, forgetting mappings unused for
+    /// `timeout`
+    pub fn new(
+        address: IpAddr,
+        ports: RangeInclusive<u16>,
+        timeout: Duration,
+        filtering: Filtering,
+    ) -> Nat {
+        let next = *ports.start();
+
+        Nat {
+            address,
+            ports,
+            timeout,
+            filtering,
+            table: Mutex::new(Table {
+                next,
+                ..Table::default()
+            }),
+        }
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Mappings not expired at `now`
+    pub fn mappings(&self, now: Instant) -> usize {
+        let table = self.table.lock().unwrap();
+        table
+            .by_port
+            .values()
+            .filter(|mapping| !self.expired(mapping, now))
+            .count()
+    }
+
+    fn expired(&self, mapping: &Mapping, now: Instant) -> bool {
+        now.saturating_duration_since(mapping.last_used) > self.timeout
+    }
+
+    fn accepts(&self, mapping: &Mapping, peer: SocketAddr) -> bool {
+        match self.filtering {
+            Filtering::EndpointIndependent => true,
+            Filtering::Address => mapping.peers.iter().any(|p| p.ip() == peer.ip()),
+            Filtering::AddressPort => mapping.peers.contains(&peer),
+        }
+    }
+
+    /// Translates a packet from `src` to `dst`. Those to the public address
+    /// and a port of the pool come in, the rest go out.
+    pub fn translate(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        now: Instant,
+    ) -> Result<Translation, NatRefusal> {
+        let mut table = self.table.lock().unwrap();
+
+        if dst.ip().to_canonical() == self.address && self.ports.contains(&dst.port()) {
+            let port = dst.port();
+            let mapping = table
+                .by_port
+                .get(&port)
+                .filter(|mapping| !self.expired(mapping, now))
+                .ok_or(NatRefusal::NoMapping(port))?;
+            if !self.accepts(mapping, src) {
+                return Err(NatRefusal::Filtered(port, src));
+            }
+            return Ok(Translation {
+                src,
+                dst: mapping.internal,
+            });
+        }
+
+        let current = table
+            .by_internal
+            .get(&src)
+            .copied()
+            .filter(|port| !self.expired(&table.by_port[port], now));
+        let port = match current {
+            Some(port) => port,
+            None => self.allocate(&mut table, src, now)?,
+        };
+        let mapping = table.by_port.get_mut(&port).unwrap();
+        mapping.last_used = now;
+        mapping.peers.insert(dst);
+
+        Ok(Translation {
+            src: SocketAddr::new(self.address, port),
+            dst,
+        })
+    }
+
+    /// Maps `internal` to the next port free at `now`, dropping the expired
+    /// mappings found on the way
+    fn allocate(
+        &self,
+        table: &mut Table,
+        internal: SocketAddr,
+        now: Instant,
+    ) -> Result<u16, NatRefusal> {
+        let (start, end) = (*self.ports.start(), *self.ports.end());
+        let size = usize::from(end - start) + 1;
+
+        for _ in 0..size {
+            let port = table.next;
+            table.next = if port == end { start } else { port + 1 };
+
+            if let Some(mapping) = table.by_port.get(&port) {
+                if !self.expired(mapping, now) {
+                    continue;
+                }
+                let old = mapping.internal;
+                table.by_port.remove(&port);
+                table.by_internal.remove(&old);
+            }
+
+            if let Some(old) = table.by_internal.insert(internal, port) {
+                table.by_port.remove(&old);
+            }
+            table.by_port.insert(
+                port,
+                Mapping {
+                    internal,
+                    last_used: now,
+                    peers: HashSet::new(),
+                },
+            );
+            return Ok(port);
+        }
+
+        Err(NatRefusal::Exhausted)
+    }
+}
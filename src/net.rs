@@ -0,0 +1,251 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Dual-stack sockets: a single IPv6 socket serving IPv4 peers as well, which
+//! show up with IPv4-mapped addresses (`::ffff:a.b.c.d`).
+
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, UdpSocket};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+#[error("invalid port range {0:?}, expected FIRST-LAST")]
+pub struct InvalidPortRange(String);
+
+/// Consecutive ports, written `FIRST-LAST` or as a single port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.first..=self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = InvalidPortRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidPortRange(s.to_owned());
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first = first.trim().parse().map_err(|_| invalid())?;
+        let last = last.trim().parse().map_err(|_| invalid())?;
+        // Port 0 lets the system pick one, which makes no sense in a range
+        if first > last || (first == 0 && last != 0) {
+            return Err(invalid());
+        }
+
+        Ok(PortRange { first, last })
+    }
+}
+
+/// Binds a socket to `port` on every IPv4 and IPv6 address, or only on the
+/// IPv4 ones where IPv6 is not available. With `reuse_port`, other sockets
+/// can share the port.
+fn bind_dual_stack(ty: Type, port: u16, reuse_port: bool) -> io::Result<Socket> {
+    let socket = |domain| -> io::Result<Socket> {
+        let socket = Socket::new(domain, ty, None)?;
+        // As std does, so listeners can be restarted at once
+        #[cfg(unix)]
+        if ty == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_port(reuse_port)?;
+        #[cfg(not(unix))]
+        let _ = reuse_port;
+        Ok(socket)
+    };
+    let dual = || -> io::Result<Socket> {
+        let socket = socket(Domain::IPV6)?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket)
+    };
+
+    dual().or_else(|_| {
+        let socket = socket(Domain::IPV4)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket)
+    })
+}
+
+/// Whether `addr` asks for a dual-stack socket: `None` or the unspecified
+/// IPv6 address
+fn wants_dual_stack(addr: Option<IpAddr>) -> bool {
+    match addr {
+        None => true,
+        Some(IpAddr::V6(ip)) => ip.is_unspecified(),
+        Some(IpAddr::V4(_)) => false,
+    }
+}
+
+/// Binds a UDP socket to `port` on `addr`, or dual-stack on every address if
+/// it is `None` or the unspecified IPv6 address
+pub fn bind(addr: Option<IpAddr>, port: u16) -> io::Result<UdpSocket> {
+    match addr {
+        Some(ip) if !wants_dual_stack(addr) => UdpSocket::bind((ip, port)),
+        _ => Ok(bind_dual_stack(Type::DGRAM, port, false)?.into()),
+    }
+}
+
+/// Binds `count` UDP sockets sharing `port` with `SO_REUSEPORT`, so the
+/// kernel spreads the incoming datagrams among them, always handing those of
+/// a peer to the same socket
+#[cfg(unix)]
+pub fn bind_reuse_port(
+    addr: Option<IpAddr>,
+    port: u16,
+    count: usize,
+) -> io::Result<Vec<UdpSocket>> {
+    let bind = |port| -> io::Result<UdpSocket> {
+        match addr {
+            Some(ip) if !wants_dual_stack(addr) => {
+                let addr = SocketAddr::from((ip, port));
+                let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+                socket.set_reuse_port(true)?;
+                socket.bind(&addr.into())?;
+                Ok(socket.into())
+            }
+            _ => Ok(bind_dual_stack(Type::DGRAM, port, true)?.into()),
+        }
+    };
+
+    // The rest join the port of the first one, in case the system chose it
+    let first = bind(port)?;
+    let port = first.local_addr()?.port();
+    let mut sockets = vec![first];
+    for _ in 1..count {
+        sockets.push(bind(port)?);
+    }
+
+    Ok(sockets)
+}
+
+/// Like `bind`, for a TCP listener
+pub fn bind_tcp(addr: Option<IpAddr>, port: u16) -> io::Result<TcpListener> {
+    match addr {
+        Some(ip) if !wants_dual_stack(addr) => TcpListener::bind((ip, port)),
+        _ => {
+            let socket = bind_dual_stack(Type::STREAM, port, false)?;
+            socket.listen(128)?;
+            Ok(socket.into())
+        }
+    }
+}
+
+/// First descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Takes the UDP sockets passed by systemd socket activation, as
+/// `sd_listen_fds` does: none unless `LISTEN_PID` is this process. The
+/// variables are removed, so children do not take them too.
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Vec<UdpSocket>> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = fds
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid LISTEN_FDS"))?;
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors over to this process
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            if socket.r#type()? != Type::DGRAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("descriptor {fd} passed by systemd is not a datagram socket"),
+                ));
+            }
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+/// Addresses of the network interfaces of this host, as `getifaddrs` lists
+/// them
+#[cfg(unix)]
+pub fn interface_addresses() -> io::Result<Vec<IpAddr>> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addresses = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: the list stays valid until freed below
+        let ifaddr = unsafe { &*entry };
+        if !ifaddr.ifa_addr.is_null() {
+            match i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                    addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        addr.sin_addr.s_addr,
+                    ))));
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                    addresses.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        entry = ifaddr.ifa_next;
+    }
+    unsafe { libc::freeifaddrs(list) };
+
+    Ok(addresses)
+}
+
+/// Turns IPv4-mapped addresses back into IPv4 ones
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::from((ip, v6.port())),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Address to pass to a socket to reach `addr`, mapping IPv4 ones for IPv6
+/// sockets
+pub fn for_socket(addr: SocketAddr, ipv6_socket: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if ipv6_socket => {
+            SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+        }
+        _ => addr,
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Event traces in the ASCII format of ns-3.
+//!
+//! Every line holds the event (`+` enqueue, `-` dequeue, `d` drop), the time
+//! in seconds since the router started, a trace source path and the packet
+//! headers as ns-3 prints them, so the traces of the router and those of an
+//! ns-3 simulation can be processed with the same tools.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const DEVICE: &str = "/NodeList/0/DeviceList/0/$ns3::ShuffleRouter/TxQueue";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ns3Event {
+    Enqueue,
+    Dequeue,
+    Drop,
+}
+
+impl Ns3Event {
+    fn symbol(self) -> char {
+        match self {
+            Ns3Event::Enqueue => '+',
+            Ns3Event::Dequeue => '-',
+            Ns3Event::Drop => 'd',
+        }
+    }
+
+    fn source(self) -> &'static str {
+        match self {
+            Ns3Event::Enqueue => "Enqueue",
+            Ns3Event::Dequeue => "Dequeue",
+            Ns3Event::Drop => "Drop",
+        }
+    }
+}
+
+/// Writes an ns-3 ASCII trace. It can be shared among threads.
+pub struct Ns3Trace {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Ns3Trace {
+    pub fn create(path: &Path, start: Instant) -> io::Result<Ns3Trace> {
+        Ok(Ns3Trace {
+            start,
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    /// Traces an event of a datagram of `len` bytes of UDP payload. The
+    /// destination can be unknown for packets dropped before parsing them.
+    pub fn event(
+        &self,
+        event: Ns3Event,
+        time: Instant,
+        src: SocketAddr,
+        dst: Option<SocketAddr>,
+        len: usize,
+    ) -> io::Result<()> {
+        let (dst_ip, dst_port) = match dst {
+            Some(dst) => (dst.ip().to_string(), dst.port().to_string()),
+            None => ("0.0.0.0".to_owned(), "0".to_owned()),
+        };
+
+        writeln!(
+            self.out.lock().unwrap(),
+            "{} {:.9} {}/{} ns3::Ipv4Header (tos 0x0 DSCP Default ECN Not-ECT ttl 64 id 0 protocol 17 offset (bytes) 0 flags [none] length: {} {} > {}) ns3::UdpHeader (length: {} {} > {}) Payload (size={})",
+            event.symbol(),
+            time.saturating_duration_since(self.start).as_secs_f64(),
+            DEVICE,
+            event.source(),
+            len + 28,
+            src.ip(),
+            dst_ip,
+            len + 8,
+            src.port(),
+            dst_port,
+            len
+        )
+    }
+}
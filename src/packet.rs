@@ -16,14 +16,16 @@
  */
 
 use super::buffer::Buffer;
+use super::fragment::{self, FragmentTag};
 use nom::{
     combinator::map,
-    number::streaming::{be_u16, be_u8},
+    number::streaming::{be_u16, be_u64, be_u8},
     sequence::tuple,
     IResult,
 };
+use rand::Rng;
 use std::cmp::Ordering;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant};
 use thiserror::Error;
 
@@ -33,6 +35,18 @@ pub enum PacketError {
     InvalidLenth(core::num::NonZeroUsize),
     #[error("not enough data. Minimum is six for IP + port")]
     NotEnoughData(),
+    #[error("unspecified destination address")]
+    UnspecifiedAddress,
+    #[error("destination port is zero")]
+    ZeroPort,
+    #[error("reserved destination address {0}")]
+    ReservedAddress(IpAddr),
+    #[error("{0} bytes do not fit in a datagram once the header is rewritten")]
+    TooLong(usize),
+    #[error("unsupported header version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown header flags {0:#04x}")]
+    UnknownFlags(u8),
     #[error("sorry, could not decode the packet header")]
     Unknown,
 }
@@ -50,9 +64,19 @@ impl<E> From<nom::Err<E>> for PacketError {
 }
 
 pub struct Packet {
-    dst: SocketAddrV4,
+    src: SocketAddr,
+    /// Host it was received from, other than that of `src` behind the NAT
+    sender: IpAddr,
+    /// The one written in the data, for `src`. None for echoed packets.
+    header: Option<Header>,
+    dst: SocketAddr,
     data: Buffer,
+    /// When the router received it
+    arrival: Instant,
     exit_time: Instant,
+    attempts: u32,
+    /// Index of the router socket it leaves through
+    socket: usize,
 }
 
 impl PartialEq for Packet {
@@ -81,49 +105,522 @@ fn address(input: &[u8]) -> IResult<&[u8], Ipv4Addr> {
     })(input)
 }
 
-fn sockaddr(input: &[u8]) -> IResult<&[u8], SocketAddrV4> {
-    map(tuple((address, be_u16)), |(ip, port)| {
-        SocketAddrV4::new(ip, port)
+fn address_v6(input: &[u8]) -> IResult<&[u8], Ipv6Addr> {
+    map(tuple((be_u64, be_u64)), |(high, low)| {
+        Ipv6Addr::from((u128::from(high) << 64) | u128::from(low))
     })(input)
 }
 
-fn get_dst(data: &[u8]) -> Result<SocketAddrV4, PacketError> {
-    Ok(sockaddr(data).map(|(_, addr)| addr)?)
+/// Address and port of a version 2 header, past its magic byte and version
+fn sockaddr_v2(input: &[u8]) -> IResult<&[u8], (u8, SocketAddr)> {
+    let (rest, (flags, family)) = tuple((be_u8, be_u8))(input)?;
+    match family {
+        V2_FAMILY_IPV4 => map(tuple((address, be_u16)), move |(ip, port)| {
+            (flags, SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        })(rest),
+        V2_FAMILY_IPV6 => map(tuple((address_v6, be_u16)), move |(ip, port)| {
+            (flags, SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+        })(rest),
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        ))),
+    }
+}
+
+fn sockaddr(input: &[u8]) -> IResult<&[u8], SocketAddr> {
+    match be_u8(input)? {
+        (rest, IPV6_FAMILY) => map(tuple((address_v6, be_u16)), |(ip, port)| {
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))
+        })(rest),
+        _ => map(tuple((address, be_u16)), |(ip, port)| {
+            SocketAddr::V4(SocketAddrV4::new(ip, port))
+        })(input),
+    }
+}
+
+/// First byte of the headers carrying IPv6 addresses. IPv4 addresses never
+/// start with it, as it lies in the reserved 240.0.0.0/4 range.
+pub const IPV6_FAMILY: u8 = 0xf6;
+
+/// First byte of version 2 headers, also in the reserved 240.0.0.0/4 range
+pub const V2_MAGIC: u8 = 0xf2;
+/// Second byte of version 2 headers
+pub const V2_VERSION: u8 = 2;
+/// Address family byte of version 2 headers carrying IPv4 addresses
+pub const V2_FAMILY_IPV4: u8 = 4;
+/// Address family byte of version 2 headers carrying IPv6 addresses
+pub const V2_FAMILY_IPV6: u8 = 6;
+/// Flag of version 2 headers announcing a hop limit byte after the port
+pub const V2_FLAG_HOP_LIMIT: u8 = 0x01;
+
+/// The bytes heading every datagram, with an address and a port in network
+/// byte order. Senders put the destination there and the router replaces it
+/// with the sender's address before forwarding.
+///
+/// IPv4 addresses take six bytes: the address followed by the port. IPv6
+/// ones take nineteen: `IPV6_FAMILY`, the address and the port.
+///
+/// Version 2 headers leave room for options: `V2_MAGIC`, `V2_VERSION`, a
+/// byte of flags announcing the options, the address family, the address and
+/// the port. The options the flags announce follow. The router answers in
+/// the version it gets.
+///
+/// The only option so far is the hop limit, which each router decrements so
+/// packets caught in a loop of routers die out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    addr: SocketAddr,
+    /// Whether it is a version 2 header
+    extended: bool,
+    /// Routers the packet can still go through, only in version 2 headers
+    hop_limit: Option<u8>,
+}
+
+impl Header {
+    /// Encoded length of IPv4 headers, in bytes
+    pub const LEN: usize = 6;
+    /// Encoded length of IPv6 headers, in bytes
+    pub const V6_LEN: usize = 19;
+    /// Encoded length of version 2 headers with IPv4 addresses and no
+    /// options, in bytes
+    pub const V2_LEN: usize = 10;
+    /// Encoded length of version 2 headers with IPv6 addresses and no
+    /// options, in bytes
+    pub const V2_V6_LEN: usize = 22;
+
+    pub fn new(addr: impl Into<SocketAddr>) -> Header {
+        Header {
+            addr: addr.into(),
+            extended: false,
+            hop_limit: None,
+        }
+    }
+
+    /// A version 2 header
+    pub fn extended(addr: impl Into<SocketAddr>) -> Header {
+        Header {
+            addr: addr.into(),
+            extended: true,
+            hop_limit: None,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    pub fn hop_limit(&self) -> Option<u8> {
+        self.hop_limit
+    }
+
+    /// The same header, with the same version and options, for `addr`
+    pub fn with_addr(&self, addr: SocketAddr) -> Header {
+        Header { addr, ..*self }
+    }
+
+    /// The same header with a hop limit, which makes it a version 2 one
+    pub fn with_hop_limit(&self, hop_limit: u8) -> Header {
+        Header {
+            extended: true,
+            hop_limit: Some(hop_limit),
+            ..*self
+        }
+    }
+
+    /// The header to forward the packet with to the next hop: the same one,
+    /// for `addr` and with one hop less
+    pub fn next_hop(&self, addr: SocketAddr) -> Header {
+        Header {
+            addr,
+            hop_limit: self.hop_limit.map(|hops| hops.saturating_sub(1)),
+            ..*self
+        }
+    }
+
+    /// Whether the packet went through as many routers as it was allowed to
+    pub fn is_expired(&self) -> bool {
+        self.hop_limit == Some(0)
+    }
+
+    /// Encoded length, in bytes
+    pub fn encoded_len(&self) -> usize {
+        let options = usize::from(self.hop_limit.is_some());
+        options
+            + match (self.extended, self.addr) {
+                (false, SocketAddr::V4(_)) => Header::LEN,
+                (false, SocketAddr::V6(_)) => Header::V6_LEN,
+                (true, SocketAddr::V4(_)) => Header::V2_LEN,
+                (true, SocketAddr::V6(_)) => Header::V2_V6_LEN,
+            }
+    }
+
+    /// Decodes the header at the start of `data`
+    pub fn decode(data: &[u8]) -> Result<Header, PacketError> {
+        if data.first() != Some(&V2_MAGIC) {
+            return Ok(sockaddr(data).map(|(_, addr)| Header::new(addr))?);
+        }
+
+        match data.get(1) {
+            None => Err(PacketError::NotEnoughData()),
+            Some(&V2_VERSION) => {
+                let (rest, (flags, addr)) = sockaddr_v2(&data[2..])?;
+                if flags & !V2_FLAG_HOP_LIMIT != 0 {
+                    return Err(PacketError::UnknownFlags(flags & !V2_FLAG_HOP_LIMIT));
+                }
+                let header = Header::extended(addr);
+                if flags & V2_FLAG_HOP_LIMIT == 0 {
+                    return Ok(header);
+                }
+                let hop_limit = *rest.first().ok_or(PacketError::NotEnoughData())?;
+                Ok(header.with_hop_limit(hop_limit))
+            }
+            Some(&version) => Err(PacketError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.encoded_len()];
+        self.write(&mut bytes);
+        bytes
+    }
+
+    /// Overwrites the first `encoded_len()` bytes of `data` with the header
+    pub fn write(&self, data: &mut [u8]) {
+        if self.extended {
+            let flags = if self.hop_limit.is_some() {
+                V2_FLAG_HOP_LIMIT
+            } else {
+                0
+            };
+            data[..3].copy_from_slice(&[V2_MAGIC, V2_VERSION, flags]);
+            let end = match self.addr {
+                SocketAddr::V4(addr) => {
+                    data[3] = V2_FAMILY_IPV4;
+                    data[4..8].copy_from_slice(&addr.ip().octets());
+                    8
+                }
+                SocketAddr::V6(addr) => {
+                    data[3] = V2_FAMILY_IPV6;
+                    data[4..20].copy_from_slice(&addr.ip().octets());
+                    20
+                }
+            };
+            data[end..end + 2].copy_from_slice(&self.addr.port().to_be_bytes());
+            if let Some(hop_limit) = self.hop_limit {
+                data[end + 2] = hop_limit;
+            }
+            return;
+        }
+
+        match self.addr {
+            SocketAddr::V4(addr) => {
+                data[..4].copy_from_slice(&addr.ip().octets());
+                data[4..6].copy_from_slice(&addr.port().to_be_bytes());
+            }
+            SocketAddr::V6(addr) => {
+                data[0] = IPV6_FAMILY;
+                data[1..17].copy_from_slice(&addr.ip().octets());
+                data[17..19].copy_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Decodes the address carried in the header of a datagram
+pub fn get_dst(data: &[u8]) -> Result<SocketAddr, PacketError> {
+    Header::decode(data).map(|header| header.addr())
+}
+
+/// Rejects destinations no packet should be sent to: the unspecified address,
+/// port zero, the reserved IPv4 (class E) range, broadcast included, and
+/// IPv6 multicast
+pub fn check_dst(dst: &SocketAddr) -> Result<(), PacketError> {
+    let reserved = match dst.ip() {
+        IpAddr::V4(ip) => ip.octets()[0] >= 240,
+        IpAddr::V6(ip) => ip.is_multicast(),
+    };
+
+    if dst.ip().is_unspecified() {
+        Err(PacketError::UnspecifiedAddress)
+    } else if dst.port() == 0 {
+        Err(PacketError::ZeroPort)
+    } else if reserved {
+        Err(PacketError::ReservedAddress(dst.ip()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Encodes `addr` in the first six bytes of `data`, in network byte order
+pub fn put_addr(data: &mut [u8], addr: SocketAddrV4) {
+    Header::new(addr).write(data);
+}
+
+/// Replaces the header of `data` with one of the same version for `addr`,
+/// returning the address it carried
+pub fn replace_header(data: &mut Vec<u8>, addr: SocketAddr) -> Result<SocketAddr, PacketError> {
+    let old = Header::decode(data)?;
+    let new = old.with_addr(addr);
+
+    data.splice(..old.encoded_len(), new.encode());
+    Ok(old.addr())
 }
 
 impl Packet {
+    /// Takes the destination from the header of `data`, replacing it with
+    /// the header for `orig`. The payload is moved when both differ in length.
     pub fn create(
-        orig: SocketAddrV4,
+        orig: SocketAddr,
         mut data: Buffer,
+        arrival: Instant,
         exit_time: Instant,
     ) -> Result<Packet, PacketError> {
-        let dst = get_dst(&data)?;
+        let dst_header = Header::decode(&data)?;
+        let src_header = dst_header.next_hop(orig);
 
-        data[..4].copy_from_slice(&orig.ip().octets());
-        data[4..6].copy_from_slice(&orig.port().to_be_bytes());
+        if src_header.encoded_len() != dst_header.encoded_len() {
+            let payload = dst_header.encoded_len()..data.len();
+            let len = src_header.encoded_len() + payload.len();
+            if len > data.capacity() {
+                return Err(PacketError::TooLong(len));
+            }
+            data.copy_within(payload, src_header.encoded_len());
+            data.set_len(len);
+        }
+        src_header.write(&mut data);
+        let dst = dst_header.addr();
 
         Ok(Packet {
+            src: orig,
+            sender: orig.ip(),
+            header: Some(src_header),
             dst,
             data,
+            arrival,
             exit_time,
+            attempts: 0,
+            socket: 0,
         })
     }
 
+    /// Like `create`, but also rejects invalid destinations (see `check_dst`)
+    pub fn create_strict(
+        orig: SocketAddr,
+        data: Buffer,
+        arrival: Instant,
+        exit_time: Instant,
+    ) -> Result<Packet, PacketError> {
+        Packet::parse_header(&data)?;
+
+        Packet::create(orig, data, arrival, exit_time)
+    }
+
+    /// Decodes the header of a datagram and checks its destination (see
+    /// `check_dst`), as a strict router does, without forwarding anything
+    pub fn parse_header(data: &[u8]) -> Result<Header, PacketError> {
+        let header = Header::decode(data)?;
+        check_dst(&header.addr())?;
+
+        Ok(header)
+    }
+
+    /// Returns `data` unchanged to `orig`, without looking for a header
+    pub fn echo(orig: SocketAddr, data: Buffer, arrival: Instant, exit_time: Instant) -> Packet {
+        Packet {
+            src: orig,
+            sender: orig.ip(),
+            header: None,
+            dst: orig,
+            data,
+            arrival,
+            exit_time,
+            attempts: 0,
+            socket: 0,
+        }
+    }
+
+    /// Length of the header, zero for echoed packets
+    fn header_len(&self) -> usize {
+        self.header.map_or(0, |header| header.encoded_len())
+    }
+
+    /// A copy of the packet, stored in `data`, leaving at `exit_time`
+    pub fn duplicate(&self, mut data: Buffer, exit_time: Instant) -> Packet {
+        let len = self.data.len();
+        data[..len].copy_from_slice(&self.data);
+        data.set_len(len);
+
+        Packet {
+            src: self.src,
+            sender: self.sender,
+            header: self.header,
+            dst: self.dst,
+            data,
+            arrival: self.arrival,
+            exit_time,
+            attempts: 0,
+            socket: self.socket,
+        }
+    }
+
+    /// Splits the packet into fragments of up to `max_len` bytes, header and
+    /// fragment tag included, stored in buffers taken from `buffer`. None if
+    /// no payload fits.
+    pub fn fragment(
+        &self,
+        max_len: usize,
+        id: u16,
+        mut buffer: impl FnMut() -> Buffer,
+    ) -> Option<Vec<Packet>> {
+        let header_len = self.header_len();
+        let payload = &self.data[header_len..];
+        let chunk = max_len.checked_sub(header_len + fragment::TAG_LEN)?;
+        if chunk == 0 {
+            return None;
+        }
+
+        // A datagram without payload is still sent, as a single fragment
+        let parts: Vec<_> = match payload.len() {
+            0 => vec![payload],
+            _ => payload.chunks(chunk).collect(),
+        };
+        let fragments = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let mut data = buffer();
+                let start = header_len + fragment::TAG_LEN;
+                if let Some(header) = self.header {
+                    header.write(&mut data);
+                }
+                FragmentTag {
+                    id,
+                    offset: (i * chunk) as u16,
+                    more: i + 1 < parts.len(),
+                }
+                .write(&mut data[header_len..]);
+                data[start..start + part.len()].copy_from_slice(part);
+                data.set_len(start + part.len());
+
+                Packet {
+                    src: self.src,
+                    sender: self.sender,
+                    header: self.header,
+                    dst: self.dst,
+                    data,
+                    arrival: self.arrival,
+                    exit_time: self.exit_time,
+                    attempts: 0,
+                    socket: self.socket,
+                }
+            })
+            .collect();
+
+        Some(fragments)
+    }
+
+    /// Flips random bits of the payload, leaving the header alone: one, and
+    /// each further one with probability one half. Returns how many.
+    pub fn corrupt<R: Rng + ?Sized>(&mut self, rng: &mut R) -> usize {
+        let payload = self.header_len()..self.data.len();
+        let bits = payload.len() * 8;
+        if bits == 0 {
+            return 0;
+        }
+
+        let mut flipped = 0;
+        loop {
+            let bit = rng.gen_range(0..bits);
+            self.data[payload.start + bit / 8] ^= 0x80 >> (bit % 8);
+            flipped += 1;
+            if flipped == bits || rng.gen_bool(0.5) {
+                return flipped;
+            }
+        }
+    }
+
+    /// Leaves at most `len` bytes of payload, past the header. Returns how
+    /// many were cut.
+    pub fn truncate(&mut self, len: usize) -> usize {
+        let end = (self.header_len() + len).min(self.data.len());
+        let cut = self.data.len() - end;
+        self.data.set_len(end);
+        cut
+    }
+
     pub fn get_duration_till_next(&self, now: Instant) -> Option<Duration> {
         Some(self.exit_time.saturating_duration_since(now))
     }
 
+    pub fn src(&self) -> SocketAddr {
+        self.src
+    }
+
+    pub fn sender(&self) -> IpAddr {
+        self.sender
+    }
+
     pub fn dst(&self) -> SocketAddr {
-        SocketAddr::from(self.dst)
+        self.dst
     }
 
     pub fn get(&self) -> &Buffer {
         &self.data
     }
 
+    /// The header written in the data, None for echoed packets
+    pub fn header(&self) -> Option<Header> {
+        self.header
+    }
+
+    pub fn arrival(&self) -> Instant {
+        self.arrival
+    }
+
     pub fn exit_time(&self) -> Instant {
         self.exit_time
     }
+
+    /// Number of times the transmission of this packet has been postponed
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn socket(&self) -> usize {
+        self.socket
+    }
+
+    /// Makes the packet leave through the router socket with index `socket`
+    pub fn set_socket(&mut self, socket: usize) {
+        self.socket = socket;
+    }
+
+    /// Sends the packet to `dst` instead of the address in its header
+    pub fn redirect(&mut self, dst: SocketAddr) {
+        self.dst = dst;
+    }
+
+    /// Records that the packet was received from `sender`, which the NAT
+    /// translated into its source
+    pub fn set_sender(&mut self, sender: IpAddr) {
+        self.sender = sender;
+    }
+
+    /// Reschedules the packet to leave no sooner than `exit_time`
+    pub fn hold_until(&mut self, exit_time: Instant) {
+        self.exit_time = self.exit_time.max(exit_time);
+    }
+
+    /// Reschedules the packet after a failed transmission attempt
+    pub fn postpone(&mut self, now: Instant, delay: Duration) {
+        self.attempts += 1;
+        self.exit_time = now + delay;
+    }
 }
 
 impl From<Packet> for Buffer {
@@ -0,0 +1,264 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Minimal support for the classic libpcap file format
+
+use crate::pcapng::{ipv4_udp, ipv6_udp};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_IPV4: u32 = 228;
+
+#[derive(Error, Debug)]
+pub enum PcapError {
+    #[error("could not read the capture: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a pcap file")]
+    BadMagic,
+    #[error("truncated capture")]
+    Truncated,
+}
+
+/// A captured frame
+pub struct PcapRecord {
+    /// Capture time, since the Unix epoch
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+pub struct PcapReader<R: Read> {
+    reader: R,
+    swapped: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<PcapReader<R>, PcapError> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => return Err(PcapError::BadMagic),
+        };
+
+        let mut pcap = PcapReader {
+            reader,
+            swapped,
+            nanos,
+            linktype: 0,
+        };
+        pcap.linktype = pcap.u32_at(&header, 20);
+
+        Ok(pcap)
+    }
+
+    pub fn linktype(&self) -> u32 {
+        self.linktype
+    }
+
+    fn u32_at(&self, data: &[u8], offset: usize) -> u32 {
+        let value = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        }
+    }
+
+    /// Reads the next frame, if any
+    pub fn next_record(&mut self) -> Result<Option<PcapRecord>, PcapError> {
+        let mut header = [0; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let secs = self.u32_at(&header, 0);
+        let frac = self.u32_at(&header, 4);
+        let caplen = self.u32_at(&header, 8) as usize;
+
+        let mut data = vec![0; caplen];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => PcapError::Truncated,
+                _ => PcapError::Io(e),
+            })?;
+
+        let timestamp = Duration::from_secs(secs.into())
+            + if self.nanos {
+                Duration::from_nanos(frac.into())
+            } else {
+                Duration::from_micros(frac.into())
+            };
+
+        Ok(Some(PcapRecord { timestamp, data }))
+    }
+}
+
+/// A UDP datagram found inside a captured frame
+pub struct UdpDatagram<'a> {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub payload: &'a [u8],
+}
+
+/// Extracts the UDP over IPv4 datagram carried by a frame, if there is one
+pub fn udp_datagram(linktype: u32, frame: &[u8]) -> Option<UdpDatagram<'_>> {
+    let ip = match linktype {
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_ETHERNET => match frame.get(12..14)? {
+            [0x08, 0x00] => frame.get(14..)?,
+            [0x81, 0x00] if frame.get(16..18)? == [0x08, 0x00] => frame.get(18..)?, // 802.1Q
+            _ => return None,
+        },
+        LINKTYPE_RAW | LINKTYPE_IPV4 => frame,
+        LINKTYPE_LINUX_SLL if frame.get(14..16)? == [0x08, 0x00] => frame.get(16..)?,
+        _ => return None,
+    };
+
+    let version = ip.first()? >> 4;
+    let header_len = usize::from(ip.first()? & 0x0f) * 4;
+    if version != 4 || ip.get(9)? != &17 {
+        return None; // Not UDP over IPv4
+    }
+    let fragment = u16::from_be_bytes([*ip.get(6)?, *ip.get(7)?]);
+    if fragment & 0x3fff != 0 {
+        return None; // Fragments cannot be replayed on their own
+    }
+
+    let src_ip = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_ip = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+    let udp = ip.get(header_len..)?;
+    let src_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let dst_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let udp_len = usize::from(u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]));
+
+    Some(UdpDatagram {
+        src: SocketAddrV4::new(src_ip, src_port),
+        dst: SocketAddrV4::new(dst_ip, dst_port),
+        payload: udp.get(8..udp_len.max(8))?,
+    })
+}
+
+/// Writes frames in the classic pcap format, with microsecond timestamps
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header, for frames of type `linktype`
+    pub fn new(mut writer: W, linktype: u32) -> io::Result<PcapWriter<W>> {
+        writer.write_all(&MAGIC_MICROS.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?; // Major version
+        writer.write_all(&4u16.to_le_bytes())?; // Minor version
+        writer.write_all(&0i32.to_le_bytes())?; // UTC
+        writer.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+        writer.write_all(&u32::from(u16::MAX).to_le_bytes())?; // Snapshot length
+        writer.write_all(&linktype.to_le_bytes())?;
+
+        Ok(PcapWriter { writer })
+    }
+
+    /// Writes a frame captured at `timestamp`
+    pub fn write_record(&mut self, timestamp: SystemTime, data: &[u8]) -> io::Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        self.writer
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Capture of the datagrams received and sent by the router, as raw IP
+/// packets. It can be shared among threads.
+pub struct TrafficCapture {
+    port: u16,
+    out: Mutex<PcapWriter<BufWriter<File>>>,
+}
+
+impl TrafficCapture {
+    /// Creates a capture of the traffic of the router listening at `port`
+    pub fn create(path: &Path, port: u16) -> io::Result<TrafficCapture> {
+        Ok(TrafficCapture {
+            port,
+            out: Mutex::new(PcapWriter::new(
+                BufWriter::new(File::create(path)?),
+                LINKTYPE_RAW,
+            )?),
+        })
+    }
+
+    /// The router address, as seen by `peer`. The unspecified address
+    /// stands for the actual one.
+    fn router(&self, peer: SocketAddr) -> SocketAddr {
+        match peer {
+            SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.port).into(),
+            SocketAddr::V6(_) => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, self.port, 0, 0).into(),
+        }
+    }
+
+    fn write(&self, src: SocketAddr, dst: SocketAddr, data: &[u8]) -> io::Result<()> {
+        let packet = match (src, dst) {
+            (SocketAddr::V4(src), SocketAddr::V4(dst)) => ipv4_udp(src, dst, data),
+            (SocketAddr::V6(src), SocketAddr::V6(dst)) => ipv6_udp(src, dst, data),
+            _ => unreachable!("the router address has the family of the peer"),
+        };
+
+        self.out
+            .lock()
+            .unwrap()
+            .write_record(SystemTime::now(), &packet)
+    }
+
+    /// Stores a datagram received from `src`
+    pub fn received(&self, src: SocketAddr, data: &[u8]) -> io::Result<()> {
+        self.write(src, self.router(src), data)
+    }
+
+    /// Stores a datagram sent to `dst`
+    pub fn sent(&self, dst: SocketAddr, data: &[u8]) -> io::Result<()> {
+        self.write(self.router(dst), dst, data)
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Minimal pcapng writer, with a comment attached to every packet.
+//!
+//! Packets are stored as raw IPv4 datagrams rebuilt from the addresses and
+//! payload seen by the router, so Wireshark can decode them as UDP.
+
+use crate::pcap::LINKTYPE_RAW;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_TSRESOL: u16 = 9;
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn option(code: u16, value: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + value.len() + 3);
+    bytes.extend_from_slice(&code.to_le_bytes());
+    bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(value);
+    bytes.resize(bytes.len() + padding(value.len()), 0);
+    bytes
+}
+
+/// Writes pcapng blocks with microsecond timestamps
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header and a single raw IP interface
+    pub fn new(writer: W) -> io::Result<PcapngWriter<W>> {
+        let mut pcapng = PcapngWriter { writer };
+
+        let mut section = Vec::new();
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes()); // Major version
+        section.extend_from_slice(&0u16.to_le_bytes()); // Minor version
+        section.extend_from_slice(&(-1i64).to_le_bytes()); // Unspecified section length
+        pcapng.block(SECTION_HEADER, &section)?;
+
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&(LINKTYPE_RAW as u16).to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+        interface.extend_from_slice(&0u32.to_le_bytes()); // No snapshot length
+        interface.extend(option(OPT_IF_TSRESOL, &[6])); // Microseconds
+        interface.extend(option(OPT_ENDOFOPT, &[]));
+        pcapng.block(INTERFACE_DESCRIPTION, &interface)?;
+
+        Ok(pcapng)
+    }
+
+    fn block(&mut self, kind: u32, body: &[u8]) -> io::Result<()> {
+        let len = (12 + body.len()) as u32;
+        self.writer.write_all(&kind.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&len.to_le_bytes())
+    }
+
+    /// Writes a packet captured at `timestamp`, with an optional comment
+    pub fn write_packet(
+        &mut self,
+        timestamp: SystemTime,
+        data: &[u8],
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        let micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut body = Vec::with_capacity(20 + data.len() + 64);
+        body.extend_from_slice(&0u32.to_le_bytes()); // Interface
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes()); // Captured
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes()); // Original
+        body.extend_from_slice(data);
+        body.resize(body.len() + padding(data.len()), 0);
+        if let Some(comment) = comment {
+            body.extend(option(OPT_COMMENT, comment.as_bytes()));
+            body.extend(option(OPT_ENDOFOPT, &[]));
+        }
+
+        self.block(ENHANCED_PACKET, &body)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Builds the IPv4 datagram carrying `payload` from `src` to `dst` over UDP
+pub fn ipv4_udp(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let total = 20 + 8 + payload.len();
+    let mut packet = Vec::with_capacity(total);
+
+    packet.extend_from_slice(&[0x45, 0]); // Version 4, 20 bytes header
+    packet.extend_from_slice(&(total as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0]); // Identification, don't fragment
+    packet.extend_from_slice(&[64, 17, 0, 0]); // TTL, UDP, checksum
+    packet.extend_from_slice(&src.ip().octets());
+    packet.extend_from_slice(&dst.ip().octets());
+
+    let checksum = !packet
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xffff) + (sum >> 16)
+        }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]); // No UDP checksum
+    packet.extend_from_slice(payload);
+
+    packet
+}
+
+/// Builds the IPv6 datagram carrying `payload` from `src` to `dst` over UDP
+pub fn ipv6_udp(src: SocketAddrV6, dst: SocketAddrV6, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut packet = Vec::with_capacity(40 + udp_len);
+
+    packet.extend_from_slice(&[0x60, 0, 0, 0]); // Version 6, no traffic class nor flow label
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&[17, 64]); // UDP, hop limit
+    packet.extend_from_slice(&src.ip().octets());
+    packet.extend_from_slice(&dst.ip().octets());
+
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    // The UDP checksum is mandatory over IPv6. It covers a pseudo header
+    // with the addresses, the length and the protocol.
+    let mut pseudo = packet[8..40].to_vec();
+    pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, 17]);
+    pseudo.extend_from_slice(&packet[40..]);
+    if pseudo.len() % 2 == 1 {
+        pseudo.push(0);
+    }
+    let checksum = match !pseudo
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xffff) + (sum >> 16)
+        }) as u16
+    {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet[46..48].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+/// Capture of the datagrams received by the router. It can be shared among
+/// threads.
+pub struct PacketCapture {
+    port: u16,
+    out: Mutex<PcapngWriter<BufWriter<File>>>,
+}
+
+impl PacketCapture {
+    /// Creates a capture of the datagrams received at `port`
+    pub fn create(path: &Path, port: u16) -> io::Result<PacketCapture> {
+        Ok(PacketCapture {
+            port,
+            out: Mutex::new(PcapngWriter::new(BufWriter::new(File::create(path)?))?),
+        })
+    }
+
+    /// Stores a datagram received from `src`, with the router decision as comment
+    pub fn capture(
+        &self,
+        timestamp: SystemTime,
+        src: SocketAddr,
+        data: &[u8],
+        comment: &str,
+    ) -> io::Result<()> {
+        // The unspecified address stands for the router one
+        let packet = match src {
+            SocketAddr::V4(src) => ipv4_udp(
+                src,
+                SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.port),
+                data,
+            ),
+            SocketAddr::V6(src) => ipv6_udp(
+                src,
+                SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, self.port, 0, 0),
+                data,
+            ),
+        };
+
+        self.out
+            .lock()
+            .unwrap()
+            .write_packet(timestamp, &packet, Some(comment))
+    }
+}
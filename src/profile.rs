@@ -0,0 +1,719 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Impairment profiles, which can be changed while the router runs.
+
+use ipnet::IpNet;
+use rand::distributions::{Bernoulli, Distribution, Uniform};
+use rand::Rng;
+use rand_distr::{Exp, Normal, Pareto};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    RwLock,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("drop probability {0} is not between 0 and 1")]
+    InvalidDrop(f64),
+    #[error("delays of {0} + {1} ms overflow")]
+    DelayOverflow(u64, u64),
+    #[error("delays too long for netem, which takes them in microseconds")]
+    NetemOverflow,
+    #[error("duplication probability {0} is not between 0 and 1")]
+    InvalidDuplicate(f64),
+    #[error("corruption probability {0} is not between 0 and 1")]
+    InvalidCorrupt(f64),
+    #[error("reordering probability {0} is not between 0 and 1")]
+    InvalidReorder(f64),
+    #[error("delay correlation {0} is not between 0 and 1")]
+    InvalidDelayCorrelation(f64),
+    #[error("drop correlation {0} is not between 0 and 1")]
+    InvalidDropCorrelation(f64),
+    #[error("truncation probability {0} is not between 0 and 1")]
+    InvalidTruncation(f64),
+    #[error("invalid truncation {0:?}. Expected PROBABILITY:BYTES, e.g. 0.1:20")]
+    InvalidTruncationFormat(String),
+    #[error("invalid fraction {0:?}. Expected a number between 0 and 1, or a percentage")]
+    InvalidFraction(String),
+    #[error("the mean delay must be positive, not {0}")]
+    InvalidMean(f64),
+    #[error("the delay standard deviation must not be negative, not {0}")]
+    InvalidStddev(f64),
+    #[error("the Pareto shape must be over 1, not {0}")]
+    InvalidShape(f64),
+    #[error(
+        "a delay randomness of {0} ms is not used by the {1} delay distribution, which takes the mean instead"
+    )]
+    RandomDelayWithModel(u64, &'static str),
+    #[error("unknown delay distribution {0:?}. Expected uniform, exponential, normal or pareto")]
+    UnknownDelayModel(String),
+    #[error(
+        "invalid profile setting {0:?}. Expected drop=, min_delay=, rand_delay=, dup=, corrupt=, reorder=, truncate=, delay_corr=, drop_corr=, delay_dist=, mean=, stddev= or shape="
+    )]
+    InvalidSetting(String),
+}
+
+/// Longest delay drawn from an unbounded distribution, in milliseconds
+pub const MAX_SAMPLED_DELAY: u64 = 3_600_000;
+
+/// How the delay over the minimum one is distributed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DelayModel {
+    /// Uniform up to the delay randomness
+    Uniform,
+    /// Exponential with this mean, in milliseconds
+    Exponential { mean: f64 },
+    /// Normal with this mean and standard deviation, in milliseconds. Samples
+    /// below zero are taken as zero.
+    Normal { mean: f64, stddev: f64 },
+    /// Pareto with this mean, in milliseconds, and shape. The lower the
+    /// shape, the heavier the tail.
+    Pareto { mean: f64, shape: f64 },
+}
+
+impl DelayModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DelayModel::Uniform => "uniform",
+            DelayModel::Exponential { .. } => "exponential",
+            DelayModel::Normal { .. } => "normal",
+            DelayModel::Pareto { .. } => "pareto",
+        }
+    }
+
+    fn mean(&self) -> Option<f64> {
+        match *self {
+            DelayModel::Uniform => None,
+            DelayModel::Exponential { mean }
+            | DelayModel::Normal { mean, .. }
+            | DelayModel::Pareto { mean, .. } => Some(mean),
+        }
+    }
+
+    fn stddev(&self) -> Option<f64> {
+        match *self {
+            DelayModel::Normal { stddev, .. } => Some(stddev),
+            _ => None,
+        }
+    }
+
+    fn shape(&self) -> Option<f64> {
+        match *self {
+            DelayModel::Pareto { shape, .. } => Some(shape),
+            _ => None,
+        }
+    }
+}
+
+/// A fraction, written as such or as a percentage, e.g. `0.25` or `25%`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fraction(pub f64);
+
+impl FromStr for Fraction {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Fraction, ProfileError> {
+        let invalid = || ProfileError::InvalidFraction(s.to_owned());
+        let value = match s.strip_suffix('%') {
+            Some(percent) => percent.parse::<f64>().map_err(|_| invalid())? / 100.0,
+            None => s.parse().map_err(|_| invalid())?,
+        };
+        if !(0.0..=1.0).contains(&value) {
+            return Err(invalid());
+        }
+
+        Ok(Fraction(value))
+    }
+}
+
+/// Cuts the payload of packets to its first `bytes`, with `probability`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Truncation {
+    pub probability: f64,
+    pub bytes: usize,
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.probability, self.bytes)
+    }
+}
+
+/// Parses `PROBABILITY:BYTES`, the output of `Display`
+impl FromStr for Truncation {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Truncation, ProfileError> {
+        let invalid = || ProfileError::InvalidTruncationFormat(s.to_owned());
+        let (probability, bytes) = s.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Truncation {
+            probability: probability.parse().map_err(|_| invalid())?,
+            bytes: bytes.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Correlates every sample with the previous result, as netem does with its
+/// random numbers: each one is `(1 - ρ)·sample + ρ·previous`
+#[derive(Clone, Copy, Debug)]
+pub struct Correlation {
+    rho: f64,
+    last: Option<f64>,
+}
+
+impl Correlation {
+    pub fn new(rho: f64) -> Correlation {
+        Correlation { rho, last: None }
+    }
+
+    pub fn next(&mut self, sample: f64) -> f64 {
+        let value = match self.last {
+            Some(last) => (1.0 - self.rho) * sample + self.rho * last,
+            None => sample,
+        };
+        self.last = Some(value);
+        value
+    }
+}
+
+/// Delay of a packet, in milliseconds
+#[derive(Clone, Copy, Debug)]
+pub enum DelayDistribution {
+    Uniform(Uniform<u64>),
+    Exponential { min: u64, extra: Exp<f64> },
+    Normal { min: u64, extra: Normal<f64> },
+    Pareto { min: u64, extra: Pareto<f64> },
+}
+
+impl DelayDistribution {
+    /// Adds a sample of the random part to the minimum delay. Negative
+    /// samples saturate to zero.
+    fn delay(min: u64, extra: f64) -> u64 {
+        min.saturating_add(extra.round() as u64)
+            .min(min.max(MAX_SAMPLED_DELAY))
+    }
+}
+
+impl Distribution<u64> for DelayDistribution {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+        match *self {
+            DelayDistribution::Uniform(uniform) => uniform.sample(rng),
+            DelayDistribution::Exponential { min, extra } => {
+                DelayDistribution::delay(min, extra.sample(rng))
+            }
+            DelayDistribution::Normal { min, extra } => {
+                DelayDistribution::delay(min, extra.sample(rng))
+            }
+            DelayDistribution::Pareto { min, extra } => {
+                DelayDistribution::delay(min, extra.sample(rng))
+            }
+        }
+    }
+}
+
+/// The impairments applied to every packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    drop: f64,
+    min_delay: u64,
+    rand_delay: u64,
+    duplicate: f64,
+    corrupt: f64,
+    reorder: f64,
+    truncation: Option<Truncation>,
+    delay_correlation: f64,
+    drop_correlation: f64,
+    delay_model: DelayModel,
+}
+
+impl Profile {
+    /// Drops packets with probability `drop` and delays the rest between
+    /// `min_delay` and `min_delay + rand_delay` milliseconds
+    pub fn new(drop: f64, min_delay: u64, rand_delay: u64) -> Result<Profile, ProfileError> {
+        if !(0.0..=1.0).contains(&drop) {
+            return Err(ProfileError::InvalidDrop(drop));
+        }
+        if min_delay.checked_add(rand_delay).is_none() {
+            return Err(ProfileError::DelayOverflow(min_delay, rand_delay));
+        }
+
+        Ok(Profile {
+            drop,
+            min_delay,
+            rand_delay,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            reorder: 0.0,
+            truncation: None,
+            delay_correlation: 0.0,
+            drop_correlation: 0.0,
+            delay_model: DelayModel::Uniform,
+        })
+    }
+
+    /// Also sends a second copy of the packets not dropped, with an
+    /// independent delay, with probability `duplicate`
+    pub fn with_duplicate(self, duplicate: f64) -> Result<Profile, ProfileError> {
+        if !(0.0..=1.0).contains(&duplicate) {
+            return Err(ProfileError::InvalidDuplicate(duplicate));
+        }
+
+        Ok(Profile { duplicate, ..self })
+    }
+
+    /// Also flips random bits of the payload of the packets sent, with
+    /// probability `corrupt`
+    pub fn with_corrupt(self, corrupt: f64) -> Result<Profile, ProfileError> {
+        if !(0.0..=1.0).contains(&corrupt) {
+            return Err(ProfileError::InvalidCorrupt(corrupt));
+        }
+
+        Ok(Profile { corrupt, ..self })
+    }
+
+    /// Also holds back packets, with probability `reorder`, until another
+    /// one to the same destination leaves, so they always arrive out of order
+    pub fn with_reorder(self, reorder: f64) -> Result<Profile, ProfileError> {
+        if !(0.0..=1.0).contains(&reorder) {
+            return Err(ProfileError::InvalidReorder(reorder));
+        }
+
+        Ok(Profile { reorder, ..self })
+    }
+
+    /// Also cuts the payload of the packets sent as `truncation` says, if
+    /// any
+    pub fn with_truncation(self, truncation: Option<Truncation>) -> Result<Profile, ProfileError> {
+        if let Some(Truncation { probability, .. }) = truncation {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(ProfileError::InvalidTruncation(probability));
+            }
+        }
+
+        Ok(Profile { truncation, ..self })
+    }
+
+    /// Correlates every delay with the previous one, by `correlation`
+    pub fn with_delay_correlation(self, correlation: f64) -> Result<Profile, ProfileError> {
+        if !(0.0..=1.0).contains(&correlation) {
+            return Err(ProfileError::InvalidDelayCorrelation(correlation));
+        }
+
+        Ok(Profile {
+            delay_correlation: correlation,
+            ..self
+        })
+    }
+
+    /// Repeats the previous drop decision with probability `correlation`
+    /// instead of drawing a new one, so losses come in bursts while the drop
+    /// rate stays the same
+    pub fn with_drop_correlation(self, correlation: f64) -> Result<Profile, ProfileError> {
+        if !(0.0..=1.0).contains(&correlation) {
+            return Err(ProfileError::InvalidDropCorrelation(correlation));
+        }
+
+        Ok(Profile {
+            drop_correlation: correlation,
+            ..self
+        })
+    }
+
+    /// Draws the delay over the minimum one from `delay_model` instead of
+    /// uniformly up to the delay randomness, which must then be zero
+    pub fn with_delay_model(self, delay_model: DelayModel) -> Result<Profile, ProfileError> {
+        if delay_model != DelayModel::Uniform && self.rand_delay > 0 {
+            return Err(ProfileError::RandomDelayWithModel(
+                self.rand_delay,
+                delay_model.name(),
+            ));
+        }
+        if let Some(mean) = delay_model.mean() {
+            if !(mean.is_finite() && mean > 0.0) {
+                return Err(ProfileError::InvalidMean(mean));
+            }
+        }
+        if let Some(stddev) = delay_model.stddev() {
+            if !(stddev.is_finite() && stddev >= 0.0) {
+                return Err(ProfileError::InvalidStddev(stddev));
+            }
+        }
+        if let Some(shape) = delay_model.shape() {
+            if !(shape.is_finite() && shape > 1.0) {
+                return Err(ProfileError::InvalidShape(shape));
+            }
+        }
+
+        Ok(Profile {
+            delay_model,
+            ..self
+        })
+    }
+
+    pub fn drop(&self) -> f64 {
+        self.drop
+    }
+
+    pub fn min_delay(&self) -> u64 {
+        self.min_delay
+    }
+
+    pub fn rand_delay(&self) -> u64 {
+        self.rand_delay
+    }
+
+    pub fn duplicate(&self) -> f64 {
+        self.duplicate
+    }
+
+    pub fn corrupt(&self) -> f64 {
+        self.corrupt
+    }
+
+    pub fn reorder(&self) -> f64 {
+        self.reorder
+    }
+
+    pub fn truncation(&self) -> Option<Truncation> {
+        self.truncation
+    }
+
+    pub fn delay_correlation(&self) -> f64 {
+        self.delay_correlation
+    }
+
+    pub fn drop_correlation(&self) -> f64 {
+        self.drop_correlation
+    }
+
+    pub fn delay_model(&self) -> DelayModel {
+        self.delay_model
+    }
+
+    /// The closest `tc` command applying these impairments with netem on the
+    /// egress of `dev`. Netem spreads its delay uniformly around the mean by
+    /// default, as the router does, and reorders packets just the same.
+    /// Fails for delays too long to write in microseconds.
+    pub fn to_netem(&self, dev: &str) -> Result<String, ProfileError> {
+        let mut command = format!("tc qdisc add dev {dev} root netem");
+
+        let min_delay = self
+            .min_delay
+            .checked_mul(1000)
+            .ok_or(ProfileError::NetemOverflow)?;
+        let plus = |micros: u64| {
+            min_delay
+                .checked_add(micros)
+                .ok_or(ProfileError::NetemOverflow)
+        };
+
+        // Halving odd delays leaves half milliseconds
+        let time = |micros: u64| match micros % 1000 {
+            0 => format!("{}ms", micros / 1000),
+            _ => format!("{micros}us"),
+        };
+        // Follows the jitter, when there is one
+        let correlation = if self.delay_correlation > 0.0 {
+            format!(" {}%", self.delay_correlation * 100.0)
+        } else {
+            String::new()
+        };
+        if let DelayModel::Normal { mean, stddev } = self.delay_model {
+            let (mean, stddev) = ((mean * 1000.0) as u64, (stddev * 1000.0) as u64);
+            command += &format!(
+                " delay {} {}{} distribution normal",
+                time(plus(mean)?),
+                time(stddev),
+                correlation
+            );
+        } else if let DelayModel::Pareto { mean, .. } = self.delay_model {
+            // Netem uses its own shape. Keep the mean and a spread as large.
+            let mean = (mean * 1000.0) as u64;
+            command += &format!(
+                " delay {} {}{} distribution pareto",
+                time(plus(mean)?),
+                time(mean),
+                correlation
+            );
+        } else if let Some(mean) = self.delay_model.mean() {
+            // Netem has no exponential table. Keep the mean and the spread.
+            let mean = (mean * 1000.0) as u64;
+            command += &format!(" delay {} {}{}", time(plus(mean)?), time(mean), correlation);
+        } else if self.min_delay + self.rand_delay > 0 {
+            let spread = self
+                .rand_delay
+                .checked_mul(500)
+                .ok_or(ProfileError::NetemOverflow)?;
+            command += &format!(" delay {}", time(plus(spread)?));
+            if self.rand_delay > 0 {
+                command += &format!(" {}{}", time(spread), correlation);
+            }
+        }
+        if self.drop > 0.0 {
+            command += &format!(" loss {}%", self.drop * 100.0);
+            if self.drop_correlation > 0.0 {
+                // Netem correlates the random numbers instead, lowering the loss rate
+                command += &format!(" {}%", self.drop_correlation * 100.0);
+            }
+        }
+        if self.duplicate > 0.0 {
+            command += &format!(" duplicate {}%", self.duplicate * 100.0);
+        }
+        if self.corrupt > 0.0 {
+            command += &format!(" corrupt {}%", self.corrupt * 100.0);
+        }
+        if self.reorder > 0.0 {
+            // Netem sends those packets right away instead, so they overtake the earlier ones
+            command += &format!(" reorder {}%", self.reorder * 100.0);
+        }
+
+        Ok(command)
+    }
+
+    pub fn drop_distribution(&self) -> Bernoulli {
+        Bernoulli::new(self.drop).unwrap() // Checked on creation
+    }
+
+    pub fn duplicate_distribution(&self) -> Bernoulli {
+        Bernoulli::new(self.duplicate).unwrap() // Checked on creation
+    }
+
+    pub fn corrupt_distribution(&self) -> Bernoulli {
+        Bernoulli::new(self.corrupt).unwrap() // Checked on creation
+    }
+
+    pub fn reorder_distribution(&self) -> Bernoulli {
+        Bernoulli::new(self.reorder).unwrap() // Checked on creation
+    }
+
+    pub fn truncate_distribution(&self) -> Bernoulli {
+        let probability = self.truncation.map_or(0.0, |t| t.probability);
+        Bernoulli::new(probability).unwrap() // Checked on creation
+    }
+
+    /// Delay in milliseconds
+    pub fn delay_distribution(&self) -> DelayDistribution {
+        match self.delay_model {
+            DelayModel::Uniform => DelayDistribution::Uniform(Uniform::new_inclusive(
+                self.min_delay,
+                self.min_delay + self.rand_delay,
+            )),
+            // The parameters are checked on creation
+            DelayModel::Exponential { mean } => DelayDistribution::Exponential {
+                min: self.min_delay,
+                extra: Exp::new(1.0 / mean).unwrap(),
+            },
+            DelayModel::Normal { mean, stddev } => DelayDistribution::Normal {
+                min: self.min_delay,
+                extra: Normal::new(mean, stddev).unwrap(),
+            },
+            // The mean of a Pareto distribution is scale * shape / (shape - 1)
+            DelayModel::Pareto { mean, shape } => DelayDistribution::Pareto {
+                min: self.min_delay,
+                extra: Pareto::new(mean * (shape - 1.0) / shape, shape).unwrap(),
+            },
+        }
+    }
+
+    /// Applies the `key=value` pairs of `settings`, in the format `FromStr`
+    /// accepts, keeping the values they do not mention
+    pub fn with_settings(&self, settings: &str) -> Result<Profile, ProfileError> {
+        let (mut drop, mut min_delay, mut rand_delay, mut duplicate, mut corrupt) = (
+            self.drop,
+            self.min_delay,
+            self.rand_delay,
+            self.duplicate,
+            self.corrupt,
+        );
+        let (mut reorder, mut truncation, mut delay_correlation, mut drop_correlation) = (
+            self.reorder,
+            self.truncation,
+            self.delay_correlation,
+            self.drop_correlation,
+        );
+        let (mut model, mut mean, mut stddev, mut shape) = (
+            self.delay_model.name(),
+            self.delay_model.mean(),
+            self.delay_model.stddev(),
+            self.delay_model.shape(),
+        );
+
+        for setting in settings
+            .split([' ', ','])
+            .filter(|setting| !setting.is_empty())
+        {
+            let invalid = || ProfileError::InvalidSetting(setting.to_owned());
+            match setting.split_once('=').ok_or_else(invalid)? {
+                ("drop", value) => drop = value.parse().map_err(|_| invalid())?,
+                ("min_delay", value) => min_delay = value.parse().map_err(|_| invalid())?,
+                ("rand_delay", value) => rand_delay = value.parse().map_err(|_| invalid())?,
+                ("dup", value) => duplicate = value.parse().map_err(|_| invalid())?,
+                ("corrupt", value) => corrupt = value.parse().map_err(|_| invalid())?,
+                ("reorder", value) => reorder = value.parse().map_err(|_| invalid())?,
+                ("truncate", value) => truncation = Some(value.parse()?),
+                ("delay_corr", value) => {
+                    delay_correlation = value.parse::<Fraction>().map_err(|_| invalid())?.0
+                }
+                ("drop_corr", value) => {
+                    drop_correlation = value.parse::<Fraction>().map_err(|_| invalid())?.0
+                }
+                ("delay_dist", value) => model = value,
+                ("mean", value) => mean = Some(value.parse().map_err(|_| invalid())?),
+                ("stddev", value) => stddev = Some(value.parse().map_err(|_| invalid())?),
+                ("shape", value) => shape = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+
+        let delay_model = match model {
+            "uniform" => DelayModel::Uniform,
+            "exponential" => DelayModel::Exponential {
+                mean: mean.unwrap_or_default(),
+            },
+            "normal" => DelayModel::Normal {
+                mean: mean.unwrap_or_default(),
+                stddev: stddev.unwrap_or_default(),
+            },
+            "pareto" => DelayModel::Pareto {
+                mean: mean.unwrap_or_default(),
+                shape: shape.unwrap_or_default(),
+            },
+            _ => return Err(ProfileError::UnknownDelayModel(model.to_owned())),
+        };
+
+        Profile::new(drop, min_delay, rand_delay)?
+            .with_duplicate(duplicate)?
+            .with_corrupt(corrupt)?
+            .with_reorder(reorder)?
+            .with_truncation(truncation)?
+            .with_delay_correlation(delay_correlation)?
+            .with_drop_correlation(drop_correlation)?
+            .with_delay_model(delay_model)
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop={} min_delay={} rand_delay={}",
+            self.drop, self.min_delay, self.rand_delay
+        )?;
+        if self.duplicate > 0.0 {
+            write!(f, " dup={}", self.duplicate)?;
+        }
+        if self.corrupt > 0.0 {
+            write!(f, " corrupt={}", self.corrupt)?;
+        }
+        if self.reorder > 0.0 {
+            write!(f, " reorder={}", self.reorder)?;
+        }
+        if let Some(truncation) = self.truncation {
+            write!(f, " truncate={truncation}")?;
+        }
+        if self.delay_correlation > 0.0 {
+            write!(f, " delay_corr={}", self.delay_correlation)?;
+        }
+        if self.drop_correlation > 0.0 {
+            write!(f, " drop_corr={}", self.drop_correlation)?;
+        }
+        if let Some(mean) = self.delay_model.mean() {
+            write!(f, " delay_dist={} mean={}", self.delay_model.name(), mean)?;
+        }
+        if let Some(stddev) = self.delay_model.stddev() {
+            write!(f, " stddev={stddev}")?;
+        }
+        if let Some(shape) = self.delay_model.shape() {
+            write!(f, " shape={shape}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses the output of `Display`: `key=value` pairs separated by spaces or
+/// commas. Missing values default to zero.
+impl FromStr for Profile {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Profile, ProfileError> {
+        Profile::new(0.0, 0, 0)?.with_settings(s)
+    }
+}
+
+/// Impairments of the packets from the sources in a network, instead of
+/// those of the shared profile
+#[derive(Debug, Clone)]
+pub struct SourceProfile {
+    pub net: IpNet,
+    pub profile: Profile,
+}
+
+impl SourceProfile {
+    /// Whether the packets from `src` get these impairments
+    pub fn contains(&self, src: IpAddr) -> bool {
+        self.net.contains(&src.to_canonical())
+    }
+}
+
+impl fmt::Display for SourceProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.net, self.profile)
+    }
+}
+
+/// The active profile, shared among the traffic processing threads.
+///
+/// Readers keep a copy and only take the lock again when [`version`]
+/// changes.
+///
+/// [`version`]: SharedProfile::version
+pub struct SharedProfile {
+    version: AtomicU64,
+    profile: RwLock<Profile>,
+}
+
+impl SharedProfile {
+    pub fn new(profile: Profile) -> SharedProfile {
+        SharedProfile {
+            version: AtomicU64::new(0),
+            profile: RwLock::new(profile),
+        }
+    }
+
+    /// Increases every time the profile changes
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// The active profile and its version
+    pub fn load(&self) -> (Profile, u64) {
+        let profile = self.profile.read().unwrap();
+        (profile.clone(), self.version())
+    }
+
+    pub fn set(&self, profile: Profile) {
+        let mut active = self.profile.write().unwrap();
+        *active = profile;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
@@ -17,29 +17,181 @@
 
 use crate::packet::Packet;
 
-use std::collections::binary_heap;
+use serde::{Deserialize, Serialize};
+use std::collections::{binary_heap, HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Instant;
+use thiserror::Error;
 
+#[derive(Error, Debug)]
+#[error(
+    "invalid queue limit {0:?}. Expected a number of packets, or of bytes followed by B, K or M"
+)]
+pub struct QueueLimitError(String);
+
+/// Largest queue accepted
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueLimit {
+    Packets(usize),
+    Bytes(usize),
+}
+
+impl QueueLimit {
+    /// Whether a packet of `len` bytes does not fit in `queue`
+    pub fn exceeded(&self, queue: &Queue, len: usize) -> bool {
+        match *self {
+            QueueLimit::Packets(limit) => queue.len() >= limit,
+            QueueLimit::Bytes(limit) => queue.bytes() + len > limit,
+        }
+    }
+}
+
+/// Parses a number of packets, optionally followed by `p`, or of bytes,
+/// followed by `B`, `K` (KiB) or `M` (MiB)
+impl FromStr for QueueLimit {
+    type Err = QueueLimitError;
+
+    fn from_str(s: &str) -> Result<QueueLimit, QueueLimitError> {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let value: usize = s[..digits]
+            .parse()
+            .map_err(|_| QueueLimitError(s.to_owned()))?;
+
+        let bytes = |unit: usize| {
+            value
+                .checked_mul(unit)
+                .map(QueueLimit::Bytes)
+                .ok_or_else(|| QueueLimitError(s.to_owned()))
+        };
+        match &s[digits..] {
+            "" | "p" => Ok(QueueLimit::Packets(value)),
+            "B" => bytes(1),
+            "K" | "KiB" => bytes(1 << 10),
+            "M" | "MiB" => bytes(1 << 20),
+            _ => Err(QueueLimitError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for QueueLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueLimit::Packets(packets) => write!(f, "{packets} packets"),
+            QueueLimit::Bytes(bytes) => write!(f, "{bytes} bytes"),
+        }
+    }
+}
+
+/// Packets waiting to leave, kept apart for every destination so those due
+/// can be served in turns and a busy destination does not starve the rest
 #[derive(Default)]
 pub struct Queue {
-    queue: binary_heap::BinaryHeap<Packet>,
+    queues: HashMap<SocketAddr, binary_heap::BinaryHeap<Packet>>,
+    /// Destinations with queued packets, in the order they will be served
+    turns: VecDeque<SocketAddr>,
+    bytes_by_sender: HashMap<IpAddr, usize>,
+    bytes: usize,
+    len: usize,
 }
 
 impl Queue {
     pub fn new() -> Queue {
         Queue {
-            queue: binary_heap::BinaryHeap::new(),
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            bytes_by_sender: HashMap::new(),
+            bytes: 0,
+            len: 0,
         }
     }
 
+    fn head(&self, turn: usize) -> Option<&Packet> {
+        self.queues[&self.turns[turn]].peek()
+    }
+
+    /// The packet leaving first
     pub fn peek(&self) -> Option<&Packet> {
-        self.queue.peek()
+        (0..self.turns.len())
+            .filter_map(|turn| self.head(turn))
+            .max()
     }
 
     pub fn pop(&mut self) -> Option<Packet> {
-        self.queue.pop()
+        let turn = (0..self.turns.len()).max_by_key(|&turn| self.head(turn))?;
+        self.take(turn)
+    }
+
+    fn due_turn(&self, now: Instant) -> Option<usize> {
+        (0..self.turns.len()).find(|&turn| self.head(turn).is_some_and(|p| p.exit_time() <= now))
+    }
+
+    /// The packet already due for the destination whose turn is next
+    pub fn peek_due(&self, now: Instant) -> Option<&Packet> {
+        self.due_turn(now).and_then(|turn| self.head(turn))
+    }
+
+    /// Takes the packet returned by [`Queue::peek_due`], leaving its
+    /// destination for the last turn
+    pub fn pop_due(&mut self, now: Instant) -> Option<Packet> {
+        let turn = self.due_turn(now)?;
+        self.take(turn)
+    }
+
+    fn take(&mut self, turn: usize) -> Option<Packet> {
+        let dst = self.turns.remove(turn)?;
+        let queue = self.queues.get_mut(&dst)?;
+        let packet = queue.pop()?;
+        if queue.is_empty() {
+            self.queues.remove(&dst);
+        } else {
+            self.turns.push_back(dst);
+        }
+
+        self.len -= 1;
+        self.bytes -= packet.get().len();
+        let sender = packet.sender();
+        if let Some(bytes) = self.bytes_by_sender.get_mut(&sender) {
+            *bytes -= packet.get().len();
+            if *bytes == 0 {
+                self.bytes_by_sender.remove(&sender);
+            }
+        }
+
+        Some(packet)
     }
 
     pub fn push(&mut self, packet: Packet) {
-        self.queue.push(packet)
+        *self.bytes_by_sender.entry(packet.sender()).or_default() += packet.get().len();
+        self.bytes += packet.get().len();
+        self.len += 1;
+
+        let dst = packet.dst();
+        let queue = self.queues.entry(dst).or_insert_with(|| {
+            self.turns.push_back(dst);
+            binary_heap::BinaryHeap::new()
+        });
+        queue.push(packet)
+    }
+
+    /// Bytes currently queued from `sender`, the host the packets were
+    /// received from
+    pub fn queued_bytes(&self, sender: IpAddr) -> usize {
+        self.bytes_by_sender.get(&sender).copied().unwrap_or(0)
+    }
+
+    /// Bytes currently queued
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 }
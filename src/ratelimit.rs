@@ -0,0 +1,151 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Token buckets, limiting the rate of a flow of bytes.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Sources limited at most. Packets from newer ones are not limited.
+pub const MAX_SOURCES: usize = 4096;
+
+/// Lets `rate` bytes per second through, in bursts of up to `burst` bytes
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket. `rate` must be positive.
+    pub fn new(rate: f64, burst: usize, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = self.updated.max(now);
+    }
+
+    /// Takes the tokens for `len` bytes, if there are enough. Messages
+    /// larger than the burst get through once the bucket is full, leaving
+    /// it in debt.
+    pub fn take(&mut self, len: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < (len as f64).min(self.burst) {
+            return false;
+        }
+
+        self.tokens -= len as f64;
+        true
+    }
+
+    /// Time until `take` can succeed for `len` bytes
+    pub fn wait(&mut self, len: usize, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = (len as f64).min(self.burst) - self.tokens;
+
+        Duration::from_secs_f64((missing / self.rate).max(0.0))
+    }
+}
+
+/// Buckets and drops of a source address
+struct Source {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    drops: usize,
+}
+
+/// Limits the packets and bytes per second of every source address. It can
+/// be shared among threads, so the limits apply to the router as a whole.
+pub struct SourceLimiter {
+    pps: Option<u64>,
+    bps: Option<u64>,
+    sources: Mutex<HashMap<IpAddr, Source>>,
+}
+
+impl SourceLimiter {
+    /// Lets up to `pps` packets and `bps` bytes per second through from
+    /// every source, in bursts of a second worth of them
+    pub fn new(pps: Option<u64>, bps: Option<u64>) -> SourceLimiter {
+        SourceLimiter {
+            pps,
+            bps,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a `len` bytes packet from `src` is within its limits, in which
+    /// case it is accounted. Otherwise it is counted as dropped.
+    pub fn admit(&self, src: IpAddr, len: usize, now: Instant) -> bool {
+        let mut sources = self.sources.lock().unwrap();
+        if sources.len() >= MAX_SOURCES && !sources.contains_key(&src) {
+            return true;
+        }
+
+        let bucket = |rate: u64| TokenBucket::new(rate as f64, rate as usize, now);
+        let source = sources.entry(src).or_insert_with(|| Source {
+            packets: self.pps.map(bucket),
+            bytes: self.bps.map(bucket),
+            drops: 0,
+        });
+        let within = source
+            .packets
+            .as_mut()
+            .is_none_or(|b| b.wait(1, now).is_zero())
+            && source
+                .bytes
+                .as_mut()
+                .is_none_or(|b| b.wait(len, now).is_zero());
+        if !within {
+            source.drops += 1;
+            return false;
+        }
+
+        if let Some(bucket) = &mut source.packets {
+            bucket.take(1, now);
+        }
+        if let Some(bucket) = &mut source.bytes {
+            bucket.take(len, now);
+        }
+        true
+    }
+
+    /// Packets dropped from every source that exceeded its limits, sorted
+    /// by source
+    pub fn drops(&self) -> Vec<(IpAddr, usize)> {
+        let mut drops: Vec<_> = self
+            .sources
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, source)| source.drops > 0)
+            .map(|(&src, source)| (src, source.drops))
+            .collect();
+        drops.sort();
+        drops
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Session recordings: every arrival with the decision taken for it.
+//!
+//! A recording is a text file with a header line followed by one line per
+//! received datagram: arrival offset in microseconds, source address,
+//! decision (`drop` or `delay:<microseconds>`) and the datagram as received,
+//! in hexadecimal.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const HEADER: &str = "# shufflerouter session v1";
+
+#[derive(Error, Debug)]
+pub enum RecordError {
+    #[error("could not access the recording: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a session recording")]
+    BadHeader,
+    #[error("malformed recording line {0}")]
+    BadLine(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Drop,
+    Delay(Duration),
+}
+
+/// A received datagram and its fate
+pub struct SessionEvent {
+    /// Arrival time, since the start of the session
+    pub offset: Duration,
+    pub src: SocketAddr,
+    pub decision: Decision,
+    /// The datagram as received, header included
+    pub data: Vec<u8>,
+}
+
+/// Writes a session recording. It can be shared among threads.
+pub struct SessionRecorder {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path, start: Instant) -> Result<SessionRecorder, RecordError> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{HEADER}")?;
+
+        Ok(SessionRecorder {
+            start,
+            out: Mutex::new(out),
+        })
+    }
+
+    pub fn record(
+        &self,
+        arrival: Instant,
+        src: SocketAddr,
+        data: &[u8],
+        decision: Decision,
+    ) -> io::Result<()> {
+        let mut line = format!(
+            "{} {} ",
+            arrival.saturating_duration_since(self.start).as_micros(),
+            src
+        );
+        match decision {
+            Decision::Drop => line.push_str("drop "),
+            Decision::Delay(delay) => line.push_str(&format!("delay:{} ", delay.as_micros())),
+        }
+        for byte in data {
+            line.push_str(&format!("{byte:02x}"));
+        }
+
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{line}")
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_event(line: &str) -> Option<SessionEvent> {
+    let mut fields = line.split(' ');
+
+    let offset = Duration::from_micros(fields.next()?.parse().ok()?);
+    let src = fields.next()?.parse().ok()?;
+    let decision = match fields.next()? {
+        "drop" => Decision::Drop,
+        delay => Decision::Delay(Duration::from_micros(
+            delay.strip_prefix("delay:")?.parse().ok()?,
+        )),
+    };
+    let data = parse_hex(fields.next().unwrap_or(""))?;
+
+    Some(SessionEvent {
+        offset,
+        src,
+        decision,
+        data,
+    })
+}
+
+/// Reads a whole session recording
+pub fn read_session(path: &Path) -> Result<Vec<SessionEvent>, RecordError> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    if lines.next().transpose()?.as_deref() != Some(HEADER) {
+        return Err(RecordError::BadHeader);
+    }
+
+    lines
+        .enumerate()
+        .map(|(n, line)| parse_event(&line?).ok_or(RecordError::BadLine(n + 2)))
+        .collect()
+}
@@ -0,0 +1,84 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! One line reports of the traffic every interval, like those of iperf, for
+//! watching a test without waiting for the summary on exit.
+
+use crate::stats::Stats;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Totals at the end of the latest interval reported
+struct Totals {
+    at: Instant,
+    received: usize,
+    sent: usize,
+    dropped: usize,
+}
+
+/// Prints the traffic of every interval. It can be shared among threads, and
+/// only the first one finding a report due prints it.
+pub struct IntervalReport {
+    interval: Duration,
+    start: Instant,
+    last: Mutex<Totals>,
+}
+
+impl IntervalReport {
+    pub fn new(interval: Duration, start: Instant) -> IntervalReport {
+        IntervalReport {
+            interval,
+            start,
+            last: Mutex::new(Totals {
+                at: start,
+                received: 0,
+                sent: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// When the next report is due
+    pub fn next(&self) -> Instant {
+        self.last.lock().unwrap().at + self.interval
+    }
+
+    /// Prints the report of the interval ending `now`, if it is due
+    pub fn report(&self, stats: &Stats, now: Instant) {
+        let mut last = self.last.lock().unwrap();
+        if now < last.at + self.interval {
+            return;
+        }
+
+        let current = Totals {
+            at: now,
+            received: Stats::get(&stats.received),
+            sent: Stats::get(&stats.sent),
+            dropped: stats.dropped(),
+        };
+        let seconds = (current.at - last.at).as_secs_f64();
+        println!(
+            "[{:7.1}-{:7.1} s] {:9.1} pps in {:9.1} pps out {:7} dropped {:7} queued",
+            (last.at - self.start).as_secs_f64(),
+            (current.at - self.start).as_secs_f64(),
+            (current.received - last.received) as f64 / seconds,
+            (current.sent - last.sent) as f64 / seconds,
+            current.dropped - last.dropped,
+            Stats::get(&stats.queued)
+        );
+        *last = current;
+    }
+}
@@ -0,0 +1,2230 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The router itself, for embedding in other programs and test harnesses
+//!
+//! ```no_run
+//! use shufflerouter::profile::DelayModel;
+//! use shufflerouter::queue::QueueLimit;
+//! use shufflerouter::Router;
+//!
+//! let router = Router::builder()
+//!     .port(2021)
+//!     .drop(0.1)
+//!     .delay(20, 30)
+//!     .delay_model(DelayModel::Exponential { mean: 25.0 })
+//!     .queue_limit(QueueLimit::Packets(100))
+//!     .build()?;
+//!
+//! std::thread::scope(|s| {
+//!     s.spawn(|| router.run());
+//!     // Exchange some traffic through the router...
+//!     router.shutdown()
+//! })?;
+//! # Ok::<(), shufflerouter::router::RouterError>(())
+//! ```
+
+use crate::acl::{Acl, Blackholes};
+use crate::aqm::{CoDel, Red, RedParams};
+use crate::buffer::{Buffer, BufferPool, DEFAULT_BUFFER_SIZE, DEFAULT_POOL_SIZE};
+use crate::checker::Checker;
+use crate::clock::{Clock, SystemClock};
+use crate::eventcsv::EventCsv;
+use crate::eventlog::{self, Event};
+use crate::hops::Hop;
+#[cfg(target_os = "linux")]
+use crate::icmp;
+use crate::inband::{self, Status};
+#[cfg(target_os = "linux")]
+use crate::mmsg;
+use crate::nat::Nat;
+use crate::net;
+use crate::ns3::{Ns3Event, Ns3Trace};
+use crate::packet::{self, Header, Packet};
+use crate::pcap::TrafficCapture;
+use crate::pcapng::PacketCapture;
+use crate::profile::{
+    Correlation, DelayDistribution, DelayModel, Profile, ProfileError, SharedProfile,
+    SourceProfile, Truncation,
+};
+use crate::queue::{Queue, QueueLimit};
+use crate::ratelimit::{SourceLimiter, TokenBucket};
+use crate::record::{Decision, SessionRecorder};
+use crate::report::IntervalReport;
+use crate::schedule::Schedule;
+use crate::stats::{FlowEvent, Stats};
+use crate::stun;
+use crate::trace::ImpairmentTrace;
+use crate::watchdog::Heartbeat;
+
+use log::{debug, info, warn};
+use mio::{Interest, Token};
+use rand::distributions::{Bernoulli, Distribution};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RouterError {
+    #[error("invalid impairments: {0}")]
+    Profile(#[from] ProfileError),
+    #[error("could not set up the router: {0}")]
+    Io(#[from] io::Error),
+    #[error("the router is already running")]
+    AlreadyRunning,
+    #[error("no port to listen on")]
+    NoPorts,
+}
+
+/// Most datagrams read or sent at once
+const BATCH_SIZE: usize = 32;
+
+/// Longest a packet is held back to reorder it when no other one follows
+const MAX_REORDER_HOLD: Duration = Duration::from_secs(1);
+
+/// Time between the departure of the packet that follows a held back one and
+/// that of the latter
+const REORDER_GAP: Duration = Duration::from_micros(1);
+
+/// Maximum number of retransmissions of a packet after transient errors
+const MAX_SEND_RETRIES: u32 = 5;
+/// Initial backoff after a transient error. It doubles on every retry.
+const SEND_BACKOFF: Duration = Duration::from_millis(1);
+
+enum SendError {
+    /// The socket buffer is full. Wait until it is writable again.
+    WouldBlock,
+    Transient,
+    Permanent,
+}
+
+fn classify_send_error(e: &std::io::Error) -> SendError {
+    if e.kind() == std::io::ErrorKind::WouldBlock {
+        return SendError::WouldBlock;
+    }
+
+    match e.raw_os_error() {
+        Some(libc::ENOBUFS) | Some(libc::EINTR) => SendError::Transient,
+        // Asynchronous ICMP error caused by an earlier datagram, not this one
+        Some(libc::ECONNREFUSED) => SendError::Transient,
+        _ => SendError::Permanent, // EACCES, ENETUNREACH, EHOSTUNREACH...
+    }
+}
+
+/// Sends the packets already due, in batches of up to [`BATCH_SIZE`] through
+/// `send`, which takes the index of the socket they leave through and returns
+/// how many of them left. Returns when the rate limit lets the next one go, if
+/// it holds it back.
+fn process_queue(
+    queue: &mut Queue,
+    send: impl Fn(usize, &[(&[u8], SocketAddr)]) -> io::Result<usize>,
+    buffer_pool: &mut BufferPool,
+    stats: &Stats,
+    settings: &Settings,
+    codel: &mut Option<CoDel>,
+) -> Option<Instant> {
+    let csv = settings.events_csv.as_deref();
+    let now = settings.clock.now();
+
+    loop {
+        let mut batch = Vec::new();
+        let mut rate_blocked = None;
+
+        // Destinations take turns, so a busy one cannot hold back the rest
+        while let Some(p) = queue.peek_due(now).filter(|_| batch.len() < BATCH_SIZE) {
+            // A batch leaves through a single socket
+            if batch
+                .first()
+                .is_some_and(|first: &Packet| first.socket() != p.socket())
+            {
+                break;
+            }
+
+            // Only the time waiting past the planned exit counts as queueing
+            let sojourn = now - p.exit_time();
+            if codel
+                .as_mut()
+                .is_some_and(|codel| codel.drop(sojourn, queue.bytes(), now))
+            {
+                debug!(
+                    "CoDel dropped a packet to {} after waiting for {} ms",
+                    p.dst(),
+                    sojourn.as_millis()
+                );
+                trace_event(
+                    settings,
+                    Ns3Event::Drop,
+                    p.src(),
+                    Some(p.dst()),
+                    p.get().len(),
+                );
+                eventlog::emit(&Event::Drop {
+                    src: p.src(),
+                    dst: Some(p.dst()),
+                    len: p.get().len(),
+                    reason: "codel",
+                });
+                csv_dropped(
+                    csv,
+                    p.arrival(),
+                    p.src(),
+                    Some(p.dst()),
+                    p.get().len(),
+                    "codel",
+                );
+                stats.count_flow(p.src(), p.dst(), FlowEvent::Dropped);
+                Stats::add(&stats.codel_drops, 1);
+                buffer_pool.recycle_buffer(queue.pop_due(now).unwrap().into());
+                continue;
+            }
+
+            if let Some(bucket) = &settings.rate_limit {
+                let mut bucket = bucket.lock().unwrap();
+                if !bucket.take(p.get().len(), now) {
+                    if settings.rate_drop {
+                        debug!("Rate exceeded. Packet to {} dropped", p.dst());
+                        trace_event(
+                            settings,
+                            Ns3Event::Drop,
+                            p.src(),
+                            Some(p.dst()),
+                            p.get().len(),
+                        );
+                        eventlog::emit(&Event::Drop {
+                            src: p.src(),
+                            dst: Some(p.dst()),
+                            len: p.get().len(),
+                            reason: "rate",
+                        });
+                        csv_dropped(
+                            csv,
+                            p.arrival(),
+                            p.src(),
+                            Some(p.dst()),
+                            p.get().len(),
+                            "rate",
+                        );
+                        stats.count_flow(p.src(), p.dst(), FlowEvent::Dropped);
+                        Stats::add(&stats.rate_drops, 1);
+                        buffer_pool.recycle_buffer(queue.pop_due(now).unwrap().into());
+                        continue;
+                    }
+
+                    // Nothing else can leave before the bucket refills
+                    Stats::add(&stats.rate_delays, 1);
+                    rate_blocked = Some(now + bucket.wait(p.get().len(), now));
+                    break;
+                }
+            }
+
+            batch.push(queue.pop_due(now).unwrap());
+        }
+        if batch.is_empty() {
+            return rate_blocked;
+        }
+
+        let datagrams: Vec<_> = batch
+            .iter()
+            .map(|p| (&p.get()[..], net::for_socket(p.dst(), settings.dual_stack)))
+            .collect();
+        let result = send(batch[0].socket(), &datagrams);
+        let mut unsent = batch.split_off(*result.as_ref().unwrap_or(&0));
+
+        for p in batch {
+            let len = p.get().len();
+            if let Some(pcap) = &settings.pcap {
+                if let Err(e) = pcap.sent(p.dst(), p.get()) {
+                    warn!("Could not write the pcap capture: {}", e);
+                }
+            }
+            debug!("Sent {} bytes to {}", len, p.dst());
+            stats.count_flow(p.src(), p.dst(), FlowEvent::Sent);
+            stats
+                .latency
+                .count(now.saturating_duration_since(p.arrival()));
+            eventlog::emit(&Event::Send {
+                src: p.src(),
+                dst: p.dst(),
+                len,
+            });
+            trace_event(settings, Ns3Event::Dequeue, p.src(), Some(p.dst()), len);
+            if let Some(csv) = csv {
+                if let Err(e) = csv.forwarded(&p, now) {
+                    warn!("Could not write the CSV export: {}", e);
+                }
+            }
+            Stats::add(&stats.sent, 1);
+            Stats::add(&stats.bytes_sent, len);
+            buffer_pool.recycle_buffer(p.into());
+        }
+
+        let mut congested = false;
+        if let Err(e) = result {
+            // The first packet of the batch caused the error
+            let mut packet = unsent.remove(0);
+            match classify_send_error(&e) {
+                SendError::WouldBlock => {
+                    // Not a failed attempt: it leaves as soon as the socket is writable
+                    queue.push(packet);
+                    congested = true;
+                }
+                SendError::Transient if packet.attempts() < MAX_SEND_RETRIES => {
+                    let backoff = SEND_BACKOFF * 2u32.pow(packet.attempts());
+                    debug!(
+                        "Transient error transmitting to {}: {}. Retrying in {} ms",
+                        packet.dst(),
+                        e,
+                        backoff.as_millis()
+                    );
+                    packet.postpone(now, backoff);
+                    queue.push(packet);
+                    Stats::add(&stats.send_retries, 1);
+
+                    congested = true; // Do not insist right now
+                }
+                _ => {
+                    warn!(
+                        "Error transmitting {} bytes to {}: {}. Packet dropped",
+                        packet.get().len(),
+                        packet.dst(),
+                        e
+                    );
+                    trace_event(
+                        settings,
+                        Ns3Event::Drop,
+                        packet.src(),
+                        Some(packet.dst()),
+                        packet.get().len(),
+                    );
+                    stats.count_flow(packet.src(), packet.dst(), FlowEvent::Dropped);
+                    eventlog::emit(&Event::Drop {
+                        src: packet.src(),
+                        dst: Some(packet.dst()),
+                        len: packet.get().len(),
+                        reason: "send error",
+                    });
+                    csv_dropped(
+                        csv,
+                        packet.arrival(),
+                        packet.src(),
+                        Some(packet.dst()),
+                        packet.get().len(),
+                        "send error",
+                    );
+
+                    buffer_pool.recycle_buffer(packet.into());
+                    Stats::add(&stats.send_errors, 1);
+                }
+            }
+        }
+        // Those after a failure are tried again
+        for packet in unsent {
+            queue.push(packet);
+        }
+
+        if congested {
+            return None;
+        }
+        if rate_blocked.is_some() {
+            return rate_blocked;
+        }
+    }
+}
+
+/// Writes an event to the ns-3 trace, if there is one
+fn trace_event(
+    settings: &Settings,
+    event: Ns3Event,
+    src: SocketAddr,
+    dst: Option<SocketAddr>,
+    len: usize,
+) {
+    if let Some(trace) = &settings.ns3_trace {
+        if let Err(e) = trace.event(event, settings.clock.now(), src, dst, len) {
+            warn!("Could not write the ns-3 trace: {}", e);
+        }
+    }
+}
+
+/// Writes the row of a dropped packet to the CSV export, if there is one
+fn csv_dropped(
+    csv: Option<&EventCsv>,
+    arrival: Instant,
+    src: SocketAddr,
+    dst: Option<SocketAddr>,
+    len: usize,
+    reason: &str,
+) {
+    if let Some(csv) = csv {
+        if let Err(e) = csv.dropped(arrival, src, dst, len, reason) {
+            warn!("Could not write the CSV export: {}", e);
+        }
+    }
+}
+
+/// Reads the ICMP errors queued for the socket, optionally telling the
+/// original senders that their destination is unreachable
+#[cfg(target_os = "linux")]
+fn process_errors(
+    socket: &mio::net::UdpSocket,
+    buffer_pool: &mut BufferPool,
+    settings: &Settings,
+    stats: &Stats,
+) {
+    let mut buffer = buffer_pool.get_buffer();
+
+    loop {
+        match icmp::recv_error(socket, &mut buffer) {
+            Ok(err) if err.is_port_unreachable() => {
+                warn!("Destination {} is unreachable", err.dst);
+                Stats::add(&stats.unreachable, 1);
+
+                if !settings.notify_unreachable {
+                    continue;
+                }
+                // The failed datagram starts with the address of its sender
+                if let Ok(sender) = packet::get_dst(&buffer[..err.len]) {
+                    let mut notice = Header::new(err.dst).encode();
+                    notice.extend_from_slice(icmp::UNREACHABLE_NOTICE);
+
+                    match socket.send_to(&notice, net::for_socket(sender, settings.dual_stack)) {
+                        Ok(_) => debug!("Notified {} that {} is unreachable", sender, err.dst),
+                        Err(e) => warn!("Could not notify {}: {}", sender, e),
+                    }
+                }
+            }
+            Ok(err) => debug!("Ignoring transmission error for {}", err.dst),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Error while reading the socket error queue: {}", e);
+                break;
+            }
+        }
+    }
+
+    buffer_pool.recycle_buffer(buffer);
+}
+
+const WAKER: Token = Token(0);
+/// That of the first socket. The rest follow.
+const FIRST_SOCKET: Token = Token(1);
+
+/// Configuration shared by every traffic processing thread
+#[derive(Clone)]
+pub struct Settings {
+    pub started: Instant,
+    pub profile: Arc<SharedProfile>,
+    pub drain_timeout: Duration,
+    pub client_limit: Option<usize>,
+    pub queue_limit: Option<QueueLimit>,
+    pub red: Option<RedParams>,
+    /// CoDel target and interval
+    pub codel: Option<(Duration, Duration)>,
+    /// Seed of the random decisions of the thread, if they must be repeatable
+    pub seed: Option<u64>,
+    pub strict: bool,
+    pub recorder: Option<Arc<SessionRecorder>>,
+    pub capture: Option<Arc<PacketCapture>>,
+    pub pcap: Option<Arc<TrafficCapture>>,
+    /// Shared by every thread, so the rate applies to the router as a whole
+    pub rate_limit: Option<Arc<Mutex<TokenBucket>>>,
+    /// Drop the packets over the rate instead of delaying them
+    pub rate_drop: bool,
+    /// Largest datagram forwarded, header included
+    pub max_size: Option<usize>,
+    /// Split the datagrams over the maximum size into tagged fragments
+    /// instead of dropping them
+    pub fragment: bool,
+    /// Bits per second of the emulated link, which transmits one packet at
+    /// a time after its delay
+    pub link_rate: Option<f64>,
+    /// Rate limits of every source address, shared by every thread
+    pub source_limit: Option<Arc<SourceLimiter>>,
+    /// Translation table, shared by every thread
+    pub nat: Option<Arc<Nat>>,
+    /// Return every packet to its sender, without looking for a header
+    pub echo: bool,
+    /// Answer status queries from other hosts, not only from this one
+    pub status_queries: bool,
+    /// Emulated links packets go through, in order, after the impairments
+    /// of the router itself
+    pub hops: Vec<Hop>,
+    /// Increased by [`Router::flush_queue`], shared by every thread
+    pub flushes: Arc<AtomicU64>,
+    pub trace: Option<Arc<ImpairmentTrace>>,
+    pub ns3_trace: Option<Arc<Ns3Trace>>,
+    pub events_csv: Option<Arc<EventCsv>>,
+    /// Source of the time packets arrive and leave at
+    pub clock: Arc<dyn Clock>,
+    /// Impairments of the packets from some sources instead of the shared
+    /// profile. The first one matching the source applies.
+    pub source_profiles: Vec<SourceProfile>,
+    pub report: Option<Arc<IntervalReport>>,
+    /// Changes of the shared profile along the execution
+    pub schedule: Option<Arc<Schedule>>,
+    pub checker: Option<Arc<Checker>>,
+    /// Destinations packets may be relayed to
+    pub acl: Acl,
+    /// Destinations packets are dropped to while they are blackholed
+    pub blackholes: Arc<Blackholes>,
+    pub public_address: Option<SocketAddrV4>,
+    /// Whether the socket is an IPv6 one, also serving IPv4 peers
+    pub dual_stack: bool,
+    #[cfg(target_os = "linux")]
+    pub notify_unreachable: bool,
+}
+
+impl Settings {
+    /// Applies `profile`, leaving every other option at its default
+    pub fn new(profile: Profile) -> Settings {
+        Settings::with_clock(profile, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, but taking the time from `clock`, which also sets when
+    /// the router started
+    pub fn with_clock(profile: Profile, clock: Arc<dyn Clock>) -> Settings {
+        Settings {
+            started: clock.now(),
+            profile: Arc::new(SharedProfile::new(profile)),
+            drain_timeout: Duration::from_millis(1000),
+            client_limit: None,
+            queue_limit: None,
+            red: None,
+            codel: None,
+            seed: None,
+            strict: false,
+            recorder: None,
+            capture: None,
+            pcap: None,
+            rate_limit: None,
+            rate_drop: false,
+            max_size: None,
+            fragment: false,
+            link_rate: None,
+            source_limit: None,
+            nat: None,
+            echo: false,
+            status_queries: false,
+            hops: Vec::new(),
+            flushes: Arc::new(AtomicU64::new(0)),
+            trace: None,
+            ns3_trace: None,
+            events_csv: None,
+            clock,
+            source_profiles: Vec::new(),
+            report: None,
+            schedule: None,
+            checker: None,
+            acl: Acl::default(),
+            blackholes: Arc::new(Blackholes::default()),
+            public_address: None,
+            dual_stack: false,
+            #[cfg(target_os = "linux")]
+            notify_unreachable: false,
+        }
+    }
+
+    /// Time since the router started, by its clock
+    pub fn uptime(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started)
+    }
+}
+
+/// Starts a traffic processing thread reading from `sockets`, one for every
+/// port. The returned waker makes it check the shutdown flag and the flushes.
+fn spawn_worker(
+    sockets: Vec<UdpSocket>,
+    settings: Settings,
+    buffer_pool: BufferPool,
+    stats: Arc<Stats>,
+    shutdown: Arc<AtomicBool>,
+    heartbeat: Arc<Heartbeat>,
+) -> io::Result<(thread::JoinHandle<()>, mio::Waker)> {
+    let poll = mio::Poll::new()?;
+    let waker = mio::Waker::new(poll.registry(), WAKER)?;
+
+    let thread = thread::spawn(move || {
+        if let Err(e) = process_traffic(
+            poll,
+            sockets,
+            settings,
+            buffer_pool,
+            stats,
+            shutdown,
+            heartbeat,
+        ) {
+            warn!("Error while processing traffic: {:?}", e);
+        };
+    });
+
+    Ok((thread, waker))
+}
+
+/// State of a virtual hop within a traffic processing thread
+struct HopLink {
+    drop_distribution: Bernoulli,
+    delay_distribution: DelayDistribution,
+    /// When it finishes transmitting the last packet
+    free: Instant,
+}
+
+impl HopLink {
+    fn new(hop: &Hop, now: Instant) -> HopLink {
+        HopLink {
+            drop_distribution: hop.profile.drop_distribution(),
+            delay_distribution: hop.profile.delay_distribution(),
+            free: now,
+        }
+    }
+}
+
+/// Random impairments of a profile, along with the state of their
+/// correlations, within a traffic processing thread
+struct Impairments {
+    profile: Profile,
+    drop_distribution: Bernoulli,
+    /// Previous drop decision, repeated when correlated
+    last_drop: Option<bool>,
+    delay_distribution: DelayDistribution,
+    delay_correlation: Correlation,
+    duplicate_distribution: Bernoulli,
+    corrupt_distribution: Bernoulli,
+    reorder_distribution: Bernoulli,
+    truncate_distribution: Bernoulli,
+}
+
+impl Impairments {
+    fn new(profile: Profile) -> Impairments {
+        Impairments {
+            drop_distribution: profile.drop_distribution(),
+            last_drop: None,
+            delay_distribution: profile.delay_distribution(),
+            delay_correlation: Correlation::new(profile.delay_correlation()),
+            duplicate_distribution: profile.duplicate_distribution(),
+            corrupt_distribution: profile.corrupt_distribution(),
+            reorder_distribution: profile.reorder_distribution(),
+            truncate_distribution: profile.truncate_distribution(),
+            profile,
+        }
+    }
+
+    /// Decides whether to drop a packet. With a drop correlation, the
+    /// previous decision is repeated with that probability, which keeps the
+    /// drop rate while losses come in bursts.
+    fn sample_drop(&mut self, rng: &mut StdRng) -> bool {
+        let correlation = self.profile.drop_correlation();
+        if correlation == 0.0 {
+            return self.drop_distribution.sample(rng);
+        }
+
+        let drop = match self.last_drop {
+            Some(last) if rng.gen::<f64>() < correlation => last,
+            _ => self.drop_distribution.sample(rng),
+        };
+        self.last_drop = Some(drop);
+        drop
+    }
+
+    /// Draws a delay, in milliseconds, correlated with the previous one
+    fn sample_delay(&mut self, rng: &mut StdRng) -> u64 {
+        let delay = self.delay_distribution.sample(rng);
+        self.delay_correlation.next(delay as f64).round() as u64
+    }
+}
+
+/// State of a traffic processing thread, whatever waits for its socket
+struct Worker {
+    settings: Settings,
+    stats: Arc<Stats>,
+    buffer_pool: BufferPool,
+    rng: StdRng,
+    queue: Queue,
+    red: Option<Red>,
+    codel: Option<CoDel>,
+    /// Those of the shared profile
+    impairments: Impairments,
+    profile_version: u64,
+    /// One for each source profile in the settings
+    source_impairments: Vec<Impairments>,
+    /// Packets held back until another one to their destination leaves
+    held: HashMap<SocketAddr, Packet>,
+    /// When the rate limit lets the next packet go, if it holds it back
+    rate_blocked: Option<Instant>,
+    /// When the emulated link finishes transmitting the last packet
+    link_free: Instant,
+    /// One for each virtual hop in the settings
+    hop_links: Vec<HopLink>,
+    /// Id of the fragments of the last packet split
+    fragment_id: u16,
+    /// Flushes of the queue already done
+    flushes: u64,
+    /// Queue length last added to the stats
+    queued: usize,
+    /// When the queue length was last added to the stats
+    gauges_updated: Instant,
+    /// Pool occupancy last added to the stats
+    pooled: usize,
+}
+
+impl Worker {
+    fn new(settings: Settings, buffer_pool: BufferPool, stats: Arc<Stats>) -> Worker {
+        let rng = match settings.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let (profile, profile_version) = settings.profile.load();
+        let flushes = settings.flushes.load(Ordering::Relaxed);
+        let now = settings.clock.now();
+
+        Worker {
+            rng,
+            queue: Queue::new(),
+            red: settings.red.map(Red::new),
+            codel: settings
+                .codel
+                .map(|(target, interval)| CoDel::new(target, interval, now)),
+            held: HashMap::new(),
+            impairments: Impairments::new(profile),
+            profile_version,
+            source_impairments: settings
+                .source_profiles
+                .iter()
+                .map(|source| Impairments::new(source.profile.clone()))
+                .collect(),
+            rate_blocked: None,
+            link_free: now,
+            hop_links: settings
+                .hops
+                .iter()
+                .map(|hop| HopLink::new(hop, now))
+                .collect(),
+            fragment_id: 0,
+            flushes,
+            queued: 0,
+            gauges_updated: now,
+            pooled: 0,
+            settings,
+            stats,
+            buffer_pool,
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.settings.clock.now()
+    }
+
+    /// Picks up any change of the impairments
+    fn refresh_profile(&mut self) {
+        if self.settings.profile.version() != self.profile_version {
+            let profile;
+            (profile, self.profile_version) = self.settings.profile.load();
+            debug!("Impairments changed to {}", profile);
+            self.impairments = Impairments::new(profile);
+        }
+    }
+
+    /// The impairments of the packets from the source profile with index
+    /// `source`, or the shared ones, along with the generator to draw them
+    fn impairments(&mut self, source: Option<usize>) -> (&mut Impairments, &mut StdRng) {
+        let impairments = match source {
+            Some(index) => &mut self.source_impairments[index],
+            None => &mut self.impairments,
+        };
+        (impairments, &mut self.rng)
+    }
+
+    /// The profile of the source profile with index `source`, or the shared one
+    fn profile(&self, source: Option<usize>) -> &Profile {
+        match source {
+            Some(index) => &self.source_impairments[index].profile,
+            None => &self.impairments.profile,
+        }
+    }
+
+    /// Decides whether to drop a packet from the source profile with index
+    /// `source`, if any
+    fn sample_drop(&mut self, source: Option<usize>) -> bool {
+        let (impairments, rng) = self.impairments(source);
+        impairments.sample_drop(rng)
+    }
+
+    /// Draws a delay, in milliseconds, for a packet from the source profile
+    /// with index `source`, if any
+    fn sample_delay(&mut self, source: Option<usize>) -> u64 {
+        let (impairments, rng) = self.impairments(source);
+        impairments.sample_delay(rng)
+    }
+
+    /// Decides whether a packet from the source profile with index `source`,
+    /// if any, suffers the impairment `distribution` picks
+    fn sample(
+        &mut self,
+        source: Option<usize>,
+        distribution: impl Fn(&Impairments) -> &Bernoulli,
+    ) -> bool {
+        let (impairments, rng) = self.impairments(source);
+        distribution(impairments).sample(rng)
+    }
+
+    /// Applies the scheduled impairments and prints the interval report, if
+    /// they are due
+    fn run_timers(&self) {
+        let now = self.now();
+        if let Some(schedule) = &self.settings.schedule {
+            schedule.apply(self.settings.started, now, &self.settings.profile);
+        }
+        if let Some(report) = &self.settings.report {
+            report.report(&self.stats, now);
+        }
+    }
+
+    /// When the next scheduled change or interval report is due
+    fn next_timer(&self) -> Option<Instant> {
+        let schedule = self
+            .settings
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.next(self.settings.started));
+        let report = self.settings.report.as_ref().map(|report| report.next());
+        schedule.into_iter().chain(report).min()
+    }
+
+    /// When the event loop must wake up, for the packet leaving at
+    /// `next_exit` or for the next timer
+    fn next_wakeup(&self, next_exit: Option<Instant>) -> Option<Instant> {
+        next_exit.into_iter().chain(self.next_timer()).min()
+    }
+
+    /// Discards every queued packet if a flush was asked for
+    fn check_flush(&mut self) {
+        let flushes = self.settings.flushes.load(Ordering::Relaxed);
+        if flushes == self.flushes {
+            return;
+        }
+        self.flushes = flushes;
+
+        let discarded = self.discard_queued("flush");
+        let now = self.now();
+        self.link_free = now;
+        for link in &mut self.hop_links {
+            link.free = now;
+        }
+
+        info!("Queue flushed. {} packets discarded.", discarded);
+        Stats::add(&self.stats.flushed, discarded);
+    }
+
+    /// Empties the queue and the held packets, recording `reason` for them
+    /// in the events CSV. Returns how many packets were discarded.
+    fn discard_queued(&mut self, reason: &'static str) -> usize {
+        let mut discarded = 0;
+        let held = self.held.drain().map(|(_, packet)| packet);
+        for packet in std::iter::from_fn(|| self.queue.pop()).chain(held) {
+            csv_dropped(
+                self.settings.events_csv.as_deref(),
+                packet.arrival(),
+                packet.src(),
+                Some(packet.dst()),
+                packet.get().len(),
+                reason,
+            );
+            self.buffer_pool.recycle_buffer(packet.into());
+            discarded += 1;
+        }
+        discarded
+    }
+
+    /// When the next packet can leave. The rate limit can hold back packets
+    /// already due.
+    fn next_exit(&self, now: Instant) -> Option<Instant> {
+        let queued = self
+            .queue
+            .peek()
+            .map(|packet| packet.exit_time().max(self.rate_blocked.unwrap_or(now)));
+        let held = self
+            .held
+            .values()
+            .map(|packet| packet.exit_time() + MAX_REORDER_HOLD)
+            .min();
+
+        queued.into_iter().chain(held).min()
+    }
+
+    /// Splits `packet` into tagged fragments of up to `max_size` bytes,
+    /// returning its buffer to the pool
+    fn fragment(&mut self, packet: Packet, max_size: usize) -> Vec<Packet> {
+        self.fragment_id = self.fragment_id.wrapping_add(1);
+        let max_size = max_size.min(packet.get().capacity());
+        let pool = &mut self.buffer_pool;
+        let fragments = packet.fragment(max_size, self.fragment_id, || pool.get_buffer());
+
+        match &fragments {
+            Some(fragments) if fragments.len() > 1 => {
+                info!("Packet split into {} fragments", fragments.len());
+                Stats::add(&self.stats.fragmented, 1);
+            }
+            Some(_) => (),
+            None => {
+                info!("No payload fits in {} bytes. Packet dropped.", max_size);
+                Stats::add(&self.stats.oversize_drops, 1);
+            }
+        }
+        self.buffer_pool.recycle_buffer(packet.into());
+
+        fragments.unwrap_or_default()
+    }
+
+    /// Delays `packet` for its transmission over a link of `rate` bits per
+    /// second, once it is done with the previous one
+    fn transmit(&mut self, packet: &mut Packet, rate: f64) {
+        let start = packet.exit_time().max(self.link_free);
+        self.link_free = start + Duration::from_secs_f64(packet.get().len() as f64 * 8.0 / rate);
+        packet.hold_until(self.link_free);
+    }
+
+    /// Takes `packet` through the virtual hops to its destination, each one
+    /// adding its transmission time and delay. Returns the index of the hop
+    /// dropping it, if any.
+    fn traverse_hops(&mut self, packet: &mut Packet) -> Option<usize> {
+        let hops = self.settings.hops.iter().zip(&mut self.hop_links);
+        for (i, (hop, link)) in hops.enumerate() {
+            if !hop.carries(packet.dst().ip()) {
+                continue;
+            }
+            if link.drop_distribution.sample(&mut self.rng) {
+                return Some(i);
+            }
+
+            let mut ready = packet.exit_time();
+            if let Some(rate) = hop.rate {
+                ready = ready.max(link.free)
+                    + Duration::from_secs_f64(packet.get().len() as f64 * 8.0 / rate);
+                link.free = ready;
+            }
+            let delay = link.delay_distribution.sample(&mut self.rng);
+            packet.hold_until(ready + Duration::from_millis(delay));
+        }
+
+        None
+    }
+
+    /// Queues `packet`, from the source profile with index `source` if any,
+    /// unless it is held back to reorder it. A packet held for the same
+    /// destination is queued to leave right after it.
+    fn enqueue(&mut self, packet: Packet, source: Option<usize>) {
+        if let Some(mut held) = self.held.remove(&packet.dst()) {
+            held.hold_until(packet.exit_time() + REORDER_GAP);
+            self.queue.push(packet);
+            self.queue.push(held);
+        } else if self.sample(source, |impairments| &impairments.reorder_distribution) {
+            info!(
+                "Packet held back until another one to {} leaves",
+                packet.dst()
+            );
+            Stats::add(&self.stats.reordered, 1);
+            self.held.insert(packet.dst(), packet);
+        } else {
+            self.queue.push(packet);
+        }
+    }
+
+    /// Queues the held packets no other one followed for too long, or every
+    /// one of them if `all`
+    fn release_held(&mut self, now: Instant, all: bool) {
+        let expired: Vec<_> = self
+            .held
+            .iter()
+            .filter(|(_, packet)| all || packet.exit_time() + MAX_REORDER_HOLD <= now)
+            .map(|(&dst, _)| dst)
+            .collect();
+        for dst in expired {
+            if let Some(packet) = self.held.remove(&dst) {
+                self.queue.push(packet);
+            }
+        }
+    }
+
+    fn report_gauges(&mut self) {
+        let now = self.now();
+        self.stats
+            .occupancy
+            .count(self.queued, now - self.gauges_updated);
+        self.gauges_updated = now;
+        Stats::adjust(&self.stats.queued, self.queued, self.queue.len());
+        self.queued = self.queue.len();
+        self.stats.occupancy.peak(Stats::get(&self.stats.queued));
+        Stats::adjust(&self.stats.pooled, self.pooled, self.buffer_pool.len());
+        self.pooled = self.buffer_pool.len();
+    }
+
+    /// Whether draining the queue is over, once no remaining packet can leave
+    /// before `deadline`. Those left are discarded.
+    fn drained(&mut self, now: Instant, deadline: Instant) -> bool {
+        self.release_held(now, true);
+        if now < deadline && self.queue.peek().is_some_and(|p| p.exit_time() <= deadline) {
+            return false;
+        }
+
+        let discarded = self.discard_queued("shutdown");
+        if discarded > 0 {
+            info!("Discarded {discarded} queued packets on shutdown");
+            Stats::add(&self.stats.drain_discards, discarded);
+        }
+        Stats::adjust(&self.stats.queued, self.queued, 0);
+        self.queued = 0;
+        Stats::adjust(&self.stats.pooled, self.pooled, 0);
+        self.pooled = 0;
+        true
+    }
+
+    /// Sends the packets already due with `send`, which returns how many of
+    /// the datagrams given left through the socket with the index given
+    fn send_due(&mut self, send: impl Fn(usize, &[(&[u8], SocketAddr)]) -> io::Result<usize>) {
+        self.release_held(self.now(), false);
+        self.rate_blocked = process_queue(
+            &mut self.queue,
+            send,
+            &mut self.buffer_pool,
+            &self.stats,
+            &self.settings,
+            &mut self.codel,
+        );
+    }
+
+    /// Answers the status query from `addr` with `send`, unless the answer
+    /// could be reflected to a spoofed source. Replies are larger than
+    /// queries, so they go through the same checks as forwarded packets.
+    fn answer_query(
+        &self,
+        addr: SocketAddr,
+        now: Instant,
+        send: impl Fn(&[u8], SocketAddr) -> io::Result<usize>,
+    ) {
+        let status = Status {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime: self.settings.uptime(),
+            profile: self.impairments.profile.to_string(),
+            queued: Stats::get(&self.stats.queued),
+            pooled: Stats::get(&self.stats.pooled),
+            public_address: self.settings.public_address,
+        }
+        .encode();
+
+        let acl = &self.settings.acl;
+        if !self.settings.status_queries && !addr.ip().to_canonical().is_loopback() {
+            debug!("Ignoring status query from {}", addr);
+            return;
+        } else if !acl.permits(addr.ip()) {
+            info!("Status query from a source not allowed. Query ignored.");
+            Stats::add(&self.stats.acl_drops, 1);
+            return;
+        } else if let Some(refusal) = acl.refusal(addr) {
+            info!("Status query source is {}. Query ignored.", refusal);
+            Stats::add(&self.stats.reflection_drops, 1);
+            return;
+        } else if self
+            .settings
+            .source_limit
+            .as_ref()
+            .is_some_and(|limit| !limit.admit(addr.ip(), status.len(), now))
+        {
+            info!("{} exceeded its rate. Query ignored.", addr.ip());
+            Stats::add(&self.stats.source_rate_drops, 1);
+            return;
+        }
+
+        let reply_to = net::for_socket(addr, self.settings.dual_stack);
+        if let Err(e) = send(&status, reply_to) {
+            warn!("Could not answer status query from {}: {}", addr, e);
+        }
+    }
+
+    /// Drops, or queues, a datagram of `len` bytes just received from
+    /// `addr` through the socket with index `socket`, which it leaves through.
+    /// Status queries are answered with `send`.
+    fn receive(
+        &mut self,
+        mut buffer: Buffer,
+        len: usize,
+        addr: SocketAddr,
+        socket: usize,
+        send: impl Fn(&[u8], SocketAddr) -> io::Result<usize>,
+    ) {
+        let arrival_time = self.now();
+        buffer.set_len(len);
+
+        debug!("Received {} bytes from {}", len, addr);
+        if let Some(pcap) = &self.settings.pcap {
+            if let Err(e) = pcap.received(addr, &buffer) {
+                warn!("Could not write the pcap capture: {}", e);
+            }
+        }
+        eventlog::emit(&Event::Receive { src: addr, len });
+
+        if inband::is_query(&buffer) {
+            self.answer_query(addr, arrival_time, send);
+            self.buffer_pool.recycle_buffer(buffer);
+            return;
+        }
+
+        Stats::add(&self.stats.received, 1);
+        Stats::add(&self.stats.bytes_received, len);
+        let source = self
+            .settings
+            .source_profiles
+            .iter()
+            .position(|profile| profile.contains(addr.ip()));
+        if let Some(checker) = &self.settings.checker {
+            checker.check(addr, &buffer);
+        }
+
+        let header = match self.settings.echo {
+            true => None,
+            false => Header::decode(&buffer).ok(),
+        };
+        let dst = match self.settings.echo {
+            true => Some(addr),
+            false => header.map(|header| header.addr()),
+        };
+        // Rejected by --strict before the reflection guard, which refuses
+        // some of the same destinations, so they count as malformed
+        let invalid = header
+            .filter(|_| self.settings.strict)
+            .and_then(|header| packet::check_dst(&header.addr()).err());
+        let translation = match (&self.settings.nat, dst, &invalid) {
+            (Some(nat), Some(dst), None) => Some(nat.translate(addr, dst, arrival_time)),
+            _ => None,
+        };
+        let dst = match translation {
+            Some(Ok(translation)) => Some(translation.dst),
+            _ => dst,
+        };
+        let (decision, reason) = if let Some(e) = invalid {
+            warn!("Could not parse packet from {}: {}", addr, e);
+            self.stats.count_malformed(&e);
+            (Decision::Drop, "malformed")
+        } else if header.is_some_and(|header| header.is_expired()) {
+            warn!(
+                "Packet from {} exhausted its hop limit. Is there a routing loop? Packet dropped.",
+                addr
+            );
+            Stats::add(&self.stats.hop_limit_drops, 1);
+            (Decision::Drop, "hop limit")
+        } else if let Some(Err(refusal)) = translation {
+            info!("The NAT refused it: {}. Packet dropped.", refusal);
+            Stats::add(&self.stats.nat_drops, 1);
+            (Decision::Drop, "nat")
+        } else if dst.is_some_and(|dst| !self.settings.acl.permits(dst.ip())) {
+            info!("Destination not allowed. Packet dropped.");
+            Stats::add(&self.stats.acl_drops, 1);
+            (Decision::Drop, "acl")
+        } else if dst.is_some_and(|dst| self.settings.blackholes.contains(dst.ip())) {
+            info!("Destination blackholed. Packet dropped.");
+            Stats::add(&self.stats.blackhole_drops, 1);
+            (Decision::Drop, "blackhole")
+        } else if let Some(refusal) = dst.and_then(|dst| self.settings.acl.refusal(dst)) {
+            info!("Destination is {}. Packet dropped.", refusal);
+            Stats::add(&self.stats.reflection_drops, 1);
+            (Decision::Drop, "reflection")
+        } else if !self.settings.fragment && self.settings.max_size.is_some_and(|max| len > max) {
+            info!("Packet of {} bytes too large. Packet dropped.", len);
+            Stats::add(&self.stats.oversize_drops, 1);
+            (Decision::Drop, "max size")
+        } else if self
+            .settings
+            .source_limit
+            .as_ref()
+            .is_some_and(|limit| !limit.admit(addr.ip(), len, arrival_time))
+        {
+            info!("{} exceeded its rate. Packet dropped.", addr.ip());
+            Stats::add(&self.stats.source_rate_drops, 1);
+            (Decision::Drop, "source rate")
+        } else if self.buffer_pool.over_budget() {
+            info!("Memory budget exhausted. Packet dropped.");
+            Stats::add(&self.stats.overflow_drops, 1);
+            (Decision::Drop, "memory budget")
+        } else if self
+            .settings
+            .queue_limit
+            .is_some_and(|limit| limit.exceeded(&self.queue, len))
+        {
+            info!("Queue full. Packet dropped.");
+            Stats::add(&self.stats.queue_drops, 1);
+            (Decision::Drop, "queue limit")
+        } else if self
+            .red
+            .as_mut()
+            .is_some_and(|red| red.early_drop(self.queue.len(), &mut self.rng))
+        {
+            info!("RED dropped the packet early.");
+            Stats::add(&self.stats.red_drops, 1);
+            (Decision::Drop, "red")
+        } else if self
+            .settings
+            .client_limit
+            .is_some_and(|limit| self.queue.queued_bytes(addr.ip()) + len > limit)
+        {
+            info!("Too many bytes queued from {}. Packet dropped.", addr.ip());
+            Stats::add(&self.stats.client_limit_drops, 1);
+            (Decision::Drop, "client limit")
+        } else if let Some(decision) = self
+            .settings
+            .trace
+            .as_deref()
+            .and_then(ImpairmentTrace::next_decision)
+        {
+            match decision {
+                Decision::Drop => {
+                    info!("The trace drops the packet.");
+                    Stats::add(&self.stats.trace_drops, 1);
+                }
+                Decision::Delay(delay) => {
+                    info!(
+                        "The trace delays the packet for {} milliseconds",
+                        delay.as_millis()
+                    );
+                    self.stats.count_delay(delay);
+                }
+            }
+            (decision, "trace")
+        } else if self.sample_drop(source) {
+            info!("Τύχη decided it. Packet dropped.");
+            Stats::add(&self.stats.random_drops, 1);
+            (Decision::Drop, "random")
+        } else {
+            let frame_delay = Duration::from_millis(self.sample_delay(source));
+
+            info!(
+                "Packet will be delayed for {} milliseconds",
+                frame_delay.as_millis()
+            );
+            self.stats.count_delay(frame_delay);
+            (Decision::Delay(frame_delay), "profile")
+        };
+        let duplicate_delay = match decision {
+            Decision::Delay(_)
+                if self.sample(source, |impairments| &impairments.duplicate_distribution) =>
+            {
+                let delay = Duration::from_millis(self.sample_delay(source));
+
+                info!(
+                    "Packet duplicated. The copy will be delayed for {} milliseconds",
+                    delay.as_millis()
+                );
+                Stats::add(&self.stats.duplicated, 1);
+                self.stats.count_delay(delay);
+                Some(delay)
+            }
+            _ => None,
+        };
+
+        if let Some(capture) = &self.settings.capture {
+            let profile = self.profile(source);
+            let comment = match (decision, duplicate_delay) {
+                (Decision::Drop, _) => {
+                    format!("drop ({reason}); profile {profile}")
+                }
+                (Decision::Delay(delay), None) => {
+                    format!("delay {} ms; profile {profile}", delay.as_millis())
+                }
+                (Decision::Delay(delay), Some(duplicate)) => format!(
+                    "delay {} ms, duplicate delay {} ms; profile {profile}",
+                    delay.as_millis(),
+                    duplicate.as_millis()
+                ),
+            };
+            if let Err(e) = capture.capture(SystemTime::now(), addr, &buffer, &comment) {
+                warn!("Could not capture the packet: {}", e);
+            }
+        }
+
+        if let Some(recorder) = &self.settings.recorder {
+            // A duplicate is recorded as a second arrival of the same packet
+            for decision in std::iter::once(decision).chain(duplicate_delay.map(Decision::Delay)) {
+                if let Err(e) = recorder.record(arrival_time, addr, &buffer, decision) {
+                    warn!("Could not record the session: {}", e);
+                }
+            }
+        }
+
+        if let Some(dst) = dst {
+            self.stats.count_flow(addr, dst, FlowEvent::Received(len));
+            if decision == Decision::Drop {
+                self.stats.count_flow(addr, dst, FlowEvent::Dropped);
+            }
+        }
+
+        match decision {
+            Decision::Drop => {
+                trace_event(&self.settings, Ns3Event::Drop, addr, dst, len);
+                eventlog::emit(&Event::Drop {
+                    src: addr,
+                    dst,
+                    len,
+                    reason,
+                });
+                csv_dropped(
+                    self.settings.events_csv.as_deref(),
+                    arrival_time,
+                    addr,
+                    dst,
+                    len,
+                    reason,
+                );
+                self.buffer_pool.recycle_buffer(buffer)
+            }
+            Decision::Delay(frame_delay) => {
+                let exit_time = arrival_time + frame_delay;
+                // Behind the NAT, the header carries the translated source
+                let src = match translation {
+                    Some(Ok(translation)) => translation.src,
+                    _ => addr,
+                };
+                let packet = if self.settings.echo {
+                    Ok(Packet::echo(addr, buffer, arrival_time, exit_time))
+                } else if self.settings.strict {
+                    Packet::create_strict(src, buffer, arrival_time, exit_time)
+                } else {
+                    Packet::create(src, buffer, arrival_time, exit_time)
+                };
+
+                match packet {
+                    Ok(mut packet) => {
+                        packet.set_socket(socket);
+                        if let Some(Ok(translation)) = translation {
+                            packet.redirect(translation.dst);
+                            packet.set_sender(addr.ip());
+                        }
+                        let duplicate = duplicate_delay.map(|delay| {
+                            let exit_time = arrival_time + delay;
+                            let copy = self.buffer_pool.get_buffer();
+                            (packet.duplicate(copy, exit_time), delay)
+                        });
+
+                        let mut packets = vec![(packet, frame_delay)];
+                        packets.extend(duplicate);
+                        if let (true, Some(max_size)) =
+                            (self.settings.fragment, self.settings.max_size)
+                        {
+                            packets = packets
+                                .into_iter()
+                                .flat_map(|(packet, delay)| {
+                                    let fragments = self.fragment(packet, max_size);
+                                    fragments.into_iter().map(move |f| (f, delay))
+                                })
+                                .collect();
+                        }
+
+                        for (mut packet, delay) in packets {
+                            if let Some(rate) = self.settings.link_rate {
+                                self.transmit(&mut packet, rate);
+                            }
+                            if let Some(hop) = self.traverse_hops(&mut packet) {
+                                info!("Hop {} dropped the packet.", self.settings.hops[hop].name);
+                                Stats::add(&self.stats.hop_drops, 1);
+                                self.stats.count_flow(
+                                    packet.src(),
+                                    packet.dst(),
+                                    FlowEvent::Dropped,
+                                );
+                                trace_event(
+                                    &self.settings,
+                                    Ns3Event::Drop,
+                                    packet.src(),
+                                    Some(packet.dst()),
+                                    len,
+                                );
+                                eventlog::emit(&Event::Drop {
+                                    src: packet.src(),
+                                    dst: Some(packet.dst()),
+                                    len,
+                                    reason: "hop",
+                                });
+                                csv_dropped(
+                                    self.settings.events_csv.as_deref(),
+                                    arrival_time,
+                                    packet.src(),
+                                    Some(packet.dst()),
+                                    len,
+                                    "hop",
+                                );
+                                self.buffer_pool.recycle_buffer(packet.into());
+                                continue;
+                            }
+                            if let Some(truncation) = self.profile(source).truncation() {
+                                if self.sample(source, |impairments| {
+                                    &impairments.truncate_distribution
+                                }) {
+                                    let cut = packet.truncate(truncation.bytes);
+                                    info!("{} bytes of the packet cut", cut);
+                                    Stats::add(&self.stats.truncated, 1);
+                                }
+                            }
+                            if self.sample(source, |impairments| &impairments.corrupt_distribution)
+                            {
+                                let bits = packet.corrupt(&mut self.rng);
+                                info!("{} bits of the packet corrupted", bits);
+                                Stats::add(&self.stats.corrupted, 1);
+                            }
+                            trace_event(
+                                &self.settings,
+                                Ns3Event::Enqueue,
+                                packet.src(),
+                                Some(packet.dst()),
+                                len,
+                            );
+                            eventlog::emit(&Event::Enqueue {
+                                src: packet.src(),
+                                dst: packet.dst(),
+                                len,
+                                delay_ms: delay.as_millis(),
+                            });
+                            self.enqueue(packet, source)
+                        }
+                    }
+                    Err(e) => {
+                        trace_event(&self.settings, Ns3Event::Drop, addr, None, len);
+                        eventlog::emit(&Event::Drop {
+                            src: addr,
+                            dst: None,
+                            len,
+                            reason: "malformed",
+                        });
+                        csv_dropped(
+                            self.settings.events_csv.as_deref(),
+                            arrival_time,
+                            addr,
+                            None,
+                            len,
+                            "malformed",
+                        );
+                        warn!("Could not parse packet from {}: {}", addr, e);
+                        self.stats.count_malformed(&e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends `datagrams` through `socket`, all at once where the system allows it
+#[cfg(target_os = "linux")]
+fn send_datagrams(
+    socket: &mio::net::UdpSocket,
+    datagrams: &[(&[u8], SocketAddr)],
+) -> io::Result<usize> {
+    mmsg::send_batch(socket, datagrams)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_datagrams(
+    socket: &mio::net::UdpSocket,
+    datagrams: &[(&[u8], SocketAddr)],
+) -> io::Result<usize> {
+    send_each(|data, dst| socket.send_to(data, dst), datagrams)
+}
+
+/// Sends `datagrams` one by one with `send`, until one fails
+#[cfg(any(not(target_os = "linux"), feature = "tokio-backend"))]
+fn send_each(
+    send: impl Fn(&[u8], SocketAddr) -> io::Result<usize>,
+    datagrams: &[(&[u8], SocketAddr)],
+) -> io::Result<usize> {
+    for (sent, &(data, dst)) in datagrams.iter().enumerate() {
+        if let Err(e) = send(data, dst) {
+            return if sent == 0 { Err(e) } else { Ok(sent) };
+        }
+    }
+
+    Ok(datagrams.len())
+}
+
+/// Hands every datagram waiting in `socket`, the one with index `index`, to
+/// `worker`, reading a batch of them at once
+#[cfg(target_os = "linux")]
+fn receive_datagrams(socket: &mio::net::UdpSocket, index: usize, worker: &mut Worker) {
+    loop {
+        let mut buffers: Vec<Buffer> = (0..BATCH_SIZE)
+            .map(|_| worker.buffer_pool.get_buffer())
+            .collect();
+        let received = mmsg::recv_batch(
+            socket,
+            &mut buffers
+                .iter_mut()
+                .map(|buffer| &mut buffer[..])
+                .collect::<Vec<_>>(),
+        );
+
+        let more = match received {
+            Ok(received) => {
+                let more = received.len() == BATCH_SIZE;
+                let mut buffers = buffers.drain(..);
+                for ((len, addr), buffer) in received.into_iter().zip(&mut buffers) {
+                    worker.receive(buffer, len, net::canonical(addr), index, |data, dst| {
+                        socket.send_to(data, dst)
+                    });
+                }
+                more
+            }
+            // We can not read more data without blocking
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            // Pending ICMP error from a previous transmission
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => true,
+            Err(e) => {
+                warn!("Error while reading datagram: {}", e);
+                false
+            }
+        };
+
+        for buffer in buffers {
+            worker.buffer_pool.recycle_buffer(buffer);
+        }
+        if !more {
+            break;
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn receive_datagrams(socket: &mio::net::UdpSocket, index: usize, worker: &mut Worker) {
+    loop {
+        // Get all pending packets
+        let mut buffer = worker.buffer_pool.get_buffer();
+        let (len, addr) = match socket.recv_from(&mut buffer) {
+            Ok((len, addr)) => (len, net::canonical(addr)),
+
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                // We can not read more data without blocking
+                break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                // Pending ICMP error from a previous transmission
+                worker.buffer_pool.recycle_buffer(buffer);
+                continue;
+            }
+            Err(e) => {
+                warn!("Error while reading datagram: {}", e);
+                break;
+            }
+        };
+
+        worker.receive(buffer, len, addr, index, |data, dst| {
+            socket.send_to(data, dst)
+        });
+    }
+}
+
+fn process_traffic(
+    mut poll: mio::Poll,
+    sockets: Vec<UdpSocket>,
+    settings: Settings,
+    buffer_pool: BufferPool,
+    stats: Arc<Stats>,
+    shutdown: Arc<AtomicBool>,
+    heartbeat: Arc<Heartbeat>,
+) -> io::Result<()> {
+    let drain_timeout = settings.drain_timeout;
+    let mut worker = Worker::new(settings, buffer_pool, stats);
+    let mut sockets: Vec<_> = sockets
+        .into_iter()
+        .map(mio::net::UdpSocket::from_std)
+        .collect();
+    let token = |i: usize| Token(FIRST_SOCKET.0 + i);
+
+    for (i, socket) in sockets.iter_mut().enumerate() {
+        poll.registry()
+            .register(socket, token(i), Interest::READABLE)?;
+    }
+
+    let mut events = mio::Events::with_capacity(32); // Just a few to store those received while transmiitting if needed
+    let mut drain_deadline: Option<Instant> = None;
+
+    loop {
+        let now = worker.now();
+
+        if drain_deadline.is_some_and(|deadline| worker.drained(now, deadline)) {
+            return Ok(());
+        }
+
+        let next_exit = worker.next_exit(now);
+        let max_delay = worker
+            .next_wakeup(next_exit)
+            .map(|wakeup| wakeup.saturating_duration_since(now));
+
+        let interest = match next_exit {
+            Some(exit) if exit <= now => Interest::READABLE | Interest::WRITABLE,
+            _ => Interest::READABLE,
+        };
+        for (i, socket) in sockets.iter_mut().enumerate() {
+            poll.registry().reregister(socket, token(i), interest)?;
+        }
+
+        worker.report_gauges();
+
+        heartbeat.idle(worker.queue.len());
+        poll.poll(&mut events, max_delay)?;
+        heartbeat.busy();
+
+        worker.run_timers();
+        worker.refresh_profile();
+        worker.check_flush();
+
+        // Every socket sends its packets once any of them can
+        let mut writable = false;
+        for event in &events {
+            match event.token() {
+                WAKER => {
+                    if drain_deadline.is_none() && shutdown.load(Ordering::Relaxed) {
+                        debug!("Draining queue before exiting");
+                        drain_deadline = Some(worker.now() + drain_timeout);
+                    }
+                }
+                Token(t) => {
+                    let index = t - FIRST_SOCKET.0;
+                    let socket = &sockets[index];
+
+                    #[cfg(target_os = "linux")]
+                    if event.is_error() {
+                        process_errors(
+                            socket,
+                            &mut worker.buffer_pool,
+                            &worker.settings,
+                            &worker.stats,
+                        );
+                    }
+
+                    writable |= event.is_writable();
+
+                    if event.is_readable() && drain_deadline.is_none() {
+                        receive_datagrams(socket, index, &mut worker);
+                    }
+                }
+            }
+        }
+        if writable {
+            worker.send_due(|index, datagrams| send_datagrams(&sockets[index], datagrams));
+        }
+    }
+}
+
+/// Sets up a [`Router`]. Every option not given keeps the default of the
+/// command line.
+pub struct RouterBuilder {
+    bind: Option<IpAddr>,
+    ports: Vec<u16>,
+    drop: f64,
+    min_delay: u64,
+    rand_delay: u64,
+    duplicate: f64,
+    corrupt: f64,
+    reorder: f64,
+    truncation: Option<Truncation>,
+    delay_correlation: f64,
+    drop_correlation: f64,
+    delay_model: DelayModel,
+    queue_limit: Option<QueueLimit>,
+    client_limit: Option<usize>,
+    max_memory: Option<usize>,
+    pool_size: usize,
+    mtu: usize,
+    threads: usize,
+    #[cfg(unix)]
+    workers: usize,
+    /// Already bound, taking the place of the ports
+    sockets: Vec<UdpSocket>,
+    stun: Option<String>,
+    settings: Option<Settings>,
+}
+
+impl Default for RouterBuilder {
+    fn default() -> RouterBuilder {
+        RouterBuilder {
+            bind: None,
+            ports: vec![2021],
+            drop: 0.0,
+            min_delay: 0,
+            rand_delay: 0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            reorder: 0.0,
+            truncation: None,
+            delay_correlation: 0.0,
+            drop_correlation: 0.0,
+            delay_model: DelayModel::Uniform,
+            queue_limit: None,
+            client_limit: None,
+            max_memory: None,
+            pool_size: DEFAULT_POOL_SIZE,
+            mtu: DEFAULT_BUFFER_SIZE,
+            threads: 1,
+            #[cfg(unix)]
+            workers: 1,
+            sockets: Vec::new(),
+            stun: None,
+            settings: None,
+        }
+    }
+}
+
+impl RouterBuilder {
+    /// Address to listen on, instead of every IPv4 and IPv6 one
+    pub fn bind(mut self, addr: impl Into<Option<IpAddr>>) -> RouterBuilder {
+        self.bind = addr.into();
+        self
+    }
+
+    /// Listening port. With 0, the system picks a free one.
+    pub fn port(mut self, port: u16) -> RouterBuilder {
+        self.ports = vec![port];
+        self
+    }
+
+    /// Listening ports, sharing the queues. Packets leave through the port
+    /// they arrived at.
+    pub fn ports(mut self, ports: impl IntoIterator<Item = u16>) -> RouterBuilder {
+        self.ports = ports.into_iter().collect();
+        self
+    }
+
+    /// Listens on these sockets, already bound, instead of binding the
+    /// ports, e.g. those passed by systemd socket activation. Every thread
+    /// reads from all of them.
+    pub fn sockets(mut self, sockets: Vec<UdpSocket>) -> RouterBuilder {
+        self.sockets = sockets;
+        self
+    }
+
+    /// Drop probability
+    pub fn drop(mut self, drop: f64) -> RouterBuilder {
+        self.drop = drop;
+        self
+    }
+
+    /// Minimum delay and delay randomness, in milliseconds
+    pub fn delay(mut self, min_delay: u64, rand_delay: u64) -> RouterBuilder {
+        self.min_delay = min_delay;
+        self.rand_delay = rand_delay;
+        self
+    }
+
+    /// Distribution of the random part of the delay
+    pub fn delay_model(mut self, delay_model: DelayModel) -> RouterBuilder {
+        self.delay_model = delay_model;
+        self
+    }
+
+    /// Takes every impairment from `profile`
+    pub fn profile(mut self, profile: &Profile) -> RouterBuilder {
+        self.drop = profile.drop();
+        self.min_delay = profile.min_delay();
+        self.rand_delay = profile.rand_delay();
+        self.duplicate = profile.duplicate();
+        self.corrupt = profile.corrupt();
+        self.reorder = profile.reorder();
+        self.truncation = profile.truncation();
+        self.delay_correlation = profile.delay_correlation();
+        self.drop_correlation = profile.drop_correlation();
+        self.delay_model = profile.delay_model();
+        self
+    }
+
+    /// Maximum queue length of every processing thread
+    pub fn queue_limit(mut self, limit: impl Into<Option<QueueLimit>>) -> RouterBuilder {
+        self.queue_limit = limit.into();
+        self
+    }
+
+    /// Maximum bytes a single source address may have queued in every
+    /// processing thread
+    pub fn client_limit(mut self, limit: impl Into<Option<usize>>) -> RouterBuilder {
+        self.client_limit = limit.into();
+        self
+    }
+
+    /// Memory budget for queued packets, in bytes
+    pub fn max_memory(mut self, budget: impl Into<Option<usize>>) -> RouterBuilder {
+        self.max_memory = budget.into();
+        self
+    }
+
+    /// Buffers each processing thread keeps for reuse
+    pub fn pool_size(mut self, buffers: usize) -> RouterBuilder {
+        self.pool_size = buffers;
+        self
+    }
+
+    /// Largest datagram accepted, in bytes. Longer ones are truncated.
+    pub fn mtu(mut self, bytes: usize) -> RouterBuilder {
+        self.mtu = bytes;
+        self
+    }
+
+    /// Number of traffic processing threads
+    pub fn threads(mut self, threads: usize) -> RouterBuilder {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Number of sockets sharing the port with `SO_REUSEPORT`, every one
+    /// with a thread of its own, so the kernel spreads the traffic among
+    /// them. There are at least as many threads.
+    #[cfg(unix)]
+    pub fn workers(mut self, workers: usize) -> RouterBuilder {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// STUN server queried for the public address of the router
+    pub fn stun(mut self, server: impl Into<Option<String>>) -> RouterBuilder {
+        self.stun = server.into();
+        self
+    }
+
+    /// Every other option. Its impairments and limits are replaced by those
+    /// of the builder.
+    pub fn settings(mut self, settings: Settings) -> RouterBuilder {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Binds the socket of the router, which does not forward anything until
+    /// it runs
+    pub fn build(self) -> Result<Router, RouterError> {
+        let profile = Profile::new(self.drop, self.min_delay, self.rand_delay)?
+            .with_duplicate(self.duplicate)?
+            .with_corrupt(self.corrupt)?
+            .with_reorder(self.reorder)?
+            .with_truncation(self.truncation)?
+            .with_delay_correlation(self.delay_correlation)?
+            .with_drop_correlation(self.drop_correlation)?
+            .with_delay_model(self.delay_model)?;
+        let mut settings = match self.settings {
+            Some(settings) => {
+                settings.profile.set(profile);
+                settings
+            }
+            None => Settings::new(profile),
+        };
+        settings.queue_limit = self.queue_limit;
+        settings.client_limit = self.client_limit;
+
+        if self.ports.is_empty() && self.sockets.is_empty() {
+            return Err(RouterError::NoPorts);
+        }
+        let ports = if self.sockets.is_empty() {
+            &self.ports[..]
+        } else {
+            &[]
+        };
+        let mut sockets: Vec<_> = self
+            .sockets
+            .into_iter()
+            .map(|socket| vec![socket])
+            .collect();
+        for &port in ports {
+            #[cfg(unix)]
+            sockets.push(match self.workers {
+                1 => vec![net::bind(self.bind, port)?],
+                workers => net::bind_reuse_port(self.bind, port, workers)?,
+            });
+            #[cfg(not(unix))]
+            sockets.push(vec![net::bind(self.bind, port)?]);
+        }
+        #[cfg(unix)]
+        let threads = self.threads.max(self.workers);
+        #[cfg(not(unix))]
+        let threads = self.threads;
+        settings.dual_stack = sockets[0][0].local_addr()?.is_ipv6();
+        if let Some(server) = &self.stun {
+            // Asked from the router socket itself, so the NAT mapping is the one its traffic gets
+            match stun::discover(&sockets[0][0], server, Duration::from_secs(1)) {
+                Ok(addr) => {
+                    info!("Public address: {}", addr);
+                    settings.public_address = Some(addr);
+                    settings.acl.own.push(SocketAddr::V4(addr));
+                }
+                Err(e) => warn!("Could not discover the public address: {}", e),
+            }
+        }
+        #[cfg(unix)]
+        let interfaces = net::interface_addresses()?;
+        for socket in sockets.iter().flatten() {
+            let addr = socket.local_addr()?;
+            settings.acl.own.push(addr);
+            // Bound to every interface, the router is reachable at any of them
+            #[cfg(unix)]
+            if addr.ip().is_unspecified() {
+                let port = addr.port();
+                settings
+                    .acl
+                    .own
+                    .extend(interfaces.iter().map(|&ip| SocketAddr::new(ip, port)));
+            }
+            socket.set_nonblocking(true)?;
+            #[cfg(target_os = "linux")]
+            icmp::enable_recverr(socket, settings.dual_stack)?;
+        }
+
+        if settings.seed.is_some() && threads > 1 {
+            warn!("Threads take packets in no particular order, so the seed cannot repeat the same impairments");
+        }
+
+        let heartbeats = (0..threads)
+            .map(|_| Arc::new(Heartbeat::new(settings.clock.clone())))
+            .collect();
+
+        Ok(Router {
+            sockets,
+            stats: Arc::new(Stats {
+                queue_limit: settings.queue_limit,
+                ..Stats::default()
+            }),
+            settings,
+            max_memory: self.max_memory,
+            pool_size: self.pool_size,
+            mtu: self.mtu,
+            heartbeats,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            wakers: Mutex::new(Vec::new()),
+            #[cfg(feature = "tokio-backend")]
+            shutdown_watch: tokio::sync::watch::channel(false).0,
+            #[cfg(feature = "tokio-backend")]
+            flush_watch: tokio::sync::watch::channel(()).0,
+            running: AtomicBool::new(false),
+        })
+    }
+}
+
+/// A router bound to its sockets
+pub struct Router {
+    /// Those of every port. Several when they share it, taken in turns by the
+    /// threads.
+    sockets: Vec<Vec<UdpSocket>>,
+    settings: Settings,
+    stats: Arc<Stats>,
+    max_memory: Option<usize>,
+    pool_size: usize,
+    mtu: usize,
+    /// One for every traffic processing thread
+    heartbeats: Vec<Arc<Heartbeat>>,
+    shutdown: Arc<AtomicBool>,
+    /// Those of the running threads
+    wakers: Mutex<Vec<mio::Waker>>,
+    /// Tells the tasks of the tokio backend to shut down
+    #[cfg(feature = "tokio-backend")]
+    shutdown_watch: tokio::sync::watch::Sender<bool>,
+    /// Tells the tasks of the tokio backend to check for a flush
+    #[cfg(feature = "tokio-backend")]
+    flush_watch: tokio::sync::watch::Sender<()>,
+    running: AtomicBool,
+}
+
+/// The traffic processing threads of a router started with [`Router::start`]
+pub struct Workers(Vec<thread::JoinHandle<()>>);
+
+impl Workers {
+    /// Waits until the router shuts down
+    pub fn join(self) {
+        for thread in self.0 {
+            if thread.join().is_err() {
+                warn!("A traffic processing thread panicked");
+            }
+        }
+    }
+}
+
+impl Router {
+    pub fn builder() -> RouterBuilder {
+        RouterBuilder::default()
+    }
+
+    /// That of the first port
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0][0].local_addr()
+    }
+
+    /// Those of every port
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.sockets
+            .iter()
+            .map(|shared| shared[0].local_addr())
+            .collect()
+    }
+
+    /// Those taken by the thread with index `i`, one for every port
+    fn thread_sockets(&self, i: usize) -> io::Result<Vec<UdpSocket>> {
+        self.sockets
+            .iter()
+            .map(|shared| shared[i % shared.len()].try_clone())
+            .collect()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Those of the traffic processing threads, for a [`crate::watchdog::Watchdog`]
+    pub fn heartbeats(&self) -> Vec<Arc<Heartbeat>> {
+        self.heartbeats.clone()
+    }
+
+    /// Set once the router is asked to shut down, for the threads that must
+    /// stop along with it
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    /// Forwards traffic until [`Router::shutdown`] is called and the queued
+    /// packets are flushed. It can only run once.
+    pub fn run(&self) -> Result<(), RouterError> {
+        self.start()?.join();
+
+        Ok(())
+    }
+
+    /// Sets up and starts the traffic processing threads, returning at once.
+    /// Every system resource they need exists on return, so a sandbox can be
+    /// installed right after.
+    pub fn start(&self) -> Result<Workers, RouterError> {
+        if self.running.swap(true, Ordering::Relaxed) {
+            return Err(RouterError::AlreadyRunning);
+        }
+
+        let memory_usage = Arc::new(AtomicUsize::default());
+        let mut threads = Vec::new();
+        for (i, heartbeat) in self.heartbeats.iter().enumerate() {
+            let (thread, waker) = spawn_worker(
+                self.thread_sockets(i)?,
+                Settings {
+                    // Every thread needs its own sequence
+                    seed: self.settings.seed.map(|seed| seed.wrapping_add(i as u64)),
+                    ..self.settings.clone()
+                },
+                BufferPool::new(memory_usage.clone(), self.max_memory)
+                    .with_capacity(self.pool_size)
+                    .with_buffer_size(self.mtu),
+                self.stats.clone(),
+                self.shutdown.clone(),
+                heartbeat.clone(),
+            )?;
+            threads.push(thread);
+            self.wakers.lock().unwrap().push(waker);
+        }
+        // In case the shutdown came before the threads could be woken
+        if self.shutdown.load(Ordering::Relaxed) {
+            self.shutdown()?;
+        }
+
+        Ok(Workers(threads))
+    }
+
+    /// Makes [`Router::run`] return once the queued packets are flushed, or
+    /// the drain timeout expires
+    pub fn shutdown(&self) -> Result<(), RouterError> {
+        self.shutdown.store(true, Ordering::Relaxed);
+        #[cfg(feature = "tokio-backend")]
+        self.shutdown_watch.send_replace(true);
+        for waker in self.wakers.lock().unwrap().iter() {
+            waker.wake()?;
+        }
+
+        Ok(())
+    }
+
+    /// Discards every packet queued, without waiting for it to leave
+    pub fn flush_queue(&self) -> Result<(), RouterError> {
+        self.settings.flushes.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "tokio-backend")]
+        self.flush_watch.send_replace(());
+        for waker in self.wakers.lock().unwrap().iter() {
+            waker.wake()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio-backend")]
+impl Router {
+    /// Like [`Router::run`], on the tokio runtime of the caller instead of
+    /// threads of its own. Every thread the router would start becomes a
+    /// task.
+    pub async fn run_async(&self) -> Result<(), RouterError> {
+        if self.running.swap(true, Ordering::Relaxed) {
+            return Err(RouterError::AlreadyRunning);
+        }
+
+        let memory_usage = Arc::new(AtomicUsize::default());
+        let mut tasks = Vec::new();
+        for (i, heartbeat) in self.heartbeats.iter().enumerate() {
+            let sockets = self
+                .thread_sockets(i)?
+                .into_iter()
+                .map(tokio::net::UdpSocket::from_std)
+                .collect::<io::Result<Vec<_>>>()?;
+            let worker = Worker::new(
+                Settings {
+                    seed: self.settings.seed.map(|seed| seed.wrapping_add(i as u64)),
+                    ..self.settings.clone()
+                },
+                BufferPool::new(memory_usage.clone(), self.max_memory)
+                    .with_capacity(self.pool_size)
+                    .with_buffer_size(self.mtu),
+                self.stats.clone(),
+            );
+            let (shutdown, flush) = (
+                self.shutdown_watch.subscribe(),
+                self.flush_watch.subscribe(),
+            );
+            let heartbeat = heartbeat.clone();
+
+            tasks.push(tokio::spawn(async move {
+                let task = process_traffic_async(sockets, worker, shutdown, flush, heartbeat);
+                if let Err(e) = task.await {
+                    warn!("Error while processing traffic: {:?}", e);
+                }
+            }));
+        }
+
+        for task in tasks {
+            if task.await.is_err() {
+                warn!("A traffic processing task panicked");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What woke up a task of the tokio backend
+#[cfg(feature = "tokio-backend")]
+enum Wakeup {
+    /// By the socket with that index
+    Received(usize, io::Result<(usize, SocketAddr)>),
+    Due,
+    Shutdown,
+    Flush,
+    Timer,
+}
+
+/// Receives a datagram from whichever of `sockets` has one first, returning
+/// its index along with the outcome
+#[cfg(feature = "tokio-backend")]
+async fn recv_any(
+    sockets: &[tokio::net::UdpSocket],
+    buffer: &mut [u8],
+) -> (usize, io::Result<(usize, SocketAddr)>) {
+    std::future::poll_fn(|cx| {
+        for (index, socket) in sockets.iter().enumerate() {
+            let mut buf = tokio::io::ReadBuf::new(buffer);
+            if let std::task::Poll::Ready(received) = socket.poll_recv_from(cx, &mut buf) {
+                let len = buf.filled().len();
+                return std::task::Poll::Ready((index, received.map(|addr| (len, addr))));
+            }
+        }
+        std::task::Poll::Pending
+    })
+    .await
+}
+
+/// The event loop of [`process_traffic`], for the tokio backend. Errors in
+/// the socket error queue are not read.
+#[cfg(feature = "tokio-backend")]
+async fn process_traffic_async(
+    sockets: Vec<tokio::net::UdpSocket>,
+    mut worker: Worker,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+    mut flush: tokio::sync::watch::Receiver<()>,
+    heartbeat: Arc<Heartbeat>,
+) -> io::Result<()> {
+    let mut drain_deadline: Option<Instant> = None;
+    let send = |index: usize| {
+        let socket = &sockets[index];
+        move |data: &[u8], dst: SocketAddr| socket.try_send_to(data, dst)
+    };
+
+    loop {
+        let now = worker.now();
+
+        // Also once the router is gone
+        let stopping = *shutdown.borrow() || shutdown.has_changed().is_err();
+        if stopping && drain_deadline.is_none() {
+            debug!("Draining queue before exiting");
+            drain_deadline = Some(now + worker.settings.drain_timeout);
+        }
+        if drain_deadline.is_some_and(|deadline| worker.drained(now, deadline)) {
+            return Ok(());
+        }
+
+        let next_exit = worker.next_exit(now);
+        let next_timer = worker.next_timer();
+        worker.report_gauges();
+
+        heartbeat.idle(worker.queue.len());
+        let mut buffer = worker.buffer_pool.get_buffer();
+        let wakeup = tokio::select! {
+            (index, received) = recv_any(&sockets, &mut buffer), if drain_deadline.is_none() => {
+                Wakeup::Received(index, received)
+            }
+            _ = tokio::time::sleep_until(next_exit.unwrap_or(now).into()), if next_exit.is_some() => {
+                Wakeup::Due
+            }
+            _ = shutdown.changed(), if drain_deadline.is_none() => Wakeup::Shutdown,
+            _ = flush.changed(), if drain_deadline.is_none() => Wakeup::Flush,
+            _ = tokio::time::sleep_until(next_timer.unwrap_or(now).into()), if next_timer.is_some() => {
+                Wakeup::Timer
+            }
+        };
+        heartbeat.busy();
+
+        worker.run_timers();
+        worker.refresh_profile();
+        worker.check_flush();
+
+        match wakeup {
+            Wakeup::Received(index, Ok((len, addr))) => {
+                worker.receive(buffer, len, net::canonical(addr), index, send(index));
+                continue;
+            }
+            Wakeup::Received(_, Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                // Pending ICMP error from a previous transmission
+            }
+            Wakeup::Received(_, Err(e)) => warn!("Error while reading datagram: {}", e),
+            Wakeup::Due => {
+                for socket in &sockets {
+                    socket.writable().await?;
+                }
+                worker.send_due(|index, datagrams| send_each(send(index), datagrams));
+            }
+            Wakeup::Shutdown | Wakeup::Flush | Wakeup::Timer => (),
+        }
+        worker.buffer_pool.recycle_buffer(buffer);
+    }
+}
@@ -0,0 +1,457 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Scenario files: impairments that change over time and per source.
+//!
+//! A scenario is a TOML file with optional `[defaults]`, a list of `[[phase]]`
+//! tables, each applying its impairments during a time window measured in
+//! seconds since the router started, and a list of `[[rule]]` tables
+//! overriding them for the sources in a network. Unset values are inherited:
+//! from the matching rule, then the active phase, then the defaults.
+//!
+//! A list of `[[action]]` tables scripts what the router does at given
+//! times, to replay demos: print a marker, change the impairments with the
+//! settings of profiles, blackhole a destination network and restore it, or
+//! flush the queue.
+//!
+//! ```toml
+//! [defaults]
+//! drop = 0.05
+//!
+//! [[phase]]
+//! name = "congestion"
+//! start = 60
+//! duration = 30
+//! min_delay = 200
+//! rand_delay = 100
+//!
+//! [[rule]]
+//! source = "10.0.1.0/24"
+//! drop = 0.5
+//!
+//! [[action]]
+//! at = 30
+//! log = "The link degrades"
+//! set = "drop=0.2 min_delay=300"
+//!
+//! [[action]]
+//! at = 45
+//! blackhole = "10.0.2.0/24"
+//! flush = true
+//! ```
+
+use crate::profile::{Profile, ProfileError};
+use ipnet::{IpNet, Ipv4Net};
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ScenarioError {
+    #[error("could not read the scenario: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid scenario")]
+    Parse(#[from] toml::de::Error),
+}
+
+/// Impairment parameters. Delays are in milliseconds.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Impairments {
+    pub drop: Option<f64>,
+    pub min_delay: Option<u64>,
+    pub rand_delay: Option<u64>,
+}
+
+impl Impairments {
+    /// Fills the values not set here with those of `fallback`
+    pub fn or(self, fallback: Impairments) -> Impairments {
+        Impairments {
+            drop: self.drop.or(fallback.drop),
+            min_delay: self.min_delay.or(fallback.min_delay),
+            rand_delay: self.rand_delay.or(fallback.rand_delay),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Impairments::default()
+    }
+
+    /// The profile applying these impairments, unset values being zero
+    pub fn profile(&self) -> Result<Profile, ProfileError> {
+        Profile::new(
+            self.drop.unwrap_or(0.0),
+            self.min_delay.unwrap_or(0),
+            self.rand_delay.unwrap_or(0),
+        )
+    }
+}
+
+impl fmt::Display for Impairments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop={} delay={}+rand({}) ms",
+            self.drop.unwrap_or(0.0),
+            self.min_delay.unwrap_or(0),
+            self.rand_delay.unwrap_or(0)
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Phase {
+    pub name: Option<String>,
+    /// Start time, in seconds
+    pub start: f64,
+    /// Length, in seconds
+    pub duration: f64,
+    pub drop: Option<f64>,
+    pub min_delay: Option<u64>,
+    pub rand_delay: Option<u64>,
+}
+
+impl Phase {
+    pub fn end(&self) -> f64 {
+        self.start + self.duration
+    }
+
+    pub fn impairments(&self) -> Impairments {
+        Impairments {
+            drop: self.drop,
+            min_delay: self.min_delay,
+            rand_delay: self.rand_delay,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Source address or network, e.g. `10.0.0.7` or `10.0.0.0/24`
+    pub source: String,
+    pub drop: Option<f64>,
+    pub min_delay: Option<u64>,
+    pub rand_delay: Option<u64>,
+}
+
+impl Rule {
+    pub fn network(&self) -> Option<Ipv4Net> {
+        self.source
+            .parse()
+            .ok()
+            .or_else(|| self.source.parse::<Ipv4Addr>().ok().map(Ipv4Net::from))
+    }
+
+    pub fn impairments(&self) -> Impairments {
+        Impairments {
+            drop: self.drop,
+            min_delay: self.min_delay,
+            rand_delay: self.rand_delay,
+        }
+    }
+}
+
+/// Something the router does at a given time. Every field set is done, in
+/// the order they are declared.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Action {
+    /// Time, in seconds
+    pub at: f64,
+    /// Marker printed and logged
+    pub log: Option<String>,
+    /// Impairments changed, as the `key=value` settings of profiles
+    pub set: Option<String>,
+    /// Destination address or network whose packets are dropped from then on
+    pub blackhole: Option<String>,
+    /// Destination blackholed before, whose packets are relayed again
+    pub restore: Option<String>,
+    /// Whether to discard the packets queued
+    #[serde(default)]
+    pub flush: bool,
+}
+
+impl Action {
+    pub fn blackhole_network(&self) -> Option<IpNet> {
+        self.blackhole.as_deref().and_then(parse_network)
+    }
+
+    pub fn restore_network(&self) -> Option<IpNet> {
+        self.restore.as_deref().and_then(parse_network)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_none()
+            && self.set.is_none()
+            && self.blackhole.is_none()
+            && self.restore.is_none()
+            && !self.flush
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut steps = Vec::new();
+        if let Some(marker) = &self.log {
+            steps.push(format!("log {marker:?}"));
+        }
+        if let Some(settings) = &self.set {
+            steps.push(format!("set {settings}"));
+        }
+        if let Some(network) = &self.blackhole {
+            steps.push(format!("blackhole {network}"));
+        }
+        if let Some(network) = &self.restore {
+            steps.push(format!("restore {network}"));
+        }
+        if self.flush {
+            steps.push("flush".to_owned());
+        }
+        f.write_str(&steps.join(", "))
+    }
+}
+
+/// A network, or a single address as a network of its own
+fn parse_network(network: &str) -> Option<IpNet> {
+    network
+        .parse()
+        .ok()
+        .or_else(|| network.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub defaults: Impairments,
+    #[serde(default, rename = "phase")]
+    pub phases: Vec<Phase>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+    #[serde(default, rename = "action")]
+    pub actions: Vec<Action>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found by [`Scenario::lint`]
+#[derive(Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Scenario, ScenarioError> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// The impairments applied at `time` seconds to packets from `src`
+    pub fn impairments_at(&self, time: f64, src: Ipv4Addr) -> Impairments {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.network().is_some_and(|net| net.contains(&src)))
+            .map(Rule::impairments)
+            .unwrap_or_default();
+        let phase = self
+            .phases
+            .iter()
+            .find(|phase| phase.start <= time && time < phase.end())
+            .map(Phase::impairments)
+            .unwrap_or_default();
+
+        rule.or(phase).or(self.defaults)
+    }
+
+    /// Phases sorted by start time
+    pub fn timeline(&self) -> Vec<&Phase> {
+        let mut phases: Vec<_> = self.phases.iter().collect();
+        phases.sort_by(|a, b| a.start.total_cmp(&b.start));
+        phases
+    }
+
+    /// Actions sorted by time. Those at the same time keep their order.
+    pub fn script(&self) -> Vec<&Action> {
+        let mut actions: Vec<_> = self.actions.iter().collect();
+        actions.sort_by(|a, b| a.at.total_cmp(&b.at));
+        actions
+    }
+
+    /// Looks for values out of range, phases that cannot happen or collide,
+    /// rules hidden by others and actions that cannot be done
+    pub fn lint(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let mut report = |severity, message| issues.push(Issue { severity, message });
+
+        let mut check = |what: &str, impairments: Impairments| {
+            if let Some(drop) = impairments.drop.filter(|p| !(0.0..=1.0).contains(p)) {
+                report(
+                    Severity::Error,
+                    format!("{what}: drop probability {drop} is not between 0 and 1"),
+                );
+            }
+        };
+        check("defaults", self.defaults);
+        for (i, phase) in self.phases.iter().enumerate() {
+            check(&phase_label(i, phase), phase.impairments());
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            check(&format!("rule {}", i + 1), rule.impairments());
+        }
+
+        for (i, phase) in self.phases.iter().enumerate() {
+            let label = phase_label(i, phase);
+            if !phase.start.is_finite() || phase.start < 0.0 {
+                report(
+                    Severity::Error,
+                    format!("{label} starts at {} s", phase.start),
+                );
+            }
+            if !phase.duration.is_finite() || phase.duration <= 0.0 {
+                report(
+                    Severity::Error,
+                    format!("{label} lasts {} s", phase.duration),
+                );
+            }
+            if phase.impairments().is_empty() {
+                report(Severity::Warning, format!("{label} changes nothing"));
+            }
+        }
+
+        let timeline = self.timeline();
+        for pair in timeline.windows(2) {
+            if pair[1].start < pair[0].end() {
+                report(
+                    Severity::Error,
+                    format!(
+                        "{} overlaps {}: one starts at {} s, before the other ends at {} s",
+                        phase_name(pair[1]),
+                        phase_name(pair[0]),
+                        pair[1].start,
+                        pair[0].end()
+                    ),
+                );
+            }
+        }
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            let Some(network) = rule.network() else {
+                report(
+                    Severity::Error,
+                    format!("rule {}: invalid source {:?}", i + 1, rule.source),
+                );
+                continue;
+            };
+            if rule.impairments().is_empty() {
+                report(Severity::Warning, format!("rule {} changes nothing", i + 1));
+            }
+            for (j, earlier) in self.rules[..i].iter().enumerate() {
+                if earlier.network().is_some_and(|n| n.contains(&network)) {
+                    report(
+                        Severity::Warning,
+                        format!(
+                            "rule {} never applies: rule {} ({}) matches its sources first",
+                            i + 1,
+                            j + 1,
+                            earlier.source
+                        ),
+                    );
+                } else if earlier.network().is_some_and(|n| network.contains(&n)) {
+                    report(
+                        Severity::Warning,
+                        format!(
+                            "rule {} overlaps rule {} ({}), which takes precedence",
+                            i + 1,
+                            j + 1,
+                            earlier.source
+                        ),
+                    );
+                }
+            }
+        }
+
+        let mut blackholed = Vec::new();
+        for action in self.script() {
+            let label = format!("action at {} s", action.at);
+            if !action.at.is_finite() || action.at < 0.0 {
+                report(Severity::Error, format!("{label} cannot happen"));
+            }
+            if action.is_empty() {
+                report(Severity::Warning, format!("{label} does nothing"));
+            }
+            if let Some(Err(e)) = action
+                .set
+                .as_deref()
+                .map(|settings| Profile::new(0.0, 0, 0)?.with_settings(settings))
+            {
+                report(Severity::Error, format!("{label}: {e}"));
+            }
+            match (&action.blackhole, action.blackhole_network()) {
+                (Some(network), None) => {
+                    report(
+                        Severity::Error,
+                        format!("{label}: invalid network {network:?} to blackhole"),
+                    );
+                }
+                (_, Some(network)) => blackholed.push(network),
+                (None, None) => (),
+            }
+            match (&action.restore, action.restore_network()) {
+                (Some(network), None) => {
+                    report(
+                        Severity::Error,
+                        format!("{label}: invalid network {network:?} to restore"),
+                    );
+                }
+                (_, Some(network)) if !blackholed.contains(&network) => {
+                    report(
+                        Severity::Warning,
+                        format!("{label} restores {network}, which is not blackholed before"),
+                    );
+                }
+                (_, Some(network)) => blackholed.retain(|blackholed| *blackholed != network),
+                (None, None) => (),
+            }
+        }
+
+        issues.sort_by_key(|issue| std::cmp::Reverse(issue.severity));
+        issues
+    }
+}
+
+fn phase_name(phase: &Phase) -> String {
+    match &phase.name {
+        Some(name) => format!("phase {name:?}"),
+        None => format!("phase at {} s", phase.start),
+    }
+}
+
+fn phase_label(index: usize, phase: &Phase) -> String {
+    match &phase.name {
+        Some(name) => format!("phase {} ({name:?})", index + 1),
+        None => format!("phase {}", index + 1),
+    }
+}
@@ -0,0 +1,104 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Impairment schedules: changes of the shared profile at given times since
+//! the router started, so the network conditions change along a lab session
+//! without anybody touching the router.
+
+use crate::profile::{Profile, ProfileError, SharedProfile};
+use log::info;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Changes of the impairments, shared among the traffic processing threads.
+/// Whichever finds a change due first applies it.
+pub struct Schedule {
+    /// When every change happens, in order, and the profile from then on
+    changes: Vec<(Duration, Profile)>,
+    /// Index of the next change to apply
+    next: AtomicUsize,
+}
+
+impl Schedule {
+    /// Applies the `key=value` settings of every change over the profile in
+    /// effect before it, starting with `base`
+    pub fn new<'a>(
+        base: &Profile,
+        changes: impl IntoIterator<Item = (Duration, &'a str)>,
+    ) -> Result<Schedule, ProfileError> {
+        let mut changes: Vec<_> = changes.into_iter().collect();
+        changes.sort_by_key(|&(at, _)| at);
+
+        let mut profile = base.clone();
+        let changes = changes
+            .into_iter()
+            .map(|(at, settings)| {
+                profile = profile.with_settings(settings)?;
+                Ok((at, profile.clone()))
+            })
+            .collect::<Result<_, ProfileError>>()?;
+
+        Ok(Schedule {
+            changes,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// When every change happens, and the profile from then on
+    pub fn changes(&self) -> impl Iterator<Item = &(Duration, Profile)> {
+        self.changes.iter()
+    }
+
+    /// When the next change is due, for a router started at `start`
+    pub fn next(&self, start: Instant) -> Option<Instant> {
+        self.changes
+            .get(self.next.load(Ordering::Acquire))
+            .map(|(at, _)| start + *at)
+    }
+
+    /// Sets in `profile` the latest change due at `now`, for a router
+    /// started at `start`, unless it was already set
+    pub fn apply(&self, start: Instant, now: Instant, profile: &SharedProfile) {
+        let mut next = self.next.load(Ordering::Acquire);
+        loop {
+            let due = self.changes[next..]
+                .iter()
+                .take_while(|(at, _)| start + *at <= now)
+                .count();
+            if due == 0 {
+                return;
+            }
+
+            match self
+                .next
+                .compare_exchange(next, next + due, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    let (at, new) = &self.changes[next + due - 1];
+                    info!(
+                        "Impairments changed as scheduled at {} s: {}",
+                        at.as_secs_f64(),
+                        new
+                    );
+                    profile.set(new.clone());
+                    return;
+                }
+                // Another thread applied some of them
+                Err(current) => next = current,
+            }
+        }
+    }
+}
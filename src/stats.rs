@@ -0,0 +1,553 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::packet::PacketError;
+use crate::queue::QueueLimit;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Buckets of the delay histogram. Bucket `i` counts delays below `2^i` ms
+/// and, except the first one, not below `2^(i-1)` ms. The last one counts
+/// every longer delay.
+pub const DELAY_BUCKETS: usize = 16;
+
+/// Buckets of the latency histogram: one for every microsecond below 32,
+/// then 16 for every power of two, so percentiles are off by 3% at most
+const LATENCY_BUCKETS: usize = 32 + 59 * 16;
+
+/// Flows tracked at most, those seen by several threads once for each.
+/// Packets of newer flows only count in the totals.
+pub const MAX_FLOWS: usize = 4096;
+
+/// Counters of the packets from a source to a destination
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlowCounters {
+    pub received: usize,
+    pub bytes: usize,
+    pub dropped: usize,
+    pub sent: usize,
+}
+
+/// Something that happened to a packet of a flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowEvent {
+    /// Arrived, with this size
+    Received(usize),
+    Dropped,
+    Sent,
+}
+
+type FlowTable = HashMap<(SocketAddr, SocketAddr), FlowCounters>;
+
+/// Identifies the flow tables of every `Stats`, for the threads to tell
+/// their own tables apart
+static NEXT_FLOW_TABLES: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Tables of this thread, by the id of the `FlowTables` they belong to
+    static FLOW_TABLES: RefCell<Vec<(usize, Arc<Mutex<FlowTable>>)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Counters of every flow. Each thread keeps a table of its own, so they do
+/// not contend for a lock on every packet, merged when read.
+pub struct FlowTables {
+    id: usize,
+    tables: Mutex<Vec<Arc<Mutex<FlowTable>>>>,
+    /// Flows in all the tables, those in several counted once for each
+    flows: AtomicUsize,
+}
+
+impl Default for FlowTables {
+    fn default() -> Self {
+        FlowTables {
+            id: NEXT_FLOW_TABLES.fetch_add(1, Ordering::Relaxed),
+            tables: Mutex::new(Vec::new()),
+            flows: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl FlowTables {
+    /// Runs `f` on the table of the calling thread, created on first use
+    fn with_local<T>(&self, f: impl FnOnce(&mut FlowTable) -> T) -> T {
+        FLOW_TABLES.with_borrow_mut(|tables| {
+            let index = match tables.iter().position(|(id, _)| *id == self.id) {
+                Some(index) => index,
+                None => {
+                    // Only this thread holds those of dropped stats
+                    tables.retain(|(_, table)| Arc::strong_count(table) > 1);
+                    let table = Arc::new(Mutex::new(FlowTable::new()));
+                    self.tables.lock().unwrap().push(table.clone());
+                    tables.push((self.id, table));
+                    tables.len() - 1
+                }
+            };
+            f(&mut tables[index].1.lock().unwrap())
+        })
+    }
+
+    fn count(&self, src: SocketAddr, dst: SocketAddr, event: FlowEvent) {
+        self.with_local(|flows| self.count_in(flows, src, dst, event));
+    }
+
+    fn count_in(&self, flows: &mut FlowTable, src: SocketAddr, dst: SocketAddr, event: FlowEvent) {
+        let flow = match flows.get_mut(&(src, dst)) {
+            Some(flow) => flow,
+            None if self.flows.load(Ordering::Relaxed) >= MAX_FLOWS => return,
+            None => {
+                self.flows.fetch_add(1, Ordering::Relaxed);
+                flows.entry((src, dst)).or_default()
+            }
+        };
+        match event {
+            FlowEvent::Received(len) => {
+                flow.received += 1;
+                flow.bytes += len;
+            }
+            FlowEvent::Dropped => flow.dropped += 1,
+            FlowEvent::Sent => flow.sent += 1,
+        }
+    }
+
+    /// Counters of every thread, added up
+    fn merged(&self) -> FlowTable {
+        let mut merged = FlowTable::new();
+        for table in self.tables.lock().unwrap().iter() {
+            for (&flow, counters) in table.lock().unwrap().iter() {
+                let total = merged.entry(flow).or_default();
+                total.received += counters.received;
+                total.bytes += counters.bytes;
+                total.dropped += counters.dropped;
+                total.sent += counters.sent;
+            }
+        }
+        merged
+    }
+}
+
+/// Counters of a flow, as exported to JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlowSnapshot {
+    pub src: String,
+    pub dst: String,
+    #[serde(flatten)]
+    pub counters: FlowCounters,
+}
+
+/// Time packets spend in the router, from their arrival until they leave
+pub struct Latency {
+    packets: AtomicUsize,
+    total_us: AtomicU64,
+    min_us: AtomicU64,
+    max_us: AtomicU64,
+    histogram: Box<[AtomicUsize]>,
+}
+
+impl Default for Latency {
+    fn default() -> Latency {
+        Latency {
+            packets: AtomicUsize::new(0),
+            total_us: AtomicU64::new(0),
+            min_us: AtomicU64::new(u64::MAX),
+            max_us: AtomicU64::new(0),
+            histogram: (0..LATENCY_BUCKETS).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+fn latency_bucket(us: u64) -> usize {
+    if us < 32 {
+        return us as usize;
+    }
+    let bits = 63 - us.leading_zeros() as usize;
+    32 + (bits - 5) * 16 + (us >> (bits - 4)) as usize % 16
+}
+
+/// Middle of the latencies counted in `bucket`, in microseconds
+fn latency_bucket_middle(bucket: usize) -> f64 {
+    if bucket < 32 {
+        return bucket as f64;
+    }
+    let (bits, step) = ((bucket - 32) / 16 + 5, (bucket - 32) % 16);
+    let width = 1u64 << (bits - 4);
+    ((16 + step as u64) * width) as f64 + width as f64 / 2.0
+}
+
+impl Latency {
+    pub fn count(&self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.min_us.fetch_min(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+        self.histogram[latency_bucket(us)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// None until a packet leaves
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let packets = self.packets.load(Ordering::Relaxed);
+        if packets == 0 {
+            return None;
+        }
+        let (min, max) = (
+            self.min_us.load(Ordering::Relaxed),
+            self.max_us.load(Ordering::Relaxed),
+        );
+
+        let rank = (packets as f64 * 0.95).ceil() as usize;
+        let mut seen = 0;
+        let p95 = self
+            .histogram
+            .iter()
+            .position(|count| {
+                seen += count.load(Ordering::Relaxed);
+                seen >= rank
+            })
+            .map_or(max as f64, latency_bucket_middle)
+            .clamp(min as f64, max as f64);
+
+        Some(LatencySummary {
+            packets,
+            min_ms: min as f64 / 1000.0,
+            mean_ms: self.total_us.load(Ordering::Relaxed) as f64 / packets as f64 / 1000.0,
+            p95_ms: p95 / 1000.0,
+            max_ms: max as f64 / 1000.0,
+        })
+    }
+}
+
+/// Time spent in the router by the packets sent, as exported to JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LatencySummary {
+    pub packets: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {:.3} ms, mean {:.3} ms, p95 {:.3} ms, max {:.3} ms",
+            self.min_ms, self.mean_ms, self.p95_ms, self.max_ms
+        )
+    }
+}
+
+/// Occupancy of the queues over time, integrated by every processing thread
+#[derive(Default)]
+pub struct Occupancy {
+    /// Packets queued times the microseconds they stayed queued
+    area: AtomicU64,
+    max: AtomicUsize,
+}
+
+impl Occupancy {
+    /// Accounts `queued` packets having been in a queue `during` some time
+    pub fn count(&self, queued: usize, during: Duration) {
+        self.area
+            .fetch_add(queued as u64 * during.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Accounts `queued` packets being in the queues right now
+    pub fn peak(&self, queued: usize) {
+        self.max.fetch_max(queued, Ordering::Relaxed);
+    }
+
+    /// Time weighted average of the packets queued along `duration`
+    pub fn mean(&self, duration: Duration) -> f64 {
+        match duration.as_micros() {
+            0 => 0.0,
+            micros => self.area.load(Ordering::Relaxed) as f64 / micros as f64,
+        }
+    }
+
+    /// Most packets queued at once
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters shared by every traffic processing thread
+#[derive(Default)]
+pub struct Stats {
+    pub received: AtomicUsize,
+    pub bytes_received: AtomicUsize,
+    /// Packets forwarded to their destination
+    pub sent: AtomicUsize,
+    pub bytes_sent: AtomicUsize,
+    pub queued: AtomicUsize,
+    pub occupancy: Occupancy,
+    /// Buffers held for reuse by the processing threads
+    pub pooled: AtomicUsize,
+    pub send_retries: AtomicUsize,
+    pub send_errors: AtomicUsize,
+    pub random_drops: AtomicUsize,
+    pub trace_drops: AtomicUsize,
+    pub rate_delays: AtomicUsize,
+    pub rate_drops: AtomicUsize,
+    pub queue_drops: AtomicUsize,
+    pub red_drops: AtomicUsize,
+    pub codel_drops: AtomicUsize,
+    pub hop_limit_drops: AtomicUsize,
+    pub nat_drops: AtomicUsize,
+    pub acl_drops: AtomicUsize,
+    pub blackhole_drops: AtomicUsize,
+    pub reflection_drops: AtomicUsize,
+    pub oversize_drops: AtomicUsize,
+    pub fragmented: AtomicUsize,
+    pub source_rate_drops: AtomicUsize,
+    pub duplicated: AtomicUsize,
+    pub reordered: AtomicUsize,
+    pub corrupted: AtomicUsize,
+    pub truncated: AtomicUsize,
+    pub hop_drops: AtomicUsize,
+    /// Packets discarded when the queue was flushed
+    pub flushed: AtomicUsize,
+    /// Packets still queued when the drain timeout expired on shutdown
+    pub drain_discards: AtomicUsize,
+    pub overflow_drops: AtomicUsize,
+    pub client_limit_drops: AtomicUsize,
+    pub unreachable: AtomicUsize,
+    pub malformed_short: AtomicUsize,
+    pub malformed_unspecified: AtomicUsize,
+    pub malformed_zero_port: AtomicUsize,
+    pub malformed_reserved: AtomicUsize,
+    pub malformed_other: AtomicUsize,
+    pub delay_histogram: [AtomicUsize; DELAY_BUCKETS],
+    pub latency: Latency,
+    pub flows: FlowTables,
+    /// Queue limit of every processing thread, if any
+    pub queue_limit: Option<QueueLimit>,
+}
+
+/// Bucket of the delay histogram
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DelayBucket {
+    /// Upper bound of the bucket, in milliseconds. None for the last one.
+    pub below_ms: Option<u64>,
+    pub count: usize,
+}
+
+/// Stats of a whole execution, as exported to JSON
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsSnapshot {
+    pub version: String,
+    pub duration_ms: u64,
+    pub counters: BTreeMap<String, usize>,
+    pub delay_histogram: Vec<DelayBucket>,
+    /// Time spent in the router by the packets sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencySummary>,
+    /// Address the router is reachable at from the Internet, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_limit: Option<QueueLimit>,
+    /// Counters of every flow, by source and destination
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flows: Vec<FlowSnapshot>,
+}
+
+impl StatsSnapshot {
+    /// Value of `counter` per second of execution
+    pub fn rate(&self, counter: &str) -> f64 {
+        let value = self.counters.get(counter).copied().unwrap_or(0);
+        if self.duration_ms == 0 {
+            0.0
+        } else {
+            value as f64 * 1000.0 / self.duration_ms as f64
+        }
+    }
+}
+
+impl Stats {
+    pub fn add(counter: &AtomicUsize, value: usize) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Updates a gauge that moved from `old` to `new`
+    pub fn adjust(gauge: &AtomicUsize, old: usize, new: usize) {
+        if new > old {
+            gauge.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            gauge.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Packets dropped for any reason, including those discarded from the
+    /// queue by flushes and on shutdown
+    pub fn dropped(&self) -> usize {
+        [
+            &self.send_errors,
+            &self.random_drops,
+            &self.trace_drops,
+            &self.rate_drops,
+            &self.queue_drops,
+            &self.red_drops,
+            &self.codel_drops,
+            &self.hop_limit_drops,
+            &self.nat_drops,
+            &self.acl_drops,
+            &self.blackhole_drops,
+            &self.reflection_drops,
+            &self.oversize_drops,
+            &self.source_rate_drops,
+            &self.hop_drops,
+            &self.flushed,
+            &self.drain_discards,
+            &self.overflow_drops,
+            &self.client_limit_drops,
+            &self.malformed_short,
+            &self.malformed_unspecified,
+            &self.malformed_zero_port,
+            &self.malformed_reserved,
+            &self.malformed_other,
+        ]
+        .into_iter()
+        .map(Stats::get)
+        .sum()
+    }
+
+    /// Accounts the delay given to a packet in the histogram
+    pub fn count_delay(&self, delay: Duration) {
+        let ms = delay.as_millis();
+        let bucket = (u128::BITS - ms.leading_zeros()) as usize;
+        Stats::add(&self.delay_histogram[bucket.min(DELAY_BUCKETS - 1)], 1);
+    }
+
+    /// Accounts an event in the counters of the flow from `src` to `dst`
+    pub fn count_flow(&self, src: SocketAddr, dst: SocketAddr, event: FlowEvent) {
+        self.flows.count(src, dst, event);
+    }
+
+    /// Counters of every flow, sorted by source and destination
+    pub fn flows(&self) -> Vec<(SocketAddr, SocketAddr, FlowCounters)> {
+        let mut flows: Vec<_> = self
+            .flows
+            .merged()
+            .into_iter()
+            .map(|((src, dst), counters)| (src, dst, counters))
+            .collect();
+        flows.sort_by_key(|&(src, dst, _)| (src, dst));
+        flows
+    }
+
+    /// Current values, for an execution that lasted `duration`
+    pub fn snapshot(&self, duration: Duration) -> StatsSnapshot {
+        let counters = [
+            ("received", &self.received),
+            ("bytes_received", &self.bytes_received),
+            ("sent", &self.sent),
+            ("bytes_sent", &self.bytes_sent),
+            ("send_retries", &self.send_retries),
+            ("send_errors", &self.send_errors),
+            ("random_drops", &self.random_drops),
+            ("trace_drops", &self.trace_drops),
+            ("rate_delays", &self.rate_delays),
+            ("rate_drops", &self.rate_drops),
+            ("queue_drops", &self.queue_drops),
+            ("red_drops", &self.red_drops),
+            ("codel_drops", &self.codel_drops),
+            ("hop_limit_drops", &self.hop_limit_drops),
+            ("nat_drops", &self.nat_drops),
+            ("acl_drops", &self.acl_drops),
+            ("blackhole_drops", &self.blackhole_drops),
+            ("reflection_drops", &self.reflection_drops),
+            ("oversize_drops", &self.oversize_drops),
+            ("fragmented", &self.fragmented),
+            ("source_rate_drops", &self.source_rate_drops),
+            ("duplicated", &self.duplicated),
+            ("reordered", &self.reordered),
+            ("corrupted", &self.corrupted),
+            ("truncated", &self.truncated),
+            ("hop_drops", &self.hop_drops),
+            ("flushed", &self.flushed),
+            ("drain_discards", &self.drain_discards),
+            ("overflow_drops", &self.overflow_drops),
+            ("client_limit_drops", &self.client_limit_drops),
+            ("unreachable", &self.unreachable),
+            ("malformed_short", &self.malformed_short),
+            ("malformed_unspecified", &self.malformed_unspecified),
+            ("malformed_zero_port", &self.malformed_zero_port),
+            ("malformed_reserved", &self.malformed_reserved),
+            ("malformed_other", &self.malformed_other),
+        ];
+
+        StatsSnapshot {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            duration_ms: duration.as_millis() as u64,
+            counters: counters
+                .into_iter()
+                .map(|(name, counter)| (name.to_owned(), Stats::get(counter)))
+                .collect(),
+            delay_histogram: self
+                .delay_histogram
+                .iter()
+                .enumerate()
+                .map(|(i, count)| DelayBucket {
+                    below_ms: (i < DELAY_BUCKETS - 1).then(|| 1 << i),
+                    count: Stats::get(count),
+                })
+                .collect(),
+            latency: self.latency.summary(),
+            public_address: None,
+            queue_limit: self.queue_limit,
+            flows: self
+                .flows()
+                .into_iter()
+                .map(|(src, dst, counters)| FlowSnapshot {
+                    src: src.to_string(),
+                    dst: dst.to_string(),
+                    counters,
+                })
+                .collect(),
+        }
+    }
+
+    /// Accounts a packet rejected by the parser under its category
+    pub fn count_malformed(&self, error: &PacketError) {
+        Stats::add(
+            match error {
+                PacketError::InvalidLenth(_) | PacketError::NotEnoughData() => {
+                    &self.malformed_short
+                }
+                PacketError::UnspecifiedAddress => &self.malformed_unspecified,
+                PacketError::ZeroPort => &self.malformed_zero_port,
+                PacketError::ReservedAddress(_) => &self.malformed_reserved,
+                PacketError::TooLong(_)
+                | PacketError::UnsupportedVersion(_)
+                | PacketError::UnknownFlags(_)
+                | PacketError::Unknown => &self.malformed_other,
+            },
+            1,
+        );
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Public address discovery with STUN (RFC 5389) binding requests.
+
+use crate::net;
+use rand::Rng;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+
+/// Requests sent before giving up
+const ATTEMPTS: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum StunError {
+    #[error("could not resolve STUN server {0}")]
+    Resolve(String),
+    #[error("STUN server {0} did not answer")]
+    Timeout(String),
+    #[error("STUN I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+pub type TransactionId = [u8; 12];
+
+/// Encodes a binding request without attributes
+pub fn binding_request(transaction: &TransactionId) -> [u8; HEADER_LEN] {
+    let mut request = [0; HEADER_LEN];
+    request[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..20].copy_from_slice(transaction);
+
+    request
+}
+
+/// Extracts the mapped address of a binding success response to the request
+/// `transaction`. Any other datagram gives `None`.
+pub fn parse_response(data: &[u8], transaction: &TransactionId) -> Option<SocketAddrV4> {
+    if data.len() < HEADER_LEN
+        || u16::from_be_bytes([data[0], data[1]]) != BINDING_SUCCESS
+        || u32::from_be_bytes(data[4..8].try_into().unwrap()) != MAGIC_COOKIE
+        || data[8..20] != transaction[..]
+    {
+        return None;
+    }
+
+    let len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+    let mut attributes = data.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes.get(4..4 + len)?;
+
+        if value.len() >= 8 && value[1] == FAMILY_IPV4 {
+            let port = u16::from_be_bytes([value[2], value[3]]);
+            let ip = u32::from_be_bytes(value[4..8].try_into().unwrap());
+            match kind {
+                XOR_MAPPED_ADDRESS => {
+                    // Preferred, as some NATs rewrite addresses found in payloads
+                    return Some(SocketAddrV4::new(
+                        Ipv4Addr::from(ip ^ MAGIC_COOKIE),
+                        port ^ (MAGIC_COOKIE >> 16) as u16,
+                    ));
+                }
+                MAPPED_ADDRESS => mapped = Some(SocketAddrV4::new(Ipv4Addr::from(ip), port)),
+                _ => (),
+            }
+        }
+
+        // Attributes are padded to a multiple of four bytes
+        attributes = attributes
+            .get((4 + len).next_multiple_of(4)..)
+            .unwrap_or(&[]);
+    }
+
+    mapped
+}
+
+/// Asks `server` the public address of `socket`, which must be in blocking
+/// mode. Datagrams other than the answer received meanwhile are discarded.
+pub fn discover(
+    socket: &UdpSocket,
+    server: &str,
+    timeout: Duration,
+) -> Result<SocketAddrV4, StunError> {
+    let server_addr = server
+        .to_socket_addrs()
+        .map_err(|_| StunError::Resolve(server.to_owned()))?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| StunError::Resolve(server.to_owned()))?;
+    let server_addr = net::for_socket(server_addr, socket.local_addr()?.is_ipv6());
+    let transaction: TransactionId = rand::thread_rng().gen();
+    let request = binding_request(&transaction);
+    let previous_timeout = socket.read_timeout()?;
+    let mut buf = [0; 1500];
+
+    let result = (|| {
+        for _ in 0..ATTEMPTS {
+            socket.send_to(&request, server_addr)?;
+            let deadline = Instant::now() + timeout;
+
+            while let Some(left) = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|left| !left.is_zero())
+            {
+                socket.set_read_timeout(Some(left))?;
+                match socket.recv_from(&mut buf) {
+                    Ok((len, from)) if from == server_addr => {
+                        if let Some(addr) = parse_response(&buf[..len], &transaction) {
+                            return Ok(addr);
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock
+                                | io::ErrorKind::TimedOut
+                                | io::ErrorKind::ConnectionRefused
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Err(StunError::Timeout(server.to_owned()))
+    })();
+
+    socket.set_read_timeout(previous_timeout)?;
+    result
+}
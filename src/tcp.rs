@@ -0,0 +1,384 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! TCP relay mode.
+//!
+//! Messages travel over TCP connections, each one preceded by its length as
+//! two bytes in network byte order and starting with the same header as
+//! datagrams. The router opens a connection to every destination the first
+//! time it relays a message there, and reads the replies sent back on it.
+//! Messages addressed to a peer already connected to the router reuse that
+//! connection. Connections are opened by threads of their own, so those that
+//! take long do not hold back the messages to other destinations.
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use rand::distributions::Distribution;
+use shufflerouter::buffer::Buffer;
+use shufflerouter::net;
+use shufflerouter::packet::Packet;
+use shufflerouter::queue::Queue;
+use shufflerouter::router::Settings;
+use shufflerouter::stats::Stats;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most connections open, or being opened, at once. Each one takes a thread.
+const MAX_CONNECTIONS: usize = 256;
+/// How often idle threads check the shutdown flag
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Relay {
+    settings: Settings,
+    stats: Arc<Stats>,
+    shutdown: Arc<AtomicBool>,
+    /// Write halves of the open connections, by peer address
+    connections: Mutex<HashMap<SocketAddr, TcpStream>>,
+    /// Messages waiting for the connections being opened, by destination
+    connecting: Mutex<HashMap<SocketAddr, Vec<Packet>>>,
+    /// How many connections are open or being opened
+    open: Arc<AtomicUsize>,
+    queue: Mutex<Queue>,
+    queued: Condvar,
+}
+
+/// Starts relaying the messages of the connections accepted by `listener`
+/// until `shutdown` is set. The returned thread ends once the queue is
+/// drained and every connection closed.
+pub(crate) fn spawn(
+    listener: TcpListener,
+    settings: Settings,
+    stats: Arc<Stats>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    listener.set_nonblocking(true)?;
+    let relay = Arc::new(Relay {
+        settings,
+        stats,
+        shutdown,
+        connections: Mutex::new(HashMap::new()),
+        connecting: Mutex::new(HashMap::new()),
+        open: Arc::new(AtomicUsize::new(0)),
+        queue: Mutex::new(Queue::new()),
+        queued: Condvar::new(),
+    });
+
+    let acceptor = relay.clone();
+    thread::Builder::new()
+        .name("tcp-accept".into())
+        .spawn(move || acceptor.accept(listener))?;
+
+    Ok(thread::Builder::new()
+        .name("tcp-delivery".into())
+        .spawn(move || relay.deliver())?)
+}
+
+fn read_message(stream: &mut TcpStream, buffer: &mut Buffer) -> io::Result<Option<usize>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+
+    let len = usize::from(u16::from_be_bytes(len));
+    if len > buffer.capacity() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{len} bytes message, longer than {}", buffer.capacity()),
+        ));
+    }
+    stream.read_exact(&mut buffer[..len])?;
+    buffer.set_len(len);
+
+    Ok(Some(len))
+}
+
+/// One of the `MAX_CONNECTIONS`, given back when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn reserve(open: &Arc<AtomicUsize>) -> io::Result<Slot> {
+        if open.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::Relaxed);
+            return Err(io::Error::other(format!(
+                "already {MAX_CONNECTIONS} TCP connections open"
+            )));
+        }
+
+        Ok(Slot(open.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Relay {
+    fn accept(self: Arc<Self>, listener: TcpListener) {
+        while !self.shutdown.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    info!("TCP connection from {}", peer);
+                    let registered = Slot::reserve(&self.open)
+                        .and_then(|slot| self.clone().register(stream, net::canonical(peer), slot));
+                    if let Err(e) = registered {
+                        warn!("Could not handle the connection from {}: {}", peer, e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => warn!("Error accepting TCP connection: {}", e),
+            }
+        }
+    }
+
+    /// Keeps the write half of `stream` and reads the messages sent through
+    /// it, holding `slot` while open
+    fn register(
+        self: Arc<Self>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        slot: Slot,
+    ) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(peer, stream.try_clone()?);
+
+        thread::Builder::new()
+            .name(format!("tcp-{peer}"))
+            .spawn(move || {
+                let _slot = slot;
+                self.read(stream, peer)
+            })?;
+
+        Ok(())
+    }
+
+    fn read(&self, mut stream: TcpStream, peer: SocketAddr) {
+        loop {
+            let mut buffer = Buffer::default();
+            match read_message(&mut stream, &mut buffer) {
+                Ok(Some(len)) => self.admit(peer, buffer, len),
+                Ok(None) => {
+                    debug!("TCP connection with {} closed", peer);
+                    break;
+                }
+                Err(e) => {
+                    if !self.shutdown.load(Ordering::Relaxed) {
+                        warn!("Closing TCP connection with {}: {}", peer, e);
+                    }
+                    break;
+                }
+            }
+        }
+
+        self.close(peer);
+    }
+
+    /// Decides the fate of a message received from `peer`
+    fn admit(&self, peer: SocketAddr, buffer: Buffer, len: usize) {
+        let arrival = self.settings.clock.now();
+        let (profile, _) = self.settings.profile.load();
+        let mut rng = rand::thread_rng();
+
+        debug!("Received {} bytes from {} over TCP", len, peer);
+        Stats::add(&self.stats.received, 1);
+
+        if profile.drop_distribution().sample(&mut rng) {
+            info!("Τύχη decided it. Message dropped.");
+            Stats::add(&self.stats.random_drops, 1);
+            return;
+        }
+
+        let delay = Duration::from_millis(profile.delay_distribution().sample(&mut rng));
+        let packet = if self.settings.strict {
+            Packet::create_strict(peer, buffer, arrival, arrival + delay)
+        } else {
+            Packet::create(peer, buffer, arrival, arrival + delay)
+        };
+        match packet {
+            // The same destinations are refused as for datagrams
+            Ok(packet) if !self.settings.acl.permits(packet.dst().ip()) => {
+                info!("Destination not allowed. Message dropped.");
+                Stats::add(&self.stats.acl_drops, 1);
+            }
+            Ok(packet) => match self.settings.acl.refusal(packet.dst()) {
+                Some(refusal) => {
+                    info!("Destination is {}. Message dropped.", refusal);
+                    Stats::add(&self.stats.reflection_drops, 1);
+                }
+                None => {
+                    self.stats.count_delay(delay);
+                    self.queue.lock().unwrap().push(packet);
+                    self.queued.notify_one();
+                }
+            },
+            Err(e) => {
+                warn!("Could not parse message from {}: {}", peer, e);
+                self.stats.count_malformed(&e);
+            }
+        }
+    }
+
+    fn close(&self, peer: SocketAddr) {
+        if let Some(stream) = self.connections.lock().unwrap().remove(&peer) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Sends `packet` through the connection with its destination, or once
+    /// it is opened
+    fn send(self: &Arc<Self>, packet: Packet) {
+        let dst = packet.dst();
+        // Held until queued, so the connection is not opened meanwhile and
+        // the message does not overtake those waiting for it
+        let mut connecting = self.connecting.lock().unwrap();
+        if let Some(waiting) = connecting.get_mut(&dst) {
+            waiting.push(packet);
+            return;
+        }
+
+        let stream = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&dst)
+            .map(TcpStream::try_clone);
+        match stream {
+            Some(stream) => {
+                drop(connecting);
+                self.write(stream, &packet);
+            }
+            None => match self.connect(dst) {
+                Ok(()) => {
+                    connecting.insert(dst, vec![packet]);
+                }
+                Err(e) => self.failed(&packet, &e),
+            },
+        }
+    }
+
+    /// Opens the connection with `dst` on a thread of its own, which then
+    /// sends the messages waiting for it
+    fn connect(self: &Arc<Self>, dst: SocketAddr) -> io::Result<()> {
+        let slot = Slot::reserve(&self.open)?;
+        let relay = self.clone();
+        thread::Builder::new()
+            .name(format!("tcp-connect-{dst}"))
+            .spawn(move || {
+                let stream = TcpStream::connect_timeout(&dst, CONNECT_TIMEOUT).and_then(|stream| {
+                    info!("TCP connection to {}", dst);
+                    relay.clone().register(stream.try_clone()?, dst, slot)?;
+                    Ok(stream)
+                });
+
+                // Sent before unlocking, so later messages do not overtake them
+                let mut connecting = relay.connecting.lock().unwrap();
+                for packet in connecting.remove(&dst).unwrap_or_default() {
+                    match &stream {
+                        Ok(stream) => relay.write(stream.try_clone(), &packet),
+                        Err(e) => relay.failed(&packet, e),
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn write(&self, stream: io::Result<TcpStream>, packet: &Packet) {
+        let len = packet.get().len() as u16;
+        let written = stream.and_then(|mut stream| {
+            stream.write_all(&[&len.to_be_bytes()[..], packet.get()].concat())
+        });
+
+        match written {
+            Ok(()) => {
+                debug!(
+                    "Sent {} bytes to {} over TCP",
+                    packet.get().len(),
+                    packet.dst()
+                );
+                Stats::add(&self.stats.bytes_sent, packet.get().len());
+            }
+            Err(e) => {
+                self.failed(packet, &e);
+                self.close(packet.dst());
+            }
+        }
+    }
+
+    fn failed(&self, packet: &Packet, e: &io::Error) {
+        warn!(
+            "Error relaying {} bytes to {}: {}. Message dropped",
+            packet.get().len(),
+            packet.dst(),
+            e
+        );
+        Stats::add(&self.stats.send_errors, 1);
+    }
+
+    fn deliver(self: Arc<Self>) {
+        let mut drain_deadline = None;
+
+        loop {
+            let now = self.settings.clock.now();
+            if drain_deadline.is_none() && self.shutdown.load(Ordering::Relaxed) {
+                drain_deadline = Some(now + self.settings.drain_timeout);
+            }
+
+            let mut due = Vec::new();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                while queue.peek().is_some_and(|p| p.exit_time() <= now) {
+                    due.push(queue.pop().unwrap());
+                }
+                if due.is_empty() {
+                    let drained = queue.is_empty() && self.connecting.lock().unwrap().is_empty();
+                    if drain_deadline.is_some_and(|deadline| drained || now >= deadline) {
+                        break;
+                    }
+                    let wait = queue
+                        .peek()
+                        .map_or(POLL_INTERVAL, |p| p.exit_time() - now)
+                        .min(POLL_INTERVAL);
+                    let _ = self.queued.wait_timeout(queue, wait).unwrap();
+                    continue;
+                }
+            }
+
+            for packet in due {
+                self.send(packet);
+            }
+        }
+
+        // Unblock the reading threads
+        for (_, stream) in self.connections.lock().unwrap().drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Impairment traces: the delay and fate of successive packets, so the same
+//! pattern can be applied to every run.
+//!
+//! A trace is a text file with one record per line: the delay in
+//! milliseconds, optionally followed by `1` (or `drop`) for packets to be
+//! dropped, separated by spaces or a comma. Empty lines and everything after
+//! a `#` are ignored.
+
+use crate::record::Decision;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TraceError {
+    #[error("could not read the trace: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed trace line {0}: {1:?}")]
+    BadLine(usize, String),
+    #[error("the trace has no records")]
+    Empty,
+}
+
+/// The records of a trace, handed out in order. It can be shared among
+/// threads.
+pub struct ImpairmentTrace {
+    records: Vec<Decision>,
+    wrap: bool,
+    next: AtomicUsize,
+}
+
+fn parse_record(line: &str) -> Option<Decision> {
+    let mut fields = line.split([' ', '\t', ',']).filter(|f| !f.is_empty());
+    let delay = Duration::from_millis(fields.next()?.parse().ok()?);
+    let drop = match fields.next() {
+        None | Some("0") | Some("false") => false,
+        Some("1") | Some("true") | Some("drop") => true,
+        Some(_) => return None,
+    };
+
+    match fields.next() {
+        Some(_) => None,
+        None if drop => Some(Decision::Drop),
+        None => Some(Decision::Delay(delay)),
+    }
+}
+
+impl ImpairmentTrace {
+    /// Parses a trace. Once its records are exhausted, it starts over if
+    /// `wrap` is set, or hands out none otherwise.
+    pub fn parse(text: &str, wrap: bool) -> Result<ImpairmentTrace, TraceError> {
+        let mut records = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            records.push(
+                parse_record(line)
+                    .ok_or_else(|| TraceError::BadLine(number + 1, line.to_owned()))?,
+            );
+        }
+        if records.is_empty() {
+            return Err(TraceError::Empty);
+        }
+
+        Ok(ImpairmentTrace {
+            records,
+            wrap,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn load(path: &Path, wrap: bool) -> Result<ImpairmentTrace, TraceError> {
+        ImpairmentTrace::parse(&fs::read_to_string(path)?, wrap)
+    }
+
+    /// The decision for the next packet, if the trace has not ended
+    pub fn next_decision(&self) -> Option<Decision> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        if self.wrap {
+            Some(self.records[index % self.records.len()])
+        } else {
+            self.records.get(index).copied()
+        }
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Terminal dashboard: the packet and drop rates, the queue and the delays
+//! given, redrawn every second, for demonstrations in class.
+
+use anyhow::{ensure, Result};
+use shufflerouter::router::Settings;
+use shufflerouter::stats::{Stats, StatsSnapshot};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REFRESH: Duration = Duration::from_secs(1);
+/// Refreshes the delay histogram covers
+const HISTOGRAM_WINDOW: usize = 10;
+/// Characters of the longest bar
+const BAR_WIDTH: usize = 40;
+
+/// Switch to the alternate screen, hiding the cursor, and back
+const ENTER: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE: &str = "\x1b[?25h\x1b[?1049l";
+/// Move to the top left corner and clear the screen
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Counters at a refresh
+struct Sample {
+    received: usize,
+    dropped: usize,
+    bytes_sent: usize,
+    delays: Vec<usize>,
+}
+
+impl Sample {
+    fn new(snapshot: &StatsSnapshot) -> Sample {
+        let counter = |name| snapshot.counters.get(name).copied().unwrap_or(0);
+
+        Sample {
+            received: counter("received"),
+            dropped: snapshot
+                .counters
+                .iter()
+                .filter(|(name, _)| name.ends_with("_drops"))
+                .map(|(_, value)| value)
+                .sum(),
+            bytes_sent: counter("bytes_sent"),
+            delays: snapshot.delay_histogram.iter().map(|b| b.count).collect(),
+        }
+    }
+}
+
+fn bar(value: usize, max: usize) -> String {
+    "#".repeat((value * BAR_WIDTH).checked_div(max).unwrap_or(0))
+}
+
+fn draw(
+    settings: &Settings,
+    stats: &Stats,
+    port: u16,
+    samples: &VecDeque<Sample>,
+    max_queued: usize,
+) -> String {
+    let (now, last) = (&samples[samples.len() - 1], &samples[samples.len() - 2]);
+    let oldest = &samples[0];
+    let uptime = settings.uptime().as_secs();
+    let queued = Stats::get(&stats.queued);
+    let (received, dropped) = (now.received - last.received, now.dropped - last.dropped);
+    let mut screen = String::from(CLEAR);
+
+    let _ = writeln!(
+        screen,
+        "ShuffleRouter {} on port {}, up for {}:{:02}:{:02}. Ctrl+C to stop.",
+        env!("CARGO_PKG_VERSION"),
+        port,
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60
+    );
+    let _ = writeln!(screen, "Impairments: {}\n", settings.profile.load().0);
+    let _ = writeln!(
+        screen,
+        "Received   {:>8} packets/s  {:>10} in total",
+        received, now.received
+    );
+    let _ = writeln!(
+        screen,
+        "Dropped    {:>8} packets/s  {:>10} in total  {:5.1}%",
+        dropped,
+        now.dropped,
+        match received {
+            0 => 0.0,
+            received => 100.0 * dropped as f64 / received as f64,
+        }
+    );
+    let _ = writeln!(
+        screen,
+        "Sent       {:>8} bytes/s    {:>10} in total",
+        now.bytes_sent - last.bytes_sent,
+        now.bytes_sent
+    );
+    let _ = writeln!(
+        screen,
+        "Queued     {:>8} packets    {}\n",
+        queued,
+        bar(queued, max_queued)
+    );
+
+    let delays: Vec<_> = now
+        .delays
+        .iter()
+        .zip(&oldest.delays)
+        .map(|(now, then)| now - then)
+        .collect();
+    let _ = writeln!(screen, "Delays over the last {} s:", samples.len() - 1);
+    let max = delays.iter().copied().max().unwrap_or(0);
+    let first = delays.iter().position(|&count| count > 0).unwrap_or(0);
+    let last = delays.iter().rposition(|&count| count > 0).unwrap_or(0);
+    for (i, &count) in delays.iter().enumerate().take(last + 1).skip(first) {
+        let bucket = if i + 1 == delays.len() {
+            format!(">= {} ms", 1u64 << (i - 1))
+        } else {
+            format!("< {} ms", 1u64 << i)
+        };
+        let _ = writeln!(screen, "{:>10} {:>8} {}", bucket, count, bar(count, max));
+    }
+
+    screen
+}
+
+/// Shows the dashboard on the terminal until `shutdown` is set, then goes
+/// back to the previous screen
+pub(crate) fn spawn(
+    settings: Settings,
+    stats: Arc<Stats>,
+    port: u16,
+    shutdown: Arc<AtomicBool>,
+) -> Result<thread::JoinHandle<()>> {
+    ensure!(io::stdout().is_terminal(), "--tui needs a terminal");
+
+    Ok(thread::Builder::new().name("tui".into()).spawn(move || {
+        let mut out = io::stdout();
+        let _ = write!(out, "{ENTER}");
+        let snapshot = || stats.snapshot(settings.uptime());
+        let mut samples = VecDeque::from([Sample::new(&snapshot())]);
+        let mut max_queued = 0;
+        let mut next = Instant::now() + REFRESH;
+
+        while !shutdown.load(Ordering::Relaxed) {
+            if Instant::now() < next {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            next += REFRESH;
+
+            samples.push_back(Sample::new(&snapshot()));
+            if samples.len() > HISTOGRAM_WINDOW + 1 {
+                samples.pop_front();
+            }
+            max_queued = max_queued.max(Stats::get(&stats.queued));
+            let screen = draw(&settings, &stats, port, &samples, max_queued);
+            let _ = out.write_all(screen.as_bytes());
+            let _ = out.flush();
+        }
+
+        let _ = write!(out, "{LEAVE}");
+        let _ = out.flush();
+    })?)
+}
@@ -0,0 +1,209 @@
+/*
+ * Copyright (C) 2023 Miguel Rodríguez Pérez <miguel@det.uvigo.gal>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::clock::{Clock, SystemClock};
+use log::warn;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Liveness information published by a traffic processing thread
+pub struct Heartbeat {
+    clock: Arc<dyn Clock>,
+    base: Instant,
+    busy_since: AtomicU64, // Microseconds since base plus one, zero while waiting for events
+    iterations: AtomicU64,
+    queue_len: AtomicUsize,
+}
+
+impl Heartbeat {
+    /// Measures the time the thread spends busy with `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Heartbeat {
+        Heartbeat {
+            base: clock.now(),
+            clock,
+            busy_since: AtomicU64::new(0),
+            iterations: AtomicU64::new(0),
+            queue_len: AtomicUsize::new(0),
+        }
+    }
+
+    /// The thread is about to wait for events. It can block as long as it needs.
+    pub fn idle(&self, queue_len: usize) {
+        self.queue_len.store(queue_len, Ordering::Relaxed);
+        self.busy_since.store(0, Ordering::Relaxed);
+        self.iterations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The thread started processing events
+    pub fn busy(&self) {
+        let now = (self.clock.now() - self.base).as_micros() as u64 + 1;
+        self.busy_since.store(now, Ordering::Relaxed);
+    }
+
+    /// How long the thread has been processing events, if it is
+    fn busy_for(&self) -> Option<Duration> {
+        match self.busy_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => Some(
+                (self.clock.now() - self.base).saturating_sub(Duration::from_micros(since - 1)),
+            ),
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat::new(Arc::new(SystemClock))
+    }
+}
+
+/// Client of the systemd service notification protocol
+#[cfg(target_os = "linux")]
+pub struct SystemdNotifier {
+    socket: std::os::unix::net::UnixDatagram,
+    addr: std::os::unix::net::SocketAddr,
+    interval: Option<Duration>,
+}
+
+#[cfg(target_os = "linux")]
+impl SystemdNotifier {
+    /// Connects to the socket in `NOTIFY_SOCKET`, if systemd set it
+    pub fn from_env() -> std::io::Result<Option<SystemdNotifier>> {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let path = match std::env::var("NOTIFY_SOCKET") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+
+        let for_us =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| for_us)
+            .map(Duration::from_micros);
+
+        Ok(Some(SystemdNotifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            interval,
+        }))
+    }
+
+    /// Watchdog timeout configured by the service manager
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    pub fn notify(&self, state: &str) -> std::io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+/// Monitors the heartbeats of the traffic processing threads
+pub struct Watchdog {
+    heartbeats: Vec<Arc<Heartbeat>>,
+    threshold: Duration,
+    #[cfg(target_os = "linux")]
+    notifier: Option<SystemdNotifier>,
+}
+
+impl Watchdog {
+    pub fn new(heartbeats: Vec<Arc<Heartbeat>>, threshold: Duration) -> Watchdog {
+        Watchdog {
+            heartbeats,
+            threshold,
+            #[cfg(target_os = "linux")]
+            notifier: None,
+        }
+    }
+
+    /// Feeds the systemd watchdog while no thread is stalled
+    #[cfg(target_os = "linux")]
+    pub fn with_systemd(mut self, notifier: SystemdNotifier) -> Watchdog {
+        if let Some(interval) = notifier.watchdog_interval() {
+            self.threshold = self.threshold.min(interval);
+        }
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Checks every thread, logging the stalled ones. Returns whether all are healthy.
+    fn check(&self) -> bool {
+        let mut healthy = true;
+
+        for (i, heartbeat) in self.heartbeats.iter().enumerate() {
+            if let Some(busy) = heartbeat.busy_for().filter(|busy| *busy > self.threshold) {
+                warn!(
+                    "Event loop of thread {} stalled for {} ms ({} iterations completed, {} packets queued)",
+                    i,
+                    busy.as_millis(),
+                    heartbeat.iterations.load(Ordering::Relaxed),
+                    heartbeat.queue_len.load(Ordering::Relaxed)
+                );
+                healthy = false;
+            }
+        }
+
+        healthy
+    }
+
+    #[cfg(target_os = "linux")]
+    fn feed(&self) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(e) = notifier.notify("WATCHDOG=1") {
+                warn!("Could not feed the systemd watchdog: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn feed(&self) {}
+
+    /// Runs the watchdog in its own thread until `shutdown` is set
+    pub fn spawn(self, shutdown: Arc<AtomicBool>) -> std::io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name("watchdog".into())
+            .spawn(move || {
+                #[cfg(target_os = "linux")]
+                if let Some(notifier) = &self.notifier {
+                    if let Err(e) = notifier.notify("READY=1") {
+                        warn!("Could not notify systemd: {}", e);
+                    }
+                }
+
+                while !shutdown.load(Ordering::Relaxed) {
+                    thread::sleep(self.threshold / 2);
+
+                    if self.check() {
+                        self.feed();
+                    }
+                }
+            })
+    }
+}